@@ -0,0 +1,542 @@
+// agent.rs
+
+// *************************************************************************
+// * Copyright (C) 2020 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+use std::env;
+use std::error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::io::BufRead as _;
+use std::io::Write as _;
+use std::mem;
+use std::os::unix::fs::MetadataExt as _;
+use std::os::unix::fs::PermissionsExt as _;
+use std::os::unix::io::AsRawFd as _;
+use std::os::unix::net;
+use std::path;
+use std::str;
+use std::thread;
+use std::time;
+
+use anyhow::Context as _;
+
+use crate::args;
+
+/// The interval in which the agent checks for new connections.
+const POLL_INTERVAL: time::Duration = time::Duration::from_millis(100);
+/// The time after which the agent gives up on a client that does not
+/// send or receive data.
+const CLIENT_TIMEOUT: time::Duration = time::Duration::from_secs(5);
+
+/// An error reported by the agent in response to a request.
+///
+/// The error carries the exit status the agent determined for the
+/// original error, so that the client exits as if it had handled the
+/// request itself.
+#[derive(Debug)]
+pub struct Error {
+  /// The exit status for the error.
+  pub status: i32,
+  /// The description of the error, including its causes.
+  pub message: String,
+}
+
+impl fmt::Display for Error {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(&self.message)
+  }
+}
+
+impl error::Error for Error {}
+
+/// A request sent to the agent.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Request {
+  /// Generate a one-time password, setting the time first for TOTP.
+  OtpGet {
+    slot: u8,
+    algorithm: args::OtpAlgorithm,
+    time: Option<u64>,
+  },
+  /// Write an OTP slot with a secret in hexadecimal representation.
+  OtpSet {
+    slot: u8,
+    algorithm: args::OtpAlgorithm,
+    name: String,
+    secret: String,
+    digits: args::OtpMode,
+    counter: u64,
+    time_window: u16,
+    use_enter: bool,
+    token_id: Option<String>,
+  },
+  /// Read the name, login, and password of a PWS slot.
+  PwsGet { slot: u8 },
+  /// Drop all sessions held by the agent.
+  Clear,
+}
+
+impl Request {
+  fn to_fields(&self) -> Vec<String> {
+    match self {
+      Request::OtpGet {
+        slot,
+        algorithm,
+        time,
+      } => vec![
+        "otp-get".to_string(),
+        slot.to_string(),
+        algorithm.to_string(),
+        time.map(|time| time.to_string()).unwrap_or_default(),
+      ],
+      Request::OtpSet {
+        slot,
+        algorithm,
+        name,
+        secret,
+        digits,
+        counter,
+        time_window,
+        use_enter,
+        token_id,
+      } => vec![
+        "otp-set".to_string(),
+        slot.to_string(),
+        algorithm.to_string(),
+        name.clone(),
+        secret.clone(),
+        digits.to_string(),
+        counter.to_string(),
+        time_window.to_string(),
+        use_enter.to_string(),
+        token_id.clone().unwrap_or_default(),
+      ],
+      Request::PwsGet { slot } => vec!["pws-get".to_string(), slot.to_string()],
+      Request::Clear => vec!["clear".to_string()],
+    }
+  }
+
+  fn from_fields(fields: &[String]) -> anyhow::Result<Self> {
+    fn parse<T>(field: &str) -> anyhow::Result<T>
+    where
+      T: str::FromStr,
+      T::Err: ToString,
+    {
+      field
+        .parse()
+        .map_err(|err: T::Err| anyhow::anyhow!(err.to_string()))
+        .with_context(|| format!("Invalid request field: {}", field))
+    }
+
+    let fields = fields.iter().map(String::as_str).collect::<Vec<_>>();
+    let request = match fields.as_slice() {
+      ["otp-get", slot, algorithm, time] => Request::OtpGet {
+        slot: parse(slot)?,
+        algorithm: parse(algorithm)?,
+        time: if time.is_empty() {
+          None
+        } else {
+          Some(parse(time)?)
+        },
+      },
+      ["otp-set", slot, algorithm, name, secret, digits, counter, time_window, use_enter, token_id] => {
+        Request::OtpSet {
+          slot: parse(slot)?,
+          algorithm: parse(algorithm)?,
+          name: (*name).to_string(),
+          secret: (*secret).to_string(),
+          digits: parse(digits)?,
+          counter: parse(counter)?,
+          time_window: parse(time_window)?,
+          use_enter: parse(use_enter)?,
+          token_id: if token_id.is_empty() {
+            None
+          } else {
+            Some((*token_id).to_string())
+          },
+        }
+      }
+      ["pws-get", slot] => Request::PwsGet { slot: parse(slot)? },
+      ["clear"] => Request::Clear,
+      _ => anyhow::bail!("Invalid request: {}", fields.join(" ")),
+    };
+    Ok(request)
+  }
+}
+
+/// Escape a field so that it does not contain any separators.
+fn escape(field: &str) -> String {
+  field
+    .replace('%', "%25")
+    .replace(' ', "%20")
+    .replace('\n', "%0A")
+    .replace('\r', "%0D")
+}
+
+/// Undo the escaping performed by `escape`.
+fn unescape(field: &str) -> anyhow::Result<String> {
+  let mut bytes = Vec::with_capacity(field.len());
+  let mut iter = field.bytes();
+  while let Some(byte) = iter.next() {
+    if byte == b'%' {
+      let hex = [
+        iter.next().unwrap_or_default(),
+        iter.next().unwrap_or_default(),
+      ];
+      let hex = str::from_utf8(&hex).unwrap_or_default();
+      let byte = u8::from_str_radix(hex, 16)
+        .with_context(|| format!("Invalid escape sequence: %{}", hex))?;
+      bytes.push(byte);
+    } else {
+      bytes.push(byte);
+    }
+  }
+  String::from_utf8(bytes).context("Invalid UTF-8 data found")
+}
+
+/// Encode the given fields as a single line.
+fn encode(fields: &[String]) -> String {
+  fields
+    .iter()
+    .map(String::as_str)
+    .map(escape)
+    .collect::<Vec<_>>()
+    .join(" ")
+}
+
+/// Decode a line created by `encode` into its fields.
+fn decode(line: &str) -> anyhow::Result<Vec<String>> {
+  line
+    .trim_end_matches(&['\n', '\r'][..])
+    .split(' ')
+    .map(unescape)
+    .collect()
+}
+
+/// Retrieve the default path of the socket the agent listens on.
+///
+/// The socket is located in a directory only accessible by the current
+/// user.
+pub fn socket_path() -> path::PathBuf {
+  let dir = match env::var_os("XDG_RUNTIME_DIR") {
+    Some(dir) => path::PathBuf::from(dir).join("nitrocli"),
+    None => env::temp_dir().join(format!("nitrocli-{}", uid())),
+  };
+  dir.join("agent.sock")
+}
+
+/// Retrieve the ID of the current user.
+fn uid() -> libc::uid_t {
+  // SAFETY: `getuid` is always successful.
+  unsafe { libc::getuid() }
+}
+
+/// Check whether the given directory is owned by the current user and
+/// only accessible by them.
+fn is_private_dir(dir: &path::Path) -> io::Result<bool> {
+  let metadata = fs::symlink_metadata(dir)?;
+  Ok(metadata.is_dir() && metadata.uid() == uid() && metadata.mode() & 0o777 == 0o700)
+}
+
+/// Check whether the process on the other end of the given stream
+/// belongs to the current user.
+fn is_own_peer(stream: &net::UnixStream) -> io::Result<bool> {
+  let mut cred = libc::ucred {
+    pid: 0,
+    uid: 0,
+    gid: 0,
+  };
+  // `size_of` is only part of the prelude in newer Rust versions.
+  #[allow(unused_qualifications)]
+  let mut len = mem::size_of::<libc::ucred>() as libc::socklen_t;
+  // SAFETY: The buffer and its length describe a valid `ucred` object.
+  let result = unsafe {
+    libc::getsockopt(
+      stream.as_raw_fd(),
+      libc::SOL_SOCKET,
+      libc::SO_PEERCRED,
+      &mut cred as *mut libc::ucred as *mut libc::c_void,
+      &mut len,
+    )
+  };
+  if result != 0 {
+    Err(io::Error::last_os_error())
+  } else {
+    Ok(cred.uid == uid())
+  }
+}
+
+/// Send a request to the agent listening on the given socket and return
+/// the response fields.
+///
+/// If no agent is running, `None` is returned. Another user may have
+/// created the socket to intercept our requests, so we treat a socket
+/// outside of a private directory of ours, or one provided by a process
+/// of another user, as if no agent was running.
+pub fn request(path: &path::Path, request: &Request) -> anyhow::Result<Option<Vec<String>>> {
+  match path.parent().map(is_private_dir) {
+    Some(Ok(true)) => (),
+    _ => return Ok(None),
+  }
+
+  let mut stream = match net::UnixStream::connect(path) {
+    Ok(stream) => stream,
+    Err(err)
+      if err.kind() == io::ErrorKind::NotFound
+        || err.kind() == io::ErrorKind::ConnectionRefused =>
+    {
+      return Ok(None)
+    }
+    Err(err) => return Err(err).context("Failed to connect to the agent"),
+  };
+  if !is_own_peer(&stream).context("Failed to check the credentials of the agent")? {
+    return Ok(None);
+  }
+
+  writeln!(stream, "{}", encode(&request.to_fields())).context("Failed to send request")?;
+
+  let mut line = String::new();
+  let _ = io::BufReader::new(stream)
+    .read_line(&mut line)
+    .context("Failed to read response")?;
+  parse_response(&line).map(Some)
+}
+
+/// Parse a response of the agent, turning an error response into an
+/// `Error`.
+fn parse_response(line: &str) -> anyhow::Result<Vec<String>> {
+  let mut fields = decode(line).context("Failed to parse response")?;
+  match fields.first().map(String::as_str) {
+    Some("OK") => Ok(fields.split_off(1)),
+    Some("ERR") if fields.len() == 3 => {
+      let status = fields[1]
+        .parse()
+        .map_err(|_| anyhow::anyhow!("Unexpected response: {}", line.trim_end()))?;
+      Err(anyhow::Error::new(Error {
+        status,
+        message: fields.pop().unwrap_or_default(),
+      }))
+    }
+    _ => anyhow::bail!("Unexpected response: {}", line.trim_end()),
+  }
+}
+
+/// Create the given socket for the agent to listen on.
+fn bind(path: &path::Path) -> anyhow::Result<net::UnixListener> {
+  if let Some(dir) = path.parent() {
+    fs::create_dir_all(dir)
+      .with_context(|| format!("Failed to create directory {}", dir.display()))?;
+    fs::set_permissions(dir, fs::Permissions::from_mode(0o700))
+      .with_context(|| format!("Failed to set permissions of {}", dir.display()))?;
+    let private = is_private_dir(dir)
+      .with_context(|| format!("Failed to check permissions of {}", dir.display()))?;
+    if !private {
+      anyhow::bail!(
+        "Directory {} is not private to the current user",
+        dir.display()
+      )
+    }
+  }
+
+  if path.exists() {
+    if net::UnixStream::connect(path).is_ok() {
+      anyhow::bail!("An agent is already listening on {}", path.display())
+    }
+    // The socket is a leftover of an agent that did not exit cleanly.
+    fs::remove_file(path)
+      .with_context(|| format!("Failed to remove stale socket {}", path.display()))?;
+  }
+
+  net::UnixListener::bind(path).with_context(|| format!("Failed to bind socket {}", path.display()))
+}
+
+/// Read a single request from the given client, handle it, and send
+/// back the response.
+fn serve_client<H>(stream: net::UnixStream, handle: &mut H) -> anyhow::Result<()>
+where
+  H: FnMut(Request) -> anyhow::Result<Vec<String>>,
+{
+  if !is_own_peer(&stream)? {
+    anyhow::bail!("Rejecting client of another user")
+  }
+
+  stream.set_nonblocking(false)?;
+  stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+  stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+
+  let mut line = String::new();
+  let _ = io::BufReader::new(&stream).read_line(&mut line)?;
+
+  let response = decode(&line)
+    .and_then(|fields| Request::from_fields(&fields))
+    .and_then(handle);
+  let fields = match response {
+    Ok(fields) => Some("OK".to_string()).into_iter().chain(fields).collect(),
+    Err(err) => vec![
+      "ERR".to_string(),
+      crate::exit_status(&err).to_string(),
+      format!("{:#}", err),
+    ],
+  };
+
+  let mut stream = stream;
+  writeln!(stream, "{}", encode(&fields))?;
+  Ok(())
+}
+
+/// Serve requests on the given socket using the given handler.
+///
+/// Once no request was received for the given timeout, the handler is
+/// invoked with a `Request::Clear`. The function only returns if an
+/// error occurs.
+pub fn serve<H>(path: &path::Path, timeout: time::Duration, mut handle: H) -> anyhow::Result<()>
+where
+  H: FnMut(Request) -> anyhow::Result<Vec<String>>,
+{
+  let listener = bind(path)?;
+  listener
+    .set_nonblocking(true)
+    .context("Failed to configure socket")?;
+
+  let mut last_request = None;
+  loop {
+    match listener.accept() {
+      Ok((stream, _)) => {
+        // Errors concern only the one client and must not bring down
+        // the agent.
+        let _ = serve_client(stream, &mut handle);
+        last_request = Some(time::Instant::now());
+      }
+      Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+        if let Some(instant) = last_request {
+          if instant.elapsed() >= timeout {
+            let _ = handle(Request::Clear)?;
+            last_request = None;
+          }
+        }
+        thread::sleep(POLL_INTERVAL);
+      }
+      Err(err) => return Err(err).context("Failed to accept connection"),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn field_escaping() {
+    let fields = vec![
+      "otp-set".to_string(),
+      "100% sure".to_string(),
+      "".to_string(),
+      "line\nbreak".to_string(),
+    ];
+    let line = encode(&fields);
+    assert_eq!(line, "otp-set 100%25%20sure  line%0Abreak");
+    assert_eq!(decode(&(line + "\n")).unwrap(), fields);
+    assert!(decode("%zz").is_err());
+  }
+
+  #[test]
+  fn request_roundtrip() {
+    let requests = vec![
+      Request::OtpGet {
+        slot: 1,
+        algorithm: args::OtpAlgorithm::Totp,
+        time: Some(1_400_000_000),
+      },
+      Request::OtpGet {
+        slot: 0,
+        algorithm: args::OtpAlgorithm::Hotp,
+        time: None,
+      },
+      Request::OtpSet {
+        slot: 2,
+        algorithm: args::OtpAlgorithm::Hotp,
+        name: "my name".to_string(),
+        secret: "3132333435".to_string(),
+        digits: args::OtpMode::EightDigits,
+        counter: 5,
+        time_window: 30,
+        use_enter: false,
+        token_id: None,
+      },
+      Request::OtpSet {
+        slot: 1,
+        algorithm: args::OtpAlgorithm::Totp,
+        name: "other".to_string(),
+        secret: "3132333435".to_string(),
+        digits: args::OtpMode::SixDigits,
+        counter: 0,
+        time_window: 60,
+        use_enter: true,
+        token_id: Some("NKPR12345678".to_string()),
+      },
+      Request::PwsGet { slot: 3 },
+      Request::Clear,
+    ];
+
+    for request in requests {
+      let fields = decode(&encode(&request.to_fields())).unwrap();
+      assert_eq!(Request::from_fields(&fields).unwrap(), request);
+    }
+  }
+
+  #[test]
+  fn invalid_request() {
+    let fields = vec!["pws-get".to_string(), "foo".to_string()];
+    let err = Request::from_fields(&fields).unwrap_err();
+    assert_eq!(err.to_string(), "Invalid request field: foo");
+
+    let fields = vec!["foo".to_string()];
+    let err = Request::from_fields(&fields).unwrap_err();
+    assert_eq!(err.to_string(), "Invalid request: foo");
+  }
+
+  #[test]
+  fn error_status() {
+    let (mut client, server) = net::UnixStream::pair().unwrap();
+    writeln!(
+      client,
+      "{}",
+      encode(&Request::PwsGet { slot: 3 }.to_fields())
+    )
+    .unwrap();
+    serve_client(server, &mut |_| {
+      Err(nitrokey::Error::CommandError(
+        nitrokey::CommandError::SlotNotProgrammed,
+      ))
+      .context("Failed to access PWS slot")
+    })
+    .unwrap();
+
+    let mut line = String::new();
+    let _ = io::BufReader::new(client).read_line(&mut line).unwrap();
+    let err = parse_response(&line).unwrap_err();
+    assert_eq!(crate::exit_status(&err), 5);
+    assert_eq!(
+      err.to_string(),
+      "Failed to access PWS slot: Command error: The given slot is not programmed"
+    );
+
+    assert!(parse_response("ERR foo bar\n").is_err());
+  }
+}
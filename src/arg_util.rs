@@ -34,13 +34,14 @@ macro_rules! tr {
 
 macro_rules! Command {
   ( $(#[$docs:meta])* $name:ident, [
-    $( $(#[$doc:meta])* $var:ident$(($inner:ty))? => $exec:expr, ) *
+    $( $(#[doc = $doc:literal])* $(#[cfg($cfg:meta)])? $var:ident$(($inner:ty))? => $exec:expr, ) *
   ] ) => {
     $(#[$docs])*
     #[derive(Debug, PartialEq, structopt::StructOpt)]
     pub enum $name {
       $(
-        $(#[$doc])*
+        $(#[doc = $doc])*
+        $(#[cfg($cfg)])?
         $var$(($inner))?,
       )*
     }
@@ -53,6 +54,7 @@ macro_rules! Command {
       ) -> anyhow::Result<()> {
         match self {
           $(
+            $(#[cfg($cfg)])?
             $name::$var$((tr!(args, $inner)))? => $exec(ctx $(,tr!(args, $inner))?),
           )*
         }
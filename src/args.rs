@@ -27,6 +27,12 @@ pub struct Args {
   /// Selects the device model to connect to
   #[structopt(short, long, global = true, possible_values = &DeviceModel::all_str())]
   pub model: Option<DeviceModel>,
+  /// Runs the command on all attached devices
+  #[structopt(long, global = true)]
+  pub all_devices: bool,
+  /// Uses PINs from the environment even if only one attempt is left
+  #[structopt(long, global = true)]
+  pub force: bool,
   #[structopt(subcommand)]
   pub cmd: Command,
 }
@@ -60,35 +66,115 @@ impl From<DeviceModel> for nitrokey::Model {
 Command! {
   /// A top-level command for nitrocli.
   Command, [
+    /// Runs an agent holding device sessions for other invocations
+    Agent(AgentArgs) => |ctx, args: AgentArgs| crate::commands::agent(ctx, args.timeout),
+    /// Acts as an askpass program backed by the password safe
+    Askpass(AskpassArgs) => |ctx, args: AskpassArgs| {
+      crate::commands::askpass(ctx, &args.prompt, args.rules.as_deref())
+    },
+    /// Interacts with the audit log of state-changing operations
+    AuditLog(AuditLogArgs) => |ctx, args: AuditLogArgs| args.subcmd.execute(ctx),
     /// Reads or writes the device configuration
     Config(ConfigArgs) => |ctx, args: ConfigArgs| args.subcmd.execute(ctx),
     /// Interacts with the device's encrypted volume
     Encrypted(EncryptedArgs) => |ctx, args: EncryptedArgs| args.subcmd.execute(ctx),
+    /// Interacts with the firmware of a Nitrokey Storage
+    Firmware(FirmwareArgs) => |ctx, args: FirmwareArgs| args.subcmd.execute(ctx),
+    /// Acts as a git credential helper backed by the password safe
+    GitCredential(GitCredentialArgs) => |ctx, args: GitCredentialArgs| {
+      crate::commands::git_credential(ctx, args.action, args.write)
+    },
     /// Interacts with the device's hidden volume
     Hidden(HiddenArgs) => |ctx, args: HiddenArgs| args.subcmd.execute(ctx),
     /// Lists the attached Nitrokey devices
-    List(ListArgs) => |ctx, args: ListArgs| crate::commands::list(ctx, args.no_connect),
+    List(ListArgs) => |ctx, args: ListArgs| {
+      crate::commands::list(ctx, args.no_connect, args.wink, args.format.as_deref())
+    },
     /// Locks the connected Nitrokey device
     Lock => crate::commands::lock,
+    /// Watches for attached and detached Nitrokey devices
+    Monitor(MonitorArgs) => |ctx, args: MonitorArgs| {
+      crate::commands::monitor(ctx, args.interval, args.poll)
+    },
+    /// Acts as a native messaging host for browser extensions
+    NativeMessaging(NativeMessagingArgs) => |ctx, args: NativeMessagingArgs| {
+      args.subcmd.execute(ctx)
+    },
     /// Accesses one-time passwords
     Otp(OtpArgs) => |ctx, args: OtpArgs| args.subcmd.execute(ctx),
     /// Manages the Nitrokey PINs
     Pin(PinArgs) => |ctx, args: PinArgs| args.subcmd.execute(ctx),
+    /// Provisions the device according to a manifest
+    Provision(ProvisionArgs) => |ctx, args: ProvisionArgs| {
+      crate::commands::provision(ctx, &args.manifest, args.dry_run)
+    },
     /// Accesses the password safe
     Pws(PwsArgs) => |ctx, args: PwsArgs| args.subcmd.execute(ctx),
     /// Performs a factory reset
-    Reset => crate::commands::reset,
+    Reset(ResetArgs) => crate::commands::reset,
     /// Prints the status of the connected Nitrokey device
-    Status => crate::commands::status,
+    Status(StatusArgs) => |ctx, args: StatusArgs| {
+      crate::commands::status(ctx, args.format.as_deref())
+    },
+    /// Provides a Secret Service backed by the password safe
+    #[cfg(feature = "secret-service")]
+    SecretService(SecretServiceArgs) => |ctx, args: SecretServiceArgs| {
+      crate::commands::secret_service(ctx, args.address.as_deref())
+    },
+    /// Interacts with the device's storage area
+    Storage(StorageArgs) => |ctx, args: StorageArgs| args.subcmd.execute(ctx),
     /// Interacts with the device's unencrypted volume
     Unencrypted(UnencryptedArgs) => |ctx, args: UnencryptedArgs| args.subcmd.execute(ctx),
+    /// Accesses a password vault on the host unlocked by a PWS slot
+    Vault(VaultArgs) => |ctx, args: VaultArgs| args.subcmd.execute(ctx),
+    /// Lets the connected Nitrokey Storage blink its LEDs
+    Wink => crate::commands::wink,
   ]
 }
 
+#[derive(Debug, PartialEq, structopt::StructOpt)]
+pub struct AgentArgs {
+  /// The number of idle seconds after which sessions are dropped
+  #[structopt(short, long, default_value = "300")]
+  pub timeout: u64,
+}
+
+#[derive(Debug, PartialEq, structopt::StructOpt)]
+pub struct AskpassArgs {
+  /// The file containing the rules mapping prompts to PWS slots
+  #[structopt(short, long)]
+  pub rules: Option<std::path::PathBuf>,
+  /// The prompt text of the requesting program
+  pub prompt: String,
+}
+
+#[derive(Debug, PartialEq, structopt::StructOpt)]
+pub struct AuditLogArgs {
+  #[structopt(subcommand)]
+  subcmd: AuditLogCommand,
+}
+
+Command! {AuditLogCommand, [
+  /// Verifies that the audit log has not been tampered with
+  Verify(AuditLogVerifyArgs) => |ctx, args: AuditLogVerifyArgs| {
+    crate::commands::audit_log_verify(ctx, args.file.as_deref(), args.head.as_deref())
+  },
+]}
+
+#[derive(Debug, PartialEq, structopt::StructOpt)]
+pub struct AuditLogVerifyArgs {
+  /// The audit log file to verify instead of the configured one
+  #[structopt(short, long)]
+  pub file: Option<std::path::PathBuf>,
+  /// The hash of the last entry reported by an earlier verification
+  #[structopt(long)]
+  pub head: Option<String>,
+}
+
 #[derive(Debug, PartialEq, structopt::StructOpt)]
 pub struct ConfigArgs {
   #[structopt(subcommand)]
-  subcmd: ConfigCommand,
+  pub subcmd: ConfigCommand,
 }
 
 Command! {ConfigCommand, [
@@ -98,7 +184,8 @@ Command! {ConfigCommand, [
   Set(ConfigSetArgs) => crate::commands::config_set,
 ]}
 
-#[derive(Debug, PartialEq, structopt::StructOpt)]
+#[derive(Debug, Default, PartialEq, serde::Deserialize, structopt::StructOpt)]
+#[serde(default, deny_unknown_fields)]
 pub struct ConfigSetArgs {
   /// Sets the numlock option to the given HOTP slot
   #[structopt(short = "n", long)]
@@ -172,6 +259,45 @@ Command! {EncryptedCommand, [
   Open => crate::commands::encrypted_open,
 ]}
 
+#[derive(Debug, PartialEq, structopt::StructOpt)]
+pub struct FirmwareArgs {
+  #[structopt(subcommand)]
+  subcmd: FirmwareCommand,
+}
+
+Command! {FirmwareCommand, [
+  /// Exports the firmware image of a Nitrokey Storage
+  Export(FirmwareExportArgs) => |ctx, args: FirmwareExportArgs| {
+    crate::commands::firmware_export(ctx, args.path.as_deref(), args.compare.as_deref())
+  },
+]}
+
+#[derive(Debug, PartialEq, structopt::StructOpt)]
+pub struct FirmwareExportArgs {
+  /// Compares the exported image against the given known-good image
+  #[structopt(short, long)]
+  pub compare: Option<std::path::PathBuf>,
+  /// The path to copy the exported image to
+  #[structopt(required_unless("compare"))]
+  pub path: Option<std::path::PathBuf>,
+}
+
+#[derive(Debug, PartialEq, structopt::StructOpt)]
+pub struct GitCredentialArgs {
+  /// Allows the store and erase actions to modify the password safe
+  #[structopt(short, long)]
+  pub write: bool,
+  /// The action requested by git
+  #[structopt(possible_values = &GitCredentialAction::all_str())]
+  pub action: GitCredentialAction,
+}
+
+Enum! {GitCredentialAction, [
+  Erase => "erase",
+  Get => "get",
+  Store => "store",
+]}
+
 #[derive(Debug, PartialEq, structopt::StructOpt)]
 pub struct HiddenArgs {
   #[structopt(subcommand)]
@@ -204,12 +330,79 @@ pub struct ListArgs {
   /// Only print the information that is available without connecting to a device
   #[structopt(short, long)]
   pub no_connect: bool,
+  /// Lets each device blink its LEDs in turn while its information is printed
+  #[structopt(short, long, conflicts_with("no-connect"))]
+  pub wink: bool,
+  /// Formats each device using a template with the fields path, model, and serial
+  #[structopt(long, value_name = "template")]
+  pub format: Option<String>,
 }
 
+#[derive(Debug, PartialEq, structopt::StructOpt)]
+pub struct MonitorArgs {
+  /// The number of seconds between two checks when polling
+  #[structopt(short, long, default_value = "1")]
+  pub interval: u64,
+  /// Polls for changes instead of listening for udev events
+  #[structopt(short, long)]
+  pub poll: bool,
+}
+
+#[derive(Debug, PartialEq, structopt::StructOpt)]
+pub struct NativeMessagingArgs {
+  #[structopt(subcommand)]
+  subcmd: NativeMessagingCommand,
+}
+
+Command! {NativeMessagingCommand, [
+  /// Serves requests of a browser extension on the standard input
+  Host(NativeMessagingHostArgs) => |ctx, args: NativeMessagingHostArgs| {
+    crate::commands::native_messaging_host(ctx, args.rules.as_deref(), &args.args)
+  },
+  /// Installs the native messaging host manifest for a browser
+  Install(NativeMessagingInstallArgs) => |ctx, args: NativeMessagingInstallArgs| {
+    crate::commands::native_messaging_install(
+      ctx,
+      args.browser,
+      args.rules.as_deref(),
+      args.dir.as_deref(),
+    )
+  },
+]}
+
+#[derive(Debug, PartialEq, structopt::StructOpt)]
+pub struct NativeMessagingHostArgs {
+  /// The file containing the rules for the allowed extensions
+  #[structopt(short, long)]
+  pub rules: Option<std::path::PathBuf>,
+  /// The arguments passed by the browser
+  pub args: Vec<String>,
+}
+
+#[derive(Debug, PartialEq, structopt::StructOpt)]
+pub struct NativeMessagingInstallArgs {
+  /// The file containing the rules for the allowed extensions
+  #[structopt(short, long)]
+  pub rules: Option<std::path::PathBuf>,
+  /// The directory to install the manifest to instead of the browser's
+  /// default directory
+  #[structopt(short, long)]
+  pub dir: Option<std::path::PathBuf>,
+  /// The browser to install the manifest for
+  #[structopt(possible_values = &Browser::all_str())]
+  pub browser: Browser,
+}
+
+Enum! {Browser, [
+  Chrome => "chrome",
+  Chromium => "chromium",
+  Firefox => "firefox",
+]}
+
 #[derive(Debug, PartialEq, structopt::StructOpt)]
 pub struct OtpArgs {
   #[structopt(subcommand)]
-  subcmd: OtpCommand,
+  pub subcmd: OtpCommand,
 }
 
 Command! {OtpCommand, [
@@ -219,12 +412,14 @@ Command! {OtpCommand, [
   },
   /// Generates a one-time password
   Get(OtpGetArgs) => |ctx, args: OtpGetArgs| {
-    crate::commands::otp_get(ctx, args.slot, args.algorithm, args.time)
+    crate::commands::otp_get(ctx, args.slot, args.algorithm, args.time, args.format.as_deref())
   },
   /// Configures a one-time password slot
   Set(OtpSetArgs) => crate::commands::otp_set,
   /// Prints the status of the one-time password slots
-  Status(OtpStatusArgs) => |ctx, args: OtpStatusArgs| crate::commands::otp_status(ctx, args.all),
+  Status(OtpStatusArgs) => |ctx, args: OtpStatusArgs| {
+    crate::commands::otp_status(ctx, args.all, args.format.as_deref())
+  },
 ]}
 
 #[derive(Debug, PartialEq, structopt::StructOpt)]
@@ -246,6 +441,9 @@ pub struct OtpGetArgs {
   /// The time to use for TOTP generation (Unix timestamp) [default: system time]
   #[structopt(short, long)]
   pub time: Option<u64>,
+  /// Formats the output using a template with the fields code, alg, slot, and time
+  #[structopt(long, value_name = "template")]
+  pub format: Option<String>,
   /// The OTP slot to use
   pub slot: u8,
 }
@@ -270,6 +468,13 @@ pub struct OtpSetArgs {
   #[structopt(short, long, default_value = OtpSecretFormat::Base32.as_ref(),
               possible_values = &OtpSecretFormat::all_str())]
   pub format: OtpSecretFormat,
+  /// Presses Enter after sending the one-time password using double-pressed numlock, capslock, or
+  /// scrollock
+  #[structopt(long)]
+  pub use_enter: bool,
+  /// The OATH token identifier to set, consisting of twelve alphanumeric characters
+  #[structopt(long, value_name = "id")]
+  pub token_id: Option<String>,
   /// The OTP slot to use
   pub slot: u8,
   /// The name of the slot
@@ -284,6 +489,9 @@ pub struct OtpStatusArgs {
   /// Shows slots that are not programmed
   #[structopt(short, long)]
   pub all: bool,
+  /// Formats each slot using a template with the fields alg, slot, name, and programmed
+  #[structopt(long, value_name = "template")]
+  pub format: Option<String>,
 }
 
 Enum! {OtpAlgorithm, [
@@ -308,13 +516,14 @@ impl From<OtpMode> for nitrokey::OtpMode {
 Enum! {OtpSecretFormat, [
   Ascii => "ascii",
   Base32 => "base32",
+  Base64 => "base64",
   Hex => "hex",
 ]}
 
 #[derive(Debug, PartialEq, structopt::StructOpt)]
 pub struct PinArgs {
   #[structopt(subcommand)]
-  subcmd: PinCommand,
+  pub subcmd: PinCommand,
 }
 
 Command! {PinCommand, [
@@ -344,6 +553,15 @@ pub struct PinSetArgs {
   pub pintype: PinType,
 }
 
+#[derive(Debug, PartialEq, structopt::StructOpt)]
+pub struct ProvisionArgs {
+  /// Only prints the changes that would be applied
+  #[structopt(short, long)]
+  pub dry_run: bool,
+  /// The manifest describing the desired device state
+  pub manifest: std::path::PathBuf,
+}
+
 #[derive(Debug, PartialEq, structopt::StructOpt)]
 pub struct PwsArgs {
   #[structopt(subcommand)]
@@ -351,20 +569,62 @@ pub struct PwsArgs {
 }
 
 Command! {PwsCommand, [
+  /// Checks the passwords in the password safe for weaknesses
+  Audit(PwsAuditArgs) => |ctx, args: PwsAuditArgs| {
+    crate::commands::pws_audit(ctx, args.min_entropy, args.breached.as_deref())
+  },
   /// Clears a password safe slot
   Clear(PwsClearArgs) => |ctx, args: PwsClearArgs| crate::commands::pws_clear(ctx, args.slot),
+  /// Executes a command with environment variables set from password safe slots
+  Exec(PwsExecArgs) => |ctx, args: PwsExecArgs| {
+    crate::commands::pws_exec(ctx, &args.env, &args.command)
+  },
+  /// Exports all programmed password safe slots
+  Export(PwsExportArgs) => |ctx, args: PwsExportArgs| {
+    crate::commands::pws_export(
+      ctx,
+      args.format,
+      args.output.as_deref(),
+      args.encrypt_to.as_deref(),
+      args.allow_plaintext,
+    )
+  },
   /// Reads a password safe slot
   Get(PwsGetArgs) => |ctx, args: PwsGetArgs| {
-    crate::commands::pws_get(ctx, args.slot, args.name, args.login, args.password, args.quiet)
+    crate::commands::pws_get(
+      ctx,
+      args.slot,
+      args.name,
+      args.login,
+      args.password,
+      args.quiet,
+      args.format.as_deref(),
+    )
+  },
+  /// Imports entries from another password manager
+  Import(PwsImportArgs) => |ctx, args: PwsImportArgs| {
+    crate::commands::pws_import(ctx, args.format, &args.path, args.dry_run, args.yes)
   },
   /// Writes a password safe slot
   Set(PwsSetArgs) => |ctx, args: PwsSetArgs| {
     crate::commands::pws_set(ctx, args.slot, &args.name, &args.login, &args.password)
   },
   /// Prints the status of the password safe slots
-  Status(PwsStatusArgs) => |ctx, args: PwsStatusArgs| crate::commands::pws_status(ctx, args.all),
+  Status(PwsStatusArgs) => |ctx, args: PwsStatusArgs| {
+    crate::commands::pws_status(ctx, args.all, args.format.as_deref())
+  },
 ]}
 
+#[derive(Debug, PartialEq, structopt::StructOpt)]
+pub struct PwsAuditArgs {
+  /// The minimum estimated entropy of a password in bits
+  #[structopt(long, default_value = "40", value_name = "bits")]
+  pub min_entropy: f64,
+  /// A file with SHA-1 hashes or hash prefixes of breached passwords
+  #[structopt(short, long, value_name = "file")]
+  pub breached: Option<std::path::PathBuf>,
+}
+
 #[derive(Debug, PartialEq, structopt::StructOpt)]
 pub struct PwsClearArgs {
   /// The PWS slot to clear
@@ -385,10 +645,71 @@ pub struct PwsGetArgs {
   /// Prints the stored data without description
   #[structopt(short, long)]
   pub quiet: bool,
+  /// Formats the output using a template with the fields slot, name, login, and password
+  #[structopt(
+    long,
+    value_name = "template",
+    conflicts_with_all(&["name", "login", "password", "quiet"])
+  )]
+  pub format: Option<String>,
   /// The PWS slot to read
   pub slot: u8,
 }
 
+#[derive(Debug, PartialEq, structopt::StructOpt)]
+pub struct PwsExecArgs {
+  /// Sets an environment variable to the name, login, or password of a slot
+  #[structopt(
+    short,
+    long,
+    value_name = "var=slot:field",
+    number_of_values = 1,
+    required = true
+  )]
+  pub env: Vec<String>,
+  /// The command to execute and its arguments
+  #[structopt(required = true)]
+  pub command: Vec<String>,
+}
+
+#[derive(Debug, PartialEq, structopt::StructOpt)]
+pub struct PwsExportArgs {
+  /// The format to export to
+  #[structopt(short, long, possible_values = &PwsFormat::all_str())]
+  pub format: PwsFormat,
+  /// The file to write to, or the password store directory for pass
+  #[structopt(short, long)]
+  pub output: Option<std::path::PathBuf>,
+  /// Encrypts the export for the given gpg recipient
+  #[structopt(short, long, value_name = "recipient")]
+  pub encrypt_to: Option<String>,
+  /// Writes plaintext passwords to a terminal or world-readable file
+  #[structopt(long)]
+  pub allow_plaintext: bool,
+}
+
+#[derive(Debug, PartialEq, structopt::StructOpt)]
+pub struct PwsImportArgs {
+  /// The format of the file to import
+  #[structopt(short, long, possible_values = &PwsFormat::all_str())]
+  pub format: PwsFormat,
+  /// Only prints the planned slot assignment
+  #[structopt(short, long, conflicts_with("yes"))]
+  pub dry_run: bool,
+  /// Skips the confirmation of the import
+  #[structopt(short, long)]
+  pub yes: bool,
+  /// The file to import, or the password store directory for pass
+  pub path: std::path::PathBuf,
+}
+
+Enum! {PwsFormat, [
+  BitwardenJson => "bitwarden-json",
+  Csv => "csv",
+  KeepassXml => "keepass-xml",
+  Pass => "pass",
+]}
+
 #[derive(Debug, PartialEq, structopt::StructOpt)]
 pub struct PwsSetArgs {
   /// The PWS slot to write
@@ -406,8 +727,53 @@ pub struct PwsStatusArgs {
   /// Shows slots that are not programmed
   #[structopt(short, long)]
   pub all: bool,
+  /// Formats each slot using a template with the fields slot, name, and programmed
+  #[structopt(long, value_name = "template")]
+  pub format: Option<String>,
 }
 
+#[derive(Debug, PartialEq, structopt::StructOpt)]
+pub struct ResetArgs {
+  /// Skips the confirmation of the factory reset
+  #[structopt(short, long)]
+  pub yes: bool,
+  /// Only prints the data that would be erased by the factory reset
+  #[structopt(short, long, conflicts_with_all(&["yes", "set-pins"]))]
+  pub dry_run: bool,
+  /// Sets new admin and user PINs right after the factory reset
+  #[structopt(short = "p", long)]
+  pub set_pins: bool,
+}
+
+#[cfg(feature = "secret-service")]
+#[derive(Debug, PartialEq, structopt::StructOpt)]
+pub struct SecretServiceArgs {
+  /// The address of the bus to connect to instead of the session bus
+  #[structopt(short, long)]
+  pub address: Option<String>,
+}
+
+#[derive(Debug, PartialEq, structopt::StructOpt)]
+pub struct StatusArgs {
+  /// Formats the output using a template with the fields model, serial, fw,
+  /// user_retries, and admin_retries
+  #[structopt(long, value_name = "template")]
+  pub format: Option<String>,
+}
+
+#[derive(Debug, PartialEq, structopt::StructOpt)]
+pub struct StorageArgs {
+  #[structopt(subcommand)]
+  subcmd: StorageCommand,
+}
+
+Command! {StorageCommand, [
+  /// Clears the new SD card warning on a Nitrokey Storage
+  ClearWarning => crate::commands::storage_clear_warning,
+  /// Prints production and SD card information of a Nitrokey Storage
+  Info => crate::commands::storage_info,
+]}
+
 #[derive(Debug, PartialEq, structopt::StructOpt)]
 pub struct UnencryptedArgs {
   #[structopt(subcommand)]
@@ -432,3 +798,96 @@ Enum! {UnencryptedVolumeMode, [
   ReadWrite => "read-write",
   ReadOnly => "read-only",
 ]}
+
+#[derive(Debug, PartialEq, structopt::StructOpt)]
+pub struct VaultArgs {
+  #[structopt(subcommand)]
+  subcmd: VaultCommand,
+}
+
+Command! {VaultCommand, [
+  /// Adds an entry to the vault
+  Add(VaultAddArgs) => |ctx, args: VaultAddArgs| {
+    crate::commands::vault_add(ctx, args.file.as_deref(), &args.name, &args.login, &args.password)
+  },
+  /// Reads an entry from the vault
+  Get(VaultGetArgs) => |ctx, args: VaultGetArgs| {
+    crate::commands::vault_get(
+      ctx,
+      args.file.as_deref(),
+      &args.name,
+      args.login,
+      args.password,
+      args.quiet,
+    )
+  },
+  /// Creates a new vault with its key stored in a PWS slot
+  Init(VaultInitArgs) => |ctx, args: VaultInitArgs| {
+    crate::commands::vault_init(ctx, args.file.as_deref(), args.slot)
+  },
+  /// Lists the names of the entries in the vault
+  List(VaultListArgs) => |ctx, args: VaultListArgs| {
+    crate::commands::vault_list(ctx, args.file.as_deref())
+  },
+  /// Removes an entry from the vault
+  Rm(VaultRmArgs) => |ctx, args: VaultRmArgs| {
+    crate::commands::vault_rm(ctx, args.file.as_deref(), &args.name)
+  },
+]}
+
+#[derive(Debug, PartialEq, structopt::StructOpt)]
+pub struct VaultAddArgs {
+  /// The vault file to use
+  #[structopt(short, long)]
+  pub file: Option<std::path::PathBuf>,
+  /// The name of the entry
+  pub name: String,
+  /// The login of the entry
+  pub login: String,
+  /// The password of the entry
+  pub password: String,
+}
+
+#[derive(Debug, PartialEq, structopt::StructOpt)]
+pub struct VaultGetArgs {
+  /// The vault file to use
+  #[structopt(short, long)]
+  pub file: Option<std::path::PathBuf>,
+  /// Shows the login of the entry
+  #[structopt(short, long)]
+  pub login: bool,
+  /// Shows the password of the entry
+  #[structopt(short, long)]
+  pub password: bool,
+  /// Prints the stored data without description
+  #[structopt(short, long)]
+  pub quiet: bool,
+  /// The name of the entry to read
+  pub name: String,
+}
+
+#[derive(Debug, PartialEq, structopt::StructOpt)]
+pub struct VaultInitArgs {
+  /// The vault file to create
+  #[structopt(short, long)]
+  pub file: Option<std::path::PathBuf>,
+  /// The PWS slot to store the key in (default: the first free slot)
+  #[structopt(short, long)]
+  pub slot: Option<u8>,
+}
+
+#[derive(Debug, PartialEq, structopt::StructOpt)]
+pub struct VaultListArgs {
+  /// The vault file to use
+  #[structopt(short, long)]
+  pub file: Option<std::path::PathBuf>,
+}
+
+#[derive(Debug, PartialEq, structopt::StructOpt)]
+pub struct VaultRmArgs {
+  /// The vault file to use
+  #[structopt(short, long)]
+  pub file: Option<std::path::PathBuf>,
+  /// The name of the entry to remove
+  pub name: String,
+}
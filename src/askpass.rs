@@ -0,0 +1,148 @@
+// askpass.rs
+
+// *************************************************************************
+// * Copyright (C) 2020 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+use std::env;
+use std::fs;
+use std::path;
+
+use anyhow::Context as _;
+
+use crate::provision;
+
+/// A rule mapping askpass prompts to a PWS slot.
+#[derive(Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+  /// The regular expression the prompt has to match.
+  #[serde(deserialize_with = "provision::from_str")]
+  pub prompt: regex::Regex,
+  /// The PWS slot storing the password for matching prompts.
+  pub slot: u8,
+}
+
+/// The rules used for answering askpass prompts.
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Rules {
+  /// The rules, in the order in which they are evaluated.
+  #[serde(rename = "rule")]
+  pub rules: Vec<Rule>,
+}
+
+impl Rules {
+  /// Parse rules from the given TOML data.
+  pub fn parse(content: &str) -> anyhow::Result<Self> {
+    toml::from_str(content).map_err(anyhow::Error::from)
+  }
+
+  /// Load the rules from the given file or, if no file is given, from
+  /// the default location.
+  ///
+  /// A missing rules file in the default location is treated as an
+  /// empty set of rules.
+  pub fn load(path: Option<&path::Path>) -> anyhow::Result<Self> {
+    load(path, "askpass.toml", "askpass rules", Self::parse)
+  }
+
+  /// Find the PWS slot for the given prompt, using the first matching
+  /// rule.
+  pub fn find(&self, prompt: &str) -> Option<u8> {
+    self
+      .rules
+      .iter()
+      .find(|rule| rule.prompt.is_match(prompt))
+      .map(|rule| rule.slot)
+  }
+}
+
+/// Retrieve the default location of the configuration file with the
+/// given name.
+pub fn default_path(name: &str) -> Option<path::PathBuf> {
+  let config = env::var_os("XDG_CONFIG_HOME")
+    .map(path::PathBuf::from)
+    .or_else(|| env::var_os("HOME").map(|home| path::PathBuf::from(home).join(".config")))?;
+  Some(config.join("nitrocli").join(name))
+}
+
+/// Load rules using the given parser from the given file or, if no file
+/// is given, from the configuration file with the given name in the
+/// default location.
+///
+/// A missing file in the default location is treated as the default
+/// set of rules. `kind` describes the rules in error messages.
+pub fn load<R>(
+  path: Option<&path::Path>,
+  name: &str,
+  kind: &str,
+  parse: fn(&str) -> anyhow::Result<R>,
+) -> anyhow::Result<R>
+where
+  R: Default,
+{
+  let path = match path {
+    Some(path) => path.to_path_buf(),
+    None => match default_path(name) {
+      Some(path) if path.exists() => path,
+      _ => return Ok(R::default()),
+    },
+  };
+
+  let content = fs::read_to_string(&path)
+    .with_context(|| format!("Failed to read {} {}", kind, path.display()))?;
+  parse(&content).with_context(|| format!("Failed to parse {} {}", kind, path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn find_slot() {
+    let rules = Rules::parse(
+      r#"
+[[rule]]
+prompt = "^Enter passphrase for key '.*/id_ed25519': $"
+slot = 3
+
+[[rule]]
+prompt = "(?i)password"
+slot = 5
+"#,
+    )
+    .unwrap();
+
+    let prompt = "Enter passphrase for key '/home/john/.ssh/id_ed25519': ";
+    assert_eq!(rules.find(prompt), Some(3));
+    assert_eq!(rules.find("[sudo] Password for john: "), Some(5));
+    assert_eq!(rules.find("Enter PIN for 'PIV Card':"), None);
+  }
+
+  #[test]
+  fn parse_empty() {
+    let rules = Rules::parse("").unwrap();
+    assert_eq!(rules.find("password"), None);
+  }
+
+  #[test]
+  fn parse_invalid() {
+    assert!(Rules::parse("[[rule]]\nprompt = \"(\"\nslot = 0\n").is_err());
+    assert!(Rules::parse("[[rule]]\nprompt = \"foo\"\n").is_err());
+    assert!(Rules::parse("[[rule]]\nprompt = \"foo\"\nslot = 0\nname = \"bar\"\n").is_err());
+  }
+}
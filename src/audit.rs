@@ -0,0 +1,245 @@
+// audit.rs
+
+// *************************************************************************
+// * Copyright (C) 2020 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+use std::collections;
+use std::fmt;
+use std::io;
+
+use anyhow::Context as _;
+
+use crate::import::Entry;
+use crate::policy;
+
+/// A problem with the password stored in a PWS slot.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Issue {
+  /// The password has less than the required entropy, in bits.
+  Weak(u32),
+  /// The password is also stored in the given other slots.
+  Duplicate(Vec<u8>),
+  /// The password is equal to the login.
+  EqualsLogin,
+  /// The password is equal to the name.
+  EqualsName,
+  /// The password is contained in the list of breached passwords.
+  Breached,
+}
+
+impl fmt::Display for Issue {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Issue::Weak(bits) => write!(f, "weak password (about {} bits of entropy)", bits),
+      Issue::Duplicate(slots) => {
+        let slots = slots
+          .iter()
+          .map(ToString::to_string)
+          .collect::<Vec<_>>()
+          .join(", ");
+        write!(f, "password reused in slot(s) {}", slots)
+      }
+      Issue::EqualsLogin => f.write_str("password equals login"),
+      Issue::EqualsName => f.write_str("password equals name"),
+      Issue::Breached => f.write_str("password found in breached password list"),
+    }
+  }
+}
+
+/// Calculate the upper case hexadecimal SHA-1 hash of the given
+/// password, as used by breached password lists.
+fn sha1(password: &str) -> String {
+  use sha1::Digest as _;
+
+  sha1::Sha1::digest(password.as_bytes())
+    .iter()
+    .map(|byte| format!("{:02X}", byte))
+    .collect()
+}
+
+/// Find the slots whose password is contained in the given list of
+/// breached password hashes.
+///
+/// Each line of the list contains a full hexadecimal SHA-1 hash,
+/// optionally followed by a colon and an occurrence count, as in the
+/// downloadable lists of Have I Been Pwned. Empty lines and lines
+/// starting with `#` are ignored. A password matches if its hash is
+/// listed.
+fn find_breached<R>(entries: &[(u8, Entry)], list: R) -> anyhow::Result<collections::HashSet<u8>>
+where
+  R: io::BufRead,
+{
+  let hashes = entries
+    .iter()
+    .map(|(slot, entry)| (*slot, sha1(&entry.password)))
+    .collect::<Vec<_>>();
+  let mut breached = collections::HashSet::new();
+
+  for (index, line) in list.lines().enumerate() {
+    let line = line.context("Failed to read breached password list")?;
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+      continue;
+    }
+
+    let hash = line.split(':').next().unwrap_or(line).trim().to_uppercase();
+    if hash.len() != 40 || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+      anyhow::bail!(
+        "Invalid SHA-1 hash in line {} of breached password list",
+        index + 1
+      )
+    }
+    for (slot, _) in hashes.iter().filter(|(_, h)| h == &hash) {
+      let _ = breached.insert(*slot);
+    }
+  }
+  Ok(breached)
+}
+
+/// Audit the given PWS entries.
+///
+/// Passwords with less than `min_entropy` bits of estimated entropy
+/// are reported as weak. If a breached password list is provided,
+/// passwords contained in it are reported as well. The result contains
+/// the issues found, ordered by slot.
+pub fn audit<R>(
+  entries: &[(u8, Entry)],
+  min_entropy: f64,
+  breached_list: Option<R>,
+) -> anyhow::Result<Vec<(u8, Issue)>>
+where
+  R: io::BufRead,
+{
+  let breached = match breached_list {
+    Some(list) => find_breached(entries, list)?,
+    None => collections::HashSet::new(),
+  };
+
+  let mut issues = Vec::new();
+  for (slot, entry) in entries {
+    let entropy = policy::entropy(&entry.password);
+    if entropy < min_entropy {
+      issues.push((*slot, Issue::Weak(entropy as u32)));
+    }
+
+    let duplicates = entries
+      .iter()
+      .filter(|(other, other_entry)| other != slot && other_entry.password == entry.password)
+      .map(|(other, _)| *other)
+      .collect::<Vec<_>>();
+    if !duplicates.is_empty() {
+      issues.push((*slot, Issue::Duplicate(duplicates)));
+    }
+
+    if !entry.login.is_empty() && entry.password == entry.login {
+      issues.push((*slot, Issue::EqualsLogin));
+    }
+    if !entry.name.is_empty() && entry.password == entry.name {
+      issues.push((*slot, Issue::EqualsName));
+    }
+    if breached.contains(slot) {
+      issues.push((*slot, Issue::Breached));
+    }
+  }
+  Ok(issues)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn entry(slot: u8, name: &str, login: &str, password: &str) -> (u8, Entry) {
+    let entry = Entry {
+      name: name.to_string(),
+      login: login.to_string(),
+      password: password.to_string(),
+    };
+    (slot, entry)
+  }
+
+  #[test]
+  fn hash() {
+    assert_eq!(sha1("password"), "5BAA61E4C9B93F3F0682250B6CF8331B7EE68FD8");
+  }
+
+  #[test]
+  fn audit_entries() {
+    let entries = vec![
+      entry(0, "mail", "john", "Xk4#pQ9!vL2@"),
+      entry(2, "web", "jane", "Xk4#pQ9!vL2@"),
+      entry(3, "login", "login", "login"),
+      entry(5, "Tr0ub4dor&3", "", "Tr0ub4dor&3"),
+      entry(7, "bank", "john", "c0rrect-H0rse"),
+    ];
+    let issues = audit::<&[u8]>(&entries, 40.0, None).unwrap();
+    assert_eq!(
+      issues,
+      vec![
+        (0, Issue::Duplicate(vec![2])),
+        (2, Issue::Duplicate(vec![0])),
+        (3, Issue::Weak(23)),
+        (3, Issue::EqualsLogin),
+        (3, Issue::EqualsName),
+        (5, Issue::EqualsName),
+      ]
+    );
+  }
+
+  #[test]
+  fn audit_breached() {
+    let entries = vec![
+      entry(1, "a", "", "password"),
+      entry(2, "b", "", "Xk4#pQ9!vL2@"),
+      entry(3, "c", "", "c0rrect-H0rse"),
+    ];
+    let list = format!(
+      "# comment\n\n5baa61e4c9b93f3f0682250b6cf8331b7ee68fd8:3730471\n{}\n",
+      sha1("c0rrect-H0rse")
+    );
+    let issues = audit(&entries, 0.0, Some(list.as_bytes())).unwrap();
+    assert_eq!(issues, vec![(1, Issue::Breached), (3, Issue::Breached)]);
+
+    let list = format!("{}\n{}:1\n", sha1("a"), "x".repeat(40));
+    let err = audit(&entries, 0.0, Some(list.as_bytes())).unwrap_err();
+    assert_eq!(
+      err.to_string(),
+      "Invalid SHA-1 hash in line 2 of breached password list"
+    );
+
+    // Hash prefixes would match passwords without any evidence.
+    for len in &[5, 35, 39] {
+      let list = format!("{}\n", &sha1("password")[..*len]);
+      let err = audit(&entries, 0.0, Some(list.as_bytes())).unwrap_err();
+      assert_eq!(
+        err.to_string(),
+        "Invalid SHA-1 hash in line 1 of breached password list"
+      );
+    }
+  }
+
+  #[test]
+  fn display_issues() {
+    assert_eq!(
+      Issue::Weak(12).to_string(),
+      "weak password (about 12 bits of entropy)"
+    );
+    assert_eq!(
+      Issue::Duplicate(vec![1, 4]).to_string(),
+      "password reused in slot(s) 1, 4"
+    );
+  }
+}
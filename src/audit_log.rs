@@ -0,0 +1,331 @@
+// audit_log.rs
+
+// *************************************************************************
+// * Copyright (C) 2020 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+use std::env;
+use std::fs;
+use std::io;
+use std::io::Read as _;
+use std::io::Write as _;
+use std::os::unix::fs::OpenOptionsExt as _;
+use std::os::unix::io::AsRawFd as _;
+use std::path;
+use std::time;
+
+use anyhow::Context as _;
+
+use crate::config;
+use crate::ExecCtx;
+
+/// The hash preceding the first entry of the log.
+const INITIAL_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// An entry of the audit log.
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+#[serde(deny_unknown_fields)]
+struct Entry {
+  /// The time the operation finished, in RFC 3339 format.
+  time: String,
+  /// The name of the user performing the operation.
+  user: String,
+  /// The ID of the user performing the operation.
+  uid: u32,
+  /// The model of the device.
+  model: String,
+  /// The serial number of the device.
+  serial: String,
+  /// The operation, without any secrets.
+  command: String,
+  /// The outcome of the operation.
+  outcome: String,
+}
+
+/// Retrieve the path of the audit log configured in the configuration
+/// directory, if the audit log is enabled.
+pub fn configured_path(config_dir: Option<&path::Path>) -> anyhow::Result<Option<path::PathBuf>> {
+  let config = config::Config::load(config_dir)?;
+  Ok(config.audit_log.map(|audit_log| audit_log.path))
+}
+
+/// Format the given Unix timestamp as an RFC 3339 date and time in UTC.
+fn format_time(secs: u64) -> String {
+  // Convert the number of days since the epoch into a civil date, see
+  // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+  let days = secs / 86400;
+  let z = days + 719_468;
+  let era = z / 146_097;
+  let doe = z - era * 146_097;
+  let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+  let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+  let mp = (5 * doy + 2) / 153;
+  let day = doy - (153 * mp + 2) / 5 + 1;
+  let month = if mp < 10 { mp + 3 } else { mp - 9 };
+  let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+  let secs = secs % 86400;
+  format!(
+    "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+    year,
+    month,
+    day,
+    secs / 3600,
+    secs % 3600 / 60,
+    secs % 60
+  )
+}
+
+/// Retrieve the name of the current user.
+fn user_name(uid: u32) -> String {
+  env::var("USER")
+    .or_else(|_| env::var("LOGNAME"))
+    .unwrap_or_else(|_| uid.to_string())
+}
+
+/// Calculate the hash of an entry given the hash of its predecessor.
+fn chain_hash(prev: &str, data: &str) -> String {
+  use sha2::Digest as _;
+
+  let mut hasher = sha2::Sha256::new();
+  hasher.update(prev.as_bytes());
+  hasher.update(data.as_bytes());
+  hasher
+    .finalize()
+    .iter()
+    .map(|byte| format!("{:02x}", byte))
+    .collect()
+}
+
+/// Split a line of the log into the hash and the data of the entry.
+fn split_line(line: &str) -> Option<(&str, &str)> {
+  let idx = line.find(' ')?;
+  let (hash, data) = (&line[..idx], &line[idx + 1..]);
+  if hash.len() == INITIAL_HASH.len() && hash.chars().all(|c| c.is_ascii_hexdigit()) {
+    Some((hash, data))
+  } else {
+    None
+  }
+}
+
+/// Verify the hash chain of the given log content, returning the number
+/// of entries and the hash of the last one.
+///
+/// The hash chain cannot reveal the removal of entries from the end of
+/// the log. If `head` is set to the hash of the last entry reported by
+/// an earlier verification, the log is additionally required to still
+/// contain that entry.
+pub fn verify(content: &str, head: Option<&str>) -> anyhow::Result<(usize, String)> {
+  let mut prev = INITIAL_HASH;
+  let mut count = 0;
+  let mut found = head.is_none() || head == Some(INITIAL_HASH);
+  for (idx, line) in content.lines().enumerate() {
+    let (hash, data) = split_line(line)
+      .filter(|(_, data)| serde_json::from_str::<Entry>(data).is_ok())
+      .ok_or_else(|| anyhow::anyhow!("Entry {} of the audit log is malformed", idx + 1))?;
+    if hash != chain_hash(prev, data) {
+      anyhow::bail!("Entry {} of the audit log has been tampered with", idx + 1);
+    }
+    prev = hash;
+    count += 1;
+    if let Some(head) = head {
+      found = found || head.eq_ignore_ascii_case(hash);
+    }
+  }
+  if !found {
+    anyhow::bail!(
+      "The audit log does not contain the expected head entry; entries have been removed"
+    )
+  }
+  Ok((count, prev.to_string()))
+}
+
+/// Append an entry to the log at the given path.
+fn append(path: &path::Path, entry: &Entry) -> anyhow::Result<()> {
+  if let Some(dir) = path.parent() {
+    fs::create_dir_all(dir)
+      .with_context(|| format!("Failed to create directory {}", dir.display()))?;
+  }
+
+  let mut file = fs::OpenOptions::new()
+    .read(true)
+    .append(true)
+    .create(true)
+    .mode(0o600)
+    .open(path)
+    .with_context(|| format!("Failed to open audit log {}", path.display()))?;
+  // Prevent concurrent invocations from forking the hash chain. The lock
+  // is released when the file is closed.
+  // SAFETY: The file descriptor is valid for the lifetime of `file`.
+  if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+    return Err(io::Error::last_os_error())
+      .with_context(|| format!("Failed to lock audit log {}", path.display()));
+  }
+
+  let mut content = String::new();
+  let _ = file
+    .read_to_string(&mut content)
+    .with_context(|| format!("Failed to read audit log {}", path.display()))?;
+  let prev = match content.lines().last() {
+    Some(line) => {
+      split_line(line)
+        .ok_or_else(|| {
+          anyhow::anyhow!(
+            "The last entry of audit log {} is malformed",
+            path.display()
+          )
+        })?
+        .0
+    }
+    None => INITIAL_HASH,
+  };
+
+  let data = serde_json::to_string(entry).context("Failed to serialize audit log entry")?;
+  let line = format!("{} {}\n", chain_hash(prev, &data), data);
+  file
+    .write_all(line.as_bytes())
+    .with_context(|| format!("Failed to write audit log {}", path.display()))
+}
+
+/// A state-changing operation that is recorded once it has finished.
+#[derive(Debug)]
+pub struct Record {
+  path: path::PathBuf,
+  model: String,
+  serial: String,
+  command: String,
+}
+
+impl Record {
+  /// Start recording the given operation on the given device, if the
+  /// audit log is enabled.
+  pub fn begin<'mgr, D>(
+    ctx: &ExecCtx<'_>,
+    device: &D,
+    command: &str,
+  ) -> anyhow::Result<Option<Self>>
+  where
+    D: nitrokey::Device<'mgr>,
+  {
+    let path = match configured_path(ctx.config_dir.as_deref())? {
+      Some(path) => path,
+      None => return Ok(None),
+    };
+    let model = device.get_model();
+    let serial = device
+      .get_serial_number()
+      .context("Failed to retrieve serial number")?;
+
+    Ok(Some(Self {
+      path,
+      model: model.to_string(),
+      serial: serial.to_string(),
+      command: command.to_string(),
+    }))
+  }
+
+  /// Record the operation along with the given outcome.
+  pub fn finish<T>(self, result: &anyhow::Result<T>) -> anyhow::Result<()> {
+    let time = time::SystemTime::now()
+      .duration_since(time::UNIX_EPOCH)
+      .context("Current system time is before the Unix epoch")?;
+    // SAFETY: `getuid` is always successful.
+    let uid = unsafe { libc::getuid() };
+    let outcome = match result {
+      Ok(_) => "success".to_string(),
+      Err(err) => format!("failure: {:#}", err),
+    };
+    let entry = Entry {
+      time: format_time(time.as_secs()),
+      user: user_name(uid),
+      uid,
+      model: self.model,
+      serial: self.serial,
+      command: self.command,
+      outcome,
+    };
+    append(&self.path, &entry)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn entry(command: &str) -> Entry {
+    Entry {
+      time: format_time(0),
+      user: "john".to_string(),
+      uid: 1000,
+      model: "Nitrokey Pro".to_string(),
+      serial: "0x12345678".to_string(),
+      command: command.to_string(),
+      outcome: "success".to_string(),
+    }
+  }
+
+  #[test]
+  fn format_times() {
+    assert_eq!(format_time(0), "1970-01-01T00:00:00Z");
+    assert_eq!(format_time(951_782_400), "2000-02-29T00:00:00Z");
+    assert_eq!(format_time(1_111_111_111), "2005-03-18T01:58:31Z");
+    assert_eq!(format_time(4_102_444_799), "2099-12-31T23:59:59Z");
+  }
+
+  #[test]
+  fn append_verify() {
+    let dir = env::temp_dir().join(format!("nitrocli-audit-log-{}", std::process::id()));
+    let path = dir.join("audit.log");
+
+    append(&path, &entry("pws set 1")).unwrap();
+    append(&path, &entry("pin set user")).unwrap();
+    append(&path, &entry("reset")).unwrap();
+
+    let content = fs::read_to_string(&path).unwrap();
+    assert_eq!(content.lines().count(), 3);
+    let head = &content.lines().last().unwrap()[..INITIAL_HASH.len()];
+    assert_eq!(verify(&content, None).unwrap(), (3, head.to_string()));
+    assert_eq!(verify(&content, Some(head)).unwrap(), (3, head.to_string()));
+    assert_eq!(verify("", None).unwrap(), (0, INITIAL_HASH.to_string()));
+
+    let truncated = content.lines().take(2).collect::<Vec<_>>().join("\n");
+    let (count, _) = verify(&truncated, None).unwrap();
+    assert_eq!(count, 2);
+    let err = verify(&truncated, Some(head)).unwrap_err().to_string();
+    assert_eq!(
+      err,
+      "The audit log does not contain the expected head entry; entries have been removed"
+    );
+
+    let modified = content.replace("pin set user", "pin set admin");
+    let err = verify(&modified, None).unwrap_err().to_string();
+    assert_eq!(err, "Entry 2 of the audit log has been tampered with");
+
+    let removed = content
+      .lines()
+      .enumerate()
+      .filter(|(idx, _)| *idx != 1)
+      .map(|(_, line)| format!("{}\n", line))
+      .collect::<String>();
+    let err = verify(&removed, None).unwrap_err().to_string();
+    assert_eq!(err, "Entry 2 of the audit log has been tampered with");
+
+    let err = verify("foobar\n", None).unwrap_err().to_string();
+    assert_eq!(err, "Entry 1 of the audit log is malformed");
+
+    fs::remove_dir_all(&dir).unwrap();
+  }
+}
@@ -17,9 +17,15 @@
 // * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
 // *************************************************************************
 
+use std::cmp;
 use std::convert::TryFrom as _;
 use std::fmt;
+use std::fs;
+use std::io;
 use std::mem;
+use std::os::unix::fs::PermissionsExt as _;
+use std::path;
+use std::process;
 use std::thread;
 use std::time;
 use std::u8;
@@ -28,13 +34,29 @@ use anyhow::Context as _;
 
 use libc::sync;
 
+use nitrokey::Authenticate as _;
 use nitrokey::ConfigureOtp;
 use nitrokey::Device;
 use nitrokey::GenerateOtp;
 use nitrokey::GetPasswordSafe;
 
+use crate::agent;
 use crate::args;
+use crate::askpass;
+use crate::audit;
+use crate::audit_log;
+use crate::config;
+use crate::credential;
+use crate::export;
+use crate::import;
+use crate::monitor;
+use crate::native;
 use crate::pinentry;
+use crate::provision;
+#[cfg(feature = "secret-service")]
+use crate::secret;
+use crate::template;
+use crate::vault;
 use crate::ExecCtx;
 
 /// Set `libnitrokey`'s log level based on the execution context's verbosity.
@@ -63,11 +85,14 @@ where
 
   set_log_level(ctx);
 
-  let device = match ctx.model {
-    Some(model) => manager.connect_model(model.into()).with_context(|| {
+  let device = match (&ctx.path, ctx.model) {
+    (Some(path), _) => manager
+      .connect_path(path.clone())
+      .context("Failed to connect to Nitrokey")?,
+    (None, Some(model)) => manager.connect_model(model.into()).with_context(|| {
       anyhow::anyhow!("Nitrokey {} device not found", model.as_user_facing_str())
     })?,
-    None => manager.connect().context("Nitrokey device not found")?,
+    (None, None) => manager.connect().context("Nitrokey device not found")?,
   };
 
   op(ctx, device)
@@ -95,25 +120,85 @@ where
   op(ctx, device)
 }
 
+/// Perform a state-changing operation on the given device, recording it
+/// along with its outcome in the audit log, if enabled.
+fn audited<'mgr, D, F>(ctx: &mut ExecCtx<'_>, device: D, command: &str, op: F) -> anyhow::Result<()>
+where
+  D: Device<'mgr>,
+  F: FnOnce(&mut ExecCtx<'_>, D) -> anyhow::Result<()>,
+{
+  let record = audit_log::Record::begin(ctx, &device, command)?;
+  let result = op(ctx, device);
+  match record {
+    Some(record) => {
+      let logged = record
+        .finish(&result)
+        .context("Failed to write audit log entry");
+      result.and(logged)
+    }
+    None => result,
+  }
+}
+
+/// Connect to any Nitrokey device and perform a state-changing operation
+/// on it that is recorded in the audit log.
+fn with_audited_device<F>(ctx: &mut ExecCtx<'_>, command: &str, op: F) -> anyhow::Result<()>
+where
+  F: FnOnce(&mut ExecCtx<'_>, nitrokey::DeviceWrapper<'_>) -> anyhow::Result<()>,
+{
+  with_device(ctx, |ctx, device| audited(ctx, device, command, op))
+}
+
+/// Connect to a Nitrokey Storage device and perform a state-changing
+/// operation on it that is recorded in the audit log.
+fn with_audited_storage_device<F>(ctx: &mut ExecCtx<'_>, command: &str, op: F) -> anyhow::Result<()>
+where
+  F: FnOnce(&mut ExecCtx<'_>, nitrokey::Storage<'_>) -> anyhow::Result<()>,
+{
+  with_storage_device(ctx, |ctx, device| audited(ctx, device, command, op))
+}
+
+/// Retrieve a password safe handle for the given device and do something
+/// with it.
+fn use_password_safe<F>(
+  ctx: &mut ExecCtx<'_>,
+  mut device: nitrokey::DeviceWrapper<'_>,
+  mut op: F,
+) -> anyhow::Result<()>
+where
+  F: FnMut(&mut ExecCtx<'_>, nitrokey::PasswordSafe<'_, '_>) -> anyhow::Result<()>,
+{
+  let pin_entry = pinentry::PinEntry::from(args::PinType::User, &device)?;
+  try_with_pin_and_data(ctx, &pin_entry, (), move |ctx, _, pin| {
+    let pws = device.get_password_safe(pin).or_else(|err| {
+      Err(err)
+        .context("Could not access the password safe")
+        .map_err(|err| ((), err))
+    })?;
+
+    op(ctx, pws).map_err(|err| ((), err))
+  })
+}
+
 /// Connect to any Nitrokey device, retrieve a password safe handle, and
 /// do something with it.
-fn with_password_safe<F>(ctx: &mut ExecCtx<'_>, mut op: F) -> anyhow::Result<()>
+fn with_password_safe<F>(ctx: &mut ExecCtx<'_>, op: F) -> anyhow::Result<()>
 where
   F: FnMut(&mut ExecCtx<'_>, nitrokey::PasswordSafe<'_, '_>) -> anyhow::Result<()>,
 {
-  with_device(ctx, |ctx, mut device| {
-    let pin_entry = pinentry::PinEntry::from(args::PinType::User, &device)?;
-    try_with_pin_and_data(ctx, &pin_entry, (), move |ctx, _, pin| {
-      let pws = device.get_password_safe(pin).or_else(|err| {
-        Err(err)
-          .context("Could not access the password safe")
-          .map_err(|err| ((), err))
-      })?;
+  with_device(ctx, |ctx, device| use_password_safe(ctx, device, op))
+}
 
-      op(ctx, pws).map_err(|err| ((), err))
-    })
-  })?;
-  Ok(())
+/// Connect to any Nitrokey device, retrieve a password safe handle, and
+/// perform a state-changing operation on it that is recorded in the
+/// audit log.
+fn with_audited_password_safe<F>(ctx: &mut ExecCtx<'_>, command: &str, op: F) -> anyhow::Result<()>
+where
+  F: FnMut(&mut ExecCtx<'_>, nitrokey::PasswordSafe<'_, '_>) -> anyhow::Result<()>,
+{
+  with_audited_device(ctx, command, |ctx, device| {
+    use_password_safe(ctx, device, op)
+  })
 }
 
 /// Authenticate the given device using the given PIN type and operation.
@@ -179,6 +264,15 @@ fn get_volume_status(status: &nitrokey::VolumeStatus) -> &'static str {
   }
 }
 
+/// Format the number of remaining attempts for display to the user.
+fn format_attempts(retry_count: u8) -> String {
+  if retry_count == 1 {
+    "1 attempt left".to_string()
+  } else {
+    format!("{} attempts left", retry_count)
+  }
+}
+
 /// Try to execute the given function with a pin queried using pinentry.
 ///
 /// This function will query the pin of the given type from the user
@@ -187,7 +281,8 @@ fn get_volume_status(status: &nitrokey::VolumeStatus) -> &'static str {
 /// returns a `CommandError::WrongPassword`, the user will be asked
 /// again to enter the pin.  Otherwise, this function returns an error
 /// containing the given error message.  The user will have at most
-/// three tries to get the pin right.
+/// three tries to get the pin right, but we never use up the last
+/// remaining attempt of the device's retry counter by retrying.
 ///
 /// The data argument can be used to pass on data between the tries.  At
 /// the first try, this function will call `op` with `data`.  At the
@@ -203,20 +298,38 @@ where
   F: FnMut(&mut ExecCtx<'_>, D, &str) -> Result<R, (D, anyhow::Error)>,
 {
   let mut data = data;
-  let mut retry = 3;
-  let mut error_msg = None;
+  let mut retry_count = pin_entry.retry_count();
+  // Never retry with the last remaining attempt, unless it is the only
+  // one we have to begin with.
+  let mut retry = match retry_count {
+    0 | 1 => 1,
+    count => cmp::min(3, count - 1),
+  };
+  let mut error_msg = if retry_count < 3 {
+    // A cached PIN would be used without the user ever seeing the
+    // warning, so make sure that it is entered explicitly.
+    pinentry::clear(ctx, pin_entry).context("Failed to clear cached secret")?;
+    Some(format_attempts(retry_count))
+  } else {
+    None
+  };
+
   loop {
-    let pin = pinentry::inquire(ctx, pin_entry, pinentry::Mode::Query, error_msg)?;
+    let pin = pinentry::inquire(ctx, pin_entry, pinentry::Mode::Query, error_msg.as_deref())?;
     match op(ctx, data, &pin) {
       Ok(result) => return Ok(result),
       Err((new_data, err)) => match err.downcast::<nitrokey::Error>() {
         Ok(err) => match err {
           nitrokey::Error::CommandError(nitrokey::CommandError::WrongPassword) => {
-            pinentry::clear(pin_entry).context("Failed to clear cached secret")?;
+            pinentry::clear(ctx, pin_entry).context("Failed to clear cached secret")?;
             retry -= 1;
+            retry_count = retry_count.saturating_sub(1);
 
             if retry > 0 {
-              error_msg = Some("Wrong password, please reenter");
+              error_msg = Some(format!(
+                "Wrong password, {}, please reenter",
+                format_attempts(retry_count)
+              ));
               data = new_data;
               continue;
             }
@@ -231,7 +344,39 @@ where
 }
 
 /// Try to execute the given function with a PIN.
+///
+/// Before using the PIN, the remaining attempts for it are checked. A
+/// PIN provided through the environment is only used for the last
+/// remaining attempt if the `--force` option is set.
 fn try_with_pin_and_data<D, F, R>(
+  ctx: &mut ExecCtx<'_>,
+  pin_entry: &pinentry::PinEntry,
+  data: D,
+  op: F,
+) -> anyhow::Result<R>
+where
+  F: FnMut(&mut ExecCtx<'_>, D, &str) -> Result<R, (D, anyhow::Error)>,
+{
+  if pin_entry.retry_count() == 0 {
+    match pin_entry.pin_type() {
+      args::PinType::Admin => anyhow::bail!(
+        "The admin PIN is locked; reset the OpenPGP card using the factory-reset command of \
+         gpg --card-edit and then run the reset command to restore the device"
+      ),
+      args::PinType::User => {
+        anyhow::bail!("The user PIN is locked; use the pin unblock command to unblock it")
+      }
+    }
+  }
+  try_with_pin_and_data_unguarded(ctx, pin_entry, data, op)
+}
+
+/// Try to execute the given function with a PIN, even if the PIN is
+/// locked.
+///
+/// This function behaves exactly as `try_with_pin_and_data`, but it
+/// does not refuse to work with a locked PIN.
+fn try_with_pin_and_data_unguarded<D, F, R>(
   ctx: &mut ExecCtx<'_>,
   pin_entry: &pinentry::PinEntry,
   data: D,
@@ -240,7 +385,15 @@ fn try_with_pin_and_data<D, F, R>(
 where
   F: FnMut(&mut ExecCtx<'_>, D, &str) -> Result<R, (D, anyhow::Error)>,
 {
-  let pin = match pin_entry.pin_type() {
+  let pin_type = pin_entry.pin_type();
+  if pin_entry.retry_count() == 1 {
+    eprintln!(
+      ctx,
+      "Warning: Only one attempt is left for the {} PIN", pin_type
+    )?;
+  }
+
+  let pin = match pin_type {
     // Ideally we would not clone here, but that would require us to
     // restrict op to work with an immutable ExecCtx, which is not
     // possible given that some clients print data.
@@ -249,6 +402,14 @@ where
   };
 
   if let Some(pin) = pin {
+    if pin_entry.retry_count() == 1 && !ctx.force {
+      anyhow::bail!(
+        "Refusing to use the {} PIN from the environment with only one attempt left; \
+         use --force to try it anyway",
+        pin_type
+      );
+    }
+
     let pin = pin
       .to_str()
       .ok_or_else(|| anyhow::anyhow!("Failed to read PIN: Invalid Unicode data found"))?;
@@ -308,6 +469,47 @@ fn print_storage_status(
   Ok(())
 }
 
+/// The fields available for templates of the `status` command.
+const STATUS_FIELDS: &[&str] = &["model", "serial", "fw", "user_retries", "admin_retries"];
+
+/// Print the status of the given device using the given template.
+fn print_status_template(
+  ctx: &mut ExecCtx<'_>,
+  model: &'static str,
+  device: &nitrokey::DeviceWrapper<'_>,
+  template: &template::Template,
+) -> anyhow::Result<()> {
+  let output = template.render(|field| match field {
+    "model" => Ok(model.to_string()),
+    "serial" => Ok(
+      device
+        .get_serial_number()
+        .context("Could not query the serial number")?
+        .to_string(),
+    ),
+    "fw" => Ok(
+      device
+        .get_firmware_version()
+        .context("Failed to retrieve firmware version")?
+        .to_string(),
+    ),
+    "user_retries" => Ok(
+      device
+        .get_user_retry_count()
+        .context("Failed to retrieve user retry count")?
+        .to_string(),
+    ),
+    _ => Ok(
+      device
+        .get_admin_retry_count()
+        .context("Failed to retrieve admin retry count")?
+        .to_string(),
+    ),
+  })?;
+  println!(ctx, "{}", output)?;
+  Ok(())
+}
+
 /// Query and pretty print the status that is common to all Nitrokey devices.
 fn print_status(
   ctx: &mut ExecCtx<'_>,
@@ -351,172 +553,875 @@ fn print_status(
 }
 
 /// Inquire the status of the nitrokey.
-pub fn status(ctx: &mut ExecCtx<'_>) -> anyhow::Result<()> {
+pub fn status(ctx: &mut ExecCtx<'_>, format: Option<&str>) -> anyhow::Result<()> {
+  let template = format
+    .map(|format| template::Template::parse(format, STATUS_FIELDS))
+    .transpose()?;
   with_device(ctx, |ctx, device| {
     let model = match device {
       nitrokey::DeviceWrapper::Pro(_) => "Pro",
       nitrokey::DeviceWrapper::Storage(_) => "Storage",
     };
-    print_status(ctx, model, &device)
+    match &template {
+      Some(template) => print_status_template(ctx, model, &device, template),
+      None => print_status(ctx, model, &device),
+    }
+  })
+}
+
+/// Read a line from standard input, without the trailing newline.
+fn read_line(ctx: &mut ExecCtx<'_>) -> anyhow::Result<String> {
+  let mut line = Vec::new();
+  let mut byte = [0; 1];
+  // We read byte by byte in order to not consume any data past the end
+  // of the line.
+  while ctx
+    .stdin
+    .read(&mut byte)
+    .context("Failed to read from standard input")?
+    > 0
+  {
+    if byte[0] == b'\n' {
+      break;
+    }
+    line.push(byte[0]);
+  }
+  String::from_utf8(line).context("Failed to read line: Invalid UTF-8 data found")
+}
+
+/// Enumerate the attached Nitrokey devices.
+///
+/// Storage devices do not have the serial number present in the device
+/// information. Unless `no_connect` is set, we connect to them to
+/// retrieve it.
+fn enumerate_devices(
+  manager: &mut nitrokey::Manager,
+  no_connect: bool,
+) -> anyhow::Result<Vec<nitrokey::DeviceInfo>> {
+  let mut device_infos =
+    nitrokey::list_devices().context("Failed to list connected Nitrokey devices")?;
+
+  if !no_connect {
+    for device_info in &mut device_infos {
+      if device_info.serial_number.is_none() {
+        let device = manager
+          .connect_path(device_info.path.clone())
+          .context("Failed to connect to Nitrokey")?;
+        let serial_number = device
+          .get_serial_number()
+          .context("Failed to retrieve device serial number")?;
+        device_info.serial_number = Some(serial_number);
+      }
+    }
+  }
+  Ok(device_infos)
+}
+
+/// Let the given Nitrokey Storage device blink its LEDs until the user
+/// confirms to continue.
+fn wink_device(
+  ctx: &mut ExecCtx<'_>,
+  manager: &mut nitrokey::Manager,
+  device_info: &nitrokey::DeviceInfo,
+  wait: bool,
+) -> anyhow::Result<()> {
+  if device_info.model != Some(nitrokey::Model::Storage) {
+    eprintln!(ctx, "Device {} does not support winking", device_info.path)?;
+    return Ok(());
+  }
+
+  let device = manager
+    .connect_path(device_info.path.clone())
+    .context("Failed to connect to Nitrokey")?;
+  if let nitrokey::DeviceWrapper::Storage(mut device) = device {
+    device.wink().context("Failed to wink the device")?;
+  }
+
+  if wait {
+    eprintln!(ctx, "Press Enter to continue with the next device")?;
+    let _ = read_line(ctx)?;
+  }
+  Ok(())
+}
+
+/// Run the given monitor hook for the given event.
+///
+/// Failures of the hook are reported but do not stop the monitor.
+fn run_monitor_hook(
+  ctx: &mut ExecCtx<'_>,
+  hook: &config::Hook,
+  event: &monitor::Event,
+) -> anyhow::Result<()> {
+  let status = process::Command::new("sh")
+    .arg("-c")
+    .arg(&hook.command)
+    .env("NITROCLI_EVENT", event.kind.to_string())
+    .env("NITROCLI_DEVICE_PATH", &event.device.path)
+    .env("NITROCLI_DEVICE_MODEL", &event.device.model)
+    .env("NITROCLI_DEVICE_SERIAL", &event.device.serial)
+    .status();
+  match status {
+    Ok(status) if status.success() => (),
+    Ok(status) => eprintln!(ctx, "Hook {} failed: {}", hook.command, status)?,
+    Err(err) => eprintln!(ctx, "Failed to run hook {}: {}", hook.command, err)?,
+  }
+  Ok(())
+}
+
+/// Watch for attached and detached Nitrokey devices.
+pub fn monitor(ctx: &mut ExecCtx<'_>, interval: u64, poll: bool) -> anyhow::Result<()> {
+  if interval == 0 {
+    anyhow::bail!("The polling interval must be at least one second");
+  }
+  let config = config::Config::load(ctx.config_dir.as_deref())?;
+  set_log_level(ctx);
+
+  let netlink = if poll {
+    None
+  } else {
+    match monitor::Netlink::open() {
+      Ok(netlink) => Some(netlink),
+      Err(err) => {
+        eprintln!(
+          ctx,
+          "Failed to listen for udev events, polling instead: {}", err
+        )?;
+        None
+      }
+    }
+  };
+
+  let mut manager =
+    nitrokey::take().context("Failed to acquire access to Nitrokey device manager")?;
+  let interval = time::Duration::from_secs(interval);
+  let mut source = monitor::NitrokeySource::new(&mut manager, netlink, interval);
+  monitor::watch(&mut source, |event| {
+    let device = &event.device;
+    println!(
+      ctx,
+      "{}\t{}\t{}\t{}", event.kind, device.path, device.model, device.serial
+    )?;
+
+    let hooks = config
+      .monitor
+      .hooks
+      .iter()
+      .filter(|hook| hook.event.is_none() || hook.event == Some(event.kind));
+    for hook in hooks {
+      run_monitor_hook(ctx, hook, event)?;
+    }
+    Ok(())
   })
 }
 
+/// The fields available for templates of the `list` command.
+const LIST_FIELDS: &[&str] = &["path", "model", "serial"];
+
 /// List the attached Nitrokey devices.
-pub fn list(ctx: &mut ExecCtx<'_>, no_connect: bool) -> anyhow::Result<()> {
+pub fn list(
+  ctx: &mut ExecCtx<'_>,
+  no_connect: bool,
+  wink: bool,
+  format: Option<&str>,
+) -> anyhow::Result<()> {
+  let template = format
+    .map(|format| template::Template::parse(format, LIST_FIELDS))
+    .transpose()?;
   set_log_level(ctx);
 
-  let device_infos =
-    nitrokey::list_devices().context("Failed to list connected Nitrokey devices")?;
+  let mut manager =
+    nitrokey::take().context("Failed to acquire access to Nitrokey device manager")?;
+  let device_infos = enumerate_devices(&mut manager, no_connect)?;
   if device_infos.is_empty() {
-    println!(ctx, "No Nitrokey device connected")?;
+    if template.is_none() {
+      println!(ctx, "No Nitrokey device connected")?;
+    }
   } else {
-    println!(ctx, "device path\tmodel\tserial number")?;
-    let mut manager =
-      nitrokey::take().context("Failed to acquire access to Nitrokey device manager")?;
+    if template.is_none() {
+      println!(ctx, "device path\tmodel\tserial number")?;
+    }
 
-    for device_info in device_infos {
+    let count = device_infos.len();
+    for (i, device_info) in device_infos.iter().enumerate() {
       let model = device_info
         .model
         .map(|m| m.to_string())
         .unwrap_or_else(|| "unknown".into());
-      let serial_number = match device_info.serial_number {
-        Some(serial_number) => serial_number.to_string(),
-        None => {
-          // Storage devices do not have the serial number present in
-          // the device information. We have to connect to them to
-          // retrieve the information.
-          if no_connect {
-            "N/A".to_string()
-          } else {
-            let device = manager
-              .connect_path(device_info.path.clone())
-              .context("Failed to connect to Nitrokey")?;
-            device
-              .get_serial_number()
-              .context("Failed to retrieve device serial number")?
-              .to_string()
-          }
+      let serial_number = device_info
+        .serial_number
+        .map(|s| s.to_string())
+        .unwrap_or_else(|| "N/A".into());
+
+      match &template {
+        Some(template) => {
+          let output = template.render(|field| match field {
+            "path" => Ok(device_info.path.clone()),
+            "model" => Ok(model.clone()),
+            _ => Ok(serial_number.clone()),
+          })?;
+          println!(ctx, "{}", output)?;
         }
-      };
+        None => println!(ctx, "{}\t{}\t{}", device_info.path, model, serial_number)?,
+      }
 
-      println!(ctx, "{}\t{}\t{}", device_info.path, model, serial_number)?;
+      if wink {
+        // Make sure that the line is visible while the device blinks.
+        ctx.stdout.flush()?;
+        wink_device(ctx, &mut manager, device_info, i + 1 < count)?;
+      }
     }
   }
 
   Ok(())
 }
 
-/// Perform a factory reset.
-pub fn reset(ctx: &mut ExecCtx<'_>) -> anyhow::Result<()> {
-  with_device(ctx, |ctx, mut device| {
-    let pin_entry = pinentry::PinEntry::from(args::PinType::Admin, &device)?;
-
-    // To force the user to enter the admin PIN before performing a
-    // factory reset, we clear the pinentry cache for the admin PIN.
-    pinentry::clear(&pin_entry).context("Failed to clear cached secret")?;
+/// Send a request to the agent, if one is running.
+///
+/// The agent is not used if a specific device was selected.
+fn request_agent(
+  ctx: &ExecCtx<'_>,
+  request: &agent::Request,
+) -> anyhow::Result<Option<Vec<String>>> {
+  match &ctx.agent_socket {
+    Some(path) if ctx.model.is_none() && ctx.path.is_none() => {
+      agent::request(path, request).context("Failed to use the nitrocli agent")
+    }
+    _ => Ok(None),
+  }
+}
 
-    try_with_pin(ctx, &pin_entry, |pin| {
-      device
-        .factory_reset(&pin)
-        .context("Failed to reset to factory settings")?;
-      // Work around for a timing issue between factory_reset and
-      // build_aes_key, see
-      // https://github.com/Nitrokey/nitrokey-storage-firmware/issues/80
-      thread::sleep(time::Duration::from_secs(3));
-      // Another work around for spurious WrongPassword returns of
-      // build_aes_key after a factory reset on Pro devices.
-      // https://github.com/Nitrokey/nitrokey-pro-firmware/issues/57
-      let _ = device.get_user_retry_count();
-      device
-        .build_aes_key(nitrokey::DEFAULT_ADMIN_PIN)
-        .context("Failed to rebuild AES key")
-    })
-  })
+/// Tell the agent, if one is running, to drop its sessions.
+fn clear_agent(ctx: &ExecCtx<'_>) -> anyhow::Result<()> {
+  if let Some(path) = &ctx.agent_socket {
+    let _ =
+      agent::request(path, &agent::Request::Clear).context("Failed to clear the agent sessions")?;
+  }
+  Ok(())
 }
 
-/// Change the configuration of the unencrypted volume.
-pub fn unencrypted_set(
-  ctx: &mut ExecCtx<'_>,
-  mode: args::UnencryptedVolumeMode,
-) -> anyhow::Result<()> {
-  with_storage_device(ctx, |ctx, mut device| {
-    let pin_entry = pinentry::PinEntry::from(args::PinType::Admin, &device)?;
-    let mode = match mode {
-      args::UnencryptedVolumeMode::ReadWrite => nitrokey::VolumeMode::ReadWrite,
-      args::UnencryptedVolumeMode::ReadOnly => nitrokey::VolumeMode::ReadOnly,
-    };
+/// The device session held by the agent.
+enum AgentSession<'mgr> {
+  /// An unauthenticated device.
+  Device(nitrokey::DeviceWrapper<'mgr>),
+  /// A user session along with the user PIN, which is required for
+  /// accessing the password safe.
+  User(nitrokey::User<'mgr, nitrokey::DeviceWrapper<'mgr>>, String),
+  /// An admin session.
+  Admin(nitrokey::Admin<'mgr, nitrokey::DeviceWrapper<'mgr>>),
+}
 
-    // The unencrypted volume may reconnect, so be sure to flush caches to
-    // disk.
-    unsafe { sync() };
+impl<'mgr> AgentSession<'mgr> {
+  fn into_device(self) -> nitrokey::DeviceWrapper<'mgr> {
+    match self {
+      AgentSession::Device(device) => device,
+      AgentSession::User(user, _) => user.device(),
+      AgentSession::Admin(admin) => admin.device(),
+    }
+  }
 
-    try_with_pin(ctx, &pin_entry, |pin| {
-      device
-        .set_unencrypted_volume_mode(&pin, mode)
-        .context("Failed to change unencrypted volume mode")
-    })
-  })
+  fn device_mut(&mut self) -> &mut nitrokey::DeviceWrapper<'mgr> {
+    match self {
+      AgentSession::Device(device) => device,
+      AgentSession::User(user, _) => user,
+      AgentSession::Admin(admin) => admin,
+    }
+  }
 }
 
-/// Open the encrypted volume on the Nitrokey.
-pub fn encrypted_open(ctx: &mut ExecCtx<'_>) -> anyhow::Result<()> {
-  with_storage_device(ctx, |ctx, mut device| {
-    let pin_entry = pinentry::PinEntry::from(args::PinType::User, &device)?;
+/// Authenticate the given device for the agent.
+///
+/// In contrast to `authenticate`, the device is handed back if the
+/// authentication fails, so that the agent can continue to use it.
+fn authenticate_agent<'mgr, A, F>(
+  ctx: &mut ExecCtx<'_>,
+  device: nitrokey::DeviceWrapper<'mgr>,
+  pin_type: args::PinType,
+  mut op: F,
+) -> Result<(A, String), (nitrokey::DeviceWrapper<'mgr>, anyhow::Error)>
+where
+  F: FnMut(
+    nitrokey::DeviceWrapper<'mgr>,
+    &str,
+  ) -> Result<A, (nitrokey::DeviceWrapper<'mgr>, nitrokey::Error)>,
+{
+  let pin_entry = match pinentry::PinEntry::from(pin_type, &device) {
+    Ok(pin_entry) => pin_entry,
+    Err(err) => return Err((device, err)),
+  };
 
-    // We may forcefully close a hidden volume, if active, so be sure to
-    // flush caches to disk.
-    unsafe { sync() };
+  let mut device = Some(device);
+  let result = try_with_pin_and_data(ctx, &pin_entry, (), |_ctx, (), pin| {
+    // The device is only taken out for the duration of an attempt.
+    let dev = device.take().expect("device is always present");
+    match op(dev, pin) {
+      Ok(authenticated) => Ok((authenticated, pin.to_string())),
+      Err((dev, err)) => {
+        device = Some(dev);
+        Err((
+          (),
+          anyhow::Error::from(err).context(format!("Failed to authenticate as {}", pin_type)),
+        ))
+      }
+    }
+  });
 
-    try_with_pin(ctx, &pin_entry, |pin| {
-      device
-        .enable_encrypted_volume(&pin)
-        .context("Failed to open encrypted volume")
-    })
-  })
+  match result {
+    Ok(result) => Ok(result),
+    Err(err) => Err((device.expect("device is always returned on failure"), err)),
+  }
 }
 
-/// Close the previously opened encrypted volume.
-pub fn encrypted_close(ctx: &mut ExecCtx<'_>) -> anyhow::Result<()> {
-  with_storage_device(ctx, |_ctx, mut device| {
-    // Flush all filesystem caches to disk. We are mostly interested in
-    // making sure that the encrypted volume on the Nitrokey we are
-    // about to close is not closed while not all data was written to
-    // it.
-    unsafe { sync() };
+/// Make sure that the agent holds a user session.
+fn agent_user_session<'mgr>(
+  ctx: &mut ExecCtx<'_>,
+  session: AgentSession<'mgr>,
+) -> (AgentSession<'mgr>, anyhow::Result<()>) {
+  if let AgentSession::User(..) = session {
+    return (session, Ok(()));
+  }
 
-    device
-      .disable_encrypted_volume()
-      .context("Failed to close encrypted volume")
-  })
+  let device = session.into_device();
+  match authenticate_agent(ctx, device, args::PinType::User, |device, pin| {
+    device.authenticate_user(pin)
+  }) {
+    Ok((user, pin)) => (AgentSession::User(user, pin), Ok(())),
+    Err((device, err)) => (AgentSession::Device(device), Err(err)),
+  }
 }
 
-/// Create a hidden volume.
-pub fn hidden_create(ctx: &mut ExecCtx<'_>, slot: u8, start: u8, end: u8) -> anyhow::Result<()> {
-  with_storage_device(ctx, |ctx, mut device| {
-    let pwd_entry = pinentry::PwdEntry::from(&device)?;
-    let pwd = if let Some(pwd) = &ctx.password {
-      pwd
-        .to_str()
-        .ok_or_else(|| anyhow::anyhow!("Failed to read password: invalid unicode data found"))
-        .map(ToOwned::to_owned)
-    } else {
-      pinentry::choose(ctx, &pwd_entry).context("Failed to select new PIN")
-    }?;
+/// Make sure that the agent holds an admin session.
+fn agent_admin_session<'mgr>(
+  ctx: &mut ExecCtx<'_>,
+  session: AgentSession<'mgr>,
+) -> (AgentSession<'mgr>, anyhow::Result<()>) {
+  if let AgentSession::Admin(..) = session {
+    return (session, Ok(()));
+  }
 
-    device
-      .create_hidden_volume(slot, start, end, &pwd)
-      .context("Failed to create hidden volume")
-  })
+  let device = session.into_device();
+  match authenticate_agent(ctx, device, args::PinType::Admin, |device, pin| {
+    device.authenticate_admin(pin)
+  }) {
+    Ok((admin, _)) => (AgentSession::Admin(admin), Ok(())),
+    Err((device, err)) => (AgentSession::Device(device), Err(err)),
+  }
 }
 
-/// Open a hidden volume.
-pub fn hidden_open(ctx: &mut ExecCtx<'_>) -> anyhow::Result<()> {
-  with_storage_device(ctx, |ctx, mut device| {
-    let pwd_entry = pinentry::PwdEntry::from(&device)?;
-    let pwd = if let Some(pwd) = &ctx.password {
-      pwd
-        .to_str()
-        .ok_or_else(|| anyhow::anyhow!("Failed to read password: Invalid Unicode data found"))
+/// Handle a request sent to the agent using the given session.
+fn handle_agent_request<'mgr>(
+  ctx: &mut ExecCtx<'_>,
+  mut session: AgentSession<'mgr>,
+  request: agent::Request,
+) -> (AgentSession<'mgr>, anyhow::Result<Vec<String>>) {
+  macro_rules! session {
+    ($ensure:ident) => {{
+      let (new_session, result) = $ensure(ctx, session);
+      session = new_session;
+      if let Err(err) = result {
+        return (session, Err(err));
+      }
+    }};
+  }
+
+  let result = match request {
+    agent::Request::OtpGet {
+      slot,
+      algorithm,
+      time,
+    } => {
+      if let Some(time) = time {
+        if let Err(err) = session.device_mut().set_time(time, true) {
+          return (session, Err(err).context("Failed to set new time"));
+        }
+      }
+      let config = match session.device_mut().get_config() {
+        Ok(config) => config,
+        Err(err) => {
+          return (
+            session,
+            Err(err).context("Failed to get get current device configuration"),
+          )
+        }
+      };
+
+      if config.user_password {
+        session!(agent_user_session);
+      }
+      match &mut session {
+        AgentSession::User(user, _) => get_otp(slot, algorithm, user),
+        session => get_otp(slot, algorithm, session.device_mut()),
+      }
+      .map(|otp| vec![otp])
+    }
+    agent::Request::OtpSet {
+      slot,
+      algorithm,
+      name,
+      secret,
+      digits,
+      counter,
+      time_window,
+      use_enter,
+      token_id,
+    } => {
+      session!(agent_admin_session);
+      let data = nitrokey::OtpSlotData {
+        number: slot,
+        name,
+        secret,
+        mode: digits.into(),
+        use_enter,
+        token_id,
+      };
+      let command = format!("otp set --algorithm {} {}", algorithm, slot);
+      let record = match audit_log::Record::begin(ctx, session.device_mut(), &command) {
+        Ok(record) => record,
+        Err(err) => return (session, Err(err)),
+      };
+      let result = match &mut session {
+        AgentSession::Admin(admin) => check_secret_len(admin, &data.secret).and_then(|_| {
+          match algorithm {
+            args::OtpAlgorithm::Hotp => admin.write_hotp_slot(data, counter),
+            args::OtpAlgorithm::Totp => admin.write_totp_slot(data, time_window),
+          }
+          .context("Failed to write OTP slot")
+          .map(|_| Vec::new())
+        }),
+        _ => unreachable!(),
+      };
+      match record {
+        Some(record) => {
+          let logged = record
+            .finish(&result)
+            .context("Failed to write audit log entry");
+          result.and_then(|data| logged.map(|_| data))
+        }
+        None => result,
+      }
+    }
+    agent::Request::PwsGet { slot } => {
+      session!(agent_user_session);
+      match &mut session {
+        AgentSession::User(user, pin) => user
+          .get_password_safe(pin)
+          .context("Could not access the password safe")
+          .and_then(|pws| {
+            check_slot(&pws, slot)
+              .and_then(|_| {
+                Ok(vec![
+                  pws.get_slot_name(slot)?,
+                  pws.get_slot_login(slot)?,
+                  pws.get_slot_password(slot)?,
+                ])
+              })
+              .context("Failed to access PWS slot")
+          }),
+        _ => unreachable!(),
+      }
+    }
+    agent::Request::Clear => {
+      session = AgentSession::Device(session.into_device());
+      Ok(Vec::new())
+    }
+  };
+  (session, result)
+}
+
+/// Run the agent, serving requests of other nitrocli invocations.
+pub fn agent(ctx: &mut ExecCtx<'_>, timeout: u64) -> anyhow::Result<()> {
+  let path = ctx
+    .agent_socket
+    .clone()
+    .ok_or_else(|| anyhow::anyhow!("The agent socket is not available"))?;
+  with_device(ctx, |ctx, device| {
+    let mut session = Some(AgentSession::Device(device));
+    eprintln!(ctx, "Listening on {}", path.display())?;
+
+    agent::serve(&path, time::Duration::from_secs(timeout), |request| {
+      let (new_session, result) = handle_agent_request(
+        ctx,
+        session.take().expect("session is always present"),
+        request,
+      );
+      session = Some(new_session);
+      result
+    })
+  })
+}
+
+/// An operation that is run on each device when `--all-devices` is set.
+type DeviceOp = Box<dyn Fn(&mut ExecCtx<'_>) -> anyhow::Result<()>>;
+
+/// Retrieve the operation to run on each device for the given command,
+/// if the command supports `--all-devices`.
+fn get_all_devices_op(cmd: &args::Command) -> Option<DeviceOp> {
+  match cmd {
+    args::Command::Status(status_args) => {
+      let format = status_args.format.clone();
+      Some(Box::new(move |ctx| status(ctx, format.as_deref())))
+    }
+    args::Command::Lock => Some(Box::new(lock)),
+    args::Command::Config(config) => match config.subcmd {
+      args::ConfigCommand::Get => Some(Box::new(config_get)),
+      _ => None,
+    },
+    args::Command::Otp(otp) => match otp.subcmd {
+      args::OtpCommand::Status(ref status) => {
+        let all = status.all;
+        let format = status.format.clone();
+        Some(Box::new(move |ctx| otp_status(ctx, all, format.as_deref())))
+      }
+      _ => None,
+    },
+    args::Command::Pin(pin) => match pin.subcmd {
+      args::PinCommand::Clear => Some(Box::new(pin_clear)),
+      _ => None,
+    },
+    _ => None,
+  }
+}
+
+/// Query the serial number of the given device, connecting to it if
+/// the serial number is not known yet.
+fn query_serial_number(
+  device_info: &nitrokey::DeviceInfo,
+) -> anyhow::Result<nitrokey::SerialNumber> {
+  if let Some(serial_number) = device_info.serial_number {
+    return Ok(serial_number);
+  }
+
+  // The manager has to be released before running the operation, as
+  // it connects to the device on its own.
+  let mut manager =
+    nitrokey::take().context("Failed to acquire access to Nitrokey device manager")?;
+  let device = manager
+    .connect_path(device_info.path.clone())
+    .context("Failed to connect to Nitrokey")?;
+  device
+    .get_serial_number()
+    .context("Failed to retrieve device serial number")
+}
+
+/// Run the given command on all attached devices.
+///
+/// The output is grouped by device. Errors do not abort the execution
+/// but are collected and reported once all devices have been handled.
+pub fn run_on_all_devices(ctx: &mut ExecCtx<'_>, cmd: args::Command) -> anyhow::Result<()> {
+  let op = get_all_devices_op(&cmd)
+    .ok_or_else(|| anyhow::anyhow!("This command does not support the --all-devices option"))?;
+
+  set_log_level(ctx);
+
+  // Connecting to a device to query its serial number may fail, so we
+  // do that for each device individually below.
+  let device_infos = {
+    let mut manager =
+      nitrokey::take().context("Failed to acquire access to Nitrokey device manager")?;
+    enumerate_devices(&mut manager, true)?
+  };
+  let device_infos = device_infos
+    .into_iter()
+    .filter(|device_info| match ctx.model {
+      Some(model) => device_info.model == Some(model.into()),
+      None => true,
+    })
+    .collect::<Vec<_>>();
+  if device_infos.is_empty() {
+    return Err(nitrokey::Error::from(
+      nitrokey::CommunicationError::NotConnected,
+    ))
+    .context("Nitrokey device not found");
+  }
+
+  let mut errors = Vec::new();
+  for (i, device_info) in device_infos.iter().enumerate() {
+    let serial_number = query_serial_number(device_info);
+    // Devices we failed to connect to are identified by their path in
+    // the error summary.
+    let label = match &serial_number {
+      Ok(serial_number) => serial_number.to_string(),
+      Err(_) => device_info.path.clone(),
+    };
+
+    if i > 0 {
+      println!(ctx)?;
+    }
+    match &serial_number {
+      Ok(serial_number) => println!(ctx, "Device {} ({}):", serial_number, device_info.path)?,
+      Err(_) => println!(ctx, "Device N/A ({}):", device_info.path)?,
+    }
+
+    let result = serial_number.and_then(|_| {
+      ctx.path = Some(device_info.path.clone());
+      let result = op(ctx);
+      ctx.path = None;
+      result
+    });
+
+    if let Err(err) = result {
+      errors.push((label, err));
+    }
+  }
+
+  if !errors.is_empty() {
+    eprintln!(ctx, "The command failed on the following devices:")?;
+    for (label, err) in &errors {
+      eprintln!(ctx, "  {}: {:#}", label, err)?;
+    }
+    anyhow::bail!(
+      "The command failed on {} of {} devices",
+      errors.len(),
+      device_infos.len()
+    );
+  }
+  Ok(())
+}
+
+/// Let the connected Nitrokey Storage device blink its LEDs.
+pub fn wink(ctx: &mut ExecCtx<'_>) -> anyhow::Result<()> {
+  with_storage_device(ctx, |_ctx, mut device| {
+    device.wink().context("Failed to wink the device")
+  })
+}
+
+/// Print the data that a factory reset of the given device would erase.
+fn print_reset_data(
+  ctx: &mut ExecCtx<'_>,
+  device: &mut nitrokey::DeviceWrapper<'_>,
+) -> anyhow::Result<()> {
+  let serial_number = device
+    .get_serial_number()
+    .context("Could not query the serial number")?;
+  println!(
+    ctx,
+    "A factory reset of Nitrokey {} {} would erase:",
+    device.get_model(),
+    serial_number
+  )?;
+
+  println!(ctx, "  OTP slots:")?;
+  let mut empty = true;
+  for &algorithm in &args::OtpAlgorithm::all_variants() {
+    for (slot, name) in get_otp_slots(algorithm, device)? {
+      if let Some(name) = name {
+        println!(ctx, "    {} {}: {}", algorithm, slot, name)?;
+        empty = false;
+      }
+    }
+  }
+  if empty {
+    println!(ctx, "    none")?;
+  }
+
+  println!(ctx, "  PWS slots:")?;
+  let pin_entry = pinentry::PinEntry::from(args::PinType::User, &*device)?;
+  let slots = try_with_pin_and_data(ctx, &pin_entry, (), |_ctx, _, pin| {
+    let pws = device
+      .get_password_safe(pin)
+      .context("Could not access the password safe")
+      .map_err(|err| ((), err))?;
+    get_pws_slots(&pws).map_err(|err| ((), err))
+  })?;
+  if slots.is_empty() {
+    println!(ctx, "    none")?;
+  }
+  for (slot, name) in slots {
+    println!(ctx, "    {}: {}", slot, name)?;
+  }
+
+  if let nitrokey::DeviceWrapper::Storage(_) = device {
+    println!(ctx, "  encrypted volume data")?;
+    // By design, there is no way to tell whether hidden volumes exist.
+    println!(ctx, "  hidden volume data (if any)")?;
+  }
+  Ok(())
+}
+
+/// Ask the user to confirm a factory reset by typing the device's
+/// serial number.
+fn confirm_reset(
+  ctx: &mut ExecCtx<'_>,
+  device: &nitrokey::DeviceWrapper<'_>,
+) -> anyhow::Result<()> {
+  let serial_number = device
+    .get_serial_number()
+    .context("Could not query the serial number")?
+    .to_string();
+
+  eprintln!(
+    ctx,
+    "This will irrevocably erase all data on Nitrokey {} {}.",
+    device.get_model(),
+    serial_number
+  )?;
+  eprintln!(ctx, "Type the serial number to confirm:")?;
+  let answer = read_line(ctx)?;
+  if answer.trim().eq_ignore_ascii_case(&serial_number) {
+    Ok(())
+  } else {
+    anyhow::bail!("Serial number does not match, aborting factory reset")
+  }
+}
+
+/// Reset the given device to factory settings using the given admin
+/// PIN.
+fn factory_reset(device: &mut nitrokey::DeviceWrapper<'_>, admin_pin: &str) -> anyhow::Result<()> {
+  device
+    .factory_reset(admin_pin)
+    .context("Failed to reset to factory settings")?;
+  // Work around for a timing issue between factory_reset and
+  // build_aes_key, see
+  // https://github.com/Nitrokey/nitrokey-storage-firmware/issues/80
+  thread::sleep(time::Duration::from_secs(3));
+  // Another work around for spurious WrongPassword returns of
+  // build_aes_key after a factory reset on Pro devices.
+  // https://github.com/Nitrokey/nitrokey-pro-firmware/issues/57
+  let _ = device.get_user_retry_count();
+  device
+    .build_aes_key(nitrokey::DEFAULT_ADMIN_PIN)
+    .context("Failed to rebuild AES key")
+}
+
+/// Perform a factory reset.
+pub fn reset(ctx: &mut ExecCtx<'_>, args: args::ResetArgs) -> anyhow::Result<()> {
+  if args.dry_run {
+    return with_device(ctx, |ctx, mut device| print_reset_data(ctx, &mut device));
+  }
+
+  with_audited_device(ctx, "reset", |ctx, mut device| {
+    if !args.yes {
+      confirm_reset(ctx, &device)?;
+    }
+
+    // Choose the new PINs before performing the reset so that a failure
+    // to do so does not leave the device with the default PINs.
+    let new_pins = if args.set_pins {
+      let admin_entry = pinentry::PinEntry::from(args::PinType::Admin, &device)?;
+      let user_entry = pinentry::PinEntry::from(args::PinType::User, &device)?;
+      let admin_pin = choose_pin(ctx, &admin_entry, true)?;
+      let user_pin = choose_pin(ctx, &user_entry, true)?;
+      Some((admin_pin, user_pin))
+    } else {
+      None
+    };
+
+    let pin_entry = pinentry::PinEntry::from(args::PinType::Admin, &device)?;
+
+    // To force the user to enter the admin PIN before performing a
+    // factory reset, we clear the pinentry cache for the admin PIN.
+    pinentry::clear(ctx, &pin_entry).context("Failed to clear cached secret")?;
+
+    // The reset command is part of the recovery from a locked admin
+    // PIN, so we must not refuse to run it in that case.
+    try_with_pin_and_data_unguarded(ctx, &pin_entry, (), |_ctx, data, pin| {
+      factory_reset(&mut device, pin).map_err(|err| (data, err))
+    })?;
+
+    if let Some((admin_pin, user_pin)) = new_pins {
+      device
+        .change_admin_pin(nitrokey::DEFAULT_ADMIN_PIN, &admin_pin)
+        .context("Failed to change admin PIN")?;
+      device
+        .change_user_pin(nitrokey::DEFAULT_USER_PIN, &user_pin)
+        .context("Failed to change user PIN")?;
+    }
+
+    // Any cached PINs, including the admin PIN we just used for the
+    // reset, are no longer valid.
+    let user_entry = pinentry::PinEntry::from(args::PinType::User, &device)?;
+    pinentry::clear(ctx, &pin_entry).context("Failed to clear cached secret")?;
+    pinentry::clear(ctx, &user_entry).context("Failed to clear cached secret")?;
+    Ok(())
+  })
+}
+
+/// Change the configuration of the unencrypted volume.
+pub fn unencrypted_set(
+  ctx: &mut ExecCtx<'_>,
+  mode: args::UnencryptedVolumeMode,
+) -> anyhow::Result<()> {
+  let command = format!("unencrypted set {}", mode);
+  with_audited_storage_device(ctx, &command, |ctx, mut device| {
+    let pin_entry = pinentry::PinEntry::from(args::PinType::Admin, &device)?;
+    let mode = match mode {
+      args::UnencryptedVolumeMode::ReadWrite => nitrokey::VolumeMode::ReadWrite,
+      args::UnencryptedVolumeMode::ReadOnly => nitrokey::VolumeMode::ReadOnly,
+    };
+
+    // The unencrypted volume may reconnect, so be sure to flush caches to
+    // disk.
+    unsafe { sync() };
+
+    try_with_pin(ctx, &pin_entry, |pin| {
+      device
+        .set_unencrypted_volume_mode(&pin, mode)
+        .context("Failed to change unencrypted volume mode")
+    })
+  })
+}
+
+/// Open the encrypted volume on the Nitrokey.
+pub fn encrypted_open(ctx: &mut ExecCtx<'_>) -> anyhow::Result<()> {
+  with_storage_device(ctx, |ctx, mut device| {
+    let pin_entry = pinentry::PinEntry::from(args::PinType::User, &device)?;
+
+    // We may forcefully close a hidden volume, if active, so be sure to
+    // flush caches to disk.
+    unsafe { sync() };
+
+    try_with_pin(ctx, &pin_entry, |pin| {
+      device
+        .enable_encrypted_volume(&pin)
+        .context("Failed to open encrypted volume")
+    })
+  })
+}
+
+/// Close the previously opened encrypted volume.
+pub fn encrypted_close(ctx: &mut ExecCtx<'_>) -> anyhow::Result<()> {
+  with_storage_device(ctx, |_ctx, mut device| {
+    // Flush all filesystem caches to disk. We are mostly interested in
+    // making sure that the encrypted volume on the Nitrokey we are
+    // about to close is not closed while not all data was written to
+    // it.
+    unsafe { sync() };
+
+    device
+      .disable_encrypted_volume()
+      .context("Failed to close encrypted volume")
+  })
+}
+
+/// Create a hidden volume.
+pub fn hidden_create(ctx: &mut ExecCtx<'_>, slot: u8, start: u8, end: u8) -> anyhow::Result<()> {
+  let command = format!("hidden create {} {} {}", slot, start, end);
+  with_audited_storage_device(ctx, &command, |ctx, mut device| {
+    let pwd_entry = pinentry::PwdEntry::from(&device)?;
+    let pwd = if let Some(pwd) = &ctx.password {
+      let pwd = pwd
+        .to_str()
+        .ok_or_else(|| anyhow::anyhow!("Failed to read password: invalid unicode data found"))?
+        .to_owned();
+      pinentry::check(ctx, &pwd_entry, &pwd).context("Failed to select new PIN")?;
+      Ok(pwd)
+    } else {
+      pinentry::choose(ctx, &pwd_entry).context("Failed to select new PIN")
+    }?;
+
+    device
+      .create_hidden_volume(slot, start, end, &pwd)
+      .context("Failed to create hidden volume")
+  })
+}
+
+/// Open a hidden volume.
+pub fn hidden_open(ctx: &mut ExecCtx<'_>) -> anyhow::Result<()> {
+  with_storage_device(ctx, |ctx, mut device| {
+    let pwd_entry = pinentry::PwdEntry::from(&device)?;
+    let pwd = if let Some(pwd) = &ctx.password {
+      pwd
+        .to_str()
+        .ok_or_else(|| anyhow::anyhow!("Failed to read password: Invalid Unicode data found"))
         .map(ToOwned::to_owned)
     } else {
       pinentry::inquire(ctx, &pwd_entry, pinentry::Mode::Query, None)
@@ -544,6 +1449,213 @@ pub fn hidden_close(ctx: &mut ExecCtx<'_>) -> anyhow::Result<()> {
   })
 }
 
+/// Return a string representation of the given flag.
+fn format_bool(value: bool) -> &'static str {
+  if value {
+    "yes"
+  } else {
+    "no"
+  }
+}
+
+/// Print production and SD card information of a Nitrokey Storage.
+pub fn storage_info(ctx: &mut ExecCtx<'_>) -> anyhow::Result<()> {
+  with_storage_device(ctx, |ctx, device| {
+    let info = device
+      .get_production_info()
+      .context("Failed to retrieve production information")?;
+    let status = device
+      .get_storage_status()
+      .context("Failed to retrieve storage status")?;
+
+    println!(
+      ctx,
+      r#"Storage:
+  CPU ID:                  {cpu:#x}
+  firmware version:        {fwv} (internal {fwi})
+  SD card:
+    serial number:         {sd:#x}
+    size:                  {size} GB
+    manufacturer:          {mf:#04x}
+    OEM:                   {oem:#06x}
+    manufacturing date:    20{year:02}-{month:02}
+    new card warning:      {new}
+    filled with random:    {random}"#,
+      cpu = info.serial_number_cpu,
+      fwv = info.firmware_version,
+      fwi = info.firmware_version_internal,
+      sd = info.sd_card.serial_number,
+      size = info.sd_card.size,
+      mf = info.sd_card.manufacturer,
+      oem = info.sd_card.oem,
+      year = info.sd_card.manufacturing_year,
+      month = info.sd_card.manufacturing_month,
+      new = format_bool(status.new_sd_card_found),
+      random = format_bool(status.filled_with_random),
+    )?;
+    Ok(())
+  })
+}
+
+/// Clear the new SD card warning of a Nitrokey Storage.
+pub fn storage_clear_warning(ctx: &mut ExecCtx<'_>) -> anyhow::Result<()> {
+  with_storage_device(ctx, |ctx, mut device| {
+    let pin_entry = pinentry::PinEntry::from(args::PinType::Admin, &device)?;
+    try_with_pin(ctx, &pin_entry, |pin| {
+      device
+        .clear_new_sd_card_warning(pin)
+        .context("Failed to clear new SD card warning")
+    })
+  })
+}
+
+/// The name of the file the Nitrokey Storage exports its firmware to.
+const FIRMWARE_FILE: &str = "firmware.bin";
+
+/// Unescape a path as found in `/proc/mounts`, where white space and
+/// backslashes are represented as octal escape sequences.
+fn unescape_mount_path(path: &str) -> String {
+  let mut result = String::with_capacity(path.len());
+  let mut chars = path.chars();
+  while let Some(c) = chars.next() {
+    if c == '\\' {
+      let octal = chars.clone().take(3).collect::<String>();
+      if let Ok(value) = u8::from_str_radix(&octal, 8) {
+        result.push(char::from(value));
+        let _ = chars.nth(2);
+        continue;
+      }
+    }
+    result.push(c);
+  }
+  result
+}
+
+/// Find the mount point of the given block device in the given mount
+/// table (in `/proc/mounts` format).
+fn find_mount_point(mounts: &str, device: &path::Path) -> Option<path::PathBuf> {
+  mounts.lines().find_map(|line| {
+    let mut fields = line.split_whitespace();
+    match (fields.next(), fields.next()) {
+      (Some(dev), Some(dir)) if path::Path::new(&unescape_mount_path(dev)) == device => {
+        Some(path::PathBuf::from(unescape_mount_path(dir)))
+      }
+      _ => None,
+    }
+  })
+}
+
+/// Find the name of the `/dev/disk/by-id` entry of the unencrypted
+/// volume of the Nitrokey Storage with the given serial number.
+///
+/// The unencrypted volume is exported as the first logical unit of the
+/// Nitrokey Storage's mass storage device, whose USB serial number is
+/// the zero-padded hex representation of the device's serial number.
+fn find_unencrypted_volume_name<'n, I>(names: I, serial: nitrokey::SerialNumber) -> Option<&'n str>
+where
+  I: IntoIterator<Item = &'n str>,
+{
+  const PREFIX: &str = "usb-Nitrokey_Nitrokey_Storage_";
+  const SUFFIX: &str = "-0:0-part1";
+
+  names.into_iter().find(|name| {
+    name.starts_with(PREFIX)
+      && name.ends_with(SUFFIX)
+      && name.len() > PREFIX.len() + SUFFIX.len()
+      && u128::from_str_radix(&name[PREFIX.len()..name.len() - SUFFIX.len()], 16)
+        .map(|value| value == u128::from(serial.as_u32()))
+        .unwrap_or(false)
+  })
+}
+
+/// Find the mount point of the unencrypted volume of the attached
+/// Nitrokey Storage with the given serial number.
+fn find_unencrypted_volume(serial: nitrokey::SerialNumber) -> anyhow::Result<path::PathBuf> {
+  let by_id = path::Path::new("/dev/disk/by-id");
+  let names = fs::read_dir(by_id)
+    .with_context(|| format!("Failed to read directory {}", by_id.display()))?
+    .filter_map(|entry| entry.ok())
+    .map(|entry| entry.file_name().to_string_lossy().into_owned())
+    .collect::<Vec<_>>();
+  let device =
+    find_unencrypted_volume_name(names.iter().map(String::as_str), serial).ok_or_else(|| {
+      anyhow::anyhow!(
+        "Unencrypted volume of the Nitrokey Storage {} not found",
+        serial
+      )
+    })?;
+  let device = fs::canonicalize(by_id.join(device))
+    .with_context(|| format!("Failed to resolve block device {}", device))?;
+
+  let mounts = fs::read_to_string("/proc/mounts").context("Failed to read mount table")?;
+  find_mount_point(&mounts, &device)
+    .ok_or_else(|| anyhow::anyhow!("Unencrypted volume ({}) is not mounted", device.display()))
+}
+
+/// Compute the SHA-256 digest of the given data as a hex string.
+fn sha256(data: &[u8]) -> String {
+  use sha2::Digest as _;
+
+  format_bytes(&sha2::Sha256::digest(data))
+}
+
+/// Export the firmware image of a Nitrokey Storage.
+pub fn firmware_export(
+  ctx: &mut ExecCtx<'_>,
+  path: Option<&path::Path>,
+  compare: Option<&path::Path>,
+) -> anyhow::Result<()> {
+  with_storage_device(ctx, |ctx, mut device| {
+    let pin_entry = pinentry::PinEntry::from(args::PinType::Admin, &device)?;
+    let serial = device
+      .get_serial_number()
+      .context("Could not query the serial number")?;
+
+    // The firmware image is written to the unencrypted volume, so be
+    // sure to flush any pending changes to it first.
+    unsafe { sync() };
+
+    try_with_pin(ctx, &pin_entry, |pin| {
+      device
+        .export_firmware(pin)
+        .context("Failed to export firmware")
+    })?;
+
+    let volume =
+      find_unencrypted_volume(serial).context("Failed to locate exported firmware image")?;
+    let image_path = volume.join(FIRMWARE_FILE);
+    let image = fs::read(&image_path)
+      .with_context(|| format!("Failed to read firmware image {}", image_path.display()))?;
+
+    if let Some(path) = path {
+      fs::write(path, &image)
+        .with_context(|| format!("Failed to write firmware image to {}", path.display()))?;
+    }
+    println!(ctx, "SHA-256: {}", sha256(&image))?;
+
+    if let Some(compare) = compare {
+      let expected = fs::read(compare)
+        .with_context(|| format!("Failed to read firmware image {}", compare.display()))?;
+      if let Some(offset) = image.iter().zip(&expected).position(|(a, b)| a != b) {
+        anyhow::bail!(
+          "Firmware image differs from {} at offset {:#x}",
+          compare.display(),
+          offset
+        )
+      } else if image.len() != expected.len() {
+        anyhow::bail!(
+          "Firmware image differs from {} in size ({} vs. {} bytes)",
+          compare.display(),
+          image.len(),
+          expected.len()
+        )
+      }
+      println!(ctx, "Firmware image matches {}", compare.display())?;
+    }
+    Ok(())
+  })
+}
+
 /// Return a String representation of the given Option.
 fn format_option<T: fmt::Display>(option: Option<T>) -> String {
   match option {
@@ -588,7 +1700,7 @@ pub fn config_set(ctx: &mut ExecCtx<'_>, args: args::ConfigSetArgs) -> anyhow::R
     None
   };
 
-  with_device(ctx, |ctx, device| {
+  with_audited_device(ctx, "config set", |ctx, device| {
     let mut device = authenticate_admin(ctx, device)?;
     let config = device
       .get_config()
@@ -607,6 +1719,7 @@ pub fn config_set(ctx: &mut ExecCtx<'_>, args: args::ConfigSetArgs) -> anyhow::R
 
 /// Lock the Nitrokey device.
 pub fn lock(ctx: &mut ExecCtx<'_>) -> anyhow::Result<()> {
+  clear_agent(ctx)?;
   with_device(ctx, |_ctx, mut device| {
     device.lock().context("Failed to lock the device")
   })
@@ -636,17 +1749,49 @@ pub fn otp_get(
   slot: u8,
   algorithm: args::OtpAlgorithm,
   time: Option<u64>,
+  format: Option<&str>,
 ) -> anyhow::Result<()> {
+  let template = format
+    .map(|format| template::Template::parse(format, OTP_GET_FIELDS))
+    .transpose()?;
+  let time = if algorithm == args::OtpAlgorithm::Totp {
+    match time {
+      Some(time) => Some(time),
+      None => Some(get_unix_timestamp().context("Failed to retrieve current time")?),
+    }
+  } else {
+    None
+  };
+
+  let request = agent::Request::OtpGet {
+    slot,
+    algorithm,
+    time,
+  };
+  let print = |ctx: &mut ExecCtx<'_>, code: &str| -> anyhow::Result<()> {
+    match &template {
+      Some(template) => {
+        let output = template.render(|field| match field {
+          "code" => Ok(code.to_string()),
+          "alg" => Ok(algorithm.to_string()),
+          "slot" => Ok(slot.to_string()),
+          _ => Ok(time.map(|time| time.to_string()).unwrap_or_default()),
+        })?;
+        println!(ctx, "{}", output)?;
+      }
+      None => println!(ctx, "{}", code)?,
+    }
+    Ok(())
+  };
+
+  if let Some(response) = request_agent(ctx, &request)? {
+    return print(ctx, &response.join(""));
+  }
+
   with_device(ctx, |ctx, mut device| {
-    if algorithm == args::OtpAlgorithm::Totp {
+    if let Some(time) = time {
       device
-        .set_time(
-          match time {
-            Some(time) => time,
-            None => get_unix_timestamp().context("Failed to retrieve current time")?,
-          },
-          true,
-        )
+        .set_time(time, true)
         .context("Failed to set new time")?;
     }
     let config = device
@@ -658,11 +1803,13 @@ pub fn otp_get(
     } else {
       get_otp(slot, algorithm, &mut device)
     }?;
-    println!(ctx, "{}", otp)?;
-    Ok(())
+    print(ctx, &otp)
   })
 }
 
+/// The fields available for templates of the `otp get` command.
+const OTP_GET_FIELDS: &[&str] = &["code", "alg", "slot", "time"];
+
 /// Format a byte vector as a hex string.
 fn format_bytes(bytes: &[u8]) -> String {
   bytes
@@ -685,42 +1832,206 @@ fn prepare_ascii_secret(secret: &str) -> anyhow::Result<String> {
   }
 }
 
+/// The maximum length of an OTP secret in bytes.
+///
+/// Only newer firmware versions support secrets of this length, see
+/// `max_secret_len`.
+const MAX_SECRET_LEN: usize = 40;
+
+/// The maximum length of an OTP secret in bytes on firmware versions
+/// without support for 320-bit secrets.
+const MAX_LEGACY_SECRET_LEN: usize = 20;
+
+/// Check whether the given firmware version supports 320-bit OTP
+/// secrets and reading back HOTP slot configurations.
+///
+/// This is the case for Nitrokey Pro devices with firmware 0.8 or newer
+/// and Nitrokey Storage devices with firmware 0.54 or newer.
+fn supports_extended_otp(model: nitrokey::Model, major: u8, minor: u8) -> bool {
+  let min_minor = match model {
+    nitrokey::Model::Pro => 8,
+    nitrokey::Model::Storage => 54,
+  };
+  major > 0 || minor >= min_minor
+}
+
+/// Determine the maximum length of an OTP secret in bytes supported by
+/// the given model and firmware version.
+fn max_secret_len(model: nitrokey::Model, major: u8, minor: u8) -> usize {
+  if supports_extended_otp(model, major, minor) {
+    MAX_SECRET_LEN
+  } else {
+    MAX_LEGACY_SECRET_LEN
+  }
+}
+
+/// Check that the given secret, as prepared by `prepare_secret`, fits
+/// into an OTP slot of the given device.
+fn check_secret_len(device: &nitrokey::DeviceWrapper<'_>, secret: &str) -> anyhow::Result<()> {
+  let version = device
+    .get_firmware_version()
+    .context("Failed to get firmware version")?;
+  let max_len = max_secret_len(device.get_model(), version.major, version.minor);
+  // The prepared secret is hex encoded.
+  let len = secret.len() / 2;
+  if len > max_len {
+    anyhow::bail!(
+      "The secret is too long: {} bytes given, but the device supports at most {} bytes",
+      len,
+      max_len
+    );
+  }
+  Ok(())
+}
+
 /// Prepare a base32 secret string for libnitrokey.
+///
+/// Secrets are often displayed lowercase, split into groups separated
+/// by spaces or hyphens, and with padding.  We accept all of these
+/// variants by normalizing the secret before decoding it.
 fn prepare_base32_secret(secret: &str) -> anyhow::Result<String> {
+  let secret = secret
+    .chars()
+    .filter(|c| !c.is_whitespace() && *c != '-')
+    .collect::<String>();
+  let secret = secret.trim_end_matches('=');
+  // The base32 crate silently decodes padding characters inside the
+  // data, so we have to reject them ourselves.
+  if secret.contains('=') {
+    anyhow::bail!("Failed to parse base32 secret");
+  }
+
   base32::decode(base32::Alphabet::RFC4648 { padding: false }, secret)
     .map(|vec| format_bytes(&vec))
     .ok_or_else(|| anyhow::anyhow!("Failed to parse base32 secret"))
 }
 
+/// Decode a single character of the standard or the URL-safe base64
+/// alphabet.
+fn decode_base64_char(c: char) -> Option<u8> {
+  match c {
+    'A'..='Z' => Some(c as u8 - b'A'),
+    'a'..='z' => Some(c as u8 - b'a' + 26),
+    '0'..='9' => Some(c as u8 - b'0' + 52),
+    '+' | '-' => Some(62),
+    '/' | '_' => Some(63),
+    _ => None,
+  }
+}
+
+/// Prepare a base64 secret string for libnitrokey.
+///
+/// Both the standard and the URL-safe alphabet according to RFC 4648
+/// are accepted, with or without padding.  Whitespace is ignored.
+fn prepare_base64_secret(secret: &str) -> anyhow::Result<String> {
+  let secret = secret
+    .chars()
+    .filter(|c| !c.is_whitespace())
+    .collect::<String>();
+  let secret = secret.trim_end_matches('=');
+
+  let mut bytes = Vec::with_capacity(secret.len() * 3 / 4);
+  let mut buffer = 0u32;
+  let mut bits = 0;
+  for c in secret.chars() {
+    let value = decode_base64_char(c).context("Failed to parse base64 secret")?;
+    buffer = (buffer << 6) | u32::from(value);
+    bits += 6;
+    if bits >= 8 {
+      bits -= 8;
+      bytes.push((buffer >> bits) as u8);
+      buffer &= (1 << bits) - 1;
+    }
+  }
+  // A single trailing character does not encode a full byte.
+  if bits >= 6 {
+    anyhow::bail!("Failed to parse base64 secret");
+  }
+  Ok(format_bytes(&bytes))
+}
+
+/// Prepare a secret string in the given format for libnitrokey.
+fn prepare_secret(format: args::OtpSecretFormat, mut secret: String) -> anyhow::Result<String> {
+  let secret = match format {
+    args::OtpSecretFormat::Ascii => prepare_ascii_secret(&secret)?,
+    args::OtpSecretFormat::Base32 => prepare_base32_secret(&secret)?,
+    args::OtpSecretFormat::Base64 => prepare_base64_secret(&secret)?,
+    args::OtpSecretFormat::Hex => {
+      // We need to ensure to provide a string with an even number of
+      // characters in it, just because that's what libnitrokey
+      // expects. So prepend a '0' if that is not the case.
+      // TODO: This code can be removed once upstream issue #164
+      //       (https://github.com/Nitrokey/libnitrokey/issues/164) is
+      //       addressed.
+      if secret.len() % 2 != 0 {
+        secret.insert(0, '0')
+      }
+      secret
+    }
+  };
+
+  let len = secret.len() / 2;
+  if len > MAX_SECRET_LEN {
+    anyhow::bail!(
+      "The secret is too long: {} bytes given, but at most {} bytes are supported",
+      len,
+      MAX_SECRET_LEN
+    );
+  }
+  Ok(secret)
+}
+
+/// Check that the given token ID is a valid OATH token identifier.
+///
+/// A token identifier consists of a two character manufacturer prefix,
+/// a two character token type, and an eight character manufacturer
+/// unique identifier, all of which are alphanumeric.
+fn check_token_id(token_id: &str) -> anyhow::Result<()> {
+  if token_id.len() != 12 || !token_id.chars().all(|c| c.is_ascii_alphanumeric()) {
+    anyhow::bail!(
+      "Invalid token ID {}: expected twelve alphanumeric characters",
+      token_id
+    );
+  }
+  Ok(())
+}
+
 /// Configure a one-time password slot on the Nitrokey device.
 pub fn otp_set(ctx: &mut ExecCtx<'_>, mut args: args::OtpSetArgs) -> anyhow::Result<()> {
-  let mut data = nitrokey::OtpSlotData {
+  if let Some(token_id) = &args.token_id {
+    check_token_id(token_id)?;
+  }
+
+  let data = nitrokey::OtpSlotData {
     number: args.slot,
     name: mem::take(&mut args.name),
     secret: mem::take(&mut args.secret),
     mode: args.digits.into(),
-    use_enter: false,
-    token_id: None,
+    use_enter: args.use_enter,
+    token_id: args.token_id.take(),
   };
 
-  with_device(ctx, |ctx, device| {
-    let secret = match args.format {
-      args::OtpSecretFormat::Ascii => prepare_ascii_secret(&data.secret)?,
-      args::OtpSecretFormat::Base32 => prepare_base32_secret(&data.secret)?,
-      args::OtpSecretFormat::Hex => {
-        // We need to ensure to provide a string with an even number of
-        // characters in it, just because that's what libnitrokey
-        // expects. So prepend a '0' if that is not the case.
-        // TODO: This code can be removed once upstream issue #164
-        //       (https://github.com/Nitrokey/libnitrokey/issues/164) is
-        //       addressed.
-        if data.secret.len() % 2 != 0 {
-          data.secret.insert(0, '0')
-        }
-        data.secret
-      }
-    };
-    let data = nitrokey::OtpSlotData { secret, ..data };
+  let secret = prepare_secret(args.format, data.secret)?;
+  let data = nitrokey::OtpSlotData { secret, ..data };
+
+  let request = agent::Request::OtpSet {
+    slot: data.number,
+    algorithm: args.algorithm,
+    name: data.name.clone(),
+    secret: data.secret.clone(),
+    digits: args.digits,
+    counter: args.counter,
+    time_window: args.time_window,
+    use_enter: data.use_enter,
+    token_id: data.token_id.clone(),
+  };
+  if request_agent(ctx, &request)?.is_some() {
+    return Ok(());
+  }
+
+  let command = format!("otp set --algorithm {} {}", args.algorithm, data.number);
+  with_audited_device(ctx, &command, |ctx, device| {
+    check_secret_len(&device, &data.secret)?;
     let mut device = authenticate_admin(ctx, device)?;
     match args.algorithm {
       args::OtpAlgorithm::Hotp => device.write_hotp_slot(data, args.counter),
@@ -737,7 +2048,8 @@ pub fn otp_clear(
   slot: u8,
   algorithm: args::OtpAlgorithm,
 ) -> anyhow::Result<()> {
-  with_device(ctx, |ctx, device| {
+  let command = format!("otp clear --algorithm {} {}", algorithm, slot);
+  with_audited_device(ctx, &command, |ctx, device| {
     let mut device = authenticate_admin(ctx, device)?;
     match algorithm {
       args::OtpAlgorithm::Hotp => device.erase_hotp_slot(slot),
@@ -748,54 +2060,195 @@ pub fn otp_clear(
   })
 }
 
+/// Retrieve the names of all OTP slots of the given algorithm, with
+/// `None` representing slots that are not programmed.
+fn get_otp_slots(
+  algorithm: args::OtpAlgorithm,
+  device: &nitrokey::DeviceWrapper<'_>,
+) -> anyhow::Result<Vec<(u8, Option<String>)>> {
+  let mut slots = Vec::new();
+  let mut slot: u8 = 0;
+  loop {
+    let result = match algorithm {
+      args::OtpAlgorithm::Hotp => device.get_hotp_slot_name(slot),
+      args::OtpAlgorithm::Totp => device.get_totp_slot_name(slot),
+    };
+    let name = match result {
+      Ok(name) => Some(name),
+      Err(nitrokey::Error::LibraryError(nitrokey::LibraryError::InvalidSlot)) => return Ok(slots),
+      Err(nitrokey::Error::CommandError(nitrokey::CommandError::SlotNotProgrammed)) => None,
+      Err(err) => return Err(err).context("Failed to check OTP slot"),
+    };
+    slots.push((slot, name));
+    slot = slot
+      .checked_add(1)
+      .ok_or_else(|| anyhow::anyhow!("Encountered integer overflow when iterating OTP slots"))?;
+  }
+}
+
+/// The configuration of an OTP slot as reported by the device.
+#[derive(Debug, PartialEq)]
+struct OtpSlotConfig {
+  use_enter: bool,
+  token_id: Option<String>,
+}
+
+impl OtpSlotConfig {
+  /// Decode the raw configuration byte and token ID of a slot.
+  fn from_raw(config: u8, token_id: &[u8]) -> Self {
+    // Bit 1 of the configuration byte indicates whether Enter is sent,
+    // bit 2 whether the token ID is used. The token ID is followed by
+    // the keyboard layout, which we ignore.
+    let token_id = if config & 0b100 != 0 {
+      let token_id = token_id
+        .iter()
+        .take(12)
+        .take_while(|&&byte| byte != 0)
+        .map(|&byte| char::from(byte))
+        .collect::<String>();
+      Some(token_id).filter(|token_id| !token_id.is_empty())
+    } else {
+      None
+    };
+    Self {
+      use_enter: config & 0b10 != 0,
+      token_id,
+    }
+  }
+}
+
+/// Read the configuration of the given HOTP slot.
+///
+/// libnitrokey only supports reading back the configuration of HOTP
+/// slots, and only devices for which `supports_extended_otp` holds
+/// report it reliably.  The caller has to check that.
+fn get_hotp_slot_config(
+  _device: &nitrokey::DeviceWrapper<'_>,
+  slot: u8,
+) -> anyhow::Result<OtpSlotConfig> {
+  let mut raw = nitrokey_sys::ReadSlot_t {
+    slot_name: [0; 15],
+    _slot_config: 0,
+    slot_token_id: [0; 13],
+    slot_counter: 0,
+  };
+  // The nitrokey crate does not expose the slot configuration, so we
+  // call into libnitrokey directly. Note that `NK_read_HOTP_slot` is
+  // marked as private in libnitrokey, i.e., we rely on its internals
+  // here and the function may change or vanish in future versions.
+  //
+  // SAFETY: `raw` is a valid `ReadSlot_t` that the function fills in.
+  // The slot is read from the device connected through `_device`, as
+  // libnitrokey only ever has a single connection.
+  let rv = unsafe { nitrokey_sys::NK_read_HOTP_slot(slot, &mut raw) };
+  if rv != 0 {
+    return Err(nitrokey::Error::from(rv)).context("Failed to read OTP slot configuration");
+  }
+  Ok(OtpSlotConfig::from_raw(
+    raw._slot_config,
+    &raw.slot_token_id,
+  ))
+}
+
+/// The fields available for templates of the `otp status` command.
+const OTP_STATUS_FIELDS: &[&str] = &["alg", "slot", "name", "programmed", "use_enter", "token_id"];
+
 fn print_otp_status(
   ctx: &mut ExecCtx<'_>,
   algorithm: args::OtpAlgorithm,
   device: &nitrokey::DeviceWrapper<'_>,
+  read_config: bool,
   all: bool,
+  template: Option<&template::Template>,
 ) -> anyhow::Result<()> {
-  let mut slot: u8 = 0;
-  loop {
-    let result = match algorithm {
-      args::OtpAlgorithm::Hotp => device.get_hotp_slot_name(slot),
-      args::OtpAlgorithm::Totp => device.get_totp_slot_name(slot),
-    };
-    slot = slot
-      .checked_add(1)
-      .ok_or_else(|| anyhow::anyhow!("Encountered integer overflow when iterating OTP slots"))?;
-    let name = match result {
-      Ok(name) => name,
-      Err(nitrokey::Error::LibraryError(nitrokey::LibraryError::InvalidSlot)) => return Ok(()),
-      Err(nitrokey::Error::CommandError(nitrokey::CommandError::SlotNotProgrammed)) => {
-        if all {
-          "[not programmed]".to_string()
-        } else {
-          continue;
-        }
+  for (slot, name) in get_otp_slots(algorithm, device)? {
+    let programmed = name.is_some();
+    if !programmed && !all {
+      continue;
+    }
+    let config = match algorithm {
+      args::OtpAlgorithm::Hotp if programmed && read_config => {
+        Some(get_hotp_slot_config(device, slot)?)
       }
-      Err(err) => return Err(err).context("Failed to check OTP slot"),
+      _ => None,
     };
-    println!(ctx, "{}\t{}\t{}", algorithm, slot - 1, name)?;
+
+    match template {
+      Some(template) => {
+        let output = template.render(|field| match field {
+          "alg" => Ok(algorithm.to_string()),
+          "slot" => Ok(slot.to_string()),
+          "name" => Ok(name.clone().unwrap_or_default()),
+          "use_enter" => Ok(
+            config
+              .as_ref()
+              .map(|config| config.use_enter.to_string())
+              .unwrap_or_default(),
+          ),
+          "token_id" => Ok(
+            config
+              .as_ref()
+              .and_then(|config| config.token_id.clone())
+              .unwrap_or_default(),
+          ),
+          _ => Ok(programmed.to_string()),
+        })?;
+        println!(ctx, "{}", output)?;
+      }
+      None => {
+        let name = name.unwrap_or_else(|| "[not programmed]".to_string());
+        let (enter, token_id) = match config {
+          Some(config) => (
+            if config.use_enter { "yes" } else { "no" }.to_string(),
+            config.token_id.unwrap_or_else(|| "-".to_string()),
+          ),
+          None => ("-".to_string(), "-".to_string()),
+        };
+        println!(
+          ctx,
+          "{}\t{}\t{}\t{}\t{}", algorithm, slot, name, enter, token_id
+        )?;
+      }
+    }
   }
+  Ok(())
 }
 
 /// Print the status of the OTP slots.
-pub fn otp_status(ctx: &mut ExecCtx<'_>, all: bool) -> anyhow::Result<()> {
+pub fn otp_status(ctx: &mut ExecCtx<'_>, all: bool, format: Option<&str>) -> anyhow::Result<()> {
+  let template = format
+    .map(|format| template::Template::parse(format, OTP_STATUS_FIELDS))
+    .transpose()?;
   with_device(ctx, |ctx, device| {
-    println!(ctx, "alg\tslot\tname")?;
-    print_otp_status(ctx, args::OtpAlgorithm::Hotp, &device, all)?;
-    print_otp_status(ctx, args::OtpAlgorithm::Totp, &device, all)?;
+    if template.is_none() {
+      println!(ctx, "alg\tslot\tname\tenter\ttoken id")?;
+    }
+    let version = device
+      .get_firmware_version()
+      .context("Failed to get firmware version")?;
+    let read_config = supports_extended_otp(device.get_model(), version.major, version.minor);
+    let template = template.as_ref();
+    for algorithm in &[args::OtpAlgorithm::Hotp, args::OtpAlgorithm::Totp] {
+      print_otp_status(ctx, *algorithm, &device, read_config, all, template)?;
+    }
     Ok(())
   })
 }
 
 /// Clear the PIN stored by various operations.
 pub fn pin_clear(ctx: &mut ExecCtx<'_>) -> anyhow::Result<()> {
-  with_device(ctx, |_ctx, device| {
-    pinentry::clear(&pinentry::PinEntry::from(args::PinType::Admin, &device)?)
-      .context("Failed to clear admin PIN")?;
-    pinentry::clear(&pinentry::PinEntry::from(args::PinType::User, &device)?)
-      .context("Failed to clear user PIN")?;
+  clear_agent(ctx)?;
+  with_device(ctx, |ctx, device| {
+    pinentry::clear(
+      ctx,
+      &pinentry::PinEntry::from(args::PinType::Admin, &device)?,
+    )
+    .context("Failed to clear admin PIN")?;
+    pinentry::clear(
+      ctx,
+      &pinentry::PinEntry::from(args::PinType::User, &device)?,
+    )
+    .context("Failed to clear user PIN")?;
     Ok(())
   })
 }
@@ -827,10 +2280,14 @@ fn choose_pin(
   };
 
   if let Some(new_pin) = new_pin {
-    new_pin
+    let new_pin = new_pin
       .to_str()
-      .ok_or_else(|| anyhow::anyhow!("Failed to read PIN: Invalid Unicode data found"))
-      .map(ToOwned::to_owned)
+      .ok_or_else(|| anyhow::anyhow!("Failed to read PIN: Invalid Unicode data found"))?
+      .to_owned();
+    if new {
+      pinentry::check(ctx, pin_entry, &new_pin).context("Failed to select PIN")?;
+    }
+    Ok(new_pin)
   } else {
     pinentry::choose(ctx, pin_entry).context("Failed to select PIN")
   }
@@ -838,7 +2295,8 @@ fn choose_pin(
 
 /// Change a PIN.
 pub fn pin_set(ctx: &mut ExecCtx<'_>, pin_type: args::PinType) -> anyhow::Result<()> {
-  with_device(ctx, |ctx, mut device| {
+  let command = format!("pin set {}", pin_type);
+  with_audited_device(ctx, &command, |ctx, mut device| {
     let pin_entry = pinentry::PinEntry::from(pin_type, &device)?;
     let new_pin = choose_pin(ctx, &pin_entry, true)?;
 
@@ -854,13 +2312,13 @@ pub fn pin_set(ctx: &mut ExecCtx<'_>, pin_type: args::PinType) -> anyhow::Result
     // We just changed the PIN but confirmed the action with the old PIN,
     // which may have caused it to be cached. Since it no longer applies,
     // make sure to evict the corresponding entry from the cache.
-    pinentry::clear(&pin_entry)
+    pinentry::clear(ctx, &pin_entry)
   })
 }
 
 /// Unblock and reset the user PIN.
 pub fn pin_unblock(ctx: &mut ExecCtx<'_>) -> anyhow::Result<()> {
-  with_device(ctx, |ctx, mut device| {
+  with_audited_device(ctx, "pin unblock", |ctx, mut device| {
     let pin_entry = pinentry::PinEntry::from(args::PinType::User, &device)?;
     let user_pin = choose_pin(ctx, &pin_entry, false)?;
     let pin_entry = pinentry::PinEntry::from(args::PinType::Admin, &device)?;
@@ -890,98 +2348,1312 @@ fn print_pws_data(
 
 fn check_slot(pws: &nitrokey::PasswordSafe<'_, '_>, slot: u8) -> anyhow::Result<()> {
   if slot >= nitrokey::SLOT_COUNT {
-    anyhow::bail!("Slot {} is not valid", slot);
+    return Err(nitrokey::Error::from(nitrokey::LibraryError::InvalidSlot))
+      .with_context(|| format!("Slot {} is not valid", slot));
   }
   let status = pws
     .get_slot_status()
     .context("Failed to read PWS slot status")?;
   if status[slot as usize] {
     Ok(())
-  } else {
-    anyhow::bail!("Slot {} is not programmed", slot)
+  } else {
+    Err(nitrokey::Error::from(
+      nitrokey::CommandError::SlotNotProgrammed,
+    ))
+    .with_context(|| format!("Slot {} is not programmed", slot))
+  }
+}
+
+/// Find the PWS slots storing the given credential.
+fn find_credential_slots(
+  pws: &nitrokey::PasswordSafe<'_, '_>,
+  credential: &credential::Credential,
+) -> anyhow::Result<Vec<u8>> {
+  let mut slots = Vec::new();
+  for (slot, name) in get_pws_slots(pws)? {
+    let login = pws
+      .get_slot_login(slot)
+      .context("Failed to read PWS slot login")?;
+    if credential.matches(&name, &login) {
+      slots.push(slot);
+    }
+  }
+  Ok(slots)
+}
+
+/// Verify the hash chain of the audit log.
+pub fn audit_log_verify(
+  ctx: &mut ExecCtx<'_>,
+  file: Option<&path::Path>,
+  head: Option<&str>,
+) -> anyhow::Result<()> {
+  let path = match file {
+    Some(file) => file.to_path_buf(),
+    None => audit_log::configured_path(ctx.config_dir.as_deref())?
+      .ok_or_else(|| anyhow::anyhow!("The audit log is not enabled"))?,
+  };
+  let content = fs::read_to_string(&path)
+    .with_context(|| format!("Failed to read audit log {}", path.display()))?;
+  let (count, head) = audit_log::verify(&content, head)
+    .with_context(|| format!("Failed to verify audit log {}", path.display()))?;
+  println!(
+    ctx,
+    "Verified {} entries of audit log {} with head {}",
+    count,
+    path.display(),
+    head
+  )?;
+  Ok(())
+}
+
+/// Act as an askpass program, answering the given prompt with a
+/// password from the password safe.
+pub fn askpass(
+  ctx: &mut ExecCtx<'_>,
+  prompt: &str,
+  rules: Option<&path::Path>,
+) -> anyhow::Result<()> {
+  let rules = askpass::Rules::load(rules)?;
+  let password = match rules.find(prompt) {
+    Some(slot) => {
+      let mut password = String::new();
+      with_password_safe(ctx, |_ctx, pws| {
+        check_slot(&pws, slot).context("Failed to access PWS slot")?;
+        password = pws
+          .get_slot_password(slot)
+          .context("Failed to read PWS slot password")?;
+        Ok(())
+      })?;
+      password
+    }
+    // Prompts we have no rule for are forwarded to the user.
+    None => {
+      let entry = pinentry::PromptEntry::new(prompt);
+      pinentry::inquire(ctx, &entry, pinentry::Mode::Query, None)?
+    }
+  };
+  println!(ctx, "{}", password)?;
+  Ok(())
+}
+
+/// Act as a git credential helper backed by the password safe.
+pub fn git_credential(
+  ctx: &mut ExecCtx<'_>,
+  action: args::GitCredentialAction,
+  write: bool,
+) -> anyhow::Result<()> {
+  let mut lines = Vec::new();
+  loop {
+    let line = read_line(ctx)?;
+    if line.is_empty() {
+      break;
+    }
+    lines.push(line);
+  }
+  let credential = credential::Credential::parse(lines.iter().map(String::as_str))
+    .context("Failed to parse credential")?;
+
+  // Without a host we cannot tell which slot to use. Git expects
+  // helpers to silently ignore requests they cannot handle.
+  let host = match &credential.host {
+    Some(host) => host.clone(),
+    None => return Ok(()),
+  };
+  // The host is used as the slot name, so longer hosts could neither be
+  // stored nor found.
+  if host.len() > import::NAME_LEN {
+    anyhow::bail!(
+      "The host {} is too long: PWS slot names are limited to {} bytes",
+      host,
+      import::NAME_LEN
+    )
+  }
+
+  match action {
+    args::GitCredentialAction::Get => with_password_safe(ctx, |ctx, pws| {
+      if let Some(&slot) = find_credential_slots(&pws, &credential)?.first() {
+        let login = pws
+          .get_slot_login(slot)
+          .context("Failed to read PWS slot login")?;
+        let password = pws
+          .get_slot_password(slot)
+          .context("Failed to read PWS slot password")?;
+        write!(ctx.stdout, "{}", credential::format(&login, &password))?;
+      }
+      Ok(())
+    }),
+    args::GitCredentialAction::Store if write => {
+      let (username, password) = match (&credential.username, &credential.password) {
+        (Some(username), Some(password)) => (username.clone(), password.clone()),
+        _ => return Ok(()),
+      };
+
+      with_audited_password_safe(ctx, "git-credential store", |_ctx, mut pws| {
+        let slot = match find_credential_slots(&pws, &credential)?.first() {
+          Some(&slot) => {
+            // Git stores the credential after each successful
+            // authentication, so only write it if it actually changed.
+            let stored = pws
+              .get_slot_password(slot)
+              .context("Failed to read PWS slot password")?;
+            if stored == password {
+              return Ok(());
+            }
+            slot
+          }
+          None => pws
+            .get_slot_status()
+            .context("Failed to read PWS slot status")?
+            .iter()
+            .position(|&programmed| !programmed)
+            .and_then(|slot| u8::try_from(slot).ok())
+            .ok_or_else(|| anyhow::anyhow!("No free PWS slot available"))?,
+        };
+        pws
+          .write_slot(slot, &host, &username, &password)
+          .context("Failed to write PWS slot")
+      })
+    }
+    args::GitCredentialAction::Erase if write => {
+      with_audited_password_safe(ctx, "git-credential erase", |_ctx, mut pws| {
+        for slot in find_credential_slots(&pws, &credential)? {
+          // Only erase the credential git tried to use, not one that may
+          // have been updated in the meantime.
+          if let Some(password) = &credential.password {
+            let stored = pws
+              .get_slot_password(slot)
+              .context("Failed to read PWS slot password")?;
+            if &stored != password {
+              continue;
+            }
+          }
+          pws.erase_slot(slot).context("Failed to clear PWS slot")?;
+        }
+        Ok(())
+      })
+    }
+    args::GitCredentialAction::Store | args::GitCredentialAction::Erase => Ok(()),
+  }
+}
+
+/// Handle a request of a browser extension.
+fn handle_native_request(
+  ctx: &mut ExecCtx<'_>,
+  request: &native::Request,
+) -> anyhow::Result<serde_json::Value> {
+  let mut result = serde_json::Value::Null;
+  match request {
+    native::Request::List => with_password_safe(ctx, |_ctx, pws| {
+      let entries = read_pws_slots(&pws, |slot| {
+        let name = pws
+          .get_slot_name(slot)
+          .context("Failed to read PWS slot name")?;
+        let login = pws
+          .get_slot_login(slot)
+          .context("Failed to read PWS slot login")?;
+        Ok(serde_json::json!({"slot": slot, "name": name, "login": login}))
+      })?;
+      let entries = entries.into_iter().flatten().collect::<Vec<_>>();
+      result = serde_json::json!({ "entries": entries });
+      Ok(())
+    })?,
+    native::Request::GetPassword { slot } => with_password_safe(ctx, |_ctx, pws| {
+      check_slot(&pws, *slot).context("Failed to access PWS slot")?;
+      let password = pws
+        .get_slot_password(*slot)
+        .context("Failed to read PWS slot password")?;
+      result = serde_json::json!({ "password": password });
+      Ok(())
+    })?,
+    native::Request::GetOtp { name } => with_device(ctx, |ctx, mut device| {
+      let slot = get_otp_slots(args::OtpAlgorithm::Totp, &device)?
+        .into_iter()
+        .find(|(_, slot_name)| slot_name.as_ref() == Some(name))
+        .map(|(slot, _)| slot)
+        .ok_or_else(|| anyhow::anyhow!("No TOTP slot with name {} found", name))?;
+
+      let time = get_unix_timestamp().context("Failed to retrieve current time")?;
+      device
+        .set_time(time, true)
+        .context("Failed to set new time")?;
+      let config = device
+        .get_config()
+        .context("Failed to get get current device configuration")?;
+      let code = if config.user_password {
+        let mut user = authenticate_user(ctx, device)?;
+        get_otp(slot, args::OtpAlgorithm::Totp, &mut user)
+      } else {
+        get_otp(slot, args::OtpAlgorithm::Totp, &mut device)
+      }?;
+      result = serde_json::json!({ "code": code });
+      Ok(())
+    })?,
+  }
+  Ok(result)
+}
+
+/// Serve requests of a browser extension using the native messaging
+/// protocol.
+pub fn native_messaging_host(
+  ctx: &mut ExecCtx<'_>,
+  rules: Option<&path::Path>,
+  args: &[String],
+) -> anyhow::Result<()> {
+  let rules = native::Rules::load(rules)?;
+  let origin = native::origin(args)?.to_string();
+
+  while let Some(message) = native::read_message(ctx.stdin)? {
+    // Errors are reported to the extension and do not terminate the
+    // host.
+    let result = native::Request::parse(&message).and_then(|request| {
+      rules.check(&origin, &request)?;
+      handle_native_request(ctx, &request)
+    });
+    let response = match result {
+      Ok(result) => serde_json::json!({ "result": result }),
+      Err(err) => serde_json::json!({ "error": format!("{:#}", err) }),
+    };
+    native::write_message(ctx.stdout, &response)?;
+  }
+  Ok(())
+}
+
+/// Install the native messaging host manifest for the given browser.
+pub fn native_messaging_install(
+  ctx: &mut ExecCtx<'_>,
+  browser: args::Browser,
+  rules: Option<&path::Path>,
+  dir: Option<&path::Path>,
+) -> anyhow::Result<()> {
+  let origins = native::Rules::load(rules)?.origins(browser);
+  if origins.is_empty() {
+    anyhow::bail!("No rules for {} extensions found", browser)
+  }
+
+  let dir = match dir {
+    Some(dir) => dir.to_path_buf(),
+    None => native::manifest_dir(browser)?,
+  };
+  fs::create_dir_all(&dir)
+    .with_context(|| format!("Failed to create directory {}", dir.display()))?;
+
+  // Browsers invoke the host without any arguments of our own, so we
+  // install a wrapper script running the actual command.
+  let exe = std::env::current_exe().context("Failed to determine the nitrocli executable")?;
+  let mut command = vec![
+    native::shell_quote(&exe.to_string_lossy()),
+    "native-messaging".to_string(),
+    "host".to_string(),
+  ];
+  if let Some(rules) = rules {
+    let rules = fs::canonicalize(rules)
+      .with_context(|| format!("Failed to resolve path {}", rules.display()))?;
+    command.push("--rules".to_string());
+    command.push(native::shell_quote(&rules.to_string_lossy()));
+  }
+  let script = format!("#!/bin/sh\nexec {} -- \"$@\"\n", command.join(" "));
+
+  let wrapper = dir.join(format!("{}-native-messaging", native::HOST_NAME));
+  fs::write(&wrapper, script)
+    .with_context(|| format!("Failed to write wrapper script {}", wrapper.display()))?;
+  fs::set_permissions(&wrapper, fs::Permissions::from_mode(0o755))
+    .with_context(|| format!("Failed to set permissions of {}", wrapper.display()))?;
+
+  let manifest = native::manifest(browser, &wrapper, origins);
+  let path = dir.join(format!("{}.json", native::HOST_NAME));
+  let content = serde_json::to_string_pretty(&manifest).context("Failed to serialize manifest")?;
+  fs::write(&path, content + "\n")
+    .with_context(|| format!("Failed to write manifest {}", path.display()))?;
+
+  println!(
+    ctx,
+    "Installed native messaging host manifest {}",
+    path.display()
+  )?;
+  Ok(())
+}
+
+/// A Secret Service backend using the password safe.
+#[cfg(feature = "secret-service")]
+struct PwsBackend<'c, 'io> {
+  ctx: &'c mut ExecCtx<'io>,
+  /// The cached metadata of the programmed PWS slots.
+  items: Option<Vec<secret::Item>>,
+}
+
+#[cfg(feature = "secret-service")]
+impl<'c, 'io> secret::Backend for PwsBackend<'c, 'io> {
+  fn items(&mut self) -> anyhow::Result<Vec<secret::Item>> {
+    if let Some(items) = &self.items {
+      return Ok(items.clone());
+    }
+
+    let mut items = Vec::new();
+    with_password_safe(self.ctx, |_ctx, pws| {
+      let slots = read_pws_slots(&pws, |slot| {
+        Ok(secret::Item {
+          slot,
+          name: pws
+            .get_slot_name(slot)
+            .context("Failed to read PWS slot name")?,
+          login: pws
+            .get_slot_login(slot)
+            .context("Failed to read PWS slot login")?,
+        })
+      })?;
+      items = slots.into_iter().flatten().collect();
+      Ok(())
+    })?;
+    self.items = Some(items.clone());
+    Ok(items)
+  }
+
+  fn secret(&mut self, slot: u8) -> anyhow::Result<String> {
+    let mut password = String::new();
+    with_password_safe(self.ctx, |_ctx, pws| {
+      password = pws
+        .get_slot_password(slot)
+        .context("Failed to read PWS slot password")?;
+      Ok(())
+    })?;
+    Ok(password)
+  }
+
+  fn create(&mut self, item: &secret::Item, secret: &str, replace: bool) -> anyhow::Result<u8> {
+    let existing = if replace {
+      self
+        .items()?
+        .into_iter()
+        .find(|other| other.name == item.name && other.login == item.login)
+        .map(|other| other.slot)
+    } else {
+      None
+    };
+
+    let mut slot = 0;
+    with_audited_password_safe(self.ctx, "secret-service create-item", |_ctx, mut pws| {
+      slot = match existing {
+        Some(slot) => slot,
+        None => pws
+          .get_slot_status()
+          .context("Failed to read PWS slot status")?
+          .iter()
+          .position(|&programmed| !programmed)
+          .and_then(|slot| u8::try_from(slot).ok())
+          .ok_or_else(|| anyhow::anyhow!("No free PWS slot available"))?,
+      };
+      pws
+        .write_slot(slot, &item.name, &item.login, secret)
+        .context("Failed to write PWS slot")
+    })?;
+    self.items = None;
+    Ok(slot)
+  }
+}
+
+/// Provide a Secret Service exposing the PWS slots on the given bus or,
+/// by default, the session bus.
+#[cfg(feature = "secret-service")]
+pub fn secret_service(ctx: &mut ExecCtx<'_>, address: Option<&str>) -> anyhow::Result<()> {
+  let channel = match address {
+    Some(address) => dbus::channel::Channel::open_private(address).and_then(|mut channel| {
+      channel.register()?;
+      Ok(channel)
+    }),
+    None => dbus::channel::Channel::get_private(dbus::channel::BusType::Session),
+  }
+  .context("Failed to connect to the bus")?;
+  let connection = dbus::blocking::Connection::from(channel);
+
+  let reply = connection
+    .request_name(secret::BUS_NAME, false, false, true)
+    .context("Failed to request the Secret Service name")?;
+  if reply != dbus::blocking::stdintf::org_freedesktop_dbus::RequestNameReply::PrimaryOwner {
+    anyhow::bail!("Another Secret Service is already running")
+  }
+
+  let backend = PwsBackend { ctx, items: None };
+  let mut service = secret::Service::new(backend);
+  let channel = connection.channel();
+  loop {
+    let msg = match channel.blocking_pop_message(time::Duration::from_secs(60)) {
+      Ok(Some(msg)) => msg,
+      Ok(None) => continue,
+      // The bus went away, so there is nobody left to serve.
+      Err(_) if !channel.is_connected() => return Ok(()),
+      Err(err) => return Err(err).context("Failed to receive message"),
+    };
+    if msg.msg_type() != dbus::message::MessageType::MethodCall {
+      continue;
+    }
+
+    let reply = service
+      .handle(&msg)
+      .or_else(|| dbus::channel::default_reply(&msg));
+    if let Some(reply) = reply {
+      let _ = channel
+        .send(reply)
+        .map_err(|()| anyhow::anyhow!("Failed to send reply"))?;
+    }
+  }
+}
+
+/// Read data from all PWS slots using the given function, yielding
+/// `None` for slots that are not programmed.
+fn read_pws_slots<T, F>(
+  pws: &nitrokey::PasswordSafe<'_, '_>,
+  mut read: F,
+) -> anyhow::Result<Vec<Option<T>>>
+where
+  F: FnMut(u8) -> anyhow::Result<T>,
+{
+  let status = pws
+    .get_slot_status()
+    .context("Failed to read PWS slot status")?;
+  status
+    .iter()
+    .enumerate()
+    .map(|(slot, &programmed)| {
+      let slot = u8::try_from(slot).map_err(|_| anyhow::anyhow!("Invalid PWS slot number"))?;
+      if programmed {
+        read(slot).map(Some)
+      } else {
+        Ok(None)
+      }
+    })
+    .collect()
+}
+
+/// Read the contents of all PWS slots.
+fn get_pws_contents(
+  pws: &nitrokey::PasswordSafe<'_, '_>,
+) -> anyhow::Result<Vec<Option<provision::PwsSlot>>> {
+  read_pws_slots(pws, |slot| {
+    Ok(provision::PwsSlot {
+      slot,
+      name: pws
+        .get_slot_name(slot)
+        .context("Failed to read PWS slot name")?,
+      login: pws
+        .get_slot_login(slot)
+        .context("Failed to read PWS slot login")?,
+      password: pws
+        .get_slot_password(slot)
+        .context("Failed to read PWS slot password")?,
+    })
+  })
+}
+
+/// The fields available for templates of the `pws get` command.
+const PWS_GET_FIELDS: &[&str] = &["slot", "name", "login", "password"];
+
+/// Read a PWS slot.
+pub fn pws_get(
+  ctx: &mut ExecCtx<'_>,
+  slot: u8,
+  show_name: bool,
+  show_login: bool,
+  show_password: bool,
+  quiet: bool,
+  format: Option<&str>,
+) -> anyhow::Result<()> {
+  let template = format
+    .map(|format| template::Template::parse(format, PWS_GET_FIELDS))
+    .transpose()?;
+  let show_all = !show_name && !show_login && !show_password;
+
+  if let Some(response) = request_agent(ctx, &agent::Request::PwsGet { slot })? {
+    if let [name, login, password] = response.as_slice() {
+      if let Some(template) = &template {
+        let output = template.render(|field| match field {
+          "slot" => Ok(slot.to_string()),
+          "name" => Ok(name.clone()),
+          "login" => Ok(login.clone()),
+          _ => Ok(password.clone()),
+        })?;
+        println!(ctx, "{}", output)?;
+        return Ok(());
+      }
+      if show_all || show_name {
+        print_pws_data(ctx, "name:    ", Ok(name.clone()), quiet)?;
+      }
+      if show_all || show_login {
+        print_pws_data(ctx, "login:   ", Ok(login.clone()), quiet)?;
+      }
+      if show_all || show_password {
+        print_pws_data(ctx, "password:", Ok(password.clone()), quiet)?;
+      }
+      return Ok(());
+    }
+    anyhow::bail!("Received invalid PWS slot data from the agent")
+  }
+
+  with_password_safe(ctx, |ctx, pws| {
+    check_slot(&pws, slot).context("Failed to access PWS slot")?;
+
+    if let Some(template) = &template {
+      let output = template.render(|field| match field {
+        "slot" => Ok(slot.to_string()),
+        "name" => pws.get_slot_name(slot).context("Failed to access PWS slot"),
+        "login" => pws
+          .get_slot_login(slot)
+          .context("Failed to access PWS slot"),
+        _ => pws
+          .get_slot_password(slot)
+          .context("Failed to access PWS slot"),
+      })?;
+      println!(ctx, "{}", output)?;
+      return Ok(());
+    }
+
+    if show_all || show_name {
+      print_pws_data(ctx, "name:    ", pws.get_slot_name(slot), quiet)?;
+    }
+    if show_all || show_login {
+      print_pws_data(ctx, "login:   ", pws.get_slot_login(slot), quiet)?;
+    }
+    if show_all || show_password {
+      print_pws_data(ctx, "password:", pws.get_slot_password(slot), quiet)?;
+    }
+    Ok(())
+  })
+}
+
+/// Write a PWS slot.
+pub fn pws_set(
+  ctx: &mut ExecCtx<'_>,
+  slot: u8,
+  name: &str,
+  login: &str,
+  password: &str,
+) -> anyhow::Result<()> {
+  let command = format!("pws set {}", slot);
+  with_audited_password_safe(ctx, &command, |_ctx, mut pws| {
+    pws
+      .write_slot(slot, name, login, password)
+      .context("Failed to write PWS slot")
+  })
+}
+
+/// Read the data of all programmed PWS slots.
+fn get_pws_entries(
+  pws: &nitrokey::PasswordSafe<'_, '_>,
+) -> anyhow::Result<Vec<(u8, import::Entry)>> {
+  let entries = get_pws_contents(pws)?
+    .into_iter()
+    .flatten()
+    .map(|slot| {
+      let entry = import::Entry {
+        name: slot.name,
+        login: slot.login,
+        password: slot.password,
+      };
+      (slot.slot, entry)
+    })
+    .collect();
+  Ok(entries)
+}
+
+/// Audit the passwords stored in all programmed PWS slots.
+pub fn pws_audit(
+  ctx: &mut ExecCtx<'_>,
+  min_entropy: f64,
+  breached: Option<&path::Path>,
+) -> anyhow::Result<()> {
+  // Open the list before accessing the device so that a wrong path
+  // does not require entering the user PIN.
+  let breached = breached
+    .map(|path| {
+      fs::File::open(path)
+        .map(io::BufReader::new)
+        .with_context(|| format!("Failed to open breached password list {}", path.display()))
+    })
+    .transpose()?;
+
+  let mut entries = Vec::new();
+  with_password_safe(ctx, |_ctx, pws| {
+    entries = get_pws_entries(&pws)?;
+    Ok(())
+  })?;
+
+  let issues = audit::audit(&entries, min_entropy, breached)?;
+  if issues.is_empty() {
+    println!(ctx, "No issues found")?;
+  } else {
+    println!(ctx, "slot\tname\tissue")?;
+    for (slot, issue) in issues {
+      let name = entries
+        .iter()
+        .find(|(other, _)| *other == slot)
+        .map(|(_, entry)| entry.name.as_str())
+        .unwrap_or_default();
+      println!(ctx, "{}\t{}\t{}", slot, name, issue)?;
+    }
+  }
+  Ok(())
+}
+
+/// Parse an environment variable specification of the form
+/// `VAR=SLOT:FIELD`.
+fn parse_env_spec(spec: &str) -> anyhow::Result<(&str, u8, &str)> {
+  let parse = || {
+    let mut parts = spec.splitn(2, '=');
+    let var = parts.next().filter(|var| !var.is_empty())?;
+    let mut parts = parts.next()?.splitn(2, ':');
+    let slot = parts.next()?.parse().ok()?;
+    let field = parts.next()?;
+    match field {
+      "name" | "login" | "password" => Some((var, slot, field)),
+      _ => None,
+    }
+  };
+  parse().ok_or_else(|| {
+    anyhow::anyhow!(
+      "Invalid environment variable specification {}: expected VAR=SLOT:FIELD \
+       with FIELD being one of name, login, or password",
+      spec
+    )
+  })
+}
+
+/// Execute a command with environment variables set from PWS slots and
+/// forward its exit status.
+pub fn pws_exec(ctx: &mut ExecCtx<'_>, env: &[String], command: &[String]) -> anyhow::Result<()> {
+  let specs = env
+    .iter()
+    .map(|spec| parse_env_spec(spec))
+    .collect::<anyhow::Result<Vec<_>>>()?;
+
+  let mut vars = Vec::new();
+  with_password_safe(ctx, |_ctx, pws| {
+    vars.clear();
+    for (var, slot, field) in &specs {
+      check_slot(&pws, *slot).context("Failed to access PWS slot")?;
+      let value = match *field {
+        "name" => pws.get_slot_name(*slot),
+        "login" => pws.get_slot_login(*slot),
+        _ => pws.get_slot_password(*slot),
+      }
+      .context("Failed to access PWS slot")?;
+      vars.push((*var, value));
+    }
+    Ok(())
+  })?;
+
+  // The command is required by the argument parser.
+  let (program, args) = command.split_first().unwrap();
+  let mut command = process::Command::new(program);
+  // The command must not see the PINs we were provided with.
+  for var in crate::SECRET_ENV_VARS {
+    let _ = command.env_remove(var);
+  }
+  let status = command
+    .args(args)
+    .envs(vars)
+    .status()
+    .with_context(|| format!("Failed to execute {}", program))?;
+
+  match status.code() {
+    Some(0) => Ok(()),
+    Some(code) => Err(crate::DirectExitError(code).into()),
+    // Report termination by a signal like shells do.
+    None => {
+      use std::os::unix::process::ExitStatusExt as _;
+      Err(crate::DirectExitError(128 + status.signal().unwrap_or_default()).into())
+    }
+  }
+}
+
+/// Export all programmed PWS slots to the format of another password
+/// manager.
+pub fn pws_export(
+  ctx: &mut ExecCtx<'_>,
+  format: args::PwsFormat,
+  output: Option<&path::Path>,
+  encrypt_to: Option<&str>,
+  allow_plaintext: bool,
+) -> anyhow::Result<()> {
+  if format == args::PwsFormat::Pass {
+    if output.is_none() || encrypt_to.is_none() {
+      anyhow::bail!("Exporting to pass requires the --output and --encrypt-to options")
+    }
+  } else if encrypt_to.is_none() && !allow_plaintext {
+    match output {
+      None if ctx.stdout_tty => anyhow::bail!(
+        "Refusing to write plaintext passwords to a terminal (use --allow-plaintext to override)"
+      ),
+      Some(path) if export::is_world_readable(path)? => anyhow::bail!(
+        "Refusing to write plaintext passwords to world-readable file {} (use --allow-plaintext to override)",
+        path.display()
+      ),
+      _ => (),
+    }
+  }
+
+  let mut entries = Vec::new();
+  with_password_safe(ctx, |_ctx, pws| {
+    entries = get_pws_entries(&pws)?;
+    Ok(())
+  })?;
+
+  if format == args::PwsFormat::Pass {
+    // Both options were checked above.
+    return export::write_pass(output.unwrap(), &entries, encrypt_to.unwrap());
+  }
+
+  let entries = entries
+    .into_iter()
+    .map(|(_, entry)| entry)
+    .collect::<Vec<_>>();
+  let mut data = export::serialize(format, &entries)?;
+  if let Some(recipient) = encrypt_to {
+    data = export::encrypt(data, recipient, output.is_none())?;
+  }
+  match output {
+    Some(path) => export::write_file(path, &data),
+    None => ctx
+      .stdout
+      .write_all(&data)
+      .context("Failed to write to standard output"),
   }
 }
 
-/// Read a PWS slot.
-pub fn pws_get(
+/// Import entries from another password manager into free PWS slots.
+pub fn pws_import(
   ctx: &mut ExecCtx<'_>,
-  slot: u8,
-  show_name: bool,
-  show_login: bool,
-  show_password: bool,
-  quiet: bool,
+  format: args::PwsFormat,
+  path: &path::Path,
+  dry_run: bool,
+  yes: bool,
 ) -> anyhow::Result<()> {
-  with_password_safe(ctx, |ctx, pws| {
-    check_slot(&pws, slot).context("Failed to access PWS slot")?;
+  let (entries, warnings) = import::fit(import::read(format, path)?);
+  for warning in &warnings {
+    eprintln!(ctx, "Warning: {}", warning)?;
+  }
+  if entries.is_empty() {
+    anyhow::bail!("No entries found to import")
+  }
 
-    let show_all = !show_name && !show_login && !show_password;
-    if show_all || show_name {
-      print_pws_data(ctx, "name:    ", pws.get_slot_name(slot), quiet)?;
+  let import = |ctx: &mut ExecCtx<'_>, mut pws: nitrokey::PasswordSafe<'_, '_>| {
+    let status = pws
+      .get_slot_status()
+      .context("Failed to read PWS slot status")?;
+    let free = status
+      .iter()
+      .enumerate()
+      .filter(|(_, &programmed)| !programmed)
+      .map(|(slot, _)| u8::try_from(slot).map_err(|_| anyhow::anyhow!("Invalid PWS slot number")))
+      .collect::<anyhow::Result<Vec<_>>>()?;
+    if entries.len() > free.len() {
+      anyhow::bail!(
+        "Cannot import {} entries: only {} of {} PWS slots are free",
+        entries.len(),
+        free.len(),
+        nitrokey::SLOT_COUNT
+      )
     }
-    if show_all || show_login {
-      print_pws_data(ctx, "login:   ", pws.get_slot_login(slot), quiet)?;
+
+    println!(ctx, "slot\tname\tlogin")?;
+    for (slot, entry) in free.iter().zip(&entries) {
+      println!(ctx, "{}\t{}\t{}", slot, entry.name, entry.login)?;
     }
-    if show_all || show_password {
-      print_pws_data(ctx, "password:", pws.get_slot_password(slot), quiet)?;
+    if dry_run {
+      return Ok(());
+    }
+    if !yes {
+      eprintln!(
+        ctx,
+        "Import {} entries into the PWS slots above? [y/N]",
+        entries.len()
+      )?;
+      let answer = read_line(ctx)?;
+      if !answer.trim().eq_ignore_ascii_case("y") {
+        anyhow::bail!("Import aborted")
+      }
     }
-    Ok(())
-  })
-}
 
-/// Write a PWS slot.
-pub fn pws_set(
-  ctx: &mut ExecCtx<'_>,
-  slot: u8,
-  name: &str,
-  login: &str,
-  password: &str,
-) -> anyhow::Result<()> {
-  with_password_safe(ctx, |_ctx, mut pws| {
-    pws
-      .write_slot(slot, name, login, password)
-      .context("Failed to write PWS slot")
-  })
+    for (&slot, entry) in free.iter().zip(&entries) {
+      pws
+        .write_slot(slot, &entry.name, &entry.login, &entry.password)
+        .with_context(|| format!("Failed to write PWS slot {}", slot))?;
+    }
+    Ok(())
+  };
+  // A dry run does not change the device and so is not recorded.
+  if dry_run {
+    with_password_safe(ctx, import)
+  } else {
+    with_audited_password_safe(ctx, "pws import", import)
+  }
 }
 
 /// Clear a PWS slot.
 pub fn pws_clear(ctx: &mut ExecCtx<'_>, slot: u8) -> anyhow::Result<()> {
-  with_password_safe(ctx, |_ctx, mut pws| {
+  let command = format!("pws clear {}", slot);
+  with_audited_password_safe(ctx, &command, |_ctx, mut pws| {
     pws.erase_slot(slot).context("Failed to clear PWS slot")
   })
 }
 
+/// Retrieve the numbers and names of all programmed PWS slots.
+fn get_pws_slots(pws: &nitrokey::PasswordSafe<'_, '_>) -> anyhow::Result<Vec<(u8, String)>> {
+  let slots = read_pws_slots(pws, |slot| {
+    let name = pws
+      .get_slot_name(slot)
+      .context("Failed to read PWS slot name")?;
+    Ok((slot, name))
+  })?;
+  Ok(slots.into_iter().flatten().collect())
+}
+
+/// The fields available for templates of the `pws status` command.
+const PWS_STATUS_FIELDS: &[&str] = &["slot", "name", "programmed"];
+
 fn print_pws_slot(
   ctx: &mut ExecCtx<'_>,
   pws: &nitrokey::PasswordSafe<'_, '_>,
   slot: usize,
   programmed: bool,
+  template: Option<&template::Template>,
 ) -> anyhow::Result<()> {
   let slot = u8::try_from(slot).map_err(|_| anyhow::anyhow!("Invalid PWS slot number"))?;
   let name = if programmed {
     pws
       .get_slot_name(slot)
       .context("Failed to read PWS slot name")?
+  } else if template.is_some() {
+    String::new()
   } else {
     "[not programmed]".to_string()
   };
-  println!(ctx, "{}\t{}", slot, name)?;
+
+  match template {
+    Some(template) => {
+      let output = template.render(|field| match field {
+        "slot" => Ok(slot.to_string()),
+        "name" => Ok(name.clone()),
+        _ => Ok(programmed.to_string()),
+      })?;
+      println!(ctx, "{}", output)?;
+    }
+    None => println!(ctx, "{}\t{}", slot, name)?,
+  }
   Ok(())
 }
 
 /// Print the status of all PWS slots.
-pub fn pws_status(ctx: &mut ExecCtx<'_>, all: bool) -> anyhow::Result<()> {
+pub fn pws_status(ctx: &mut ExecCtx<'_>, all: bool, format: Option<&str>) -> anyhow::Result<()> {
+  let template = format
+    .map(|format| template::Template::parse(format, PWS_STATUS_FIELDS))
+    .transpose()?;
   with_password_safe(ctx, |ctx, pws| {
     let slots = pws
       .get_slot_status()
       .context("Failed to read PWS slot status")?;
-    println!(ctx, "slot\tname")?;
+    if template.is_none() {
+      println!(ctx, "slot\tname")?;
+    }
     for (i, &value) in slots.iter().enumerate().filter(|(_, &value)| all || value) {
-      print_pws_slot(ctx, &pws, i, value)?;
+      print_pws_slot(ctx, &pws, i, value, template.as_ref())?;
+    }
+    Ok(())
+  })
+}
+
+/// Determine the path of the vault file to use.
+fn vault_path(file: Option<&path::Path>) -> anyhow::Result<path::PathBuf> {
+  match file {
+    Some(file) => Ok(file.to_path_buf()),
+    None => vault::default_path(),
+  }
+}
+
+/// Find the PWS slot holding the key of the vault with the given
+/// identifier.
+fn find_vault_slot(pws: &nitrokey::PasswordSafe<'_, '_>, id: &str) -> anyhow::Result<u8> {
+  for (slot, name) in get_pws_slots(pws)? {
+    if name == vault::SLOT_NAME {
+      let login = pws
+        .get_slot_login(slot)
+        .context("Failed to read PWS slot login")?;
+      if login == id {
+        return Ok(slot);
+      }
+    }
+  }
+  anyhow::bail!("No PWS slot holds the key of vault {}", id)
+}
+
+/// Open the vault at the given path, retrieving its key from the
+/// password safe.
+fn open_vault(ctx: &mut ExecCtx<'_>, path: &path::Path) -> anyhow::Result<(vault::Vault, String)> {
+  let data = vault::read(path)?;
+  let id = vault::id(&data).with_context(|| format!("Failed to read vault {}", path.display()))?;
+
+  let mut secret = String::new();
+  with_password_safe(ctx, |_ctx, pws| {
+    let slot = find_vault_slot(&pws, &id)?;
+    secret = pws
+      .get_slot_password(slot)
+      .context("Failed to read PWS slot password")?;
+    Ok(())
+  })?;
+
+  let vault = vault::Vault::decrypt(&data, &secret)
+    .with_context(|| format!("Failed to decrypt vault {}", path.display()))?;
+  Ok((vault, secret))
+}
+
+/// Create a new vault with its key stored in a PWS slot.
+pub fn vault_init(
+  ctx: &mut ExecCtx<'_>,
+  file: Option<&path::Path>,
+  slot: Option<u8>,
+) -> anyhow::Result<()> {
+  let path = vault_path(file)?;
+  if path.exists() {
+    anyhow::bail!("Vault {} already exists", path.display())
+  }
+
+  let vault = vault::Vault::new()?;
+  let secret = vault::generate_secret()?;
+  let data = vault.encrypt(&secret)?;
+
+  with_audited_password_safe(ctx, "vault init", |ctx, mut pws| {
+    let status = pws
+      .get_slot_status()
+      .context("Failed to read PWS slot status")?;
+    let slot = match slot {
+      Some(slot) if slot >= nitrokey::SLOT_COUNT => anyhow::bail!("Slot {} is not valid", slot),
+      Some(slot) if status[usize::from(slot)] => {
+        anyhow::bail!("PWS slot {} is already programmed", slot)
+      }
+      Some(slot) => slot,
+      None => status
+        .iter()
+        .position(|&programmed| !programmed)
+        .and_then(|slot| u8::try_from(slot).ok())
+        .ok_or_else(|| anyhow::anyhow!("No free PWS slot found"))?,
+    };
+
+    pws
+      .write_slot(slot, vault::SLOT_NAME, &vault.id(), &secret)
+      .context("Failed to write PWS slot")?;
+    if let Err(err) = vault::write(&path, &data) {
+      // Do not leave behind a key for a vault that does not exist.
+      let _ = pws.erase_slot(slot);
+      return Err(err);
     }
+    println!(
+      ctx,
+      "Created vault {} with its key in PWS slot {}",
+      path.display(),
+      slot
+    )?;
     Ok(())
   })
 }
 
+/// Add an entry to a vault.
+pub fn vault_add(
+  ctx: &mut ExecCtx<'_>,
+  file: Option<&path::Path>,
+  name: &str,
+  login: &str,
+  password: &str,
+) -> anyhow::Result<()> {
+  let path = vault_path(file)?;
+  let (mut vault, secret) = open_vault(ctx, &path)?;
+  if vault.find(name).is_some() {
+    anyhow::bail!("Entry {} already exists in the vault", name)
+  }
+
+  vault.entries.push(vault::Entry {
+    name: name.to_string(),
+    login: login.to_string(),
+    password: password.to_string(),
+  });
+  vault.entries.sort_by(|a, b| a.name.cmp(&b.name));
+  vault::write(&path, &vault.encrypt(&secret)?)
+}
+
+/// Read an entry from a vault.
+pub fn vault_get(
+  ctx: &mut ExecCtx<'_>,
+  file: Option<&path::Path>,
+  name: &str,
+  show_login: bool,
+  show_password: bool,
+  quiet: bool,
+) -> anyhow::Result<()> {
+  let path = vault_path(file)?;
+  let (vault, _) = open_vault(ctx, &path)?;
+  let entry = vault
+    .find(name)
+    .ok_or_else(|| anyhow::anyhow!("Entry {} not found in the vault", name))?;
+
+  let show_all = !show_login && !show_password;
+  let mut fields = Vec::new();
+  if show_all {
+    fields.push(("name:    ", &entry.name));
+  }
+  if show_all || show_login {
+    fields.push(("login:   ", &entry.login));
+  }
+  if show_all || show_password {
+    fields.push(("password:", &entry.password));
+  }
+  for (description, value) in fields {
+    if quiet {
+      println!(ctx, "{}", value)?;
+    } else {
+      println!(ctx, "{} {}", description, value)?;
+    }
+  }
+  Ok(())
+}
+
+/// List the names of the entries in a vault.
+pub fn vault_list(ctx: &mut ExecCtx<'_>, file: Option<&path::Path>) -> anyhow::Result<()> {
+  let path = vault_path(file)?;
+  let (vault, _) = open_vault(ctx, &path)?;
+  for entry in &vault.entries {
+    println!(ctx, "{}", entry.name)?;
+  }
+  Ok(())
+}
+
+/// Remove an entry from a vault.
+pub fn vault_rm(
+  ctx: &mut ExecCtx<'_>,
+  file: Option<&path::Path>,
+  name: &str,
+) -> anyhow::Result<()> {
+  let path = vault_path(file)?;
+  let (mut vault, secret) = open_vault(ctx, &path)?;
+  let index = vault
+    .entries
+    .iter()
+    .position(|entry| entry.name == name)
+    .ok_or_else(|| anyhow::anyhow!("Entry {} not found in the vault", name))?;
+  let _ = vault.entries.remove(index);
+  vault::write(&path, &vault.encrypt(&secret)?)
+}
+
+/// Retrieve the state of the given device that is relevant for the
+/// provided manifest.
+fn get_device_state(
+  ctx: &mut ExecCtx<'_>,
+  device: &mut nitrokey::DeviceWrapper<'_>,
+  manifest: &provision::Manifest,
+) -> anyhow::Result<provision::DeviceState> {
+  let config = device.get_config().context("Failed to get configuration")?;
+  let names = |slots: Vec<(u8, Option<String>)>| slots.into_iter().map(|(_, name)| name).collect();
+  let hotp = names(get_otp_slots(args::OtpAlgorithm::Hotp, device)?);
+  let totp = names(get_otp_slots(args::OtpAlgorithm::Totp, device)?);
+
+  // Reading the password safe requires the user PIN, so only do that
+  // if the manifest actually contains PWS slots.
+  let pws = if manifest.pws.is_empty() {
+    Vec::new()
+  } else {
+    let pin_entry = pinentry::PinEntry::from(args::PinType::User, &*device)?;
+    try_with_pin_and_data(ctx, &pin_entry, (), |_ctx, _, pin| {
+      let pws = device
+        .get_password_safe(pin)
+        .context("Could not access the password safe")
+        .map_err(|err| ((), err))?;
+      get_pws_contents(&pws).map_err(|err| ((), err))
+    })?
+  };
+
+  let unencrypted_read_only = match device {
+    nitrokey::DeviceWrapper::Storage(storage) => {
+      let status = storage
+        .get_storage_status()
+        .context("Failed to retrieve storage status")?;
+      Some(status.unencrypted_volume.read_only)
+    }
+    nitrokey::DeviceWrapper::Pro(_) => None,
+  };
+
+  Ok(provision::DeviceState {
+    config,
+    hotp,
+    totp,
+    pws,
+    unencrypted_read_only,
+  })
+}
+
+/// Apply the given changes requiring admin authentication.
+fn apply_admin_changes(
+  ctx: &mut ExecCtx<'_>,
+  device: nitrokey::DeviceWrapper<'_>,
+  changes: &[provision::Change],
+  new_admin_pin: Option<&str>,
+  new_user_pin: Option<&str>,
+) -> anyhow::Result<()> {
+  let pin_entry = pinentry::PinEntry::from(args::PinType::Admin, &device)?;
+  // We authenticate only once and keep the admin PIN around for the
+  // operations that expect it directly.
+  let (mut admin, admin_pin) =
+    try_with_pin_and_data(ctx, &pin_entry, device, |_ctx, device, pin| {
+      device
+        .authenticate_admin(pin)
+        .map(|admin| (admin, pin.to_string()))
+        .or_else(|(x, err)| {
+          Err(err)
+            .context("Failed to authenticate as admin")
+            .map_err(|err| (x, err))
+        })
+    })?;
+
+  for change in changes {
+    match change {
+      provision::Change::Config(config) => admin
+        .write_config(*config)
+        .context("Failed to set new configuration")?,
+      provision::Change::Otp(otp) => {
+        let data = nitrokey::OtpSlotData {
+          number: otp.slot,
+          name: otp.name.clone(),
+          secret: prepare_secret(otp.format, otp.secret.clone())?,
+          mode: otp.mode()?.into(),
+          use_enter: otp.use_enter,
+          token_id: otp.token_id.clone(),
+        };
+        check_secret_len(&admin, &data.secret)?;
+        match otp.algorithm {
+          args::OtpAlgorithm::Hotp => admin.write_hotp_slot(data, otp.counter),
+          args::OtpAlgorithm::Totp => admin.write_totp_slot(data, otp.time_window),
+        }
+        .context("Failed to write OTP slot")?
+      }
+      _ => (),
+    }
+  }
+
+  let mut device = admin.device();
+  for change in changes {
+    match change {
+      provision::Change::Unencrypted(mode) => {
+        if let nitrokey::DeviceWrapper::Storage(storage) = &mut device {
+          let mode = match mode {
+            args::UnencryptedVolumeMode::ReadWrite => nitrokey::VolumeMode::ReadWrite,
+            args::UnencryptedVolumeMode::ReadOnly => nitrokey::VolumeMode::ReadOnly,
+          };
+          // The unencrypted volume may reconnect, so be sure to flush
+          // caches to disk.
+          unsafe { sync() };
+          storage
+            .set_unencrypted_volume_mode(&admin_pin, mode)
+            .context("Failed to change unencrypted volume mode")?
+        }
+      }
+      provision::Change::UserPin => {
+        if let Some(new_user_pin) = new_user_pin {
+          device
+            .unlock_user_pin(&admin_pin, new_user_pin)
+            .context("Failed to set user PIN")?
+        }
+      }
+      provision::Change::AdminPin => {
+        if let Some(new_admin_pin) = new_admin_pin {
+          device
+            .change_admin_pin(&admin_pin, new_admin_pin)
+            .context("Failed to change admin PIN")?;
+          pinentry::clear(ctx, &pin_entry).context("Failed to clear cached secret")?
+        }
+      }
+      _ => (),
+    }
+  }
+  Ok(())
+}
+
+/// Provision a Nitrokey device according to a manifest.
+pub fn provision(
+  ctx: &mut ExecCtx<'_>,
+  manifest: &path::Path,
+  dry_run: bool,
+) -> anyhow::Result<()> {
+  let manifest = provision::Manifest::load(manifest)?;
+  for otp in &manifest.otp {
+    if let Some(token_id) = &otp.token_id {
+      check_token_id(token_id)?;
+    }
+  }
+
+  with_device(ctx, |ctx, mut device| {
+    let state = get_device_state(ctx, &mut device, &manifest)?;
+    let changes = provision::plan(&manifest, &state).context("Failed to plan changes")?;
+    if changes.is_empty() {
+      println!(ctx, "Device is up to date")?;
+      return Ok(());
+    }
+
+    println!(ctx, "Planned changes:")?;
+    for change in &changes {
+      println!(ctx, "  {}", change)?;
+    }
+    if dry_run {
+      return Ok(());
+    }
+
+    audited(ctx, device, "provision", |ctx, mut device| {
+      let new_admin_pin = if changes.contains(&provision::Change::AdminPin) {
+        let pin_entry = pinentry::PinEntry::from(args::PinType::Admin, &device)?;
+        Some(choose_pin(ctx, &pin_entry, true)?)
+      } else {
+        None
+      };
+      let new_user_pin = if changes.contains(&provision::Change::UserPin) {
+        let pin_entry = pinentry::PinEntry::from(args::PinType::User, &device)?;
+        Some(choose_pin(ctx, &pin_entry, true)?)
+      } else {
+        None
+      };
+
+      let pws_changes = changes.iter().filter_map(|change| match change {
+        provision::Change::Pws(pws) => Some(pws),
+        _ => None,
+      });
+      if pws_changes.clone().next().is_some() {
+        let pin_entry = pinentry::PinEntry::from(args::PinType::User, &device)?;
+        try_with_pin_and_data(ctx, &pin_entry, (), |_ctx, _, pin| {
+          let mut pws = device
+            .get_password_safe(pin)
+            .context("Could not access the password safe")
+            .map_err(|err| ((), err))?;
+          for slot in pws_changes.clone() {
+            pws
+              .write_slot(slot.slot, &slot.name, &slot.login, &slot.password)
+              .context("Failed to write PWS slot")
+              .map_err(|err| ((), err))?;
+          }
+          Ok(())
+        })?;
+      }
+
+      let user_entry = pinentry::PinEntry::from(args::PinType::User, &device)?;
+      if changes.iter().any(provision::Change::requires_admin) {
+        apply_admin_changes(
+          ctx,
+          device,
+          &changes,
+          new_admin_pin.as_deref(),
+          new_user_pin.as_deref(),
+        )?;
+      }
+      if new_user_pin.is_some() {
+        pinentry::clear(ctx, &user_entry).context("Failed to clear cached secret")?;
+      }
+      Ok(())
+    })
+  })
+}
+
 #[cfg(test)]
 mod tests {
   use super::*;
@@ -1001,10 +3673,209 @@ mod tests {
     assert!(result.is_err());
   }
 
+  #[test]
+  fn prepare_secret_base32() {
+    let expected = "3132333435363738393031323334353637383930";
+    for secret in &[
+      "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ",
+      "gezdgnbvgy3tqojqgezdgnbvgy3tqojq",
+      "gezd gnbv gy3t qojq gezd gnbv gy3t qojq",
+      "GEZD-GNBV-GY3T-QOJQ-GEZD-GNBV-GY3T-QOJQ",
+    ] {
+      assert_eq!(
+        prepare_base32_secret(secret).unwrap(),
+        expected,
+        "{}",
+        secret
+      );
+    }
+  }
+
+  #[test]
+  fn prepare_secret_base32_padding() {
+    assert_eq!(prepare_base32_secret("MZXW6===").unwrap(), "666f6f");
+    assert_eq!(prepare_base32_secret("mzxw 6===").unwrap(), "666f6f");
+    assert_eq!(prepare_base32_secret("MZXW6").unwrap(), "666f6f");
+  }
+
+  #[test]
+  fn prepare_secret_base32_invalid() {
+    for secret in &["MZ=XW6", "MZXW1", "MZXW6!", "Österreich"] {
+      assert!(prepare_base32_secret(secret).is_err(), "{}", secret);
+    }
+  }
+
+  #[test]
+  fn prepare_secret_base64() {
+    assert_eq!(prepare_base64_secret("").unwrap(), "");
+    assert_eq!(prepare_base64_secret("Zm9v").unwrap(), "666f6f");
+    assert_eq!(prepare_base64_secret("Zm9vYg==").unwrap(), "666f6f62");
+    assert_eq!(prepare_base64_secret("Zm9vYg").unwrap(), "666f6f62");
+    assert_eq!(prepare_base64_secret("Zm9v YmE=").unwrap(), "666f6f6261");
+    assert_eq!(prepare_base64_secret("+/8=").unwrap(), "fbff");
+    assert_eq!(prepare_base64_secret("-_8").unwrap(), "fbff");
+  }
+
+  #[test]
+  fn prepare_secret_base64_invalid() {
+    for secret in &["Zm9vY", "Zm=9v", "Zm9v!", "Österreich"] {
+      assert!(prepare_base64_secret(secret).is_err(), "{}", secret);
+    }
+  }
+
+  #[test]
+  fn prepare_secret_length() {
+    let secret = "a".repeat(MAX_SECRET_LEN);
+    let result = prepare_secret(args::OtpSecretFormat::Ascii, secret.clone());
+    assert_eq!(result.unwrap(), "61".repeat(MAX_SECRET_LEN));
+
+    let secret = secret + "a";
+    let err = prepare_secret(args::OtpSecretFormat::Ascii, secret).unwrap_err();
+    assert_eq!(
+      err.to_string(),
+      "The secret is too long: 41 bytes given, but at most 40 bytes are supported"
+    );
+
+    let secret = "1".repeat(2 * MAX_SECRET_LEN + 1);
+    assert!(prepare_secret(args::OtpSecretFormat::Hex, secret).is_err());
+  }
+
+  #[test]
+  fn max_secret_len_by_firmware() {
+    assert_eq!(max_secret_len(nitrokey::Model::Pro, 0, 7), 20);
+    assert_eq!(max_secret_len(nitrokey::Model::Pro, 0, 8), 40);
+    assert_eq!(max_secret_len(nitrokey::Model::Pro, 0, 15), 40);
+    assert_eq!(max_secret_len(nitrokey::Model::Storage, 0, 53), 20);
+    assert_eq!(max_secret_len(nitrokey::Model::Storage, 0, 54), 40);
+    assert_eq!(max_secret_len(nitrokey::Model::Storage, 1, 0), 40);
+  }
+
+  #[test]
+  fn token_ids() {
+    assert!(check_token_id("NKPR12345678").is_ok());
+    assert!(check_token_id("ab0123456789").is_ok());
+    for token_id in &[
+      "",
+      "NKPR1234567",
+      "NKPR123456789",
+      "NKPR-1234567",
+      "NKPR1234567Ä",
+    ] {
+      assert!(check_token_id(token_id).is_err(), "{}", token_id);
+    }
+  }
+
+  #[test]
+  fn otp_slot_config() {
+    let mut token_id = *b"NKPR12345678\x01";
+    assert_eq!(
+      OtpSlotConfig::from_raw(0b110, &token_id),
+      OtpSlotConfig {
+        use_enter: true,
+        token_id: Some("NKPR12345678".to_string()),
+      }
+    );
+    assert_eq!(
+      OtpSlotConfig::from_raw(0b001, &token_id),
+      OtpSlotConfig {
+        use_enter: false,
+        token_id: None,
+      }
+    );
+
+    token_id[4] = 0;
+    assert_eq!(
+      OtpSlotConfig::from_raw(0b100, &token_id),
+      OtpSlotConfig {
+        use_enter: false,
+        token_id: Some("NKPR".to_string()),
+      }
+    );
+    assert_eq!(
+      OtpSlotConfig::from_raw(0b100, &[0; 13]),
+      OtpSlotConfig {
+        use_enter: false,
+        token_id: None,
+      }
+    );
+  }
+
+  #[test]
+  fn mount_path_unescaping() {
+    assert_eq!(unescape_mount_path("/media/usb"), "/media/usb");
+    assert_eq!(
+      unescape_mount_path("/media/my\\040stick"),
+      "/media/my stick"
+    );
+    assert_eq!(unescape_mount_path("/a\\134b\\011"), "/a\\b\t");
+    assert_eq!(unescape_mount_path("/a\\9"), "/a\\9");
+  }
+
+  #[test]
+  fn unencrypted_volume_lookup() {
+    let names = [
+      "usb-Nitrokey_Nitrokey_Storage_000000000000000000001A2B-0:0",
+      "usb-Nitrokey_Nitrokey_Storage_000000000000000000001A2B-0:0-part1",
+      "usb-Nitrokey_Nitrokey_Storage_00000000000000000000BEEF-0:0-part1",
+      "usb-Nitrokey_Nitrokey_Storage_000000000000000000001A2B-0:1",
+    ];
+    let serial = "0x1a2b".parse::<nitrokey::SerialNumber>().unwrap();
+    assert_eq!(
+      find_unencrypted_volume_name(names.iter().copied(), serial),
+      Some(names[1])
+    );
+    let serial = "0xbeef".parse::<nitrokey::SerialNumber>().unwrap();
+    assert_eq!(
+      find_unencrypted_volume_name(names.iter().copied(), serial),
+      Some(names[2])
+    );
+    let serial = "0x1234".parse::<nitrokey::SerialNumber>().unwrap();
+    assert_eq!(
+      find_unencrypted_volume_name(names.iter().copied(), serial),
+      None
+    );
+  }
+
+  #[test]
+  fn mount_point_lookup() {
+    let mounts = r#"proc /proc proc rw,nosuid,nodev,noexec,relatime 0 0
+/dev/sda2 / ext4 rw,relatime 0 0
+/dev/sdb1 /media/NITRO\040KEY vfat rw,nosuid,nodev 0 0
+"#;
+    let device = path::Path::new("/dev/sdb1");
+    assert_eq!(
+      find_mount_point(mounts, device),
+      Some(path::PathBuf::from("/media/NITRO KEY"))
+    );
+    assert_eq!(find_mount_point(mounts, path::Path::new("/dev/sdc1")), None);
+  }
+
   #[test]
   fn hex_string() {
     assert_eq!(format_bytes(&[b' ']), "20");
     assert_eq!(format_bytes(&[b' ', b' ']), "2020");
     assert_eq!(format_bytes(&[b'\n', b'\n']), "0a0a");
   }
+
+  #[test]
+  fn parse_env_specs() {
+    assert_eq!(
+      parse_env_spec("DB_PASS=3:password").unwrap(),
+      ("DB_PASS", 3, "password")
+    );
+    assert_eq!(
+      parse_env_spec("USER=15:login").unwrap(),
+      ("USER", 15, "login")
+    );
+    for spec in &[
+      "DB_PASS",
+      "=3:name",
+      "X=foo:name",
+      "X=3",
+      "X=3:secret",
+      "X=-1:name",
+    ] {
+      assert!(parse_env_spec(spec).is_err(), "{}", spec);
+    }
+  }
 }
@@ -0,0 +1,155 @@
+// config.rs
+
+// *************************************************************************
+// * Copyright (C) 2020 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+use std::fs;
+use std::io;
+use std::path;
+
+use anyhow::Context as _;
+
+use crate::monitor;
+
+/// The name of the configuration file in the configuration directory.
+const CONFIG_FILE: &str = "config.toml";
+
+/// The configuration of the audit log.
+#[derive(Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AuditLogConfig {
+  /// The path of the log file.
+  pub path: path::PathBuf,
+}
+
+/// A command run by the `monitor` command when a device is attached or
+/// detached.
+#[derive(Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Hook {
+  /// The kind of event the hook is run for, or `None` if it is run for
+  /// all events.
+  #[serde(default)]
+  pub event: Option<monitor::EventKind>,
+  /// The command to run using the shell.
+  pub command: String,
+}
+
+/// The configuration of the `monitor` command.
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MonitorConfig {
+  /// The hooks to run, in the given order.
+  #[serde(rename = "hook")]
+  pub hooks: Vec<Hook>,
+}
+
+/// The configuration of the program.
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+  /// The audit log configuration, if the audit log is enabled.
+  #[serde(rename = "audit-log")]
+  pub audit_log: Option<AuditLogConfig>,
+  /// The configuration of the `monitor` command.
+  pub monitor: MonitorConfig,
+}
+
+impl Config {
+  /// Parse the configuration from the given TOML data.
+  pub fn parse(content: &str) -> anyhow::Result<Self> {
+    toml::from_str(content).map_err(anyhow::Error::from)
+  }
+
+  /// Load the configuration from the given configuration directory.
+  ///
+  /// A missing configuration file is treated as an empty configuration.
+  pub fn load(config_dir: Option<&path::Path>) -> anyhow::Result<Self> {
+    let path = match config_dir {
+      Some(dir) => dir.join(CONFIG_FILE),
+      None => return Ok(Self::default()),
+    };
+    let content = match fs::read_to_string(&path) {
+      Ok(content) => content,
+      Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Self::default()),
+      Err(err) => {
+        return Err(err).with_context(|| format!("Failed to read configuration {}", path.display()))
+      }
+    };
+    Self::parse(&content)
+      .with_context(|| format!("Failed to parse configuration {}", path.display()))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use std::env;
+
+  #[test]
+  fn parse_config() {
+    let config = Config::parse(
+      r#"
+[audit-log]
+path = "/tmp/audit.log"
+
+[[monitor.hook]]
+command = "logger nitrokey $NITROCLI_EVENT"
+
+[[monitor.hook]]
+event = "detach"
+command = "nitrocli pin clear"
+"#,
+    )
+    .unwrap();
+
+    let path = config.audit_log.unwrap().path;
+    assert_eq!(path, path::PathBuf::from("/tmp/audit.log"));
+    let hooks = config.monitor.hooks;
+    assert_eq!(hooks.len(), 2);
+    assert_eq!(hooks[0].event, None);
+    assert_eq!(hooks[1].event, Some(monitor::EventKind::Detach));
+    assert_eq!(hooks[1].command, "nitrocli pin clear");
+  }
+
+  #[test]
+  fn parse_invalid() {
+    assert!(Config::parse("[audit-log]\nfile = \"/tmp/audit.log\"\n").is_err());
+    assert!(Config::parse("[[monitor.hook]]\nevent = \"insert\"\ncommand = \"true\"\n").is_err());
+    assert!(Config::parse("[[monitor.hook]]\nevent = \"attach\"\n").is_err());
+    assert!(Config::parse("[foo]\n").is_err());
+  }
+
+  #[test]
+  fn load_config() {
+    let dir = env::temp_dir().join(format!("nitrocli-config-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+
+    assert!(Config::load(None).unwrap().audit_log.is_none());
+    assert!(Config::load(Some(&dir)).unwrap().audit_log.is_none());
+
+    fs::write(
+      dir.join(CONFIG_FILE),
+      "[audit-log]\npath = \"/tmp/audit.log\"\n",
+    )
+    .unwrap();
+    assert!(Config::load(Some(&dir)).unwrap().audit_log.is_some());
+
+    fs::remove_dir_all(&dir).unwrap();
+  }
+}
@@ -0,0 +1,118 @@
+// credential.rs
+
+// *************************************************************************
+// * Copyright (C) 2020 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+/// A credential as exchanged with git using the credential helper
+/// protocol.
+///
+/// Credentials are stored in PWS slots, using the host as the slot name
+/// and the username as the login.
+#[derive(Debug, Default, PartialEq)]
+pub struct Credential {
+  pub host: Option<String>,
+  pub username: Option<String>,
+  pub password: Option<String>,
+}
+
+impl Credential {
+  /// Parse a credential from the given attribute lines.
+  ///
+  /// Attributes not relevant to us are ignored.
+  pub fn parse<'l, I>(lines: I) -> anyhow::Result<Self>
+  where
+    I: IntoIterator<Item = &'l str>,
+  {
+    let mut credential = Credential::default();
+    for line in lines {
+      let mut parts = line.splitn(2, '=');
+      let (key, value) = match (parts.next(), parts.next()) {
+        (Some(key), Some(value)) => (key, value.to_string()),
+        _ => anyhow::bail!("Invalid credential attribute: {}", line),
+      };
+
+      match key {
+        "host" => credential.host = Some(value),
+        "username" => credential.username = Some(value),
+        "password" => credential.password = Some(value),
+        _ => (),
+      }
+    }
+    Ok(credential)
+  }
+
+  /// Check whether a PWS slot with the given name and login stores this
+  /// credential.
+  pub fn matches(&self, name: &str, login: &str) -> bool {
+    self.host.as_deref() == Some(name)
+      && self
+        .username
+        .as_deref()
+        .map(|username| username == login)
+        .unwrap_or(true)
+  }
+}
+
+/// Format a username and password as credential attribute lines.
+pub fn format(username: &str, password: &str) -> String {
+  format!("username={}\npassword={}\n", username, password)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parse_credential() {
+    let input = "protocol=https\nhost=example.com\nusername=john\nwwwauth[]=Basic";
+    let credential = Credential::parse(input.lines()).unwrap();
+    let expected = Credential {
+      host: Some("example.com".to_string()),
+      username: Some("john".to_string()),
+      password: None,
+    };
+    assert_eq!(credential, expected);
+  }
+
+  #[test]
+  fn parse_credential_with_equals_sign() {
+    let credential = Credential::parse(vec!["password=a=b"]).unwrap();
+    assert_eq!(credential.password, Some("a=b".to_string()));
+  }
+
+  #[test]
+  fn parse_invalid_credential() {
+    let err = Credential::parse(vec!["host"]).unwrap_err();
+    assert_eq!(err.to_string(), "Invalid credential attribute: host");
+  }
+
+  #[test]
+  fn credential_matching() {
+    let mut credential = Credential::parse(vec!["host=example.com"]).unwrap();
+    assert!(credential.matches("example.com", "john"));
+    assert!(!credential.matches("example.org", "john"));
+
+    credential.username = Some("jane".to_string());
+    assert!(!credential.matches("example.com", "john"));
+    assert!(credential.matches("example.com", "jane"));
+  }
+
+  #[test]
+  fn format_credential() {
+    assert_eq!(format("john", "secret"), "username=john\npassword=secret\n");
+  }
+}
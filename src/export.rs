@@ -0,0 +1,330 @@
+// export.rs
+
+// *************************************************************************
+// * Copyright (C) 2020 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+use std::fs;
+use std::io;
+use std::io::Write as _;
+use std::os::unix::fs::OpenOptionsExt as _;
+use std::path;
+use std::process;
+use std::thread;
+
+use anyhow::Context as _;
+
+use crate::args;
+use crate::import::Entry;
+
+/// Serialize the given entries using the given format.
+///
+/// The `pass` format is not a single file and has to be written using
+/// `write_pass` instead.
+pub fn serialize(format: args::PwsFormat, entries: &[Entry]) -> anyhow::Result<Vec<u8>> {
+  match format {
+    args::PwsFormat::BitwardenJson => serialize_bitwarden_json(entries),
+    args::PwsFormat::Csv => serialize_csv(entries),
+    args::PwsFormat::KeepassXml => Ok(serialize_keepass_xml(entries).into_bytes()),
+    args::PwsFormat::Pass => anyhow::bail!("The pass format cannot be serialized to a file"),
+  }
+}
+
+/// Serialize the given entries as CSV with a `name`, `url`, `username`,
+/// and `password` column, as understood by browsers and most password
+/// managers.
+fn serialize_csv(entries: &[Entry]) -> anyhow::Result<Vec<u8>> {
+  let mut writer = csv::Writer::from_writer(Vec::new());
+  writer
+    .write_record(["name", "url", "username", "password"].iter())
+    .context("Failed to write CSV header")?;
+  for entry in entries {
+    writer
+      .write_record([&entry.name, "", &entry.login, &entry.password].iter())
+      .context("Failed to write CSV record")?;
+  }
+  writer
+    .into_inner()
+    .map_err(|err| anyhow::anyhow!("{}", err.error()))
+    .context("Failed to write CSV data")
+}
+
+/// Escape the given string for use in XML text.
+fn xml_escape(string: &str) -> String {
+  let mut escaped = String::with_capacity(string.len());
+  for c in string.chars() {
+    match c {
+      '&' => escaped.push_str("&amp;"),
+      '<' => escaped.push_str("&lt;"),
+      '>' => escaped.push_str("&gt;"),
+      '"' => escaped.push_str("&quot;"),
+      '\'' => escaped.push_str("&apos;"),
+      c => escaped.push(c),
+    }
+  }
+  escaped
+}
+
+/// Serialize the given entries as a KeePass 2 XML export with a single
+/// group.
+fn serialize_keepass_xml(entries: &[Entry]) -> String {
+  let mut xml = String::from(
+    "<?xml version=\"1.0\" encoding=\"utf-8\" standalone=\"yes\"?>\n\
+     <KeePassFile>\n\
+     \t<Meta>\n\
+     \t\t<Generator>nitrocli</Generator>\n\
+     \t</Meta>\n\
+     \t<Root>\n\
+     \t\t<Group>\n\
+     \t\t\t<Name>nitrocli</Name>\n",
+  );
+  for entry in entries {
+    xml.push_str("\t\t\t<Entry>\n");
+    for (key, value) in &[
+      ("Title", &entry.name),
+      ("UserName", &entry.login),
+      ("Password", &entry.password),
+    ] {
+      xml.push_str(&format!(
+        "\t\t\t\t<String><Key>{}</Key><Value>{}</Value></String>\n",
+        key,
+        xml_escape(value)
+      ));
+    }
+    xml.push_str("\t\t\t</Entry>\n");
+  }
+  xml.push_str("\t\t</Group>\n\t</Root>\n</KeePassFile>\n");
+  xml
+}
+
+/// The Bitwarden item type of logins.
+const BITWARDEN_LOGIN: u8 = 1;
+
+/// Serialize the given entries as an unencrypted Bitwarden JSON export.
+fn serialize_bitwarden_json(entries: &[Entry]) -> anyhow::Result<Vec<u8>> {
+  let items = entries
+    .iter()
+    .map(|entry| {
+      serde_json::json!({
+        "type": BITWARDEN_LOGIN,
+        "name": entry.name,
+        "login": {
+          "username": entry.login,
+          "password": entry.password,
+          "uris": [],
+        },
+      })
+    })
+    .collect::<Vec<_>>();
+  let export = serde_json::json!({
+    "encrypted": false,
+    "folders": [],
+    "items": items,
+  });
+  let mut json = serde_json::to_vec_pretty(&export).context("Failed to write JSON data")?;
+  json.push(b'\n');
+  Ok(json)
+}
+
+/// Format the given entry as the content of a pass entry.
+fn pass_content(entry: &Entry) -> String {
+  if entry.login.is_empty() {
+    format!("{}\n", entry.password)
+  } else {
+    format!("{}\nlogin: {}\n", entry.password, entry.login)
+  }
+}
+
+/// Derive the file name of the pass entry for the given entry.
+fn pass_file_name(entry: &Entry, slot: u8) -> String {
+  let name = entry
+    .name
+    .chars()
+    .map(|c| if c == '/' || c == '\0' { '_' } else { c })
+    .collect::<String>();
+  if name.is_empty() || name.starts_with('.') {
+    format!("slot{}.gpg", slot)
+  } else {
+    format!("{}.gpg", name)
+  }
+}
+
+/// Encrypt the given data for the given gpg recipient.
+pub fn encrypt(data: Vec<u8>, recipient: &str, armor: bool) -> anyhow::Result<Vec<u8>> {
+  let mut command = process::Command::new("gpg");
+  let _ = command
+    .arg("--batch")
+    .arg("--quiet")
+    .arg("--encrypt")
+    .arg("--recipient")
+    .arg(recipient);
+  if armor {
+    let _ = command.arg("--armor");
+  }
+  let mut child = command
+    .stdin(process::Stdio::piped())
+    .stdout(process::Stdio::piped())
+    .stderr(process::Stdio::piped())
+    .spawn()
+    .context("Failed to invoke gpg")?;
+
+  // Write the data from a separate thread so that gpg cannot block on a
+  // full output pipe while we are still writing.
+  let mut stdin = child.stdin.take().unwrap();
+  let writer = thread::spawn(move || stdin.write_all(&data));
+  let output = child.wait_with_output().context("Failed to invoke gpg")?;
+  let result = writer
+    .join()
+    .map_err(|_| anyhow::anyhow!("Failed to write data to gpg"))?;
+
+  if !output.status.success() {
+    anyhow::bail!(
+      "Failed to encrypt data for {}: {}",
+      recipient,
+      String::from_utf8_lossy(&output.stderr).trim()
+    )
+  }
+  result.context("Failed to write data to gpg")?;
+  Ok(output.stdout)
+}
+
+/// Write the given entries as a pass password store into the given
+/// directory, encrypting them for the given gpg recipient.
+///
+/// Existing pass entries are never overwritten.
+pub fn write_pass(
+  dir: &path::Path,
+  entries: &[(u8, Entry)],
+  recipient: &str,
+) -> anyhow::Result<()> {
+  fs::create_dir_all(dir)
+    .with_context(|| format!("Failed to create directory {}", dir.display()))?;
+  let gpg_id = dir.join(".gpg-id");
+  if !gpg_id.exists() {
+    fs::write(&gpg_id, format!("{}\n", recipient))
+      .with_context(|| format!("Failed to write {}", gpg_id.display()))?;
+  }
+
+  for (slot, entry) in entries {
+    let path = dir.join(pass_file_name(entry, *slot));
+    let data = encrypt(pass_content(entry).into_bytes(), recipient, false)?;
+    let mut file = fs::OpenOptions::new()
+      .write(true)
+      .create_new(true)
+      .mode(0o600)
+      .open(&path)
+      .with_context(|| format!("Failed to create {}", path.display()))?;
+    file
+      .write_all(&data)
+      .with_context(|| format!("Failed to write {}", path.display()))?;
+  }
+  Ok(())
+}
+
+/// Check whether the file at the given path can be read by everybody.
+pub fn is_world_readable(path: &path::Path) -> anyhow::Result<bool> {
+  use std::os::unix::fs::PermissionsExt as _;
+
+  match fs::metadata(path) {
+    Ok(metadata) => Ok(metadata.permissions().mode() & 0o004 != 0),
+    Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(false),
+    Err(err) => Err(err).with_context(|| format!("Failed to access {}", path.display())),
+  }
+}
+
+/// Write the given data to the file at the given path, creating it as
+/// readable only by the current user if it does not exist.
+pub fn write_file(path: &path::Path, data: &[u8]) -> anyhow::Result<()> {
+  let mut file = fs::OpenOptions::new()
+    .write(true)
+    .create(true)
+    .truncate(true)
+    .mode(0o600)
+    .open(path)
+    .with_context(|| format!("Failed to create {}", path.display()))?;
+  file
+    .write_all(data)
+    .with_context(|| format!("Failed to write {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn entries() -> Vec<Entry> {
+    vec![
+      Entry {
+        name: "GitHub".to_string(),
+        login: "john".to_string(),
+        password: "pa,ss\"<&>".to_string(),
+      },
+      Entry {
+        name: "a/b".to_string(),
+        login: String::new(),
+        password: "secret".to_string(),
+      },
+    ]
+  }
+
+  #[test]
+  fn csv() {
+    let csv = serialize(args::PwsFormat::Csv, &entries()).unwrap();
+    assert_eq!(
+      String::from_utf8(csv).unwrap(),
+      "name,url,username,password\nGitHub,,john,\"pa,ss\"\"<&>\"\na/b,,,secret\n"
+    );
+  }
+
+  #[test]
+  fn keepass_xml() {
+    let xml = serialize(args::PwsFormat::KeepassXml, &entries()).unwrap();
+    let xml = String::from_utf8(xml).unwrap();
+    assert!(xml.starts_with("<?xml"), "{}", xml);
+    assert!(
+      xml.contains("<String><Key>Password</Key><Value>pa,ss&quot;&lt;&amp;&gt;</Value></String>"),
+      "{}",
+      xml
+    );
+    assert!(roxmltree::Document::parse(&xml).is_ok());
+  }
+
+  #[test]
+  fn bitwarden_json() {
+    let json = serialize(args::PwsFormat::BitwardenJson, &entries()).unwrap();
+    let json = serde_json::from_slice::<serde_json::Value>(&json).unwrap();
+    assert_eq!(json["encrypted"], false);
+    assert_eq!(json["items"][0]["type"], 1);
+    assert_eq!(json["items"][0]["name"], "GitHub");
+    assert_eq!(json["items"][0]["login"]["username"], "john");
+    assert_eq!(json["items"][0]["login"]["password"], "pa,ss\"<&>");
+    assert_eq!(json["items"][1]["login"]["username"], "");
+  }
+
+  #[test]
+  fn pass() {
+    let entries = entries();
+    assert_eq!(pass_content(&entries[0]), "pa,ss\"<&>\nlogin: john\n");
+    assert_eq!(pass_content(&entries[1]), "secret\n");
+    assert_eq!(pass_file_name(&entries[0], 0), "GitHub.gpg");
+    assert_eq!(pass_file_name(&entries[1], 1), "a_b.gpg");
+    let entry = Entry {
+      name: "..".to_string(),
+      ..Default::default()
+    };
+    assert_eq!(pass_file_name(&entry, 3), "slot3.gpg");
+    assert!(serialize(args::PwsFormat::Pass, &entries).is_err());
+  }
+}
@@ -0,0 +1,536 @@
+// import.rs
+
+// *************************************************************************
+// * Copyright (C) 2020 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+use std::fs;
+use std::path;
+use std::process;
+
+use anyhow::Context as _;
+
+use crate::args;
+
+/// The maximum length of a PWS slot name, in bytes.
+pub const NAME_LEN: usize = 11;
+/// The maximum length of a PWS slot login, in bytes.
+pub const LOGIN_LEN: usize = 32;
+/// The maximum length of a PWS slot password, in bytes.
+pub const PASSWORD_LEN: usize = 20;
+
+/// A password entry read from another password manager.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Entry {
+  pub name: String,
+  pub login: String,
+  pub password: String,
+}
+
+/// Read the entries stored in the given file using the given format.
+///
+/// For the `pass` format, the path is the password store directory.
+pub fn read(format: args::PwsFormat, path: &path::Path) -> anyhow::Result<Vec<Entry>> {
+  if format == args::PwsFormat::Pass {
+    return read_pass(path);
+  }
+
+  let content =
+    fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+  match format {
+    args::PwsFormat::BitwardenJson => parse_bitwarden_json(&content),
+    args::PwsFormat::Csv => parse_csv(&content),
+    args::PwsFormat::KeepassXml => parse_keepass_xml(&content),
+    args::PwsFormat::Pass => unreachable!(),
+  }
+  .with_context(|| format!("Failed to parse {}", path.display()))
+}
+
+/// Extract the host name from the given URL.
+fn host(url: &str) -> &str {
+  let url = match url.find("://") {
+    Some(index) => &url[index + 3..],
+    None => url,
+  };
+  let host = url.split(&['/', '?', '#'][..]).next().unwrap_or(url);
+  let host = host.rsplit('@').next().unwrap_or(host);
+  let host = host.split(':').next().unwrap_or(host);
+  host.trim_start_matches("www.")
+}
+
+/// Parse a CSV export as created by browsers and most password
+/// managers.
+///
+/// The columns are identified by their header. If no name or title
+/// column exists, the host of the URL is used as name.
+fn parse_csv(content: &str) -> anyhow::Result<Vec<Entry>> {
+  let mut reader = csv::ReaderBuilder::new()
+    .flexible(true)
+    .from_reader(content.as_bytes());
+  let headers = reader
+    .headers()
+    .context("Failed to read CSV header")?
+    .iter()
+    .map(|header| header.trim().to_lowercase())
+    .collect::<Vec<_>>();
+  let column = |names: &[&str]| {
+    names
+      .iter()
+      .find_map(|name| headers.iter().position(|header| header == name))
+  };
+
+  let name = column(&["name", "title"]);
+  let url = column(&["url", "login_uri"]);
+  let login = column(&["username", "login_username", "login", "user"]);
+  let password = column(&["password", "login_password"])
+    .ok_or_else(|| anyhow::anyhow!("CSV data does not contain a password column"))?;
+  if name.is_none() && url.is_none() {
+    anyhow::bail!("CSV data does not contain a name, title, or URL column")
+  }
+
+  let mut entries = Vec::new();
+  for record in reader.records() {
+    let record = record.context("Failed to read CSV record")?;
+    let field = |index: Option<usize>| {
+      index
+        .and_then(|index| record.get(index))
+        .unwrap_or_default()
+        .to_string()
+    };
+    let name = match field(name) {
+      name if !name.is_empty() => name,
+      _ => host(&field(url)).to_string(),
+    };
+    entries.push(Entry {
+      name,
+      login: field(login),
+      password: field(Some(password)),
+    });
+  }
+  Ok(entries)
+}
+
+/// Parse an XML export of KeePass 2.
+///
+/// Entries in the recycle bin and historic versions of entries are
+/// ignored.
+fn parse_keepass_xml(content: &str) -> anyhow::Result<Vec<Entry>> {
+  let document = roxmltree::Document::parse(content).context("Failed to parse XML data")?;
+  let root = document.root_element();
+  if !root.has_tag_name("KeePassFile") {
+    anyhow::bail!("XML data is not a KeePass export")
+  }
+
+  let child_text = |node: roxmltree::Node<'_, '_>, name: &str| {
+    node
+      .children()
+      .find(|child| child.has_tag_name(name))
+      .and_then(|child| child.text())
+      .map(str::to_string)
+  };
+  let recycle_bin = root
+    .children()
+    .find(|node| node.has_tag_name("Meta"))
+    .and_then(|meta| child_text(meta, "RecycleBinUUID"));
+
+  let in_recycle_bin = |node: roxmltree::Node<'_, '_>| {
+    node
+      .ancestors()
+      .filter(|ancestor| ancestor.has_tag_name("Group"))
+      .any(|group| child_text(group, "UUID").is_some() && child_text(group, "UUID") == recycle_bin)
+  };
+  let in_history = |node: roxmltree::Node<'_, '_>| {
+    node
+      .ancestors()
+      .any(|ancestor| ancestor.has_tag_name("History"))
+  };
+
+  let entries = root
+    .descendants()
+    .filter(|node| node.has_tag_name("Entry"))
+    .filter(|&node| !in_history(node) && !in_recycle_bin(node))
+    .map(|node| {
+      let mut entry = Entry::default();
+      for string in node.children().filter(|child| child.has_tag_name("String")) {
+        let value = child_text(string, "Value").unwrap_or_default();
+        match child_text(string, "Key").as_deref() {
+          Some("Title") => entry.name = value,
+          Some("UserName") => entry.login = value,
+          Some("Password") => entry.password = value,
+          _ => (),
+        }
+      }
+      entry
+    })
+    .collect();
+  Ok(entries)
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct BitwardenExport {
+  #[serde(default)]
+  encrypted: bool,
+  #[serde(default)]
+  items: Vec<BitwardenItem>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct BitwardenItem {
+  #[serde(rename = "type")]
+  kind: u8,
+  name: String,
+  login: Option<BitwardenLogin>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct BitwardenLogin {
+  username: Option<String>,
+  password: Option<String>,
+}
+
+/// The Bitwarden item type of logins.
+const BITWARDEN_LOGIN: u8 = 1;
+
+/// Parse an unencrypted JSON export of Bitwarden.
+///
+/// Only login items are imported.
+fn parse_bitwarden_json(content: &str) -> anyhow::Result<Vec<Entry>> {
+  let export =
+    serde_json::from_str::<BitwardenExport>(content).context("Failed to parse JSON data")?;
+  if export.encrypted {
+    anyhow::bail!("Encrypted Bitwarden exports are not supported")
+  }
+
+  let entries = export
+    .items
+    .into_iter()
+    .filter(|item| item.kind == BITWARDEN_LOGIN)
+    .map(|item| {
+      let login = item.login.unwrap_or(BitwardenLogin {
+        username: None,
+        password: None,
+      });
+      Entry {
+        name: item.name,
+        login: login.username.unwrap_or_default(),
+        password: login.password.unwrap_or_default(),
+      }
+    })
+    .collect();
+  Ok(entries)
+}
+
+/// Parse the decrypted content of a pass entry.
+///
+/// The first line contains the password. The login is taken from a
+/// `login`, `username`, or `user` line, if present.
+fn parse_pass_entry(name: &str, content: &str) -> Entry {
+  let mut lines = content.lines();
+  let password = lines.next().unwrap_or_default().to_string();
+  let login = lines
+    .filter_map(|line| {
+      let mut parts = line.splitn(2, ':');
+      match (parts.next(), parts.next()) {
+        (Some(key), Some(value)) => Some((key.trim().to_lowercase(), value.trim())),
+        _ => None,
+      }
+    })
+    .find(|(key, _)| key == "login" || key == "username" || key == "user")
+    .map(|(_, value)| value.to_string())
+    .unwrap_or_default();
+
+  Entry {
+    name: name.to_string(),
+    login,
+    password,
+  }
+}
+
+/// Collect the paths of all pass entries in the given directory.
+fn find_pass_entries(dir: &path::Path, paths: &mut Vec<path::PathBuf>) -> anyhow::Result<()> {
+  let mut entries = fs::read_dir(dir)
+    .with_context(|| format!("Failed to read directory {}", dir.display()))?
+    .collect::<Result<Vec<_>, _>>()
+    .with_context(|| format!("Failed to read directory {}", dir.display()))?;
+  entries.sort_by_key(|entry| entry.file_name());
+
+  for entry in entries {
+    let path = entry.path();
+    let hidden = entry.file_name().to_string_lossy().starts_with('.');
+    if path.is_dir() && !hidden {
+      find_pass_entries(&path, paths)?;
+    } else if path.extension().map(|ext| ext == "gpg").unwrap_or(false) {
+      paths.push(path);
+    }
+  }
+  Ok(())
+}
+
+/// Read the entries of the pass password store in the given directory,
+/// decrypting them using gpg.
+///
+/// The file name of an entry, without the extension, is used as its
+/// name.
+fn read_pass(dir: &path::Path) -> anyhow::Result<Vec<Entry>> {
+  let mut paths = Vec::new();
+  find_pass_entries(dir, &mut paths)?;
+
+  let mut entries = Vec::new();
+  for path in paths {
+    let output = process::Command::new("gpg")
+      .arg("--quiet")
+      .arg("--decrypt")
+      .arg(&path)
+      .output()
+      .context("Failed to invoke gpg")?;
+    if !output.status.success() {
+      anyhow::bail!(
+        "Failed to decrypt {}: {}",
+        path.display(),
+        String::from_utf8_lossy(&output.stderr).trim()
+      )
+    }
+
+    let content = String::from_utf8(output.stdout).with_context(|| {
+      format!(
+        "Failed to decrypt {}: Invalid UTF-8 data found",
+        path.display()
+      )
+    })?;
+    let name = path
+      .file_stem()
+      .map(|stem| stem.to_string_lossy().into_owned())
+      .unwrap_or_default();
+    entries.push(parse_pass_entry(&name, &content));
+  }
+  Ok(entries)
+}
+
+/// Truncate the given string to at most the given number of bytes,
+/// respecting character boundaries.
+fn truncate(string: &mut String, len: usize) -> bool {
+  if string.len() <= len {
+    return false;
+  }
+  let mut len = len;
+  while !string.is_char_boundary(len) {
+    len -= 1;
+  }
+  string.truncate(len);
+  true
+}
+
+/// Make the given entries fit into PWS slots.
+///
+/// Names and logins exceeding the length supported by the device are
+/// truncated. Entries with a password that is too long cannot be
+/// imported meaningfully and are skipped. A warning is returned for
+/// each modified or skipped entry.
+pub fn fit(entries: Vec<Entry>) -> (Vec<Entry>, Vec<String>) {
+  let mut fitted = Vec::new();
+  let mut warnings = Vec::new();
+  for mut entry in entries {
+    let name = entry.name.clone();
+    if entry.password.len() > PASSWORD_LEN {
+      warnings.push(format!(
+        "Skipping entry {}: the password is longer than {} bytes",
+        name, PASSWORD_LEN
+      ));
+      continue;
+    }
+    if truncate(&mut entry.name, NAME_LEN) {
+      warnings.push(format!(
+        "Truncated name of entry {} to {} bytes",
+        name, NAME_LEN
+      ));
+    }
+    if truncate(&mut entry.login, LOGIN_LEN) {
+      warnings.push(format!(
+        "Truncated login of entry {} to {} bytes",
+        name, LOGIN_LEN
+      ));
+    }
+    fitted.push(entry);
+  }
+  (fitted, warnings)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn entry(name: &str, login: &str, password: &str) -> Entry {
+    Entry {
+      name: name.to_string(),
+      login: login.to_string(),
+      password: password.to_string(),
+    }
+  }
+
+  #[test]
+  fn extract_host() {
+    assert_eq!(host("https://www.example.org/login?x=1"), "example.org");
+    assert_eq!(host("https://john@git.example.com:8443"), "git.example.com");
+    assert_eq!(host("example.net"), "example.net");
+  }
+
+  #[test]
+  fn parse_chrome_csv() {
+    let content = "\
+name,url,username,password
+GitHub,https://github.com/login,john,\"pa,ss\"
+,https://www.example.org/,jane,secret
+";
+    let entries = parse_csv(content).unwrap();
+    assert_eq!(
+      entries,
+      vec![
+        entry("GitHub", "john", "pa,ss"),
+        entry("example.org", "jane", "secret"),
+      ]
+    );
+  }
+
+  #[test]
+  fn parse_firefox_csv() {
+    let content = "\
+\"url\",\"username\",\"password\",\"httpRealm\",\"formActionOrigin\",\"guid\"
+\"https://example.com\",\"john\",\"\"\"quoted\"\"\",,\"https://example.com\",\"{1}\"
+";
+    let entries = parse_csv(content).unwrap();
+    assert_eq!(entries, vec![entry("example.com", "john", "\"quoted\"")]);
+  }
+
+  #[test]
+  fn parse_invalid_csv() {
+    let err = parse_csv("name,username\nfoo,bar\n").unwrap_err();
+    assert_eq!(
+      err.to_string(),
+      "CSV data does not contain a password column"
+    );
+  }
+
+  #[test]
+  fn parse_keepass() {
+    let content = r#"<?xml version="1.0" encoding="utf-8" standalone="yes"?>
+<KeePassFile>
+  <Meta><RecycleBinUUID>cmVjeWNsZQ==</RecycleBinUUID></Meta>
+  <Root>
+    <Group>
+      <UUID>cm9vdA==</UUID>
+      <Name>Root</Name>
+      <Entry>
+        <String><Key>Title</Key><Value>GitHub</Value></String>
+        <String><Key>UserName</Key><Value>john</Value></String>
+        <String><Key>Password</Key><Value ProtectInMemory="True">s3cr3t</Value></String>
+        <History>
+          <Entry>
+            <String><Key>Title</Key><Value>GitHub</Value></String>
+            <String><Key>Password</Key><Value>old</Value></String>
+          </Entry>
+        </History>
+      </Entry>
+      <Group>
+        <UUID>cmVjeWNsZQ==</UUID>
+        <Name>Recycle Bin</Name>
+        <Entry>
+          <String><Key>Title</Key><Value>Deleted</Value></String>
+        </Entry>
+      </Group>
+      <Entry>
+        <String><Key>Title</Key><Value>Empty</Value></String>
+        <String><Key>Password</Key><Value /></String>
+      </Entry>
+    </Group>
+  </Root>
+</KeePassFile>
+"#;
+    let entries = parse_keepass_xml(content).unwrap();
+    assert_eq!(
+      entries,
+      vec![entry("GitHub", "john", "s3cr3t"), entry("Empty", "", "")]
+    );
+
+    assert!(parse_keepass_xml("<foo/>").is_err());
+  }
+
+  #[test]
+  fn parse_bitwarden() {
+    let content = r#"{
+  "encrypted": false,
+  "folders": [],
+  "items": [
+    {
+      "type": 1,
+      "name": "GitHub",
+      "login": {"uris": [], "username": "john", "password": "s3cr3t", "totp": null}
+    },
+    {"type": 2, "name": "Note", "secureNote": {"type": 0}},
+    {"type": 1, "name": "No login", "login": {"username": null, "password": "pw"}}
+  ]
+}"#;
+    let entries = parse_bitwarden_json(content).unwrap();
+    assert_eq!(
+      entries,
+      vec![
+        entry("GitHub", "john", "s3cr3t"),
+        entry("No login", "", "pw")
+      ]
+    );
+
+    let err = parse_bitwarden_json(r#"{"encrypted": true, "items": []}"#).unwrap_err();
+    assert_eq!(
+      err.to_string(),
+      "Encrypted Bitwarden exports are not supported"
+    );
+  }
+
+  #[test]
+  fn parse_pass() {
+    let entry = parse_pass_entry("github", "s3cr3t\nurl: https://github.com\nLogin: john\n");
+    assert_eq!(entry, self::entry("github", "john", "s3cr3t"));
+    let entry = parse_pass_entry("empty", "");
+    assert_eq!(entry, self::entry("empty", "", ""));
+  }
+
+  #[test]
+  fn fit_entries() {
+    let entries = vec![
+      entry("example.org", "john", "secret"),
+      entry("verylongname.org", &"l".repeat(33), "secret"),
+      entry("a", "b", &"p".repeat(21)),
+      entry("äääääää", "", ""),
+    ];
+    let (entries, warnings) = fit(entries);
+    assert_eq!(
+      entries,
+      vec![
+        entry("example.org", "john", "secret"),
+        entry("verylongnam", &"l".repeat(32), "secret"),
+        entry("äääää", "", ""),
+      ]
+    );
+    assert_eq!(
+      warnings,
+      vec![
+        "Truncated name of entry verylongname.org to 11 bytes",
+        "Truncated login of entry verylongname.org to 32 bytes",
+        "Skipping entry a: the password is longer than 20 bytes",
+        "Truncated name of entry äääääää to 11 bytes",
+      ]
+    );
+  }
+}
@@ -0,0 +1,247 @@
+// keyring.rs
+
+// *************************************************************************
+// * Copyright (C) 2020 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+use std::ffi;
+use std::io;
+
+use anyhow::Context as _;
+
+/// The type of the keys we store secrets in.
+const KEY_TYPE: &str = "user";
+
+/// The permissions of the keys we store secrets in: everything for
+/// possessors and everything but changing the permissions for the
+/// owning user, so that other processes of the user can use them even
+/// if the key is not reachable from their session keyring.
+const KEY_PERM: u32 = 0x3f00_0000 | 0x001f_0000;
+
+fn to_cstring(string: &str) -> anyhow::Result<ffi::CString> {
+  ffi::CString::new(string).context("Failed to convert string: NUL byte found")
+}
+
+/// Invoke the `keyctl` system call with the given arguments.
+fn keyctl(operation: u32, args: [libc::c_long; 4]) -> io::Result<libc::c_long> {
+  // SAFETY: All pointers passed in `args` are valid for the duration
+  //         of the call and the kernel checks the buffer sizes.
+  let result = unsafe {
+    libc::syscall(
+      libc::SYS_keyctl,
+      libc::c_long::from(operation),
+      args[0],
+      args[1],
+      args[2],
+      args[3],
+    )
+  };
+  if result < 0 {
+    Err(io::Error::last_os_error())
+  } else {
+    Ok(result)
+  }
+}
+
+/// Check whether the given error indicates that a key is not available.
+fn is_missing(err: &io::Error) -> bool {
+  matches!(
+    err.raw_os_error(),
+    Some(libc::ENOKEY) | Some(libc::EKEYEXPIRED) | Some(libc::EKEYREVOKED)
+  )
+}
+
+/// Search the user keyring for the key with the given description.
+fn search(description: &str) -> anyhow::Result<Option<libc::c_long>> {
+  let key_type = to_cstring(KEY_TYPE)?;
+  let description = to_cstring(description)?;
+  let result = keyctl(
+    libc::KEYCTL_SEARCH,
+    [
+      libc::c_long::from(libc::KEY_SPEC_USER_KEYRING),
+      key_type.as_ptr() as libc::c_long,
+      description.as_ptr() as libc::c_long,
+      0,
+    ],
+  );
+
+  match result {
+    Ok(id) => Ok(Some(id)),
+    Err(ref err) if is_missing(err) => Ok(None),
+    Err(err) => Err(err).context("Failed to search the kernel keyring"),
+  }
+}
+
+/// Retrieve the secret stored under the given description, if any.
+pub fn get(description: &str) -> anyhow::Result<Option<String>> {
+  let id = match search(description)? {
+    Some(id) => id,
+    None => return Ok(None),
+  };
+
+  // The key may expire or get revoked between the search and the read
+  // operation, in which case we treat it as not cached.
+  let read = |buffer: &mut [u8]| match keyctl(
+    libc::KEYCTL_READ,
+    [
+      id,
+      buffer.as_mut_ptr() as libc::c_long,
+      buffer.len() as libc::c_long,
+      0,
+    ],
+  ) {
+    Ok(len) => Ok(Some(len as usize)),
+    Err(ref err) if is_missing(err) => Ok(None),
+    Err(err) => Err(err).context("Failed to read key from the kernel keyring"),
+  };
+
+  let len = match read(&mut [])? {
+    Some(len) => len,
+    None => return Ok(None),
+  };
+  let mut buffer = vec![0; len];
+  match read(&mut buffer)? {
+    Some(read) => buffer.truncate(read),
+    None => return Ok(None),
+  }
+
+  String::from_utf8(buffer)
+    .map(Some)
+    .context("Failed to read key from the kernel keyring: Invalid UTF-8 data found")
+}
+
+/// Store the given secret under the given description.
+///
+/// The secret is added to the user keyring, which is shared by all
+/// processes of the user and so outlives the current process. The key
+/// expires after the given number of seconds, unless the timeout is
+/// zero.
+pub fn set(description: &str, secret: &str, timeout: u32) -> anyhow::Result<()> {
+  let key_type = to_cstring(KEY_TYPE)?;
+  let description = to_cstring(description)?;
+  // SAFETY: All pointers are valid for the duration of the call and
+  //         the payload length matches the secret.
+  let id = unsafe {
+    libc::syscall(
+      libc::SYS_add_key,
+      key_type.as_ptr(),
+      description.as_ptr(),
+      secret.as_ptr(),
+      secret.len(),
+      libc::c_long::from(libc::KEY_SPEC_USER_KEYRING),
+    )
+  };
+  if id < 0 {
+    return Err(io::Error::last_os_error()).context("Failed to add key to the kernel keyring");
+  }
+
+  let _ = keyctl(
+    libc::KEYCTL_SETPERM,
+    [id, libc::c_long::from(KEY_PERM), 0, 0],
+  )
+  .context("Failed to set key permissions")?;
+
+  if timeout > 0 {
+    let _ = keyctl(
+      libc::KEYCTL_SET_TIMEOUT,
+      [id, libc::c_long::from(timeout), 0, 0],
+    )
+    .context("Failed to set key timeout")?;
+  }
+  Ok(())
+}
+
+/// Remove the secret stored under the given description, if any.
+pub fn remove(description: &str) -> anyhow::Result<()> {
+  if let Some(id) = search(description)? {
+    match keyctl(libc::KEYCTL_INVALIDATE, [id, 0, 0, 0]) {
+      Ok(_) => (),
+      Err(ref err) if is_missing(err) => (),
+      Err(err) => return Err(err).context("Failed to remove key from the kernel keyring"),
+    }
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use std::env;
+  use std::process;
+
+  /// The environment variable telling `read_in_child` which key to read.
+  const CHILD_KEY: &str = "NITROCLI_TEST_KEYRING_KEY";
+
+  /// Check whether we have access to the user keyring, which is commonly
+  /// prohibited in containers.
+  fn available() -> bool {
+    keyctl(
+      libc::KEYCTL_GET_KEYRING_ID,
+      [libc::c_long::from(libc::KEY_SPEC_USER_KEYRING), 0, 0, 0],
+    )
+    .is_ok()
+  }
+
+  #[test]
+  fn set_get_remove() -> anyhow::Result<()> {
+    if !available() {
+      return Ok(());
+    }
+
+    let description = format!("nitrocli:test:keyring:{}", process::id());
+    set(&description, "secret", 60)?;
+    assert_eq!(get(&description)?, Some("secret".to_string()));
+
+    remove(&description)?;
+    assert_eq!(get(&description)?, None);
+    Ok(())
+  }
+
+  /// Helper for `get_in_other_process`, run in a child process.
+  #[test]
+  fn read_in_child() -> anyhow::Result<()> {
+    if let Some(description) = env::var_os(CHILD_KEY) {
+      let description = description.into_string().unwrap();
+      assert_eq!(get(&description)?, Some("secret".to_string()));
+      remove(&description)?;
+    }
+    Ok(())
+  }
+
+  #[test]
+  fn get_in_other_process() -> anyhow::Result<()> {
+    if !available() {
+      return Ok(());
+    }
+
+    let description = format!("nitrocli:test:keyring-child:{}", process::id());
+    set(&description, "secret", 60)?;
+
+    let status = process::Command::new(env::current_exe()?)
+      .arg("--exact")
+      .arg("keyring::tests::read_in_child")
+      .env(CHILD_KEY, &description)
+      .stdout(process::Stdio::null())
+      .status()?;
+    let remaining = get(&description)?;
+    let _ = remove(&description);
+    assert!(status.success());
+    // The child removed the key after reading it.
+    assert_eq!(remaining, None);
+    Ok(())
+  }
+}
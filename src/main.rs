@@ -67,15 +67,35 @@ mod redefine;
 #[macro_use]
 mod arg_util;
 
+mod agent;
 mod args;
+mod askpass;
+mod audit;
+mod audit_log;
 mod commands;
+mod config;
+mod credential;
+mod export;
+mod import;
+mod keyring;
+mod monitor;
+mod native;
 mod pinentry;
+mod policy;
+mod provision;
+#[cfg(feature = "secret-service")]
+mod secret;
+mod template;
 #[cfg(test)]
 mod tests;
+mod vault;
 
 use std::env;
+use std::error;
 use std::ffi;
+use std::fmt;
 use std::io;
+use std::path;
 use std::process;
 
 const NITROCLI_ADMIN_PIN: &str = "NITROCLI_ADMIN_PIN";
@@ -84,6 +104,45 @@ const NITROCLI_NEW_ADMIN_PIN: &str = "NITROCLI_NEW_ADMIN_PIN";
 const NITROCLI_NEW_USER_PIN: &str = "NITROCLI_NEW_USER_PIN";
 const NITROCLI_PASSWORD: &str = "NITROCLI_PASSWORD";
 const NITROCLI_NO_CACHE: &str = "NITROCLI_NO_CACHE";
+const NITROCLI_CACHE: &str = "NITROCLI_CACHE";
+const NITROCLI_CACHE_TIMEOUT: &str = "NITROCLI_CACHE_TIMEOUT";
+const NITROCLI_PIN_POLICY: &str = "NITROCLI_PIN_POLICY";
+
+/// The environment variables that may contain PINs or passwords.
+const SECRET_ENV_VARS: &[&str] = &[
+  NITROCLI_ADMIN_PIN,
+  NITROCLI_USER_PIN,
+  NITROCLI_NEW_ADMIN_PIN,
+  NITROCLI_NEW_USER_PIN,
+  NITROCLI_PASSWORD,
+];
+
+/// The exit status for errors without a more specific status.
+const EXIT_FAILURE: i32 = 1;
+/// The exit status for invalid command-line arguments.
+const EXIT_USAGE: i32 = 2;
+/// The exit status for a wrong PIN or password.
+const EXIT_WRONG_PIN: i32 = 3;
+/// The exit status for a missing device or a failed connection to it.
+const EXIT_NOT_CONNECTED: i32 = 4;
+/// The exit status for an invalid or unprogrammed slot.
+const EXIT_INVALID_SLOT: i32 = 5;
+/// The exit status for a pinentry dialog cancelled by the user.
+const EXIT_CANCELLED: i32 = 6;
+
+/// An error indicating that the program should exit with the given
+/// status without reporting anything, e.g., because the status of a
+/// child process is forwarded.
+#[derive(Debug)]
+pub struct DirectExitError(pub i32);
+
+impl fmt::Display for DirectExitError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "Exiting with status {}", self.0)
+  }
+}
+
+impl error::Error for DirectExitError {}
 
 trait Stdio {
   fn stdio(&mut self) -> (&mut dyn io::Write, &mut dyn io::Write);
@@ -110,6 +169,11 @@ where
 pub struct ExecCtx<'io> {
   /// The Nitrokey model to use.
   pub model: Option<args::DeviceModel>,
+  /// The path of the device to connect to, if a specific one was
+  /// selected.
+  pub path: Option<String>,
+  /// See `RunCtx::stdin`.
+  pub stdin: &'io mut dyn io::Read,
   /// See `RunCtx::stdout`.
   pub stdout: &'io mut dyn io::Write,
   /// See `RunCtx::stderr`.
@@ -126,6 +190,21 @@ pub struct ExecCtx<'io> {
   pub password: Option<ffi::OsString>,
   /// See `RunCtx::no_cache`.
   pub no_cache: bool,
+  /// See `RunCtx::cache`.
+  pub cache: Option<ffi::OsString>,
+  /// See `RunCtx::cache_timeout`.
+  pub cache_timeout: Option<ffi::OsString>,
+  /// See `RunCtx::pin_policy`.
+  pub pin_policy: Option<ffi::OsString>,
+  /// See `RunCtx::stdout_tty`.
+  pub stdout_tty: bool,
+  /// See `RunCtx::config_dir`.
+  pub config_dir: Option<path::PathBuf>,
+  /// See `RunCtx::agent_socket`.
+  pub agent_socket: Option<path::PathBuf>,
+  /// Whether to use a PIN from the environment even if only one attempt
+  /// is left for it.
+  pub force: bool,
   /// The verbosity level to use for logging.
   pub verbosity: u64,
 }
@@ -144,6 +223,8 @@ fn handle_arguments(ctx: &mut RunCtx<'_>, args: Vec<String>) -> anyhow::Result<(
     Ok(args) => {
       let mut ctx = ExecCtx {
         model: args.model,
+        path: None,
+        stdin: ctx.stdin,
         stdout: ctx.stdout,
         stderr: ctx.stderr,
         admin_pin: ctx.admin_pin.take(),
@@ -152,9 +233,20 @@ fn handle_arguments(ctx: &mut RunCtx<'_>, args: Vec<String>) -> anyhow::Result<(
         new_user_pin: ctx.new_user_pin.take(),
         password: ctx.password.take(),
         no_cache: ctx.no_cache,
+        cache: ctx.cache.take(),
+        cache_timeout: ctx.cache_timeout.take(),
+        pin_policy: ctx.pin_policy.take(),
+        stdout_tty: ctx.stdout_tty,
+        config_dir: ctx.config_dir.take(),
+        agent_socket: ctx.agent_socket.take(),
+        force: args.force,
         verbosity: args.verbose.into(),
       };
-      args.cmd.execute(&mut ctx)
+      if args.all_devices {
+        commands::run_on_all_devices(&mut ctx, args.cmd)
+      } else {
+        args.cmd.execute(&mut ctx)
+      }
     }
     Err(err) => {
       if err.use_stderr() {
@@ -169,6 +261,8 @@ fn handle_arguments(ctx: &mut RunCtx<'_>, args: Vec<String>) -> anyhow::Result<(
 
 /// The context used when running the program.
 pub(crate) struct RunCtx<'io> {
+  /// The `Read` object used as standard input throughout the program.
+  pub stdin: &'io mut dyn io::Read,
   /// The `Write` object used as standard output throughout the program.
   pub stdout: &'io mut dyn io::Write,
   /// The `Write` object used as standard error throughout the program.
@@ -189,14 +283,72 @@ pub(crate) struct RunCtx<'io> {
   pub password: Option<ffi::OsString>,
   /// Whether to bypass the cache for all secrets or not.
   pub no_cache: bool,
+  /// The backend to use for caching secrets, if provided through an
+  /// environment variable.
+  pub cache: Option<ffi::OsString>,
+  /// The number of seconds secrets are cached in the kernel keyring, if
+  /// provided through an environment variable.
+  pub cache_timeout: Option<ffi::OsString>,
+  /// The strictness of the policy for newly chosen secrets, if provided
+  /// through an environment variable.
+  pub pin_policy: Option<ffi::OsString>,
+  /// Whether the standard output refers to a terminal.
+  pub stdout_tty: bool,
+  /// The directory containing the configuration files, if it could be
+  /// determined.
+  pub config_dir: Option<path::PathBuf>,
+  /// The socket of the agent to use, if any.
+  pub agent_socket: Option<path::PathBuf>,
+}
+
+/// Determine the exit status for the given error.
+///
+/// The status is derived from the first error in the chain that belongs
+/// to one of the known failure classes.
+fn exit_status(err: &anyhow::Error) -> i32 {
+  for cause in err.chain() {
+    if cause.is::<structopt::clap::Error>() {
+      return EXIT_USAGE;
+    } else if cause.is::<pinentry::CancelledError>() {
+      return EXIT_CANCELLED;
+    } else if let Some(err) = cause.downcast_ref::<agent::Error>() {
+      return err.status;
+    } else if let Some(err) = cause.downcast_ref::<nitrokey::Error>() {
+      match err {
+        nitrokey::Error::CommandError(nitrokey::CommandError::WrongPassword) => {
+          return EXIT_WRONG_PIN
+        }
+        nitrokey::Error::CommunicationError(_) => return EXIT_NOT_CONNECTED,
+        nitrokey::Error::CommandError(nitrokey::CommandError::SlotNotProgrammed)
+        | nitrokey::Error::CommandError(nitrokey::CommandError::WrongSlot)
+        | nitrokey::Error::LibraryError(nitrokey::LibraryError::InvalidSlot) => {
+          return EXIT_INVALID_SLOT
+        }
+        _ => (),
+      }
+    }
+  }
+  EXIT_FAILURE
+}
+
+/// Retrieve the directory containing the configuration files.
+fn config_dir() -> Option<path::PathBuf> {
+  let config = env::var_os("XDG_CONFIG_HOME")
+    .map(path::PathBuf::from)
+    .or_else(|| env::var_os("HOME").map(|home| path::PathBuf::from(home).join(".config")))?;
+  Some(config.join("nitrocli"))
 }
 
 fn run<'ctx, 'io: 'ctx>(ctx: &'ctx mut RunCtx<'io>, args: Vec<String>) -> i32 {
   match handle_arguments(ctx, args) {
     Ok(()) => 0,
     Err(err) => {
-      let _ = eprintln!(ctx, "{:?}", err);
-      1
+      if let Some(DirectExitError(status)) = err.downcast_ref() {
+        *status
+      } else {
+        let _ = eprintln!(ctx, "{:?}", err);
+        exit_status(&err)
+      }
     }
   }
 }
@@ -204,10 +356,12 @@ fn run<'ctx, 'io: 'ctx>(ctx: &'ctx mut RunCtx<'io>, args: Vec<String>) -> i32 {
 fn main() {
   use std::io::Write;
 
+  let mut stdin = io::stdin();
   let mut stdout = io::stdout();
   let mut stderr = io::stderr();
   let args = env::args().collect::<Vec<_>>();
   let ctx = &mut RunCtx {
+    stdin: &mut stdin,
     stdout: &mut stdout,
     stderr: &mut stderr,
     admin_pin: env::var_os(NITROCLI_ADMIN_PIN),
@@ -216,6 +370,13 @@ fn main() {
     new_user_pin: env::var_os(NITROCLI_NEW_USER_PIN),
     password: env::var_os(NITROCLI_PASSWORD),
     no_cache: env::var_os(NITROCLI_NO_CACHE).is_some(),
+    cache: env::var_os(NITROCLI_CACHE),
+    cache_timeout: env::var_os(NITROCLI_CACHE_TIMEOUT),
+    pin_policy: env::var_os(NITROCLI_PIN_POLICY),
+    // SAFETY: `isatty` is safe to call with any file descriptor.
+    stdout_tty: unsafe { libc::isatty(libc::STDOUT_FILENO) } == 1,
+    config_dir: config_dir(),
+    agent_socket: Some(agent::socket_path()),
   };
 
   let rc = run(ctx, args);
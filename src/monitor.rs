@@ -0,0 +1,419 @@
+// monitor.rs
+
+// *************************************************************************
+// * Copyright (C) 2020 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+use std::fmt;
+use std::io;
+use std::mem;
+use std::thread;
+use std::time;
+
+use anyhow::Context as _;
+
+use nitrokey::Device as _;
+
+/// The netlink multicast group of uevents sent by the kernel.
+const KERNEL_UEVENT_GROUP: u32 = 1;
+
+/// The time to wait after a uevent before listing the devices, giving
+/// udev the chance to set up the device node.
+const SETTLE_TIME: time::Duration = time::Duration::from_millis(500);
+
+/// The kind of a device event.
+#[derive(Clone, Copy, Debug, PartialEq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EventKind {
+  /// A device was attached.
+  Attach,
+  /// A device was detached.
+  Detach,
+}
+
+impl fmt::Display for EventKind {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let kind = match self {
+      EventKind::Attach => "attach",
+      EventKind::Detach => "detach",
+    };
+    f.write_str(kind)
+  }
+}
+
+/// An attached Nitrokey device.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Device {
+  /// The path of the device.
+  pub path: String,
+  /// The model of the device, or "unknown".
+  pub model: String,
+  /// The serial number of the device, or "N/A".
+  pub serial: String,
+}
+
+/// The attachment or detachment of a device.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Event {
+  /// The kind of the event.
+  pub kind: EventKind,
+  /// The device the event refers to.
+  pub device: Device,
+}
+
+/// A source of the list of attached devices.
+pub trait Source {
+  /// Retrieve the currently attached devices.
+  fn devices(&mut self) -> anyhow::Result<Vec<Device>>;
+
+  /// Wait until the attached devices may have changed, returning
+  /// `false` if no more changes are to be expected.
+  fn wait(&mut self) -> anyhow::Result<bool>;
+}
+
+/// Determine the events leading from the old to the new list of devices.
+///
+/// Devices are identified by their path.
+pub fn diff(old: &[Device], new: &[Device]) -> Vec<Event> {
+  let detached = old
+    .iter()
+    .filter(|device| !new.iter().any(|other| other.path == device.path))
+    .map(|device| Event {
+      kind: EventKind::Detach,
+      device: device.clone(),
+    });
+  let attached = new
+    .iter()
+    .filter(|device| !old.iter().any(|other| other.path == device.path))
+    .map(|device| Event {
+      kind: EventKind::Attach,
+      device: device.clone(),
+    });
+  detached.chain(attached).collect()
+}
+
+/// Watch the given source for changes of the attached devices, passing
+/// each resulting event to the given handler.
+pub fn watch<S, F>(source: &mut S, mut handle: F) -> anyhow::Result<()>
+where
+  S: Source,
+  F: FnMut(&Event) -> anyhow::Result<()>,
+{
+  let mut devices = source.devices()?;
+  while source.wait()? {
+    let new_devices = source.devices()?;
+    for event in diff(&devices, &new_devices) {
+      handle(&event)?;
+    }
+    devices = new_devices;
+  }
+  Ok(())
+}
+
+/// Check whether the given uevent refers to a USB or hidraw device.
+fn is_relevant(uevent: &[u8]) -> bool {
+  uevent
+    .split(|byte| *byte == 0)
+    .any(|field| field == b"SUBSYSTEM=hidraw" || field == b"SUBSYSTEM=usb")
+}
+
+/// A netlink socket receiving the uevents sent by the kernel.
+#[derive(Debug)]
+pub struct Netlink {
+  fd: libc::c_int,
+}
+
+impl Netlink {
+  /// Open a netlink socket subscribed to kernel uevents.
+  pub fn open() -> io::Result<Self> {
+    // SAFETY: `socket` is safe to call with any arguments.
+    let fd = unsafe {
+      libc::socket(
+        libc::AF_NETLINK,
+        libc::SOCK_DGRAM | libc::SOCK_CLOEXEC,
+        libc::NETLINK_KOBJECT_UEVENT,
+      )
+    };
+    if fd < 0 {
+      return Err(io::Error::last_os_error());
+    }
+    let netlink = Self { fd };
+
+    // SAFETY: `sockaddr_nl` is a plain C struct for which all zeroes is
+    //         a valid value.
+    let mut addr: libc::sockaddr_nl = unsafe { mem::zeroed() };
+    addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+    addr.nl_groups = KERNEL_UEVENT_GROUP;
+    // SAFETY: `addr` is a valid `sockaddr_nl` of the given size.
+    // `size_of` is only part of the prelude in more recent Rust versions.
+    #[allow(unused_qualifications)]
+    let rc = unsafe {
+      libc::bind(
+        netlink.fd,
+        &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+        mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+      )
+    };
+    if rc != 0 {
+      return Err(io::Error::last_os_error());
+    }
+    Ok(netlink)
+  }
+
+  /// Wait for uevents, returning whether any of them refers to a USB or
+  /// hidraw device.
+  fn wait(&self) -> io::Result<bool> {
+    let mut pollfd = libc::pollfd {
+      fd: self.fd,
+      events: libc::POLLIN,
+      revents: 0,
+    };
+    // SAFETY: `pollfd` is a valid array of one element.
+    if unsafe { libc::poll(&mut pollfd, 1, -1) } < 0 {
+      let err = io::Error::last_os_error();
+      return match err.kind() {
+        io::ErrorKind::Interrupted => Ok(false),
+        _ => Err(err),
+      };
+    }
+
+    let mut relevant = false;
+    let mut buffer = [0u8; 8192];
+    loop {
+      // SAFETY: `buffer` is valid for writes of its length.
+      let count = unsafe {
+        libc::recv(
+          self.fd,
+          buffer.as_mut_ptr() as *mut libc::c_void,
+          buffer.len(),
+          libc::MSG_DONTWAIT,
+        )
+      };
+      if count < 0 {
+        let err = io::Error::last_os_error();
+        match err.raw_os_error() {
+          // Events were lost because the socket buffer overflowed, so
+          // the devices may have changed.
+          Some(libc::ENOBUFS) => relevant = true,
+          Some(libc::EINTR) => (),
+          _ if err.kind() == io::ErrorKind::WouldBlock => break,
+          _ => return Err(err),
+        }
+      } else {
+        relevant |= is_relevant(&buffer[..count as usize]);
+      }
+    }
+    Ok(relevant)
+  }
+}
+
+impl Drop for Netlink {
+  fn drop(&mut self) {
+    // SAFETY: The file descriptor is owned by this object.
+    let _ = unsafe { libc::close(self.fd) };
+  }
+}
+
+/// A source listing the attached Nitrokey devices, waiting for changes
+/// using kernel uevents, if available, or by polling otherwise.
+#[allow(missing_debug_implementations)]
+pub struct NitrokeySource<'mgr> {
+  manager: &'mgr mut nitrokey::Manager,
+  netlink: Option<Netlink>,
+  interval: time::Duration,
+  devices: Vec<Device>,
+}
+
+impl<'mgr> NitrokeySource<'mgr> {
+  /// Create a new source that waits for uevents received using the
+  /// given netlink socket or, if there is none, polls using the given
+  /// interval.
+  pub fn new(
+    manager: &'mgr mut nitrokey::Manager,
+    netlink: Option<Netlink>,
+    interval: time::Duration,
+  ) -> Self {
+    Self {
+      manager,
+      netlink,
+      interval,
+      devices: Vec::new(),
+    }
+  }
+
+  /// Retrieve the serial number of the device with the given path by
+  /// connecting to it.
+  fn serial_number(&mut self, path: &str) -> Option<nitrokey::SerialNumber> {
+    let device = self.manager.connect_path(path.to_string()).ok()?;
+    device.get_serial_number().ok()
+  }
+}
+
+impl<'mgr> Source for NitrokeySource<'mgr> {
+  fn devices(&mut self) -> anyhow::Result<Vec<Device>> {
+    let device_infos =
+      nitrokey::list_devices().context("Failed to list connected Nitrokey devices")?;
+
+    let mut devices = Vec::with_capacity(device_infos.len());
+    for device_info in device_infos {
+      // Only connect to devices we have not seen before to retrieve
+      // their serial number.
+      let known = self
+        .devices
+        .iter()
+        .find(|device| device.path == device_info.path)
+        .cloned();
+      let device = match known {
+        Some(device) => device,
+        None => {
+          let serial = device_info
+            .serial_number
+            .or_else(|| self.serial_number(&device_info.path));
+          Device {
+            model: device_info
+              .model
+              .map(|model| model.to_string())
+              .unwrap_or_else(|| "unknown".into()),
+            serial: serial
+              .map(|serial| serial.to_string())
+              .unwrap_or_else(|| "N/A".into()),
+            path: device_info.path,
+          }
+        }
+      };
+      devices.push(device);
+    }
+
+    self.devices = devices.clone();
+    Ok(devices)
+  }
+
+  fn wait(&mut self) -> anyhow::Result<bool> {
+    match &self.netlink {
+      Some(netlink) => {
+        while !netlink.wait().context("Failed to receive uevents")? {}
+        thread::sleep(SETTLE_TIME);
+      }
+      None => thread::sleep(self.interval),
+    }
+    Ok(true)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use std::slice;
+
+  /// A source reporting a scripted sequence of device lists.
+  struct ScriptedSource {
+    lists: Vec<Vec<Device>>,
+  }
+
+  impl Source for ScriptedSource {
+    fn devices(&mut self) -> anyhow::Result<Vec<Device>> {
+      Ok(self.lists.remove(0))
+    }
+
+    fn wait(&mut self) -> anyhow::Result<bool> {
+      Ok(!self.lists.is_empty())
+    }
+  }
+
+  fn device(path: &str, serial: &str) -> Device {
+    Device {
+      path: path.to_string(),
+      model: "Pro".to_string(),
+      serial: serial.to_string(),
+    }
+  }
+
+  fn event(kind: EventKind, device: &Device) -> Event {
+    Event {
+      kind,
+      device: device.clone(),
+    }
+  }
+
+  #[test]
+  fn diff_devices() {
+    let dev1 = device("/dev/hidraw1", "0x00000001");
+    let dev2 = device("/dev/hidraw2", "0x00000002");
+
+    assert_eq!(diff(&[], &[]), vec![]);
+    let old = slice::from_ref(&dev1);
+    assert_eq!(diff(old, old), vec![]);
+    assert_eq!(
+      diff(old, slice::from_ref(&dev2)),
+      vec![
+        event(EventKind::Detach, &dev1),
+        event(EventKind::Attach, &dev2)
+      ]
+    );
+  }
+
+  #[test]
+  fn watch_scripted() {
+    let dev1 = device("/dev/hidraw1", "0x00000001");
+    let dev2 = device("/dev/hidraw2", "N/A");
+
+    let mut source = ScriptedSource {
+      lists: vec![
+        vec![dev1.clone()],
+        vec![dev1.clone()],
+        vec![dev1.clone(), dev2.clone()],
+        vec![dev2.clone()],
+        vec![],
+      ],
+    };
+    let mut events = Vec::new();
+    watch(&mut source, |event| {
+      events.push(event.clone());
+      Ok(())
+    })
+    .unwrap();
+
+    assert_eq!(
+      events,
+      vec![
+        event(EventKind::Attach, &dev2),
+        event(EventKind::Detach, &dev1),
+        event(EventKind::Detach, &dev2),
+      ]
+    );
+  }
+
+  #[test]
+  fn watch_error() {
+    let mut source = ScriptedSource {
+      lists: vec![vec![], vec![device("/dev/hidraw1", "N/A")]],
+    };
+    let err = watch(&mut source, |_| anyhow::bail!("Handler failed")).unwrap_err();
+    assert_eq!(err.to_string(), "Handler failed");
+  }
+
+  #[test]
+  fn relevant_uevents() {
+    let hidraw = b"add@/devices/pci0000:00/usb1/1-1/1-1:1.0/hidraw/hidraw3\0\
+ACTION=add\0DEVPATH=/devices/pci0000:00/usb1/1-1/1-1:1.0/hidraw/hidraw3\0\
+SUBSYSTEM=hidraw\0DEVNAME=hidraw3\0SEQNUM=4242\0";
+    let block = b"change@/devices/virtual/block/loop0\0ACTION=change\0SUBSYSTEM=block\0";
+    assert!(is_relevant(hidraw));
+    assert!(!is_relevant(block));
+    assert!(!is_relevant(b""));
+  }
+}
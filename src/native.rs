@@ -0,0 +1,350 @@
+// native.rs
+
+// *************************************************************************
+// * Copyright (C) 2020 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+use std::convert::TryFrom as _;
+use std::env;
+use std::io;
+use std::path;
+
+use anyhow::Context as _;
+
+use crate::args;
+use crate::askpass;
+
+/// The name under which the native messaging host is registered.
+pub const HOST_NAME: &str = "nitrocli";
+
+/// The maximum size of a message we are willing to read.
+const MAX_MESSAGE_LEN: u32 = 1024 * 1024;
+
+/// A request sent by a browser extension.
+#[derive(Debug, PartialEq, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum Request {
+  /// List the name and login of all programmed PWS slots.
+  List,
+  /// Retrieve the password stored in a PWS slot.
+  GetPassword { slot: u8 },
+  /// Generate the current code of the TOTP slot with the given name.
+  GetOtp { name: String },
+}
+
+impl Request {
+  /// Parse a request from the given JSON data.
+  pub fn parse(data: &[u8]) -> anyhow::Result<Self> {
+    serde_json::from_slice(data).context("Failed to parse request")
+  }
+}
+
+/// Read a length-prefixed message from the given reader.
+///
+/// `None` is returned once the reader reached its end, i.e., once the
+/// browser closed the connection.
+pub fn read_message(reader: &mut dyn io::Read) -> anyhow::Result<Option<Vec<u8>>> {
+  let mut len = [0; 4];
+  match reader.read_exact(&mut len) {
+    Ok(()) => (),
+    Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+    Err(err) => return Err(err).context("Failed to read message length"),
+  }
+
+  // The length is encoded in native byte order.
+  let len = u32::from_ne_bytes(len);
+  if len > MAX_MESSAGE_LEN {
+    anyhow::bail!("Message of {} bytes exceeds the maximum size", len)
+  }
+
+  let mut message = vec![0; len as usize];
+  reader
+    .read_exact(&mut message)
+    .context("Failed to read message")?;
+  Ok(Some(message))
+}
+
+/// Write the given value as a length-prefixed message.
+pub fn write_message(writer: &mut dyn io::Write, value: &serde_json::Value) -> anyhow::Result<()> {
+  let message = serde_json::to_vec(value).context("Failed to serialize message")?;
+  let len = u32::try_from(message.len()).context("Message too large")?;
+  writer
+    .write_all(&len.to_ne_bytes())
+    .and_then(|()| writer.write_all(&message))
+    .and_then(|()| writer.flush())
+    .context("Failed to write message")
+}
+
+/// Determine the origin of the calling extension from the arguments
+/// the browser passed to the host.
+///
+/// Chrome passes the origin of the extension, while Firefox passes the
+/// path of the host manifest followed by the ID of the extension.
+pub fn origin(args: &[String]) -> anyhow::Result<&str> {
+  match args {
+    [origin, ..] if origin.starts_with("chrome-extension://") => Ok(origin),
+    [_manifest, extension, ..] => Ok(extension),
+    _ => anyhow::bail!("Unable to determine the origin of the calling extension"),
+  }
+}
+
+/// A rule describing the requests an extension is allowed to make.
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+  /// The origin of a Chrome extension or the ID of a Firefox extension.
+  pub origin: String,
+  /// Whether the extension may list the PWS slots.
+  #[serde(default)]
+  pub list: bool,
+  /// The PWS slots whose password the extension may retrieve.
+  #[serde(default)]
+  pub slots: Vec<u8>,
+  /// The names of the TOTP slots the extension may generate codes for.
+  #[serde(default)]
+  pub otp: Vec<String>,
+}
+
+impl Rule {
+  /// Check whether the rule applies to extensions of the given browser.
+  fn applies_to(&self, browser: args::Browser) -> bool {
+    let chrome = self.origin.starts_with("chrome-extension://");
+    match browser {
+      args::Browser::Chrome | args::Browser::Chromium => chrome,
+      args::Browser::Firefox => !chrome,
+    }
+  }
+
+  /// Check whether the rule allows the given request.
+  fn allows(&self, request: &Request) -> bool {
+    match request {
+      Request::List => self.list,
+      Request::GetPassword { slot } => self.slots.contains(slot),
+      Request::GetOtp { name } => self.otp.contains(name),
+    }
+  }
+}
+
+/// The rules deciding which extensions may make which requests.
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Rules {
+  #[serde(rename = "allow")]
+  pub rules: Vec<Rule>,
+}
+
+impl Rules {
+  /// Parse rules from the given TOML data.
+  pub fn parse(content: &str) -> anyhow::Result<Self> {
+    toml::from_str(content).map_err(anyhow::Error::from)
+  }
+
+  /// Load the rules from the given file or, if no file is given, from
+  /// the default location.
+  ///
+  /// A missing rules file in the default location is treated as an
+  /// empty set of rules, denying all requests.
+  pub fn load(path: Option<&path::Path>) -> anyhow::Result<Self> {
+    askpass::load(
+      path,
+      "native-messaging.toml",
+      "native messaging rules",
+      Self::parse,
+    )
+  }
+
+  /// Check whether the extension with the given origin may make the
+  /// given request.
+  pub fn check(&self, origin: &str, request: &Request) -> anyhow::Result<()> {
+    let rule = self
+      .rules
+      .iter()
+      .find(|rule| rule.origin == origin)
+      .ok_or_else(|| anyhow::anyhow!("Origin {} is not allowed", origin))?;
+    if rule.allows(request) {
+      Ok(())
+    } else {
+      anyhow::bail!("Request not allowed for origin {}", origin)
+    }
+  }
+
+  /// Retrieve the origins of all extensions of the given browser that
+  /// the rules mention.
+  pub fn origins(&self, browser: args::Browser) -> Vec<String> {
+    self
+      .rules
+      .iter()
+      .filter(|rule| rule.applies_to(browser))
+      .map(|rule| rule.origin.clone())
+      .collect()
+  }
+}
+
+/// Retrieve the directory the given browser looks for host manifests
+/// of the current user in.
+pub fn manifest_dir(browser: args::Browser) -> anyhow::Result<path::PathBuf> {
+  let home = env::var_os("HOME")
+    .map(path::PathBuf::from)
+    .ok_or_else(|| anyhow::anyhow!("Failed to determine the home directory"))?;
+  let config = env::var_os("XDG_CONFIG_HOME")
+    .map(path::PathBuf::from)
+    .unwrap_or_else(|| home.join(".config"));
+
+  let dir = match browser {
+    args::Browser::Chrome => config.join("google-chrome").join("NativeMessagingHosts"),
+    args::Browser::Chromium => config.join("chromium").join("NativeMessagingHosts"),
+    args::Browser::Firefox => home.join(".mozilla").join("native-messaging-hosts"),
+  };
+  Ok(dir)
+}
+
+/// Create the host manifest for the given browser.
+pub fn manifest(
+  browser: args::Browser,
+  path: &path::Path,
+  origins: Vec<String>,
+) -> serde_json::Value {
+  let allowed = match browser {
+    args::Browser::Chrome | args::Browser::Chromium => "allowed_origins",
+    args::Browser::Firefox => "allowed_extensions",
+  };
+
+  let mut manifest = serde_json::json!({
+    "name": HOST_NAME,
+    "description": "Access to the password safe of Nitrokey devices",
+    "path": path,
+    "type": "stdio",
+  });
+  manifest[allowed] = origins.into();
+  manifest
+}
+
+/// Quote the given string for use in a POSIX shell.
+pub fn shell_quote(string: &str) -> String {
+  format!("'{}'", string.replace('\'', r"'\''"))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn message_framing() {
+    let value = serde_json::json!({"type": "list"});
+    let mut buffer = Vec::new();
+    write_message(&mut buffer, &value).unwrap();
+    assert_eq!(&buffer[..4], &15u32.to_ne_bytes());
+
+    let mut reader = buffer.as_slice();
+    let message = read_message(&mut reader).unwrap().unwrap();
+    assert_eq!(Request::parse(&message).unwrap(), Request::List);
+    assert_eq!(read_message(&mut reader).unwrap(), None);
+  }
+
+  #[test]
+  fn message_too_large() {
+    let mut reader = &(MAX_MESSAGE_LEN + 1).to_ne_bytes()[..];
+    assert!(read_message(&mut reader).is_err());
+  }
+
+  #[test]
+  fn parse_requests() {
+    let request = Request::parse(br#"{"type":"get-password","slot":2}"#).unwrap();
+    assert_eq!(request, Request::GetPassword { slot: 2 });
+    let request = Request::parse(br#"{"type":"get-otp","name":"github"}"#).unwrap();
+    assert_eq!(
+      request,
+      Request::GetOtp {
+        name: "github".to_string()
+      }
+    );
+    assert!(Request::parse(br#"{"type":"get-password"}"#).is_err());
+    assert!(Request::parse(br#"{"type":"erase"}"#).is_err());
+  }
+
+  #[test]
+  fn determine_origin() {
+    let args = vec!["chrome-extension://abcdef/".to_string()];
+    assert_eq!(origin(&args).unwrap(), "chrome-extension://abcdef/");
+    let args = vec![
+      "/home/john/.mozilla/native-messaging-hosts/nitrocli.json".to_string(),
+      "ext@example.org".to_string(),
+    ];
+    assert_eq!(origin(&args).unwrap(), "ext@example.org");
+    assert!(origin(&[]).is_err());
+  }
+
+  #[test]
+  fn check_rules() {
+    let rules = Rules::parse(
+      r#"
+[[allow]]
+origin = "chrome-extension://abcdef/"
+list = true
+slots = [1, 3]
+
+[[allow]]
+origin = "ext@example.org"
+otp = ["github"]
+"#,
+    )
+    .unwrap();
+
+    let chrome = "chrome-extension://abcdef/";
+    assert!(rules.check(chrome, &Request::List).is_ok());
+    assert!(rules
+      .check(chrome, &Request::GetPassword { slot: 3 })
+      .is_ok());
+    let err = rules
+      .check(chrome, &Request::GetPassword { slot: 2 })
+      .unwrap_err();
+    assert_eq!(
+      err.to_string(),
+      "Request not allowed for origin chrome-extension://abcdef/"
+    );
+
+    let firefox = "ext@example.org";
+    assert!(rules.check(firefox, &Request::List).is_err());
+    let otp = Request::GetOtp {
+      name: "github".to_string(),
+    };
+    assert!(rules.check(firefox, &otp).is_ok());
+
+    let err = rules.check("other@example.org", &otp).unwrap_err();
+    assert_eq!(err.to_string(), "Origin other@example.org is not allowed");
+
+    assert_eq!(rules.origins(args::Browser::Chromium), vec![chrome]);
+    assert_eq!(rules.origins(args::Browser::Firefox), vec![firefox]);
+  }
+
+  #[test]
+  fn create_manifest() {
+    let path = path::Path::new("/usr/bin/nitrocli-native-messaging");
+    let origins = vec!["ext@example.org".to_string()];
+    let manifest = manifest(args::Browser::Firefox, path, origins);
+    assert_eq!(manifest["name"], "nitrocli");
+    assert_eq!(manifest["path"], "/usr/bin/nitrocli-native-messaging");
+    assert_eq!(manifest["type"], "stdio");
+    assert_eq!(manifest["allowed_extensions"][0], "ext@example.org");
+    assert!(manifest.get("allowed_origins").is_none());
+  }
+
+  #[test]
+  fn quote_for_shell() {
+    assert_eq!(shell_quote("/usr/bin/nitrocli"), "'/usr/bin/nitrocli'");
+    assert_eq!(shell_quote("it's"), r"'it'\''s'");
+  }
+}
@@ -18,6 +18,8 @@
 // *************************************************************************
 
 use std::borrow;
+use std::error;
+use std::ffi;
 use std::fmt;
 use std::process;
 use std::str;
@@ -25,8 +27,91 @@ use std::str;
 use anyhow::Context as _;
 
 use crate::args;
+use crate::keyring;
+use crate::policy;
 use crate::ExecCtx;
 
+/// The maximum number of characters the Nitrokey supports for PINs and
+/// passwords.
+const MAX_SECRET_LEN: u8 = 20;
+
+/// The default number of seconds secrets are cached in the kernel
+/// keyring.
+const DEFAULT_CACHE_TIMEOUT: u32 = 600;
+
+/// The GnuPG error code reported when the user cancelled an operation.
+const GPG_ERR_CANCELED: u32 = 99;
+
+/// The mask for extracting the error code from a GnuPG error value,
+/// which additionally encodes the error source.
+const GPG_ERR_CODE_MASK: u32 = 0xffff;
+
+/// An error indicating that the user cancelled the pinentry dialog.
+#[derive(Debug)]
+pub struct CancelledError(String);
+
+impl fmt::Display for CancelledError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(&self.0)
+  }
+}
+
+impl error::Error for CancelledError {}
+
+Enum! {
+  /// The available backends for caching secrets.
+  CacheBackend, [
+    GpgAgent => "gpg-agent",
+    Keyring => "keyring",
+  ]
+}
+
+impl CacheBackend {
+  /// Parse the cache backend from the value of an environment
+  /// variable, falling back to gpg-agent if it is not set.
+  fn from_env(value: Option<&ffi::OsStr>) -> anyhow::Result<Self> {
+    match value {
+      Some(value) => value
+        .to_str()
+        .ok_or_else(|| anyhow::anyhow!("Invalid Unicode data found"))
+        .and_then(|value| value.parse().map_err(anyhow::Error::msg)),
+      None => Ok(CacheBackend::GpgAgent),
+    }
+    .context("Failed to parse the cache backend")
+  }
+
+  /// Retrieve the cache backend configured in the given execution
+  /// context.
+  fn from_ctx(ctx: &ExecCtx<'_>) -> anyhow::Result<Self> {
+    Self::from_env(ctx.cache.as_deref())
+  }
+
+  /// Split the given cache ID into the one to use with gpg-agent and
+  /// the one to use with the kernel keyring for a secret inquired in
+  /// the given mode.
+  fn cache_ids(self, mode: Mode, cache_id: Option<CowStr>) -> (Option<CowStr>, Option<CowStr>) {
+    match self {
+      CacheBackend::GpgAgent => (cache_id, None),
+      // Only secrets we query are cached, newly chosen ones are not.
+      CacheBackend::Keyring if mode == Mode::Query => (None, cache_id),
+      CacheBackend::Keyring => (None, None),
+    }
+  }
+}
+
+/// Parse the number of seconds secrets are cached in the kernel
+/// keyring from the value of an environment variable.
+fn parse_cache_timeout(value: Option<&ffi::OsStr>) -> anyhow::Result<u32> {
+  match value {
+    Some(value) => value
+      .to_str()
+      .ok_or_else(|| anyhow::anyhow!("Invalid Unicode data found"))
+      .and_then(|value| value.parse().map_err(anyhow::Error::from)),
+    None => Ok(DEFAULT_CACHE_TIMEOUT),
+  }
+  .context("Failed to parse the cache timeout")
+}
+
 type CowStr = borrow::Cow<'static, str>;
 
 /// A trait representing a secret to be entered by the user.
@@ -39,6 +124,8 @@ pub trait SecretEntry: fmt::Debug {
   fn description(&self, mode: Mode) -> CowStr;
   /// The minimum number of characters the secret needs to have.
   fn min_len(&self) -> u8;
+  /// The maximum number of characters the secret may have.
+  fn max_len(&self) -> u8;
 }
 
 #[derive(Debug)]
@@ -46,6 +133,7 @@ pub struct PinEntry {
   pin_type: args::PinType,
   model: nitrokey::Model,
   serial: nitrokey::SerialNumber,
+  retry_count: u8,
 }
 
 impl PinEntry {
@@ -57,17 +145,29 @@ impl PinEntry {
     let serial = device
       .get_serial_number()
       .context("Failed to retrieve serial number")?;
+    let retry_count = match pin_type {
+      args::PinType::Admin => device.get_admin_retry_count(),
+      args::PinType::User => device.get_user_retry_count(),
+    }
+    .context("Failed to retrieve retry count")?;
 
     Ok(Self {
       pin_type,
       model,
       serial,
+      retry_count,
     })
   }
 
   pub fn pin_type(&self) -> args::PinType {
     self.pin_type
   }
+
+  /// The number of remaining attempts for the PIN at the time the entry
+  /// was created.
+  pub fn retry_count(&self) -> u8 {
+    self.retry_count
+  }
 }
 
 impl SecretEntry for PinEntry {
@@ -116,6 +216,10 @@ impl SecretEntry for PinEntry {
       args::PinType::User => 6,
     }
   }
+
+  fn max_len(&self) -> u8 {
+    MAX_SECRET_LEN
+  }
 }
 
 #[derive(Debug)]
@@ -168,6 +272,55 @@ impl SecretEntry for PwdEntry {
     // too opinionated.
     6
   }
+
+  fn max_len(&self) -> u8 {
+    MAX_SECRET_LEN
+  }
+}
+
+/// A secret requested by another program using a free-form prompt.
+#[derive(Debug)]
+pub struct PromptEntry {
+  prompt: String,
+}
+
+impl PromptEntry {
+  pub fn new(prompt: &str) -> Self {
+    Self {
+      prompt: prompt.to_string(),
+    }
+  }
+}
+
+impl SecretEntry for PromptEntry {
+  fn cache_id(&self) -> Option<CowStr> {
+    None
+  }
+
+  fn prompt(&self) -> CowStr {
+    "Passphrase".into()
+  }
+
+  fn description(&self, _mode: Mode) -> CowStr {
+    // The prompt is provided by a different program, so we have to
+    // escape characters that are special to the Assuan protocol.
+    self
+      .prompt
+      .trim()
+      .replace('%', "%25")
+      .replace('+', "%2B")
+      .replace('\n', "%0A")
+      .replace('\r', "%0D")
+      .into()
+  }
+
+  fn min_len(&self) -> u8 {
+    0
+  }
+
+  fn max_len(&self) -> u8 {
+    u8::MAX
+  }
 }
 
 /// Secret entry mode for pinentry.
@@ -214,6 +367,13 @@ where
   // specially.
   if !lines.is_empty() && lines[0].starts_with("ERR ") {
     let (_, error) = lines[0].split_at(4);
+    let code = error
+      .split(' ')
+      .next()
+      .and_then(|code| code.parse::<u32>().ok());
+    if code.map(|code| code & GPG_ERR_CODE_MASK) == Some(GPG_ERR_CANCELED) {
+      return Err(CancelledError(error.to_string()).into());
+    }
     anyhow::bail!("{}", error);
   }
   anyhow::bail!("Unexpected response: {}", string)
@@ -238,10 +398,24 @@ where
 {
   let cache_id = entry
     .cache_id()
-    .and_then(|id| if ctx.no_cache { None } else { Some(id) })
-    // "X" is a sentinel value indicating that no caching is desired.
-    .unwrap_or_else(|| "X".into())
-    .into();
+    .and_then(|id| if ctx.no_cache { None } else { Some(id) });
+  let (cache_id, keyring_id) = CacheBackend::from_ctx(ctx)?.cache_ids(mode, cache_id);
+
+  // Parse the cache timeout up front, so that an invalid value is
+  // reported before the user enters the secret.
+  let keyring = match keyring_id {
+    Some(keyring_id) => {
+      let timeout = parse_cache_timeout(ctx.cache_timeout.as_deref())?;
+      if let Some(secret) = keyring::get(&keyring_id)? {
+        return Ok(secret);
+      }
+      Some((keyring_id, timeout))
+    }
+    None => None,
+  };
+
+  // "X" is a sentinel value indicating that no caching is desired.
+  let cache_id = cache_id.unwrap_or_else(|| "X".into()).into();
 
   let error_msg = error_msg
     .map(|msg| msg.replace(" ", "+"))
@@ -255,6 +429,7 @@ where
     command += "--qualitybar ";
   }
   command += &args;
+
   // An error reported for the GET_PASSPHRASE command does not actually
   // cause gpg-connect-agent to exit with a non-zero error code, we have
   // to evaluate the output to determine success/failure.
@@ -266,34 +441,53 @@ where
 
   let response =
     str::from_utf8(&output.stdout).context("Failed to parse gpg-connect-agent output as UTF-8")?;
-  parse_pinentry_pin(response).context("Failed to parse pinentry secret")
+  let secret = parse_pinentry_pin(response).context("Failed to parse pinentry secret")?;
+
+  if let Some((keyring_id, timeout)) = &keyring {
+    keyring::set(keyring_id, &secret, *timeout)?;
+  }
+  Ok(secret)
 }
 
-fn check<E>(entry: &E, secret: &str) -> anyhow::Result<()>
+/// Retrieve the policy for new secrets of the given entry, as
+/// configured in the given execution context.
+fn policy<E>(ctx: &ExecCtx<'_>, entry: &E) -> anyhow::Result<policy::Policy>
 where
   E: SecretEntry,
 {
-  if secret.len() < usize::from(entry.min_len()) {
-    anyhow::bail!(
-      "The secret must be at least {} characters long",
-      entry.min_len()
-    )
-  } else {
-    Ok(())
-  }
+  let strictness = policy::Strictness::from_env(ctx.pin_policy.as_deref())?;
+  Ok(policy::Policy::new(
+    strictness,
+    entry.min_len(),
+    entry.max_len(),
+  ))
 }
 
+/// Check that a new secret that was not chosen using pinentry, e.g.,
+/// because it was provided in the environment, fulfills the PIN
+/// policy configured in the given execution context.
+pub fn check<E>(ctx: &ExecCtx<'_>, entry: &E, secret: &str) -> anyhow::Result<()>
+where
+  E: SecretEntry,
+{
+  policy(ctx, entry)?.check(secret)
+}
+
+/// Choose a new secret, enforcing the PIN policy configured in the
+/// given execution context.
 pub fn choose<E>(ctx: &mut ExecCtx<'_>, entry: &E) -> anyhow::Result<String>
 where
   E: SecretEntry,
 {
-  clear(entry)?;
+  let policy = policy(ctx, entry)?;
+
+  clear(ctx, entry)?;
   let chosen = inquire(ctx, entry, Mode::Choose, None)?;
-  clear(entry)?;
-  check(entry, &chosen)?;
+  clear(ctx, entry)?;
+  policy.check(&chosen)?;
 
   let confirmed = inquire(ctx, entry, Mode::Confirm, None)?;
-  clear(entry)?;
+  clear(ctx, entry)?;
 
   if chosen != confirmed {
     anyhow::bail!("Entered secrets do not match")
@@ -317,11 +511,16 @@ where
 }
 
 /// Clear the cached secret represented by the given entry.
-pub fn clear<E>(entry: &E) -> anyhow::Result<()>
+pub fn clear<E>(ctx: &ExecCtx<'_>, entry: &E) -> anyhow::Result<()>
 where
   E: SecretEntry,
 {
+  let backend = CacheBackend::from_ctx(ctx)?;
   if let Some(cache_id) = entry.cache_id() {
+    if backend == CacheBackend::Keyring {
+      return keyring::remove(&cache_id);
+    }
+
     let command = format!("CLEAR_PASSPHRASE {}", cache_id);
     let output = process::Command::new("gpg-connect-agent")
       .arg(command)
@@ -357,7 +556,13 @@ mod tests {
     let expected = error;
 
     let error = parse_pinentry_pin(response).unwrap_err();
-    assert_eq!(error.to_string(), expected)
+    assert_eq!(error.to_string(), expected);
+    assert!(error.is::<CancelledError>());
+    assert_eq!(crate::exit_status(&error), 6);
+
+    let error = parse_pinentry_pin("ERR 83886081 General error\n").unwrap_err();
+    assert_eq!(error.to_string(), "83886081 General error");
+    assert!(!error.is::<CancelledError>());
   }
 
   #[test]
@@ -385,4 +590,73 @@ mod tests {
     let error = parse_pinentry_response(response).unwrap_err();
     assert_eq!(error.to_string(), expected)
   }
+
+  #[test]
+  fn cache_backend_from_str() {
+    assert_eq!("keyring".parse::<CacheBackend>(), Ok(CacheBackend::Keyring));
+    assert_eq!(
+      "gpg-agent".parse::<CacheBackend>(),
+      Ok(CacheBackend::GpgAgent)
+    );
+    assert!("foo".parse::<CacheBackend>().is_err());
+  }
+
+  #[test]
+  fn cache_backend_from_env() {
+    assert_eq!(
+      CacheBackend::from_env(None).unwrap(),
+      CacheBackend::GpgAgent
+    );
+    let keyring = ffi::OsString::from("keyring");
+    assert_eq!(
+      CacheBackend::from_env(Some(&keyring)).unwrap(),
+      CacheBackend::Keyring
+    );
+    let invalid = ffi::OsString::from("kwallet");
+    let err = CacheBackend::from_env(Some(&invalid)).unwrap_err();
+    assert_eq!(err.to_string(), "Failed to parse the cache backend");
+  }
+
+  #[test]
+  fn cache_backend_ids() {
+    let id = || Some(CowStr::from("nitrocli:user"));
+    assert_eq!(
+      CacheBackend::GpgAgent.cache_ids(Mode::Query, id()),
+      (id(), None)
+    );
+    assert_eq!(
+      CacheBackend::GpgAgent.cache_ids(Mode::Choose, id()),
+      (id(), None)
+    );
+    assert_eq!(
+      CacheBackend::Keyring.cache_ids(Mode::Query, id()),
+      (None, id())
+    );
+    assert_eq!(
+      CacheBackend::Keyring.cache_ids(Mode::Choose, id()),
+      (None, None)
+    );
+    assert_eq!(
+      CacheBackend::Keyring.cache_ids(Mode::Confirm, id()),
+      (None, None)
+    );
+    assert_eq!(
+      CacheBackend::Keyring.cache_ids(Mode::Query, None),
+      (None, None)
+    );
+  }
+
+  #[test]
+  fn cache_timeout_from_env() {
+    assert_eq!(parse_cache_timeout(None).unwrap(), DEFAULT_CACHE_TIMEOUT);
+    let zero = ffi::OsString::from("0");
+    assert_eq!(parse_cache_timeout(Some(&zero)).unwrap(), 0);
+    let timeout = ffi::OsString::from("3600");
+    assert_eq!(parse_cache_timeout(Some(&timeout)).unwrap(), 3600);
+    for value in &["", "-1", "10m", "4294967296"] {
+      let value = ffi::OsString::from(value);
+      let err = parse_cache_timeout(Some(&value)).unwrap_err();
+      assert_eq!(err.to_string(), "Failed to parse the cache timeout");
+    }
+  }
 }
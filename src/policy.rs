@@ -0,0 +1,221 @@
+// policy.rs
+
+// *************************************************************************
+// * Copyright (C) 2020 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+use std::collections;
+use std::ffi;
+
+use anyhow::Context as _;
+
+/// The PINs a Nitrokey device uses after a factory reset.
+const DEFAULT_PINS: [&str; 2] = ["123456", "12345678"];
+
+/// The length of a run of repeated or consecutive characters that is
+/// rejected in strict mode.
+const MAX_RUN_LEN: usize = 3;
+
+Enum! {
+  /// The strictness with which the secret policy is enforced.
+  Strictness, [
+    Lax => "lax",
+    Normal => "normal",
+    Strict => "strict",
+  ]
+}
+
+impl Strictness {
+  /// Parse the strictness from the value of an environment variable,
+  /// falling back to `Normal` if it is not set.
+  pub fn from_env(value: Option<&ffi::OsStr>) -> anyhow::Result<Self> {
+    match value {
+      Some(value) => value
+        .to_str()
+        .ok_or_else(|| anyhow::anyhow!("Invalid Unicode data found"))
+        .and_then(|value| value.parse().map_err(anyhow::Error::msg)),
+      None => Ok(Strictness::Normal),
+    }
+    .context("Failed to parse the PIN policy")
+  }
+}
+
+/// A policy describing the requirements a new secret has to fulfill.
+#[derive(Clone, Copy, Debug)]
+pub struct Policy {
+  strictness: Strictness,
+  min_len: u8,
+  max_len: u8,
+}
+
+impl Policy {
+  pub fn new(strictness: Strictness, min_len: u8, max_len: u8) -> Self {
+    Self {
+      strictness,
+      min_len,
+      max_len,
+    }
+  }
+
+  /// Check whether the given secret fulfills the policy.
+  pub fn check(&self, secret: &str) -> anyhow::Result<()> {
+    if secret.len() < usize::from(self.min_len) {
+      anyhow::bail!(
+        "The secret must be at least {} characters long",
+        self.min_len
+      )
+    }
+    if secret.len() > usize::from(self.max_len) {
+      anyhow::bail!(
+        "The secret must be at most {} characters long",
+        self.max_len
+      )
+    }
+    if self.strictness == Strictness::Lax {
+      return Ok(());
+    }
+
+    if DEFAULT_PINS.contains(&secret) {
+      anyhow::bail!("The secret must not be a default PIN")
+    }
+    if is_run(&codes(secret)) {
+      anyhow::bail!("The secret must not consist of repeated or consecutive characters")
+    }
+    if self.strictness == Strictness::Strict {
+      let codes = codes(secret);
+      if codes.windows(MAX_RUN_LEN).any(is_run) {
+        anyhow::bail!(
+          "The secret must not contain {} or more repeated or consecutive characters",
+          MAX_RUN_LEN
+        )
+      }
+
+      let distinct = codes.iter().collect::<collections::HashSet<_>>().len();
+      let min_distinct = codes.len() / 2 + codes.len() % 2;
+      if distinct < min_distinct {
+        anyhow::bail!(
+          "The secret must consist of at least {} different characters",
+          min_distinct
+        )
+      }
+    }
+    Ok(())
+  }
+}
+
+/// Estimate the entropy of the given secret in bits.
+///
+/// The estimate is based on the size of the character classes used and
+/// the number of distinct characters.
+pub fn entropy(secret: &str) -> f64 {
+  let codes = codes(secret);
+  let mut charset = 0;
+  if codes.iter().any(|c| c.is_ascii_digit()) {
+    charset += 10;
+  }
+  if codes.iter().any(|c| c.is_ascii_lowercase()) {
+    charset += 26;
+  }
+  if codes.iter().any(|c| c.is_ascii_uppercase()) {
+    charset += 26;
+  }
+  if codes.iter().any(|c| !c.is_ascii_alphanumeric()) {
+    charset += 33;
+  }
+
+  let distinct = codes.iter().collect::<collections::HashSet<_>>().len();
+  distinct as f64 * f64::from(charset).max(1.0).log2()
+}
+
+/// Split the given secret into its characters.
+fn codes(secret: &str) -> Vec<char> {
+  secret.chars().collect()
+}
+
+/// Check whether the given characters are all the same or a sequence
+/// of consecutive characters, such as `123456` or `fedcba`.
+fn is_run(chars: &[char]) -> bool {
+  let steps = chars
+    .windows(2)
+    .map(|pair| i64::from(u32::from(pair[1])) - i64::from(u32::from(pair[0])))
+    .collect::<Vec<_>>();
+
+  match steps.first() {
+    Some(first) if first.abs() <= 1 => steps.iter().all(|step| step == first),
+    _ => false,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn strictness_from_env() {
+    assert_eq!(Strictness::from_env(None).unwrap(), Strictness::Normal);
+    let strict = ffi::OsString::from("strict");
+    assert_eq!(
+      Strictness::from_env(Some(&strict)).unwrap(),
+      Strictness::Strict
+    );
+    let invalid = ffi::OsString::from("foo");
+    let err = Strictness::from_env(Some(&invalid)).unwrap_err();
+    assert_eq!(err.to_string(), "Failed to parse the PIN policy");
+  }
+
+  #[test]
+  fn check_length() {
+    let policy = Policy::new(Strictness::Lax, 6, 20);
+    assert!(policy.check("12345").is_err());
+    assert!(policy.check("123456").is_ok());
+    assert!(policy.check(&"8".repeat(20)).is_ok());
+    let err = policy.check(&"8".repeat(21)).unwrap_err();
+    assert_eq!(
+      err.to_string(),
+      "The secret must be at most 20 characters long"
+    );
+  }
+
+  #[test]
+  fn check_normal() {
+    let policy = Policy::new(Strictness::Normal, 6, 20);
+    let err = policy.check("123456").unwrap_err();
+    assert_eq!(err.to_string(), "The secret must not be a default PIN");
+    assert!(policy.check("12345678").is_err());
+    assert!(policy.check("111111").is_err());
+    assert!(policy.check("987654").is_err());
+    assert!(policy.check("abcdefg").is_err());
+    assert!(policy.check("135790").is_ok());
+    assert!(policy.check("111222").is_ok());
+  }
+
+  #[test]
+  fn check_strict() {
+    let policy = Policy::new(Strictness::Strict, 6, 20);
+    assert!(policy.check("135790").is_ok());
+    let err = policy.check("111222").unwrap_err();
+    assert_eq!(
+      err.to_string(),
+      "The secret must not contain 3 or more repeated or consecutive characters"
+    );
+    assert!(policy.check("901234").is_err());
+    let err = policy.check("121212").unwrap_err();
+    assert_eq!(
+      err.to_string(),
+      "The secret must consist of at least 3 different characters"
+    );
+  }
+}
@@ -0,0 +1,510 @@
+// provision.rs
+
+// *************************************************************************
+// * Copyright (C) 2020 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+use std::fmt;
+use std::fs;
+use std::path;
+use std::str;
+
+use anyhow::Context as _;
+
+use crate::args;
+
+/// Deserialize a value using its `FromStr` implementation.
+pub fn from_str<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+  D: serde::Deserializer<'de>,
+  T: str::FromStr,
+  T::Err: fmt::Display,
+{
+  let string = <String as serde::Deserialize<'_>>::deserialize(deserializer)?;
+  string.parse().map_err(serde::de::Error::custom)
+}
+
+fn default_algorithm() -> args::OtpAlgorithm {
+  args::OtpAlgorithm::Totp
+}
+
+fn default_digits() -> u8 {
+  6
+}
+
+fn default_format() -> args::OtpSecretFormat {
+  args::OtpSecretFormat::Base32
+}
+
+fn default_time_window() -> u16 {
+  30
+}
+
+/// The desired state of a Nitrokey device.
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Manifest {
+  /// The configuration to apply.
+  pub config: Option<args::ConfigSetArgs>,
+  /// The OTP slots to program.
+  pub otp: Vec<OtpSlot>,
+  /// The PWS slots to program.
+  pub pws: Vec<PwsSlot>,
+  /// The PINs to change.
+  pub pins: Pins,
+  /// The configuration of the unencrypted volume.
+  pub unencrypted: Option<Unencrypted>,
+}
+
+impl Manifest {
+  /// Load a manifest from the given file.
+  pub fn load(path: &path::Path) -> anyhow::Result<Self> {
+    let content = fs::read_to_string(path)
+      .with_context(|| format!("Failed to read manifest {}", path.display()))?;
+    toml::from_str(&content).with_context(|| format!("Failed to parse manifest {}", path.display()))
+  }
+}
+
+/// An OTP slot as described in a manifest.
+#[derive(Clone, Debug, PartialEq, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OtpSlot {
+  /// The OTP algorithm to use.
+  #[serde(default = "default_algorithm", deserialize_with = "from_str")]
+  pub algorithm: args::OtpAlgorithm,
+  /// The OTP slot to program.
+  pub slot: u8,
+  /// The name of the slot.
+  pub name: String,
+  /// The secret to store on the slot.
+  pub secret: String,
+  /// The format of the secret.
+  #[serde(default = "default_format", deserialize_with = "from_str")]
+  pub format: args::OtpSecretFormat,
+  /// The number of digits to use for the one-time password.
+  #[serde(default = "default_digits")]
+  pub digits: u8,
+  /// The counter value for HOTP.
+  #[serde(default)]
+  pub counter: u64,
+  /// The time window for TOTP.
+  #[serde(default = "default_time_window")]
+  pub time_window: u16,
+  /// Whether to press Enter after typing the one-time password.
+  #[serde(default)]
+  pub use_enter: bool,
+  /// The OATH token identifier to use.
+  #[serde(default)]
+  pub token_id: Option<String>,
+}
+
+impl OtpSlot {
+  /// Retrieve the OTP mode corresponding to the number of digits.
+  pub fn mode(&self) -> anyhow::Result<args::OtpMode> {
+    match self.digits {
+      6 => Ok(args::OtpMode::SixDigits),
+      8 => Ok(args::OtpMode::EightDigits),
+      digits => anyhow::bail!("Unsupported number of OTP digits: {}", digits),
+    }
+  }
+}
+
+/// A PWS slot as described in a manifest.
+#[derive(Clone, Debug, PartialEq, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PwsSlot {
+  /// The PWS slot to program.
+  pub slot: u8,
+  /// The name to store on the slot.
+  pub name: String,
+  /// The login to store on the slot.
+  pub login: String,
+  /// The password to store on the slot.
+  pub password: String,
+}
+
+/// The PINs to change as described in a manifest.
+///
+/// The PINs themselves are never part of the manifest. They are taken
+/// from the environment or inquired from the user instead.
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Pins {
+  /// Whether to set a new admin PIN.
+  pub admin: bool,
+  /// Whether to set a new user PIN.
+  pub user: bool,
+}
+
+/// The unencrypted volume configuration as described in a manifest.
+#[derive(Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Unencrypted {
+  /// The mode of the unencrypted volume.
+  #[serde(deserialize_with = "from_str")]
+  pub mode: args::UnencryptedVolumeMode,
+}
+
+/// The relevant current state of a Nitrokey device.
+#[derive(Debug)]
+pub struct DeviceState {
+  /// The device configuration.
+  pub config: nitrokey::Config,
+  /// The names of the HOTP slots, `None` if a slot is not programmed.
+  pub hotp: Vec<Option<String>>,
+  /// The names of the TOTP slots, `None` if a slot is not programmed.
+  pub totp: Vec<Option<String>>,
+  /// The contents of the PWS slots, `None` if a slot is not programmed.
+  pub pws: Vec<Option<PwsSlot>>,
+  /// Whether the unencrypted volume is read-only, `None` if the device
+  /// does not have one.
+  pub unencrypted_read_only: Option<bool>,
+}
+
+/// A change to apply to a Nitrokey device.
+#[derive(Debug, PartialEq)]
+pub enum Change {
+  /// Write the given configuration.
+  Config(nitrokey::Config),
+  /// Program an OTP slot.
+  Otp(OtpSlot),
+  /// Program a PWS slot.
+  Pws(PwsSlot),
+  /// Change the mode of the unencrypted volume.
+  Unencrypted(args::UnencryptedVolumeMode),
+  /// Set a new admin PIN.
+  AdminPin,
+  /// Set a new user PIN.
+  UserPin,
+}
+
+impl Change {
+  /// Check whether the change requires admin authentication.
+  pub fn requires_admin(&self) -> bool {
+    !matches!(self, Change::Pws(_))
+  }
+}
+
+impl fmt::Display for Change {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Change::Config(config) => write!(
+        f,
+        "set configuration (numlock: {}, capslock: {}, scrollock: {}, OTP PIN: {})",
+        format_binding(config.numlock),
+        format_binding(config.capslock),
+        format_binding(config.scrollock),
+        config.user_password,
+      ),
+      Change::Otp(otp) => write!(
+        f,
+        "write {} slot {} ({})",
+        otp.algorithm, otp.slot, otp.name
+      ),
+      Change::Pws(pws) => write!(f, "write PWS slot {} ({})", pws.slot, pws.name),
+      Change::Unencrypted(mode) => write!(f, "set unencrypted volume to {}", mode),
+      Change::AdminPin => write!(f, "set new admin PIN"),
+      Change::UserPin => write!(f, "set new user PIN"),
+    }
+  }
+}
+
+fn format_binding(binding: Option<u8>) -> String {
+  match binding {
+    Some(slot) => slot.to_string(),
+    None => "not set".to_string(),
+  }
+}
+
+/// Compute the changes required to bring a device from the given state
+/// into the state described by the manifest.
+///
+/// Note that OTP secrets cannot be read back from a device. All OTP
+/// slots listed in the manifest are hence always rewritten.
+pub fn plan(manifest: &Manifest, state: &DeviceState) -> anyhow::Result<Vec<Change>> {
+  let mut changes = Vec::new();
+
+  if let Some(config) = &manifest.config {
+    let numlock = args::ConfigOption::try_from(config.no_numlock, config.numlock, "numlock")?;
+    let capslock = args::ConfigOption::try_from(config.no_capslock, config.capslock, "capslock")?;
+    let scrollock =
+      args::ConfigOption::try_from(config.no_scrollock, config.scrollock, "scrollock")?;
+    anyhow::ensure!(
+      !(config.otp_pin && config.no_otp_pin),
+      "otp_pin and no_otp_pin are mutually exclusive"
+    );
+    let user_password = if config.otp_pin {
+      true
+    } else if config.no_otp_pin {
+      false
+    } else {
+      state.config.user_password
+    };
+
+    let desired = nitrokey::Config {
+      numlock: numlock.or(state.config.numlock),
+      capslock: capslock.or(state.config.capslock),
+      scrollock: scrollock.or(state.config.scrollock),
+      user_password,
+    };
+    if desired != state.config {
+      changes.push(Change::Config(desired));
+    }
+  }
+
+  for otp in &manifest.otp {
+    let _ = otp.mode()?;
+    let slots = match otp.algorithm {
+      args::OtpAlgorithm::Hotp => &state.hotp,
+      args::OtpAlgorithm::Totp => &state.totp,
+    };
+    if usize::from(otp.slot) >= slots.len() {
+      anyhow::bail!("{} slot {} is not valid", otp.algorithm, otp.slot)
+    }
+    changes.push(Change::Otp(otp.clone()));
+  }
+
+  for pws in &manifest.pws {
+    match state.pws.get(usize::from(pws.slot)) {
+      Some(Some(current)) if current == pws => (),
+      Some(_) => changes.push(Change::Pws(pws.clone())),
+      None => anyhow::bail!("PWS slot {} is not valid", pws.slot),
+    }
+  }
+
+  if let Some(unencrypted) = &manifest.unencrypted {
+    let read_only = match state.unencrypted_read_only {
+      Some(read_only) => read_only,
+      None => anyhow::bail!("The unencrypted volume is only available on the Nitrokey Storage"),
+    };
+    let desired = unencrypted.mode == args::UnencryptedVolumeMode::ReadOnly;
+    if desired != read_only {
+      changes.push(Change::Unencrypted(unencrypted.mode));
+    }
+  }
+
+  // PINs cannot be compared, so we always change them if requested.
+  // The admin PIN is changed last, as all other changes are authorized
+  // with the current admin PIN.
+  if manifest.pins.user {
+    changes.push(Change::UserPin);
+  }
+  if manifest.pins.admin {
+    changes.push(Change::AdminPin);
+  }
+  Ok(changes)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn state() -> DeviceState {
+    DeviceState {
+      config: nitrokey::Config {
+        numlock: Some(1),
+        capslock: None,
+        scrollock: None,
+        user_password: false,
+      },
+      hotp: vec![Some("hotp-0".to_string()), None, None],
+      totp: vec![None; 15],
+      pws: vec![None; 16],
+      unencrypted_read_only: None,
+    }
+  }
+
+  #[test]
+  fn parse_manifest() {
+    let manifest = r#"
+[config]
+numlock = 0
+no_capslock = true
+otp_pin = true
+
+[[otp]]
+algorithm = "hotp"
+slot = 1
+name = "hotp-1"
+secret = "3132333435363738393031323334353637383930"
+format = "hex"
+use_enter = true
+token_id = "NKPR12345678"
+
+[[otp]]
+slot = 2
+name = "totp-2"
+secret = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ"
+digits = 8
+
+[[pws]]
+slot = 0
+name = "example.org"
+login = "john.doe"
+password = "passw0rd"
+
+[pins]
+user = true
+
+[unencrypted]
+mode = "read-only"
+"#;
+    let manifest = toml::from_str::<Manifest>(manifest).unwrap();
+    let config = manifest.config.unwrap();
+    assert_eq!(config.numlock, Some(0));
+    assert!(config.no_capslock);
+    assert!(config.otp_pin);
+    assert_eq!(manifest.otp.len(), 2);
+    assert_eq!(manifest.otp[0].algorithm, args::OtpAlgorithm::Hotp);
+    assert_eq!(manifest.otp[0].format, args::OtpSecretFormat::Hex);
+    assert!(manifest.otp[0].use_enter);
+    assert_eq!(manifest.otp[0].token_id.as_deref(), Some("NKPR12345678"));
+    assert!(!manifest.otp[1].use_enter);
+    assert_eq!(manifest.otp[1].token_id, None);
+    assert_eq!(manifest.otp[1].algorithm, args::OtpAlgorithm::Totp);
+    assert_eq!(manifest.otp[1].format, args::OtpSecretFormat::Base32);
+    assert_eq!(manifest.otp[1].mode().unwrap(), args::OtpMode::EightDigits);
+    assert_eq!(manifest.otp[1].time_window, 30);
+    assert_eq!(manifest.pws[0].login, "john.doe");
+    assert!(manifest.pins.user);
+    assert!(!manifest.pins.admin);
+    assert_eq!(
+      manifest.unencrypted.unwrap().mode,
+      args::UnencryptedVolumeMode::ReadOnly
+    );
+  }
+
+  #[test]
+  fn parse_manifest_invalid() {
+    assert!(toml::from_str::<Manifest>("[foo]\n").is_err());
+    assert!(toml::from_str::<Manifest>("[unencrypted]\nmode = \"rw\"\n").is_err());
+    assert!(toml::from_str::<Manifest>("[[otp]]\nslot = 1\nname = \"a\"\n").is_err());
+  }
+
+  #[test]
+  fn plan_nothing() {
+    let manifest = Manifest::default();
+    assert_eq!(plan(&manifest, &state()).unwrap(), vec![]);
+  }
+
+  #[test]
+  fn plan_config() {
+    let mut manifest = Manifest {
+      config: Some(args::ConfigSetArgs {
+        numlock: Some(1),
+        ..Default::default()
+      }),
+      ..Default::default()
+    };
+    assert_eq!(plan(&manifest, &state()).unwrap(), vec![]);
+
+    manifest.config = Some(args::ConfigSetArgs {
+      no_numlock: true,
+      otp_pin: true,
+      ..Default::default()
+    });
+    let expected = nitrokey::Config {
+      numlock: None,
+      capslock: None,
+      scrollock: None,
+      user_password: true,
+    };
+    assert_eq!(
+      plan(&manifest, &state()).unwrap(),
+      vec![Change::Config(expected)]
+    );
+  }
+
+  #[test]
+  fn plan_otp_and_pws() {
+    let otp = |slot, name: &str| OtpSlot {
+      algorithm: args::OtpAlgorithm::Hotp,
+      slot,
+      name: name.to_string(),
+      secret: "abcdef".to_string(),
+      format: args::OtpSecretFormat::Hex,
+      digits: 6,
+      counter: 0,
+      time_window: 30,
+      use_enter: false,
+      token_id: None,
+    };
+    let pws = PwsSlot {
+      slot: 2,
+      name: "name".to_string(),
+      login: "login".to_string(),
+      password: "password".to_string(),
+    };
+
+    let mut state = state();
+    let mut manifest = Manifest {
+      otp: vec![otp(0, "hotp-0"), otp(1, "hotp-1")],
+      pws: vec![pws.clone()],
+      ..Default::default()
+    };
+    // OTP slots are always rewritten, even if the name matches.
+    assert_eq!(
+      plan(&manifest, &state).unwrap(),
+      vec![
+        Change::Otp(otp(0, "hotp-0")),
+        Change::Otp(otp(1, "hotp-1")),
+        Change::Pws(pws.clone())
+      ]
+    );
+
+    state.pws[2] = Some(pws.clone());
+    assert_eq!(
+      plan(&manifest, &state).unwrap(),
+      vec![Change::Otp(otp(0, "hotp-0")), Change::Otp(otp(1, "hotp-1"))]
+    );
+
+    manifest.otp = vec![otp(3, "hotp-3")];
+    assert!(plan(&manifest, &state).is_err());
+  }
+
+  #[test]
+  fn plan_unencrypted_and_pins() {
+    let manifest = Manifest {
+      unencrypted: Some(Unencrypted {
+        mode: args::UnencryptedVolumeMode::ReadOnly,
+      }),
+      pins: Pins {
+        admin: true,
+        user: true,
+      },
+      ..Default::default()
+    };
+
+    let mut state = state();
+    assert!(plan(&manifest, &state).is_err());
+
+    state.unencrypted_read_only = Some(false);
+    assert_eq!(
+      plan(&manifest, &state).unwrap(),
+      vec![
+        Change::Unencrypted(args::UnencryptedVolumeMode::ReadOnly),
+        Change::UserPin,
+        Change::AdminPin,
+      ]
+    );
+
+    state.unencrypted_read_only = Some(true);
+    assert_eq!(
+      plan(&manifest, &state).unwrap(),
+      vec![Change::UserPin, Change::AdminPin]
+    );
+  }
+}
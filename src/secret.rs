@@ -0,0 +1,688 @@
+// secret.rs
+
+// *************************************************************************
+// * Copyright (C) 2020 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+use std::collections;
+use std::ffi;
+
+use dbus::arg;
+
+/// The well-known name of the Secret Service on the bus.
+pub const BUS_NAME: &str = "org.freedesktop.secrets";
+
+const SERVICE_PATH: &str = "/org/freedesktop/secrets";
+const COLLECTION_PATH: &str = "/org/freedesktop/secrets/collection/nitrokey";
+const SESSION_PATH: &str = "/org/freedesktop/secrets/session";
+/// The path used to indicate that no prompt is necessary or that an
+/// object does not exist.
+const NO_PATH: &str = "/";
+
+const SERVICE_INTERFACE: &str = "org.freedesktop.Secret.Service";
+const COLLECTION_INTERFACE: &str = "org.freedesktop.Secret.Collection";
+const ITEM_INTERFACE: &str = "org.freedesktop.Secret.Item";
+const SESSION_INTERFACE: &str = "org.freedesktop.Secret.Session";
+const PROPERTIES_INTERFACE: &str = "org.freedesktop.DBus.Properties";
+
+const ERROR_FAILED: &str = "org.freedesktop.DBus.Error.Failed";
+const ERROR_INVALID_ARGS: &str = "org.freedesktop.DBus.Error.InvalidArgs";
+const ERROR_NOT_SUPPORTED: &str = "org.freedesktop.DBus.Error.NotSupported";
+const ERROR_NO_SESSION: &str = "org.freedesktop.Secret.Error.NoSession";
+const ERROR_NO_SUCH_OBJECT: &str = "org.freedesktop.Secret.Error.NoSuchObject";
+
+/// The content type of the secrets we hand out.
+const CONTENT_TYPE: &str = "text/plain; charset=utf8";
+
+type Path = dbus::Path<'static>;
+/// A secret as transferred over the bus: the session, the algorithm
+/// parameters, the value, and its content type.
+type Secret = (Path, Vec<u8>, Vec<u8>, String);
+
+/// The metadata of a programmed PWS slot.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Item {
+  pub slot: u8,
+  pub name: String,
+  pub login: String,
+}
+
+impl Item {
+  fn path(&self) -> Path {
+    item_path(self.slot)
+  }
+
+  fn attributes(&self) -> collections::HashMap<String, String> {
+    let mut attributes = collections::HashMap::new();
+    let _ = attributes.insert("name".to_string(), self.name.clone());
+    let _ = attributes.insert("login".to_string(), self.login.clone());
+    attributes
+  }
+
+  /// Check whether the item has all of the given attributes.
+  ///
+  /// The password safe only stores a name and a login, so other
+  /// attributes (such as the `xdg:schema` attribute libsecret adds to
+  /// every request) are ignored.
+  fn matches(&self, attributes: &collections::HashMap<String, String>) -> bool {
+    attributes.iter().all(|(key, value)| match key.as_str() {
+      "name" => value == &self.name,
+      "login" => value == &self.login,
+      _ => true,
+    })
+  }
+}
+
+/// The storage the Secret Service exposes.
+pub trait Backend {
+  /// Retrieve the metadata of all programmed slots.
+  fn items(&mut self) -> anyhow::Result<Vec<Item>>;
+  /// Retrieve the password stored in the given slot.
+  fn secret(&mut self, slot: u8) -> anyhow::Result<String>;
+  /// Store a new secret, replacing the secret of the item with the
+  /// given name and login if requested, and return its slot.
+  fn create(&mut self, item: &Item, secret: &str, replace: bool) -> anyhow::Result<u8>;
+}
+
+/// An error reported to the caller of a method.
+#[derive(Debug)]
+struct Error {
+  name: &'static str,
+  message: String,
+}
+
+impl Error {
+  fn new(name: &'static str, message: impl Into<String>) -> Self {
+    Self {
+      name,
+      message: message.into(),
+    }
+  }
+}
+
+impl From<anyhow::Error> for Error {
+  fn from(err: anyhow::Error) -> Self {
+    Self::new(ERROR_FAILED, format!("{:#}", err))
+  }
+}
+
+impl From<arg::TypeMismatchError> for Error {
+  fn from(err: arg::TypeMismatchError) -> Self {
+    Self::new(ERROR_INVALID_ARGS, err.to_string())
+  }
+}
+
+fn object_path(path: impl Into<String>) -> Path {
+  // All paths we create are valid object paths.
+  dbus::Path::new(path.into()).unwrap()
+}
+
+fn item_path(slot: u8) -> Path {
+  object_path(format!("{}/{}", COLLECTION_PATH, slot))
+}
+
+/// Check whether the given path refers to an item, i.e., to a PWS slot.
+fn is_item_path(path: &str) -> bool {
+  let prefix = format!("{}/", COLLECTION_PATH);
+  path.starts_with(&prefix) && path[prefix.len()..].parse::<u8>().is_ok()
+}
+
+/// Check whether the given path refers to an object we export.
+fn is_exported(path: &str) -> bool {
+  path == SERVICE_PATH || path == COLLECTION_PATH || is_item_path(path)
+}
+
+fn variant<T>(value: T) -> arg::Variant<Box<dyn arg::RefArg>>
+where
+  T: arg::RefArg + 'static,
+{
+  arg::Variant(Box::new(value))
+}
+
+/// Extract a dictionary of strings from the given value.
+fn string_map(value: &dyn arg::RefArg) -> Option<collections::HashMap<String, String>> {
+  let mut map = collections::HashMap::new();
+  let mut iter = value.as_iter()?;
+  while let Some(key) = iter.next() {
+    let value = iter.next()?;
+    let _ = map.insert(key.as_str()?.to_string(), value.as_str()?.to_string());
+  }
+  Some(map)
+}
+
+/// A Secret Service exposing the items of a backend as a single
+/// collection.
+#[derive(Debug)]
+pub struct Service<B> {
+  backend: B,
+  sessions: collections::HashSet<Path>,
+  next_session: u64,
+}
+
+impl<B> Service<B>
+where
+  B: Backend,
+{
+  pub fn new(backend: B) -> Self {
+    Self {
+      backend,
+      sessions: collections::HashSet::new(),
+      next_session: 0,
+    }
+  }
+
+  /// Handle a method call, returning the reply to send.
+  ///
+  /// `None` is returned for calls that are not directed at one of our
+  /// objects.
+  pub fn handle(&mut self, msg: &dbus::Message) -> Option<dbus::Message> {
+    let result = match (msg.path(), msg.interface(), msg.member()) {
+      (Some(path), Some(interface), Some(member)) => self.dispatch(msg, &path, &interface, &member),
+      _ => None,
+    }?;
+
+    let reply = result.unwrap_or_else(|err| {
+      let name = dbus::strings::ErrorName::from(err.name);
+      let message = ffi::CString::new(err.message.replace('\0', ""))
+        .unwrap_or_else(|_| ffi::CString::default());
+      msg.error(&name, &message)
+    });
+    Some(reply)
+  }
+
+  fn dispatch(
+    &mut self,
+    msg: &dbus::Message,
+    path: &str,
+    interface: &str,
+    member: &str,
+  ) -> Option<Result<dbus::Message, Error>> {
+    let result = match (path, interface, member) {
+      // Retrieving the properties of items requires the user PIN, so
+      // we must not do that for paths we do not even export.
+      (_, PROPERTIES_INTERFACE, _) if !is_exported(path) => return None,
+      (_, PROPERTIES_INTERFACE, "Get") => self.get_property(msg, path),
+      (_, PROPERTIES_INTERFACE, "GetAll") => self.get_properties(msg, path),
+      (_, PROPERTIES_INTERFACE, "Set") => Err(Error::new(
+        ERROR_NOT_SUPPORTED,
+        "Properties cannot be changed",
+      )),
+      (SERVICE_PATH, SERVICE_INTERFACE, "OpenSession") => self.open_session(msg),
+      (SERVICE_PATH, SERVICE_INTERFACE, "SearchItems") => self
+        .search_items(msg)
+        .map(|items| msg.method_return().append2(items, Vec::<Path>::new())),
+      (SERVICE_PATH, SERVICE_INTERFACE, "Unlock") => self.unlock(msg),
+      (SERVICE_PATH, SERVICE_INTERFACE, "Lock") => Ok(
+        msg
+          .method_return()
+          .append2(Vec::<Path>::new(), object_path(NO_PATH)),
+      ),
+      (SERVICE_PATH, SERVICE_INTERFACE, "GetSecrets") => self.get_secrets(msg),
+      (SERVICE_PATH, SERVICE_INTERFACE, "ReadAlias") => msg
+        .read1::<&str>()
+        .map_err(Error::from)
+        .map(|alias| match alias {
+          "default" => msg.method_return().append1(object_path(COLLECTION_PATH)),
+          _ => msg.method_return().append1(object_path(NO_PATH)),
+        }),
+      (SERVICE_PATH, SERVICE_INTERFACE, _) => Err(Error::new(
+        ERROR_NOT_SUPPORTED,
+        format!("{} is not supported", member),
+      )),
+      (COLLECTION_PATH, COLLECTION_INTERFACE, "SearchItems") => self
+        .search_items(msg)
+        .map(|items| msg.method_return().append1(items)),
+      (COLLECTION_PATH, COLLECTION_INTERFACE, "CreateItem") => self.create_item(msg),
+      (COLLECTION_PATH, COLLECTION_INTERFACE, _) => Err(Error::new(
+        ERROR_NOT_SUPPORTED,
+        format!("{} is not supported", member),
+      )),
+      (_, ITEM_INTERFACE, "GetSecret") if is_item_path(path) => self.get_secret(msg, path),
+      (_, ITEM_INTERFACE, _) if is_item_path(path) => Err(Error::new(
+        ERROR_NOT_SUPPORTED,
+        format!("{} is not supported", member),
+      )),
+      (_, SESSION_INTERFACE, "Close") => {
+        let _ = self.sessions.remove(&dbus::Path::from(path).into_static());
+        Ok(msg.method_return())
+      }
+      _ => return None,
+    };
+    Some(result)
+  }
+
+  fn open_session(&mut self, msg: &dbus::Message) -> Result<dbus::Message, Error> {
+    let (algorithm, _input) = msg.read2::<&str, arg::Variant<Box<dyn arg::RefArg>>>()?;
+    // Secrets are transferred without encryption, as the bus is only
+    // accessible to the current user anyway.
+    if algorithm != "plain" {
+      return Err(Error::new(
+        ERROR_NOT_SUPPORTED,
+        format!("Algorithm {} is not supported", algorithm),
+      ));
+    }
+
+    let session = object_path(format!("{}/{}", SESSION_PATH, self.next_session));
+    self.next_session += 1;
+    let _ = self.sessions.insert(session.clone());
+    Ok(msg.method_return().append2(variant(String::new()), session))
+  }
+
+  fn search_items(&mut self, msg: &dbus::Message) -> Result<Vec<Path>, Error> {
+    let attributes = msg.read1::<collections::HashMap<String, String>>()?;
+    let items = self
+      .backend
+      .items()?
+      .iter()
+      .filter(|item| item.matches(&attributes))
+      .map(Item::path)
+      .collect();
+    Ok(items)
+  }
+
+  fn unlock(&mut self, msg: &dbus::Message) -> Result<dbus::Message, Error> {
+    let objects = msg.read1::<Vec<Path>>()?;
+    // Reading the items requires the user PIN, so the items are
+    // unlocked once we could retrieve them.
+    let _ = self.backend.items()?;
+    Ok(msg.method_return().append2(objects, object_path(NO_PATH)))
+  }
+
+  fn find_item(&mut self, path: &str) -> Result<Item, Error> {
+    self
+      .backend
+      .items()?
+      .into_iter()
+      .find(|item| &*item.path() == path)
+      .ok_or_else(|| Error::new(ERROR_NO_SUCH_OBJECT, format!("No such item: {}", path)))
+  }
+
+  fn secret(&mut self, item: &Item, session: &Path) -> Result<Secret, Error> {
+    if !self.sessions.contains(session) {
+      return Err(Error::new(
+        ERROR_NO_SESSION,
+        format!("No such session: {}", session),
+      ));
+    }
+    let secret = self.backend.secret(item.slot)?;
+    Ok((
+      session.clone(),
+      Vec::new(),
+      secret.into_bytes(),
+      CONTENT_TYPE.to_string(),
+    ))
+  }
+
+  fn get_secrets(&mut self, msg: &dbus::Message) -> Result<dbus::Message, Error> {
+    let (items, session) = msg.read2::<Vec<Path>, Path>()?;
+    let mut secrets = collections::HashMap::new();
+    for path in items {
+      // Unknown items are skipped as mandated by the specification.
+      if let Ok(item) = self.find_item(&path) {
+        let secret = self.secret(&item, &session)?;
+        let _ = secrets.insert(path, secret);
+      }
+    }
+    Ok(msg.method_return().append1(secrets))
+  }
+
+  fn get_secret(&mut self, msg: &dbus::Message, path: &str) -> Result<dbus::Message, Error> {
+    let session = msg.read1::<Path>()?;
+    let item = self.find_item(path)?;
+    let secret = self.secret(&item, &session)?;
+    Ok(msg.method_return().append1(secret))
+  }
+
+  fn create_item(&mut self, msg: &dbus::Message) -> Result<dbus::Message, Error> {
+    let (properties, secret, replace) = msg.read3::<arg::PropMap, Secret, bool>()?;
+    let (session, _, value, _) = secret;
+    if !self.sessions.contains(&session) {
+      return Err(Error::new(
+        ERROR_NO_SESSION,
+        format!("No such session: {}", session),
+      ));
+    }
+
+    let attributes = properties
+      .get("org.freedesktop.Secret.Item.Attributes")
+      .and_then(|attributes| string_map(&attributes.0))
+      .unwrap_or_default();
+    let label = arg::prop_cast::<String>(&properties, "org.freedesktop.Secret.Item.Label");
+    let name = attributes
+      .get("name")
+      .or(label)
+      .cloned()
+      .ok_or_else(|| Error::new(ERROR_INVALID_ARGS, "The item requires a name or label"))?;
+    // Attributes other than the name and the login cannot be stored
+    // in the password safe and are dropped.
+    let login = attributes.get("login").cloned().unwrap_or_default();
+    let secret = String::from_utf8(value)
+      .map_err(|_| Error::new(ERROR_INVALID_ARGS, "The secret is not valid UTF-8"))?;
+
+    let item = Item {
+      slot: 0,
+      name,
+      login,
+    };
+    let slot = self.backend.create(&item, &secret, replace)?;
+    Ok(
+      msg
+        .method_return()
+        .append2(item_path(slot), object_path(NO_PATH)),
+    )
+  }
+
+  fn properties(&mut self, path: &str) -> Result<(&'static str, arg::PropMap), Error> {
+    let mut properties = arg::PropMap::new();
+    let interface = match path {
+      SERVICE_PATH => {
+        let _ = properties.insert(
+          "Collections".to_string(),
+          variant(vec![object_path(COLLECTION_PATH)]),
+        );
+        SERVICE_INTERFACE
+      }
+      COLLECTION_PATH => {
+        let items = self
+          .backend
+          .items()?
+          .iter()
+          .map(Item::path)
+          .collect::<Vec<_>>();
+        let _ = properties.insert("Items".to_string(), variant(items));
+        let _ = properties.insert("Label".to_string(), variant("Nitrokey".to_string()));
+        let _ = properties.insert("Locked".to_string(), variant(false));
+        let _ = properties.insert("Created".to_string(), variant(0u64));
+        let _ = properties.insert("Modified".to_string(), variant(0u64));
+        COLLECTION_INTERFACE
+      }
+      _ => {
+        let item = self.find_item(path)?;
+        let _ = properties.insert("Attributes".to_string(), variant(item.attributes()));
+        let _ = properties.insert("Label".to_string(), variant(item.name));
+        let _ = properties.insert("Locked".to_string(), variant(false));
+        let _ = properties.insert("Created".to_string(), variant(0u64));
+        let _ = properties.insert("Modified".to_string(), variant(0u64));
+        ITEM_INTERFACE
+      }
+    };
+    Ok((interface, properties))
+  }
+
+  fn get_properties(&mut self, msg: &dbus::Message, path: &str) -> Result<dbus::Message, Error> {
+    let interface = msg.read1::<&str>()?;
+    let (expected, properties) = self.properties(path)?;
+    let properties = if interface == expected {
+      properties
+    } else {
+      arg::PropMap::new()
+    };
+    Ok(msg.method_return().append1(properties))
+  }
+
+  fn get_property(&mut self, msg: &dbus::Message, path: &str) -> Result<dbus::Message, Error> {
+    let (interface, name) = msg.read2::<&str, &str>()?;
+    let (expected, mut properties) = self.properties(path)?;
+    match properties.remove(name) {
+      Some(value) if interface == expected => Ok(msg.method_return().append1(value)),
+      _ => Err(Error::new(
+        ERROR_INVALID_ARGS,
+        format!("No such property: {}.{}", interface, name),
+      )),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[derive(Debug, Default)]
+  struct TestBackend {
+    slots: Vec<(Item, String)>,
+    /// Whether the items were retrieved, which requires the user PIN.
+    queried: bool,
+  }
+
+  impl Backend for TestBackend {
+    fn items(&mut self) -> anyhow::Result<Vec<Item>> {
+      self.queried = true;
+      Ok(self.slots.iter().map(|(item, _)| item.clone()).collect())
+    }
+
+    fn secret(&mut self, slot: u8) -> anyhow::Result<String> {
+      self
+        .slots
+        .iter()
+        .find(|(item, _)| item.slot == slot)
+        .map(|(_, secret)| secret.clone())
+        .ok_or_else(|| anyhow::anyhow!("Slot {} is not programmed", slot))
+    }
+
+    fn create(&mut self, item: &Item, secret: &str, _replace: bool) -> anyhow::Result<u8> {
+      let slot = self.slots.len() as u8;
+      let item = Item {
+        slot,
+        ..item.clone()
+      };
+      self.slots.push((item, secret.to_string()));
+      Ok(slot)
+    }
+  }
+
+  fn service() -> Service<TestBackend> {
+    let item = Item {
+      slot: 0,
+      name: "example.org".to_string(),
+      login: "john".to_string(),
+    };
+    let backend = TestBackend {
+      slots: vec![(item, "secret".to_string())],
+      queried: false,
+    };
+    Service::new(backend)
+  }
+
+  fn call(path: &str, interface: &str, member: &str) -> dbus::Message {
+    let mut msg = dbus::Message::new_method_call(BUS_NAME, path, interface, member).unwrap();
+    // libdbus refuses to create replies to messages without a serial.
+    msg.set_serial(1);
+    msg
+  }
+
+  fn error_name(mut reply: dbus::Message) -> String {
+    reply.as_result().unwrap_err().name().unwrap().to_string()
+  }
+
+  fn open_session(service: &mut Service<TestBackend>) -> Path {
+    let msg =
+      call(SERVICE_PATH, SERVICE_INTERFACE, "OpenSession").append2("plain", variant(String::new()));
+    let reply = service.handle(&msg).unwrap();
+    let (_, session) = reply
+      .read2::<arg::Variant<Box<dyn arg::RefArg>>, Path>()
+      .unwrap();
+    session
+  }
+
+  #[test]
+  fn exported_paths() {
+    assert!(is_exported(SERVICE_PATH));
+    assert!(is_exported(COLLECTION_PATH));
+    assert!(is_exported(
+      "/org/freedesktop/secrets/collection/nitrokey/15"
+    ));
+    assert!(!is_exported(
+      "/org/freedesktop/secrets/collection/nitrokey/"
+    ));
+    assert!(!is_exported(
+      "/org/freedesktop/secrets/collection/nitrokey/256"
+    ));
+    assert!(!is_exported(
+      "/org/freedesktop/secrets/collection/nitrokey/0/x"
+    ));
+    assert!(!is_exported(
+      "/org/freedesktop/secrets/collection/nitrokeyfoo"
+    ));
+    assert!(!is_exported("/org/freedesktop/secrets/session/0"));
+    assert!(!is_exported("/"));
+  }
+
+  #[test]
+  fn unknown_paths() {
+    let mut service = service();
+    let session = open_session(&mut service);
+
+    for path in &["/org/freedesktop/secrets/session/0", "/org/example"] {
+      let msg = call(path, PROPERTIES_INTERFACE, "GetAll").append1(ITEM_INTERFACE);
+      assert!(service.handle(&msg).is_none(), "{}", path);
+      let msg = call(path, PROPERTIES_INTERFACE, "Get").append2(ITEM_INTERFACE, "Label");
+      assert!(service.handle(&msg).is_none(), "{}", path);
+      let msg = call(path, ITEM_INTERFACE, "GetSecret").append1(session.clone());
+      assert!(service.handle(&msg).is_none(), "{}", path);
+    }
+    assert!(!service.backend.queried);
+  }
+
+  #[test]
+  fn unsupported_algorithm() {
+    let msg = call(SERVICE_PATH, SERVICE_INTERFACE, "OpenSession")
+      .append2("dh-ietf1024-sha256-aes128-cbc-pkcs7", variant(vec![1u8]));
+    let reply = service().handle(&msg).unwrap();
+    assert_eq!(error_name(reply), ERROR_NOT_SUPPORTED);
+  }
+
+  #[test]
+  fn search_and_get_secrets() {
+    let mut service = service();
+    let session = open_session(&mut service);
+
+    let mut attributes = collections::HashMap::new();
+    let _ = attributes.insert("name", "example.org");
+    let msg = call(SERVICE_PATH, SERVICE_INTERFACE, "SearchItems").append1(&attributes);
+    let reply = service.handle(&msg).unwrap();
+    let (unlocked, locked) = reply.read2::<Vec<Path>, Vec<Path>>().unwrap();
+    assert_eq!(unlocked, vec![item_path(0)]);
+    assert!(locked.is_empty());
+
+    let _ = attributes.insert("login", "jane");
+    let msg = call(COLLECTION_PATH, COLLECTION_INTERFACE, "SearchItems").append1(&attributes);
+    let reply = service.handle(&msg).unwrap();
+    assert!(reply.read1::<Vec<Path>>().unwrap().is_empty());
+
+    let msg =
+      call(SERVICE_PATH, SERVICE_INTERFACE, "GetSecrets").append2(vec![item_path(0)], &session);
+    let reply = service.handle(&msg).unwrap();
+    let secrets = reply.read1::<collections::HashMap<Path, Secret>>().unwrap();
+    let (secret_session, _, value, content_type) = &secrets[&item_path(0)];
+    assert_eq!(secret_session, &session);
+    assert_eq!(value, b"secret");
+    assert_eq!(content_type, CONTENT_TYPE);
+
+    let msg = call(&item_path(0), ITEM_INTERFACE, "GetSecret").append1(&session);
+    let reply = service.handle(&msg).unwrap();
+    let (_, _, value, _) = reply.read1::<Secret>().unwrap();
+    assert_eq!(value, b"secret");
+  }
+
+  #[test]
+  fn invalid_session() {
+    let mut service = service();
+    let session = open_session(&mut service);
+    let msg = call(&session, SESSION_INTERFACE, "Close");
+    let _ = service.handle(&msg).unwrap();
+
+    let msg = call(&item_path(0), ITEM_INTERFACE, "GetSecret").append1(&session);
+    let reply = service.handle(&msg).unwrap();
+    assert_eq!(error_name(reply), ERROR_NO_SESSION);
+  }
+
+  #[test]
+  fn unknown_item() {
+    let mut service = service();
+    let session = open_session(&mut service);
+    let msg = call(&item_path(7), ITEM_INTERFACE, "GetSecret").append1(&session);
+    let reply = service.handle(&msg).unwrap();
+    assert_eq!(error_name(reply), ERROR_NO_SUCH_OBJECT);
+  }
+
+  #[test]
+  fn create_item() {
+    let mut service = service();
+    let session = open_session(&mut service);
+
+    let mut attributes = collections::HashMap::new();
+    let _ = attributes.insert("login".to_string(), "jane".to_string());
+    let _ = attributes.insert("xdg:schema".to_string(), "org.example.Password".to_string());
+    let mut properties = arg::PropMap::new();
+    let _ = properties.insert(
+      "org.freedesktop.Secret.Item.Label".to_string(),
+      variant("example.com".to_string()),
+    );
+    let _ = properties.insert(
+      "org.freedesktop.Secret.Item.Attributes".to_string(),
+      variant(attributes.clone()),
+    );
+    let secret = (
+      session,
+      Vec::<u8>::new(),
+      b"password".to_vec(),
+      CONTENT_TYPE.to_string(),
+    );
+    let msg =
+      call(COLLECTION_PATH, COLLECTION_INTERFACE, "CreateItem").append3(properties, secret, true);
+    let reply = service.handle(&msg).unwrap();
+    let (item, prompt) = reply.read2::<Path, Path>().unwrap();
+    assert_eq!(item, item_path(1));
+    assert_eq!(&*prompt, NO_PATH);
+
+    let (created, secret) = &service.backend.slots[1];
+    assert_eq!(created.name, "example.com");
+    assert_eq!(created.login, "jane");
+    assert_eq!(secret, "password");
+
+    let msg = call(COLLECTION_PATH, COLLECTION_INTERFACE, "SearchItems").append1(&attributes);
+    let reply = service.handle(&msg).unwrap();
+    assert_eq!(reply.read1::<Vec<Path>>().unwrap(), vec![item_path(1)]);
+  }
+
+  #[test]
+  fn properties() {
+    let mut service = service();
+    let msg =
+      call(SERVICE_PATH, PROPERTIES_INTERFACE, "Get").append2(SERVICE_INTERFACE, "Collections");
+    let reply = service.handle(&msg).unwrap();
+    let collections = reply.read1::<arg::Variant<Vec<Path>>>().unwrap();
+    assert_eq!(collections.0, vec![object_path(COLLECTION_PATH)]);
+
+    let msg = call(&item_path(0), PROPERTIES_INTERFACE, "GetAll").append1(ITEM_INTERFACE);
+    let reply = service.handle(&msg).unwrap();
+    let properties = reply.read1::<arg::PropMap>().unwrap();
+    let label = arg::prop_cast::<String>(&properties, "Label");
+    assert_eq!(label, Some(&"example.org".to_string()));
+    let attributes = string_map(&properties["Attributes"].0).unwrap();
+    assert_eq!(attributes["login"], "john");
+
+    let msg = call(&item_path(0), PROPERTIES_INTERFACE, "Get").append2(ITEM_INTERFACE, "Secret");
+    let reply = service.handle(&msg).unwrap();
+    assert_eq!(error_name(reply), ERROR_INVALID_ARGS);
+  }
+
+  #[test]
+  fn unknown_object() {
+    let msg = call("/org/example", "org.example.Foo", "Bar");
+    assert!(service().handle(&msg).is_none());
+  }
+}
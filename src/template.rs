@@ -0,0 +1,171 @@
+// template.rs
+
+// *************************************************************************
+// * Copyright (C) 2020 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+/// A part of a template.
+#[derive(Debug, PartialEq)]
+enum Part {
+  /// Literal text.
+  Text(String),
+  /// A placeholder for the field with the given name.
+  Field(String),
+}
+
+/// A template for custom output.
+///
+/// A template consists of literal text and placeholders of the form
+/// `{field}`, which are replaced with the value of the respective field.
+/// `{{` and `}}` denote literal braces. The escape sequences `\n`, `\t`,
+/// and `\\` are supported as well.
+#[derive(Debug, PartialEq)]
+pub struct Template {
+  parts: Vec<Part>,
+}
+
+impl Template {
+  /// Parse the given template, accepting only placeholders for the given
+  /// fields.
+  pub fn parse(template: &str, fields: &[&str]) -> anyhow::Result<Self> {
+    let mut parts = Vec::new();
+    let mut text = String::new();
+    let mut chars = template.chars();
+
+    while let Some(c) = chars.next() {
+      match c {
+        '\\' => match chars.next() {
+          Some('n') => text.push('\n'),
+          Some('t') => text.push('\t'),
+          Some('\\') => text.push('\\'),
+          Some(c) => anyhow::bail!("Invalid escape sequence \\{} in format template", c),
+          None => anyhow::bail!("Incomplete escape sequence at end of format template"),
+        },
+        '{' => {
+          if chars.as_str().starts_with('{') {
+            let _ = chars.next();
+            text.push('{');
+            continue;
+          }
+
+          let rest = chars.as_str();
+          let end = rest
+            .find('}')
+            .ok_or_else(|| anyhow::anyhow!("Unterminated placeholder in format template"))?;
+          let field = &rest[..end];
+          if !fields.contains(&field) {
+            anyhow::bail!(
+              "Unknown field {{{}}} in format template (available fields: {})",
+              field,
+              fields.join(", ")
+            )
+          }
+          chars = rest[end + 1..].chars();
+
+          if !text.is_empty() {
+            parts.push(Part::Text(text.split_off(0)));
+          }
+          parts.push(Part::Field(field.to_string()));
+        }
+        '}' => {
+          if chars.as_str().starts_with('}') {
+            let _ = chars.next();
+            text.push('}');
+          } else {
+            anyhow::bail!("{}", "Unmatched } in format template")
+          }
+        }
+        c => text.push(c),
+      }
+    }
+    if !text.is_empty() {
+      parts.push(Part::Text(text));
+    }
+    Ok(Self { parts })
+  }
+
+  /// Render the template, retrieving the values of the used fields
+  /// through the given function.
+  ///
+  /// Only the fields that are actually used in the template are
+  /// retrieved.
+  pub fn render<F>(&self, mut value: F) -> anyhow::Result<String>
+  where
+    F: FnMut(&str) -> anyhow::Result<String>,
+  {
+    let mut output = String::new();
+    for part in &self.parts {
+      match part {
+        Part::Text(text) => output.push_str(text),
+        Part::Field(field) => output.push_str(&value(field)?),
+      }
+    }
+    Ok(output)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const FIELDS: &[&str] = &["name", "login", "password"];
+
+  fn render(template: &str) -> anyhow::Result<String> {
+    Template::parse(template, FIELDS)?.render(|field| Ok(field.to_uppercase()))
+  }
+
+  #[test]
+  fn render_fields() {
+    assert_eq!(render("").unwrap(), "");
+    assert_eq!(render("foo").unwrap(), "foo");
+    assert_eq!(
+      render("{name}\\t{login}\\t{password}").unwrap(),
+      "NAME\tLOGIN\tPASSWORD"
+    );
+    assert_eq!(render("{name}{name}!").unwrap(), "NAMENAME!");
+    assert_eq!(render("{{{login}}} \\\\n\\n").unwrap(), "{LOGIN} \\n\n");
+  }
+
+  #[test]
+  fn render_lazily() {
+    let template = Template::parse("{login}", FIELDS).unwrap();
+    let mut requested = Vec::new();
+    let output = template
+      .render(|field| {
+        requested.push(field.to_string());
+        Ok("foo".to_string())
+      })
+      .unwrap();
+    assert_eq!(output, "foo");
+    assert_eq!(requested, vec!["login"]);
+  }
+
+  #[test]
+  fn parse_invalid() {
+    let err = |template| render(template).unwrap_err().to_string();
+    assert_eq!(
+      err("{foo}"),
+      "Unknown field {foo} in format template (available fields: name, login, password)"
+    );
+    assert_eq!(err("{name"), "Unterminated placeholder in format template");
+    assert_eq!(err("name}"), "Unmatched } in format template");
+    assert_eq!(err("\\x"), "Invalid escape sequence \\x in format template");
+    assert_eq!(
+      err("foo\\"),
+      "Incomplete escape sequence at end of format template"
+    );
+  }
+}
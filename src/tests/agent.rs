@@ -0,0 +1,103 @@
+// agent.rs
+
+// *************************************************************************
+// * Copyright (C) 2020 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+use std::env;
+use std::fs;
+use std::os::unix::fs::PermissionsExt as _;
+use std::path;
+use std::sync;
+use std::thread;
+use std::time;
+
+use super::*;
+
+use crate::agent;
+
+/// Start an agent answering `pws get` requests for slot 1 on a socket
+/// in a new temporary directory, returning the socket path and a flag
+/// indicating whether the agent handled a request.
+fn start_agent(name: &str) -> (path::PathBuf, sync::Arc<sync::atomic::AtomicBool>) {
+  let dir = env::temp_dir().join(format!("nitrocli-agent-{}-{}", name, std::process::id()));
+  let _ = fs::remove_dir_all(&dir);
+  let path = dir.join("agent.sock");
+
+  let used = sync::Arc::new(sync::atomic::AtomicBool::new(false));
+  let agent_used = used.clone();
+  let agent_path = path.clone();
+  let _ = thread::spawn(move || {
+    agent::serve(&agent_path, time::Duration::from_secs(60), |request| {
+      agent_used.store(true, sync::atomic::Ordering::SeqCst);
+      match request {
+        agent::Request::PwsGet { slot: 1 } => Ok(vec![
+          "name".to_string(),
+          "login".to_string(),
+          "pass word".to_string(),
+        ]),
+        agent::Request::PwsGet { .. } => anyhow::bail!("Slot is not programmed"),
+        _ => Ok(Vec::new()),
+      }
+    })
+  });
+
+  while !path.exists() {
+    thread::sleep(time::Duration::from_millis(10));
+  }
+  (path, used)
+}
+
+#[test]
+fn pws_get() {
+  let (path, used) = start_agent("pws-get");
+  let mut ncli = Nitrocli::new();
+  ncli.agent_socket(&path);
+
+  let out = ncli.handle(&["pws", "get", "1"]).unwrap();
+  assert_eq!(
+    out,
+    "name:     name\nlogin:    login\npassword: pass word\n"
+  );
+
+  let out = ncli
+    .handle(&["pws", "get", "--quiet", "--password", "1"])
+    .unwrap();
+  assert_eq!(out, "pass word\n");
+
+  let err = ncli.handle(&["pws", "get", "2"]).unwrap_err();
+  assert_eq!(
+    format!("{:#}", err),
+    "Failed to use the nitrocli agent: Slot is not programmed"
+  );
+  assert!(used.load(sync::atomic::Ordering::SeqCst));
+  let _ = fs::remove_dir_all(path.parent().unwrap());
+}
+
+#[test]
+fn untrusted_dir() {
+  let (path, used) = start_agent("untrusted-dir");
+  let dir = path.parent().unwrap();
+  fs::set_permissions(dir, fs::Permissions::from_mode(0o755)).unwrap();
+
+  let mut ncli = Nitrocli::new();
+  ncli.agent_socket(&path);
+  // Without the agent, the command has to connect to a device, which
+  // fails in the absence of one.
+  let _ = ncli.handle(&["pws", "get", "1"]);
+  assert!(!used.load(sync::atomic::Ordering::SeqCst));
+  let _ = fs::remove_dir_all(dir);
+}
@@ -0,0 +1,70 @@
+// askpass.rs
+
+// *************************************************************************
+// * Copyright (C) 2020 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+use std::env;
+use std::fs;
+use std::path;
+
+use super::*;
+
+/// Write the given askpass rules to a temporary file.
+fn write_rules(name: &str, content: &str) -> path::PathBuf {
+  let path = env::temp_dir().join(format!(
+    "nitrocli-askpass-{}-{}.toml",
+    name,
+    std::process::id()
+  ));
+  fs::write(&path, content).unwrap();
+  path
+}
+
+#[test]
+fn missing_rules() {
+  let path = env::temp_dir().join("nitrocli-askpass-does-not-exist.toml");
+  let err = Nitrocli::new()
+    .handle(&["askpass", "--rules", path.to_str().unwrap(), "Password:"])
+    .unwrap_err()
+    .to_string();
+  assert_eq!(
+    err,
+    format!("Failed to read askpass rules {}", path.display())
+  );
+}
+
+#[test_device]
+fn matching_rule(model: nitrokey::Model) -> anyhow::Result<()> {
+  let rules = r#"
+[[rule]]
+prompt = "^Enter passphrase for key '.*/id_ed25519': $"
+slot = 2
+"#;
+  let path = write_rules("matching", rules);
+
+  let mut ncli = Nitrocli::with_model(model);
+  let _ = ncli.handle(&["pws", "set", "2", "ssh", "john", "passphrase"])?;
+  let res = ncli.handle(&[
+    "askpass",
+    "--rules",
+    path.to_str().unwrap(),
+    "Enter passphrase for key '/home/john/.ssh/id_ed25519': ",
+  ]);
+  let _ = fs::remove_file(&path);
+  assert_eq!(res?, "passphrase\n");
+  Ok(())
+}
@@ -0,0 +1,117 @@
+// audit_log.rs
+
+// *************************************************************************
+// * Copyright (C) 2020 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+use std::env;
+use std::fs;
+use std::path;
+
+use super::*;
+
+/// Create a temporary configuration directory enabling the audit log,
+/// returning the directory and the path of the log.
+fn config_dir(name: &str) -> (path::PathBuf, path::PathBuf) {
+  let dir = env::temp_dir().join(format!(
+    "nitrocli-audit-log-{}-{}",
+    name,
+    std::process::id()
+  ));
+  let _ = fs::remove_dir_all(&dir);
+  fs::create_dir_all(&dir).unwrap();
+
+  let log = dir.join("audit.log");
+  let config = format!("[audit-log]\npath = \"{}\"\n", log.display());
+  fs::write(dir.join("config.toml"), config).unwrap();
+  (dir, log)
+}
+
+#[test]
+fn verify_not_enabled() {
+  let res = Nitrocli::new().handle(&["audit-log", "verify"]);
+  let err = res.unwrap_err().to_string();
+  assert_eq!(err, "The audit log is not enabled");
+}
+
+#[test]
+fn verify_tampered() {
+  let (dir, log) = config_dir("tampered");
+  let entry = r#"{"time":"1970-01-01T00:00:00Z","user":"john","uid":1000,"model":"Nitrokey Pro","serial":"0x12345678","command":"reset","outcome":"success"}"#;
+  let content = format!("{} {}\n", "0".repeat(64), entry);
+  fs::write(&log, content).unwrap();
+
+  let mut ncli = Nitrocli::new();
+  ncli.config_dir(&dir);
+  let res = ncli.handle(&["audit-log", "verify"]);
+  let _ = fs::remove_dir_all(&dir);
+
+  let err = res.unwrap_err();
+  assert_eq!(
+    err.to_string(),
+    format!("Failed to verify audit log {}", log.display())
+  );
+  assert_eq!(
+    err.root_cause().to_string(),
+    "Entry 1 of the audit log has been tampered with"
+  );
+}
+
+#[test_device]
+fn record(model: nitrokey::Model) -> anyhow::Result<()> {
+  const PASSWORD: &str = "audit-log-password";
+
+  let (dir, log) = config_dir("record");
+  let mut ncli = Nitrocli::with_model(model);
+  ncli.config_dir(&dir);
+
+  let _ = ncli.handle(&["pws", "set", "4", "audit", "login", PASSWORD])?;
+  let _ = ncli.handle(&["pws", "get", "4"])?;
+  let _ = ncli.handle(&["pws", "clear", "4"])?;
+
+  let content = fs::read_to_string(&log)?;
+  let lines = content.lines().collect::<Vec<_>>();
+  assert_eq!(lines.len(), 2, "{}", content);
+  assert!(
+    lines[0].contains(r#""command":"pws set 4""#),
+    "{}",
+    lines[0]
+  );
+  assert!(lines[0].contains(r#""outcome":"success""#), "{}", lines[0]);
+  assert!(
+    lines[1].contains(r#""command":"pws clear 4""#),
+    "{}",
+    lines[1]
+  );
+  assert!(!content.contains(PASSWORD), "{}", content);
+
+  let head = &lines[1][..64];
+  let out = ncli.handle(&["audit-log", "verify"])?;
+  assert_eq!(
+    out,
+    format!(
+      "Verified 2 entries of audit log {} with head {}\n",
+      log.display(),
+      head
+    )
+  );
+
+  // Verifying against an earlier head must succeed as well.
+  let first = &lines[0][..64];
+  let _ = ncli.handle(&["audit-log", "verify", "--head", first])?;
+  let _ = fs::remove_dir_all(&dir);
+  Ok(())
+}
@@ -0,0 +1,93 @@
+// git_credential.rs
+
+// *************************************************************************
+// * Copyright (C) 2020 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+use super::*;
+
+const INPUT: &str = "protocol=https\nhost=example.org\nusername=john\n\n";
+
+#[test_device]
+fn get_unknown(model: nitrokey::Model) -> anyhow::Result<()> {
+  let mut ncli = Nitrocli::with_model(model);
+  let _ = ncli.handle(&["reset", "--yes"])?;
+
+  ncli.stdin(INPUT);
+  let out = ncli.handle(&["git-credential", "get"])?;
+  assert!(out.is_empty());
+  Ok(())
+}
+
+#[test]
+fn host_too_long() {
+  let mut ncli = Nitrocli::new();
+  ncli.stdin("protocol=https\nhost=gitlab.example.com\n\n");
+  let err = ncli
+    .handle(&["git-credential", "get"])
+    .unwrap_err()
+    .to_string();
+  assert_eq!(
+    err,
+    "The host gitlab.example.com is too long: PWS slot names are limited to 11 bytes"
+  );
+}
+
+#[test_device]
+fn store_get_erase(model: nitrokey::Model) -> anyhow::Result<()> {
+  let mut ncli = Nitrocli::with_model(model);
+  let _ = ncli.handle(&["pws", "set", "0", "example.org", "jane", "other"])?;
+  let _ = ncli.handle(&["pws", "clear", "1"])?;
+
+  // Without --write, store is a no-op.
+  ncli.stdin(format!("{}password=token\n", INPUT));
+  let _ = ncli.handle(&["git-credential", "store"])?;
+  ncli.stdin(INPUT);
+  let out = ncli.handle(&["git-credential", "get"])?;
+  assert!(out.is_empty());
+
+  ncli.stdin(format!("{}password=token\n", INPUT));
+  let _ = ncli.handle(&["git-credential", "--write", "store"])?;
+  ncli.stdin(INPUT);
+  let out = ncli.handle(&["git-credential", "get"])?;
+  assert_eq!(out, "username=john\npassword=token\n");
+
+  // Storing the same credential again does not use another slot.
+  ncli.stdin(format!("{}password=token\n", INPUT));
+  let _ = ncli.handle(&["git-credential", "--write", "store"])?;
+  let out = ncli.handle(&["pws", "status"])?;
+  assert_eq!(out.matches("example.org").count(), 2, "{}", out);
+
+  let out = ncli.handle(&["pws", "get", "1", "--quiet", "--login"])?;
+  assert_eq!(out, "john\n");
+
+  // A credential with a different password is not erased.
+  ncli.stdin(format!("{}password=old-token\n", INPUT));
+  let _ = ncli.handle(&["git-credential", "--write", "erase"])?;
+  ncli.stdin(INPUT);
+  let out = ncli.handle(&["git-credential", "get"])?;
+  assert_eq!(out, "username=john\npassword=token\n");
+
+  ncli.stdin(format!("{}password=token\n", INPUT));
+  let _ = ncli.handle(&["git-credential", "--write", "erase"])?;
+  ncli.stdin(INPUT);
+  let out = ncli.handle(&["git-credential", "get"])?;
+  assert!(out.is_empty());
+
+  let out = ncli.handle(&["pws", "get", "0", "--quiet", "--login"])?;
+  assert_eq!(out, "jane\n");
+  Ok(())
+}
@@ -40,3 +40,19 @@ fn connected(model: nitrokey::Model) -> anyhow::Result<()> {
   assert!(re.is_match(&out), out);
   Ok(())
 }
+
+#[test_device]
+fn connected_format(model: nitrokey::Model) -> anyhow::Result<()> {
+  let re = regex::Regex::new(r#"^(0x[[:xdigit:]]+ (Pro|Storage|unknown)\n)+$"#).unwrap();
+
+  let out = Nitrocli::with_model(model).handle(&["list", "--format", "{serial} {model}"])?;
+  assert!(re.is_match(&out), out);
+  Ok(())
+}
+
+#[test]
+fn wink_no_connect() {
+  let res = Nitrocli::new().handle(&["list", "--wink", "--no-connect"]);
+  let err = res.unwrap_err().to_string();
+  assert!(err.contains("cannot be used with"), err);
+}
@@ -18,21 +18,34 @@
 // *************************************************************************
 
 use std::ffi;
+use std::path;
 
 use nitrokey_test::test as test_device;
 
+mod agent;
+mod askpass;
+mod audit_log;
 mod config;
 mod encrypted;
+mod git_credential;
 mod hidden;
 mod list;
 mod lock;
+mod monitor;
+mod native_messaging;
 mod otp;
 mod pin;
+mod provision;
 mod pws;
 mod reset;
 mod run;
+#[cfg(feature = "secret-service")]
+mod secret_service;
 mod status;
+mod storage;
 mod unencrypted;
+mod vault;
+mod wink;
 
 struct Nitrocli {
   model: Option<nitrokey::Model>,
@@ -41,6 +54,10 @@ struct Nitrocli {
   new_admin_pin: Option<ffi::OsString>,
   new_user_pin: Option<ffi::OsString>,
   password: Option<ffi::OsString>,
+  pin_policy: Option<ffi::OsString>,
+  config_dir: Option<path::PathBuf>,
+  agent_socket: Option<path::PathBuf>,
+  stdin: Vec<u8>,
 }
 
 impl Nitrocli {
@@ -52,6 +69,10 @@ impl Nitrocli {
       new_admin_pin: None,
       new_user_pin: None,
       password: None,
+      pin_policy: None,
+      config_dir: None,
+      agent_socket: None,
+      stdin: Vec::new(),
     }
   }
 
@@ -66,6 +87,10 @@ impl Nitrocli {
       new_admin_pin: None,
       new_user_pin: None,
       password: Some("1234567".into()),
+      pin_policy: None,
+      config_dir: None,
+      agent_socket: None,
+      stdin: Vec::new(),
     }
   }
 
@@ -85,6 +110,22 @@ impl Nitrocli {
     self.new_user_pin = Some(pin.into())
   }
 
+  pub fn pin_policy(&mut self, policy: impl Into<ffi::OsString>) {
+    self.pin_policy = Some(policy.into())
+  }
+
+  pub fn config_dir(&mut self, dir: impl Into<path::PathBuf>) {
+    self.config_dir = Some(dir.into())
+  }
+
+  pub fn agent_socket(&mut self, path: impl Into<path::PathBuf>) {
+    self.agent_socket = Some(path.into())
+  }
+
+  pub fn stdin(&mut self, data: impl Into<Vec<u8>>) {
+    self.stdin = data.into()
+  }
+
   fn model_to_arg(model: nitrokey::Model) -> &'static str {
     match model {
       nitrokey::Model::Pro => "--model=pro",
@@ -104,10 +145,13 @@ impl Nitrocli {
       .map(ToOwned::to_owned)
       .collect();
 
+    let stdin = self.stdin.clone();
+    let mut stdin = stdin.as_slice();
     let mut stdout = Vec::new();
     let mut stderr = Vec::new();
 
     let ctx = &mut crate::RunCtx {
+      stdin: &mut stdin,
       stdout: &mut stdout,
       stderr: &mut stderr,
       admin_pin: self.admin_pin.clone(),
@@ -116,6 +160,12 @@ impl Nitrocli {
       new_user_pin: self.new_user_pin.clone(),
       password: self.password.clone(),
       no_cache: true,
+      cache: None,
+      cache_timeout: None,
+      pin_policy: self.pin_policy.clone(),
+      stdout_tty: false,
+      config_dir: self.config_dir.clone(),
+      agent_socket: self.agent_socket.clone(),
     };
 
     (f(ctx, args), stdout, stderr)
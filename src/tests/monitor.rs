@@ -0,0 +1,53 @@
+// monitor.rs
+
+// *************************************************************************
+// * Copyright (C) 2020 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+use std::env;
+use std::fs;
+
+use super::*;
+
+#[test]
+fn zero_interval() {
+  let res = Nitrocli::new().handle(&["monitor", "--poll", "--interval", "0"]);
+  let err = res.unwrap_err().to_string();
+  assert_eq!(err, "The polling interval must be at least one second");
+}
+
+#[test]
+fn invalid_hook() {
+  let dir = env::temp_dir().join(format!("nitrocli-monitor-{}", std::process::id()));
+  fs::create_dir_all(&dir).unwrap();
+  let config = dir.join("config.toml");
+  fs::write(
+    &config,
+    "[[monitor.hook]]\nevent = \"insert\"\ncommand = \"true\"\n",
+  )
+  .unwrap();
+
+  let mut ncli = Nitrocli::new();
+  ncli.config_dir(&dir);
+  let res = ncli.handle(&["monitor", "--poll"]);
+  let _ = fs::remove_dir_all(&dir);
+
+  let err = res.unwrap_err().to_string();
+  assert_eq!(
+    err,
+    format!("Failed to parse configuration {}", config.display())
+  );
+}
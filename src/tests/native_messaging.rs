@@ -0,0 +1,213 @@
+// native_messaging.rs
+
+// *************************************************************************
+// * Copyright (C) 2020 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+use std::convert::TryFrom as _;
+use std::env;
+use std::fs;
+use std::path;
+
+use super::*;
+
+const ORIGIN: &str = "chrome-extension://abcdefghijklmnop/";
+
+/// Write the given rules to a temporary file.
+fn write_rules(name: &str, content: &str) -> path::PathBuf {
+  let path = env::temp_dir().join(format!(
+    "nitrocli-native-{}-{}.toml",
+    name,
+    std::process::id()
+  ));
+  fs::write(&path, content).unwrap();
+  path
+}
+
+/// Frame the given JSON messages as sent by a browser.
+fn frame(messages: &[&str]) -> Vec<u8> {
+  let mut data = Vec::new();
+  for message in messages {
+    let len = u32::try_from(message.len()).unwrap();
+    data.extend_from_slice(&len.to_ne_bytes());
+    data.extend_from_slice(message.as_bytes());
+  }
+  data
+}
+
+/// Split the framed output of the host into its messages.
+fn unframe(mut data: &[u8]) -> Vec<serde_json::Value> {
+  let mut messages = Vec::new();
+  while !data.is_empty() {
+    let mut len = [0; 4];
+    len.copy_from_slice(&data[..4]);
+    let len = u32::from_ne_bytes(len) as usize;
+    messages.push(serde_json::from_slice(&data[4..4 + len]).unwrap());
+    data = &data[4 + len..];
+  }
+  messages
+}
+
+#[test]
+fn denied_requests() {
+  let rules = format!("[[allow]]\norigin = \"{}\"\nslots = [1]\n", ORIGIN);
+  let path = write_rules("denied", &rules);
+
+  let mut ncli = Nitrocli::new();
+  ncli.stdin(frame(&[
+    r#"{"type":"list"}"#,
+    r#"{"type":"get-password","slot":2}"#,
+    r#"{"type":"erase"}"#,
+  ]));
+  let (rc, out, _) = ncli.run(&[
+    "native-messaging",
+    "host",
+    "--rules",
+    path.to_str().unwrap(),
+    ORIGIN,
+  ]);
+  let _ = fs::remove_file(&path);
+
+  assert_eq!(rc, 0);
+  let messages = unframe(&out);
+  assert_eq!(messages.len(), 3);
+  let expected = format!("Request not allowed for origin {}", ORIGIN);
+  assert_eq!(messages[0]["error"], expected.as_str());
+  assert_eq!(messages[1]["error"], expected.as_str());
+  let error = messages[2]["error"].as_str().unwrap();
+  assert!(error.starts_with("Failed to parse request"), "{}", error);
+}
+
+#[test]
+fn unknown_origin() {
+  let path = write_rules("unknown", "");
+  let mut ncli = Nitrocli::new();
+  ncli.stdin(frame(&[r#"{"type":"list"}"#]));
+  let (rc, out, _) = ncli.run(&[
+    "native-messaging",
+    "host",
+    "--rules",
+    path.to_str().unwrap(),
+    "/home/john/.mozilla/native-messaging-hosts/nitrocli.json",
+    "ext@example.org",
+  ]);
+  let _ = fs::remove_file(&path);
+
+  assert_eq!(rc, 0);
+  let messages = unframe(&out);
+  assert_eq!(
+    messages,
+    vec![serde_json::json!({"error": "Origin ext@example.org is not allowed"})]
+  );
+}
+
+#[test]
+fn install() {
+  let rules = format!(
+    "[[allow]]\norigin = \"{}\"\nlist = true\n\n[[allow]]\norigin = \"ext@example.org\"\n",
+    ORIGIN
+  );
+  let path = write_rules("install", &rules);
+  let dir = env::temp_dir().join(format!("nitrocli-native-install-{}", std::process::id()));
+
+  let res = Nitrocli::new().handle(&[
+    "native-messaging",
+    "install",
+    "--rules",
+    path.to_str().unwrap(),
+    "--dir",
+    dir.to_str().unwrap(),
+    "chromium",
+  ]);
+  let manifest = fs::read(dir.join("nitrocli.json"));
+  let script = fs::read_to_string(dir.join("nitrocli-native-messaging"));
+  let _ = fs::remove_file(&path);
+  let _ = fs::remove_dir_all(&dir);
+
+  assert!(res
+    .unwrap()
+    .starts_with("Installed native messaging host manifest"));
+  let manifest = serde_json::from_slice::<serde_json::Value>(&manifest.unwrap()).unwrap();
+  assert_eq!(manifest["name"], "nitrocli");
+  assert_eq!(manifest["type"], "stdio");
+  assert_eq!(manifest["allowed_origins"], serde_json::json!([ORIGIN]));
+  assert_eq!(
+    manifest["path"],
+    dir.join("nitrocli-native-messaging").to_str().unwrap()
+  );
+
+  let script = script.unwrap();
+  assert!(script.starts_with("#!/bin/sh\n"), "{}", script);
+  assert!(
+    script.contains(" native-messaging host --rules "),
+    "{}",
+    script
+  );
+}
+
+#[test]
+fn install_without_rules() {
+  let path = write_rules("install-without", "");
+  let err = Nitrocli::new()
+    .handle(&[
+      "native-messaging",
+      "install",
+      "--rules",
+      path.to_str().unwrap(),
+      "firefox",
+    ])
+    .unwrap_err()
+    .to_string();
+  let _ = fs::remove_file(&path);
+  assert_eq!(err, "No rules for firefox extensions found");
+}
+
+#[test_device]
+fn list_and_get_password(model: nitrokey::Model) -> anyhow::Result<()> {
+  let rules = format!(
+    "[[allow]]\norigin = \"{}\"\nlist = true\nslots = [1]\n",
+    ORIGIN
+  );
+  let path = write_rules("list", &rules);
+
+  let mut ncli = Nitrocli::with_model(model);
+  let _ = ncli.handle(&["pws", "set", "1", "example.org", "john", "secret"])?;
+  ncli.stdin(frame(&[
+    r#"{"type":"list"}"#,
+    r#"{"type":"get-password","slot":1}"#,
+  ]));
+  let (rc, out, _) = ncli.run(&[
+    "native-messaging",
+    "host",
+    "--rules",
+    path.to_str().unwrap(),
+    ORIGIN,
+  ]);
+  let _ = fs::remove_file(&path);
+
+  assert_eq!(rc, 0);
+  let messages = unframe(&out);
+  assert_eq!(messages.len(), 2);
+  let entries = messages[0]["result"]["entries"].as_array().unwrap().clone();
+  let entry = serde_json::json!({"slot": 1, "name": "example.org", "login": "john"});
+  assert!(entries.contains(&entry), "{:?}", entries);
+  assert!(entries.iter().all(|entry| entry.get("password").is_none()));
+  assert_eq!(
+    messages[1],
+    serde_json::json!({"result": {"password": "secret"}})
+  );
+  Ok(())
+}
@@ -44,8 +44,8 @@ fn set_invalid_slot(model: nitrokey::Model) {
 #[test_device]
 fn status(model: nitrokey::Model) -> anyhow::Result<()> {
   let re = regex::Regex::new(
-    r#"^alg\tslot\tname
-((totp|hotp)\t\d+\t.+\n)+$"#,
+    r#"^alg\tslot\tname\tenter\ttoken id
+((totp|hotp)\t\d+\t.+\t(yes|no|-)\t.+\n)+$"#,
   )
   .unwrap();
 
@@ -96,11 +96,96 @@ fn set_get_totp(model: nitrokey::Model) -> anyhow::Result<()> {
   Ok(())
 }
 
+#[test_device]
+fn get_status_format(model: nitrokey::Model) -> anyhow::Result<()> {
+  const SECRET: &str = "12345678901234567890";
+
+  let mut ncli = Nitrocli::with_model(model);
+  let _ = ncli.handle(&["otp", "set", "-d", "8", "-f", "ascii", "3", "fmt", SECRET])?;
+
+  let args = [
+    "otp",
+    "get",
+    "-t",
+    "1111111111",
+    "--format",
+    "{alg}/{slot}@{time}: {code}",
+    "3",
+  ];
+  let out = ncli.handle(&args)?;
+  assert_eq!(out, "totp/3@1111111111: 14050471\n");
+
+  let out = ncli.handle(&["otp", "status", "--format", "{alg} {slot} {name}"])?;
+  assert!(out.lines().any(|line| line == "totp 3 fmt"), "{}", out);
+  Ok(())
+}
+
+#[test]
+fn set_invalid_token_id() {
+  let args = ["otp", "set", "--token-id", "NK-1", "0", "name", "12345678"];
+  let res = Nitrocli::new().handle(&args);
+  let err = res.unwrap_err().to_string();
+  assert_eq!(
+    err,
+    "Invalid token ID NK-1: expected twelve alphanumeric characters"
+  );
+}
+
+#[test]
+fn set_secret_too_long() {
+  let secret = "ab".repeat(41);
+  let args = ["otp", "set", "-f", "hex", "0", "name", &secret];
+  let res = Nitrocli::new().handle(&args);
+  let err = res.unwrap_err().to_string();
+  assert_eq!(
+    err,
+    "The secret is too long: 41 bytes given, but at most 40 bytes are supported"
+  );
+}
+
+#[test_device]
+fn set_use_enter_token_id(model: nitrokey::Model) -> anyhow::Result<()> {
+  const SECRET: &str = "12345678901234567890";
+  const OTP: &str = concat!(755224, "\n");
+
+  let mut ncli = Nitrocli::with_model(model);
+  let args = [
+    "otp",
+    "set",
+    "-a",
+    "hotp",
+    "-f",
+    "ascii",
+    "--use-enter",
+    "--token-id",
+    "NKPR12345678",
+    "4",
+    "name",
+    SECRET,
+  ];
+  let _ = ncli.handle(&args)?;
+
+  let out = ncli.handle(&["otp", "get", "-a", "hotp", "4"])?;
+  assert_eq!(out, OTP);
+
+  let format = "{alg} {slot} {use_enter} {token_id}";
+  let out = ncli.handle(&["otp", "status", "--format", format])?;
+  assert!(out.contains("hotp 4 true NKPR12345678\n"), "{}", out);
+
+  let _ = ncli.handle(&[
+    "otp", "set", "-a", "hotp", "-f", "ascii", "4", "name", SECRET,
+  ])?;
+  let out = ncli.handle(&["otp", "status", "--format", format])?;
+  assert!(out.contains("hotp 4 false \n"), "{}", out);
+  Ok(())
+}
+
 #[test_device]
 fn set_totp_uneven_chars(model: nitrokey::Model) -> anyhow::Result<()> {
   let secrets = [
     (args::OtpSecretFormat::Hex, "123"),
     (args::OtpSecretFormat::Base32, "FBILDWWGA2"),
+    (args::OtpSecretFormat::Base64, "KFCyXJL"),
   ];
 
   for (format, secret) in &secrets {
@@ -66,9 +66,11 @@ fn set_user(model: nitrokey::Model) -> anyhow::Result<()> {
     }
   }
 
-  // Revert to the default user PIN.
+  // Revert to the default user PIN, which the default PIN policy
+  // rejects.
   ncli.user_pin("new-pin");
   ncli.new_user_pin(nitrokey::DEFAULT_USER_PIN);
+  ncli.pin_policy("lax");
 
   let out = ncli.handle(&["pin", "set", "user"])?;
   assert!(out.is_empty());
@@ -82,3 +84,51 @@ fn set_user(model: nitrokey::Model) -> anyhow::Result<()> {
   }
   Ok(())
 }
+
+#[test_device]
+fn set_user_policy(model: nitrokey::Model) -> anyhow::Result<()> {
+  let mut ncli = Nitrocli::with_model(model);
+  ncli.new_user_pin(nitrokey::DEFAULT_USER_PIN);
+  let err = ncli.handle(&["pin", "set", "user"]).unwrap_err();
+  assert_eq!(
+    format!("{:#}", err),
+    "Failed to select PIN: The secret must not be a default PIN"
+  );
+
+  ncli.new_user_pin("999999");
+  let err = ncli.handle(&["pin", "set", "user"]).unwrap_err();
+  assert_eq!(
+    format!("{:#}", err),
+    "Failed to select PIN: The secret must not consist of repeated or consecutive characters"
+  );
+  Ok(())
+}
+
+#[test_device]
+fn last_attempt(model: nitrokey::Model) -> anyhow::Result<()> {
+  {
+    let mut manager = nitrokey::force_take()?;
+    let mut device = manager.connect_model(model)?;
+    while device.get_user_retry_count()? > 1 {
+      let (new_device, _) = device.authenticate_user("wrong-pin").unwrap_err();
+      device = new_device;
+    }
+  }
+
+  let mut ncli = Nitrocli::with_model(model);
+  let err = ncli.handle(&["pws", "status"]).unwrap_err().to_string();
+  assert_eq!(
+    err,
+    "Refusing to use the user PIN from the environment with only one attempt left; use --force \
+     to try it anyway"
+  );
+
+  let _ = ncli.handle(&["--force", "pws", "status"])?;
+
+  {
+    let mut manager = nitrokey::force_take()?;
+    let device = manager.connect_model(model)?;
+    assert_eq!(device.get_user_retry_count()?, 3);
+  }
+  Ok(())
+}
@@ -0,0 +1,99 @@
+// provision.rs
+
+// *************************************************************************
+// * Copyright (C) 2020 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+use std::env;
+use std::fs;
+use std::path;
+
+use super::*;
+
+/// Write the given manifest to a temporary file.
+fn write_manifest(name: &str, content: &str) -> path::PathBuf {
+  let path = env::temp_dir().join(format!("nitrocli-{}-{}.toml", name, std::process::id()));
+  fs::write(&path, content).unwrap();
+  path
+}
+
+#[test]
+fn invalid_manifest() {
+  let path = write_manifest("invalid", "[config]\nfoo = 1\n");
+  let err = Nitrocli::new()
+    .handle(&["provision", path.to_str().unwrap()])
+    .unwrap_err()
+    .to_string();
+  let _ = fs::remove_file(&path);
+  assert_eq!(err, format!("Failed to parse manifest {}", path.display()));
+}
+
+#[test_device]
+fn provision(model: nitrokey::Model) -> anyhow::Result<()> {
+  let manifest = r#"
+[config]
+no_numlock = true
+capslock = 1
+otp_pin = true
+
+[[otp]]
+algorithm = "hotp"
+slot = 1
+name = "provisioned"
+secret = "3132333435363738393031323334353637383930"
+format = "hex"
+use_enter = true
+token_id = "NKPR12345678"
+
+[[pws]]
+slot = 4
+name = "provisioned"
+login = "some-login"
+password = "some-password"
+"#;
+  let path = write_manifest("provision", manifest);
+  let path = path.to_str().unwrap();
+
+  let mut ncli = Nitrocli::with_model(model);
+  let _ = ncli.handle(&["config", "set", "--no-capslock", "--no-otp-pin"])?;
+
+  let out = ncli.handle(&["provision", "--dry-run", path])?;
+  assert!(out.starts_with("Planned changes:\n"), out);
+  assert!(out.contains("  write PWS slot 4 (provisioned)\n"), out);
+
+  let out = ncli.handle(&["provision", path])?;
+  assert!(out.starts_with("Planned changes:\n"), out);
+
+  let out = ncli.handle(&["config", "get"])?;
+  assert!(out.contains("  capslock binding:         1\n"), out);
+  assert!(out.contains("  require user PIN for OTP: true\n"), out);
+
+  let out = ncli.handle(&["pws", "get", "4", "--quiet", "--login"])?;
+  assert_eq!(out, "some-login\n");
+
+  let format = "{alg} {slot} {use_enter} {token_id}";
+  let out = ncli.handle(&["otp", "status", "--format", format])?;
+  assert!(out.contains("hotp 1 true NKPR12345678\n"), out);
+
+  // Everything but the OTP slot, whose secret cannot be compared, is
+  // up to date now.
+  let out = ncli.handle(&["provision", path])?;
+  assert_eq!(out, "Planned changes:\n  write hotp slot 1 (provisioned)\n");
+
+  let _ = ncli.handle(&["config", "set", "--no-capslock", "--no-otp-pin"])?;
+  let _ = fs::remove_file(path);
+  Ok(())
+}
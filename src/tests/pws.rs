@@ -17,8 +17,19 @@
 // * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
 // *************************************************************************
 
+use std::env;
+use std::fs;
+use std::path;
+
 use super::*;
 
+/// Write the given CSV data to a temporary file.
+fn write_csv(name: &str, content: &str) -> path::PathBuf {
+  let path = env::temp_dir().join(format!("nitrocli-{}-{}.csv", name, std::process::id()));
+  fs::write(&path, content).unwrap();
+  path
+}
+
 #[test_device]
 fn set_invalid_slot(model: nitrokey::Model) {
   let err = Nitrocli::with_model(model)
@@ -79,6 +90,36 @@ fn set_get(model: nitrokey::Model) -> anyhow::Result<()> {
   Ok(())
 }
 
+#[test_device]
+fn get_format(model: nitrokey::Model) -> anyhow::Result<()> {
+  let mut ncli = Nitrocli::with_model(model);
+  let _ = ncli.handle(&["pws", "set", "3", "svc", "user", "pass"])?;
+
+  let out = ncli.handle(&[
+    "pws",
+    "get",
+    "3",
+    "--format",
+    "{name}\\t{login}\\t{password}",
+  ])?;
+  assert_eq!(out, "svc\tuser\tpass\n");
+
+  let out = ncli.handle(&["pws", "status", "--format", "{slot}={name}"])?;
+  assert!(out.lines().any(|line| line == "3=svc"), "{}", out);
+  assert!(!out.contains("slot\tname"), "{}", out);
+  Ok(())
+}
+
+#[test]
+fn format_unknown_field() {
+  let res = Nitrocli::new().handle(&["pws", "status", "--format", "{password}"]);
+  let err = res.unwrap_err().to_string();
+  assert_eq!(
+    err,
+    "Unknown field {password} in format template (available fields: slot, name, programmed)"
+  );
+}
+
 #[test_device]
 fn set_reset_get(model: nitrokey::Model) -> anyhow::Result<()> {
   const NAME: &str = "some/svc";
@@ -88,7 +129,7 @@ fn set_reset_get(model: nitrokey::Model) -> anyhow::Result<()> {
   let mut ncli = Nitrocli::with_model(model);
   let _ = ncli.handle(&["pws", "set", "2", &NAME, &LOGIN, &PASSWORD])?;
 
-  let out = ncli.handle(&["reset"])?;
+  let out = ncli.handle(&["reset", "--yes"])?;
   assert_eq!(out, "");
 
   let res = ncli.handle(&["pws", "get", "2"]);
@@ -97,6 +138,21 @@ fn set_reset_get(model: nitrokey::Model) -> anyhow::Result<()> {
   Ok(())
 }
 
+#[test_device]
+fn get_exit_status(model: nitrokey::Model) -> anyhow::Result<()> {
+  let mut ncli = Nitrocli::with_model(model);
+  let _ = ncli.handle(&["pws", "clear", "11"])?;
+
+  let (rc, _, err) = ncli.run(&["pws", "get", "11"]);
+  assert_eq!(rc, 5);
+  let err = String::from_utf8_lossy(&err);
+  assert!(err.contains("Slot 11 is not programmed"), "{}", err);
+
+  let (rc, _, _) = ncli.run(&["pws", "get", "16"]);
+  assert_eq!(rc, 5);
+  Ok(())
+}
+
 #[test_device]
 fn clear(model: nitrokey::Model) -> anyhow::Result<()> {
   let mut ncli = Nitrocli::with_model(model);
@@ -108,3 +164,219 @@ fn clear(model: nitrokey::Model) -> anyhow::Result<()> {
   assert_eq!(err, "Failed to access PWS slot");
   Ok(())
 }
+
+#[test]
+fn import_empty() {
+  let path = write_csv("import-empty", "name,username,password\n");
+  let err = Nitrocli::new()
+    .handle(&["pws", "import", "--format=csv", path.to_str().unwrap()])
+    .unwrap_err()
+    .to_string();
+  let _ = fs::remove_file(&path);
+  assert_eq!(err, "No entries found to import");
+}
+
+#[test_device]
+fn import(model: nitrokey::Model) -> anyhow::Result<()> {
+  let content = "\
+name,url,username,password
+,https://www.example.org/login,john,secret
+a-very-long-name,,jane,hunter2
+";
+  let path = write_csv("import", content);
+  let path = path.to_str().unwrap();
+
+  let mut ncli = Nitrocli::with_model(model);
+  let _ = ncli.handle(&["reset", "--yes"])?;
+
+  let out = ncli.handle(&["pws", "import", "--format=csv", "--dry-run", path])?;
+  assert_eq!(
+    out,
+    "slot\tname\tlogin\n0\texample.org\tjohn\n1\ta-very-long\tjane\n"
+  );
+  let res = ncli.handle(&["pws", "get", "0"]);
+  assert_eq!(res.unwrap_err().to_string(), "Failed to access PWS slot");
+
+  let _ = ncli.handle(&["pws", "import", "--format=csv", "--yes", path])?;
+  let out = ncli.handle(&["pws", "get", "1", "--quiet"])?;
+  assert_eq!(out, "a-very-long\njane\nhunter2\n");
+
+  let _ = fs::remove_file(path);
+  Ok(())
+}
+
+#[test]
+fn export_world_readable() {
+  use std::os::unix::fs::PermissionsExt as _;
+
+  let path = write_csv("export-readable", "");
+  fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+  let err = Nitrocli::new()
+    .handle(&[
+      "pws",
+      "export",
+      "--format=csv",
+      "-o",
+      path.to_str().unwrap(),
+    ])
+    .unwrap_err()
+    .to_string();
+  let _ = fs::remove_file(&path);
+  assert_eq!(
+    err,
+    format!(
+      "Refusing to write plaintext passwords to world-readable file {} (use --allow-plaintext to override)",
+      path.display()
+    )
+  );
+}
+
+#[test]
+fn export_pass_without_recipient() {
+  let err = Nitrocli::new()
+    .handle(&["pws", "export", "--format=pass", "-o", "/tmp"])
+    .unwrap_err()
+    .to_string();
+  assert_eq!(
+    err,
+    "Exporting to pass requires the --output and --encrypt-to options"
+  );
+}
+
+#[test_device]
+fn export(model: nitrokey::Model) -> anyhow::Result<()> {
+  use std::os::unix::fs::PermissionsExt as _;
+
+  let path = env::temp_dir().join(format!("nitrocli-export-{}.csv", std::process::id()));
+  let _ = fs::remove_file(&path);
+
+  let mut ncli = Nitrocli::with_model(model);
+  let _ = ncli.handle(&["reset", "--yes"])?;
+  let _ = ncli.handle(&["pws", "set", "3", "example", "john", "pa,ss"])?;
+
+  let out = ncli.handle(&[
+    "pws",
+    "export",
+    "--format=csv",
+    "-o",
+    path.to_str().unwrap(),
+  ])?;
+  assert_eq!(out, "");
+  let content = fs::read_to_string(&path)?;
+  let mode = fs::metadata(&path)?.permissions().mode();
+  let _ = fs::remove_file(&path);
+  assert_eq!(
+    content,
+    "name,url,username,password\nexample,,john,\"pa,ss\"\n"
+  );
+  assert_eq!(mode & 0o777, 0o600);
+
+  let out = ncli.handle(&["pws", "export", "--format=csv"])?;
+  assert_eq!(out, "name,url,username,password\nexample,,john,\"pa,ss\"\n");
+  Ok(())
+}
+
+#[test]
+fn audit_missing_breached_list() {
+  let err = Nitrocli::new()
+    .handle(&["pws", "audit", "--breached", "/does/not/exist"])
+    .unwrap_err()
+    .to_string();
+  assert_eq!(err, "Failed to open breached password list /does/not/exist");
+}
+
+#[test_device]
+fn audit(model: nitrokey::Model) -> anyhow::Result<()> {
+  let mut ncli = Nitrocli::with_model(model);
+  let _ = ncli.handle(&["reset", "--yes"])?;
+
+  let out = ncli.handle(&["pws", "audit"])?;
+  assert_eq!(out, "No issues found\n");
+
+  let _ = ncli.handle(&["pws", "set", "1", "mail", "john", "Xk4#pQ9!vL2@"])?;
+  let _ = ncli.handle(&["pws", "set", "4", "web", "jane", "Xk4#pQ9!vL2@"])?;
+  let _ = ncli.handle(&["pws", "set", "6", "login", "login", "login"])?;
+
+  let out = ncli.handle(&["pws", "audit"])?;
+  let expected = "\
+slot\tname\tissue
+1\tmail\tpassword reused in slot(s) 4
+4\tweb\tpassword reused in slot(s) 1
+6\tlogin\tweak password (about 23 bits of entropy)
+6\tlogin\tpassword equals login
+6\tlogin\tpassword equals name
+";
+  assert_eq!(out, expected);
+  assert!(!out.contains("Xk4#pQ9!vL2@"), "{}", out);
+  Ok(())
+}
+
+#[test]
+fn exec_invalid_env() {
+  let err = Nitrocli::new()
+    .handle(&["pws", "exec", "--env", "FOO=1", "--", "true"])
+    .unwrap_err()
+    .to_string();
+  assert_eq!(
+    err,
+    "Invalid environment variable specification FOO=1: expected VAR=SLOT:FIELD with FIELD \
+     being one of name, login, or password"
+  );
+}
+
+#[test_device]
+fn exec(model: nitrokey::Model) -> anyhow::Result<()> {
+  let mut ncli = Nitrocli::with_model(model);
+  let _ = ncli.handle(&["pws", "set", "3", "db", "admin", "s3cr3t"])?;
+
+  let script = r#"test "$DB_USER" = admin && test "$DB_PASS" = s3cr3t && exit 42"#;
+  let (rc, out, err) = ncli.run(&[
+    "pws",
+    "exec",
+    "--env",
+    "DB_PASS=3:password",
+    "-e",
+    "DB_USER=3:login",
+    "--",
+    "sh",
+    "-c",
+    script,
+  ]);
+  assert_eq!(rc, 42);
+  assert!(out.is_empty());
+  assert!(err.is_empty());
+
+  let out = ncli.handle(&["pws", "exec", "-e", "NAME=3:name", "--", "true"])?;
+  assert_eq!(out, "");
+  Ok(())
+}
+
+#[test_device]
+fn exec_hides_pins(model: nitrokey::Model) -> anyhow::Result<()> {
+  // The test harness does not read PINs from the environment, so
+  // setting them here does not affect other tests.
+  for var in crate::SECRET_ENV_VARS {
+    env::set_var(var, "123456");
+  }
+
+  let mut ncli = Nitrocli::with_model(model);
+  let _ = ncli.handle(&["pws", "set", "3", "db", "admin", "s3cr3t"])?;
+
+  let script = r#"
+    test -z "$NITROCLI_ADMIN_PIN$NITROCLI_USER_PIN$NITROCLI_PASSWORD" &&
+    test -z "$NITROCLI_NEW_ADMIN_PIN$NITROCLI_NEW_USER_PIN" &&
+    test "$DB_USER" = admin
+  "#;
+  let (rc, _, _) = ncli.run(&[
+    "pws",
+    "exec",
+    "-e",
+    "DB_USER=3:login",
+    "--",
+    "sh",
+    "-c",
+    script,
+  ]);
+  assert_eq!(rc, 0);
+  Ok(())
+}
@@ -18,13 +18,14 @@
 // *************************************************************************
 
 use nitrokey::Authenticate;
+use nitrokey::Device;
 use nitrokey::GetPasswordSafe;
 
 use super::*;
 
 #[test_device]
 fn reset(model: nitrokey::Model) -> anyhow::Result<()> {
-  let new_admin_pin = "87654321";
+  let new_admin_pin = "84736251";
   let mut ncli = Nitrocli::with_model(model);
 
   // Change the admin PIN.
@@ -40,7 +41,7 @@ fn reset(model: nitrokey::Model) -> anyhow::Result<()> {
 
   // Perform factory reset
   ncli.admin_pin(new_admin_pin);
-  let out = ncli.handle(&["reset"])?;
+  let out = ncli.handle(&["reset", "--yes"])?;
   assert!(out.is_empty());
 
   {
@@ -58,3 +59,66 @@ fn reset(model: nitrokey::Model) -> anyhow::Result<()> {
 
   Ok(())
 }
+
+#[test_device]
+fn reset_confirm(model: nitrokey::Model) -> anyhow::Result<()> {
+  let serial_number = {
+    let mut manager = nitrokey::force_take()?;
+    let device = manager.connect_model(model)?;
+    device.get_serial_number()?
+  };
+
+  let mut ncli = Nitrocli::with_model(model);
+  ncli.stdin("0xdeadbeef\n");
+  let res = ncli.handle(&["reset"]);
+  assert_eq!(
+    res.unwrap_err().to_string(),
+    "Serial number does not match, aborting factory reset"
+  );
+
+  ncli.stdin(format!("{}\n", serial_number));
+  let out = ncli.handle(&["reset"])?;
+  assert!(out.is_empty());
+  Ok(())
+}
+
+#[test_device]
+fn reset_dry_run(model: nitrokey::Model) -> anyhow::Result<()> {
+  let mut ncli = Nitrocli::with_model(model);
+  let _ = ncli.handle(&["otp", "set", "-a", "hotp", "1", "dry-run-otp", "abcdef"])?;
+  let _ = ncli.handle(&["pws", "set", "3", "dry-run-pws", "login", "password"])?;
+
+  let out = ncli.handle(&["reset", "--dry-run"])?;
+  assert!(out.contains("    hotp 1: dry-run-otp\n"), out);
+  assert!(out.contains("    3: dry-run-pws\n"), out);
+
+  // Make sure that nothing has been erased.
+  let out = ncli.handle(&["pws", "get", "3", "--quiet", "--name"])?;
+  assert_eq!(out, "dry-run-pws\n");
+  Ok(())
+}
+
+#[test_device]
+fn reset_set_pins(model: nitrokey::Model) -> anyhow::Result<()> {
+  let new_admin_pin = "84736251";
+  let new_user_pin = "946284";
+  let mut ncli = Nitrocli::with_model(model);
+  ncli.new_admin_pin(new_admin_pin);
+  ncli.new_user_pin(new_user_pin);
+
+  let out = ncli.handle(&["reset", "--yes", "--set-pins"])?;
+  assert!(out.is_empty());
+
+  {
+    let mut manager = nitrokey::force_take()?;
+    let device = manager.connect_model(model)?;
+    let device = device.authenticate_admin(new_admin_pin).unwrap();
+    let device = device.device();
+    let _ = device.authenticate_user(new_user_pin).unwrap();
+  }
+
+  // Revert to the default PINs for other tests.
+  ncli.admin_pin(new_admin_pin);
+  let _ = ncli.handle(&["reset", "--yes"])?;
+  Ok(())
+}
@@ -31,6 +31,16 @@ fn no_command_or_option() {
   assert!(s.contains("USAGE:\n"), s);
 }
 
+#[test]
+fn all_devices_unsupported() {
+  let res = Nitrocli::new().handle(&["--all-devices", "pws", "status"]);
+  let err = res.unwrap_err().to_string();
+  assert_eq!(
+    err,
+    "This command does not support the --all-devices option"
+  );
+}
+
 #[test]
 fn help_options() {
   fn test_run(args: &[&str], help: &str) {
@@ -55,17 +65,28 @@ fn help_options() {
   }
 
   test(&[]);
+  test(&["agent"]);
+  test(&["askpass"]);
+  test(&["audit-log"]);
+  test(&["audit-log", "verify"]);
   test(&["config"]);
   test(&["config", "get"]);
   test(&["config", "set"]);
   test(&["encrypted"]);
   test(&["encrypted", "open"]);
   test(&["encrypted", "close"]);
+  test(&["firmware"]);
+  test(&["firmware", "export"]);
+  test(&["git-credential"]);
   test(&["hidden"]);
   test(&["hidden", "close"]);
   test(&["hidden", "create"]);
   test(&["hidden", "open"]);
   test(&["lock"]);
+  test(&["monitor"]);
+  test(&["native-messaging"]);
+  test(&["native-messaging", "host"]);
+  test(&["native-messaging", "install"]);
   test(&["otp"]);
   test(&["otp", "clear"]);
   test(&["otp", "get"]);
@@ -75,15 +96,32 @@ fn help_options() {
   test(&["pin", "clear"]);
   test(&["pin", "set"]);
   test(&["pin", "unblock"]);
+  test(&["provision"]);
   test(&["pws"]);
+  test(&["pws", "audit"]);
   test(&["pws", "clear"]);
+  test(&["pws", "exec"]);
+  test(&["pws", "export"]);
   test(&["pws", "get"]);
+  test(&["pws", "import"]);
   test(&["pws", "set"]);
   test(&["pws", "status"]);
   test(&["reset"]);
+  #[cfg(feature = "secret-service")]
+  test(&["secret-service"]);
   test(&["status"]);
+  test(&["storage"]);
+  test(&["storage", "clear-warning"]);
+  test(&["storage", "info"]);
   test(&["unencrypted"]);
   test(&["unencrypted", "set"]);
+  test(&["vault"]);
+  test(&["vault", "add"]);
+  test(&["vault", "get"]);
+  test(&["vault", "init"]);
+  test(&["vault", "list"]);
+  test(&["vault", "rm"]);
+  test(&["wink"]);
 }
 
 #[test]
@@ -108,3 +146,52 @@ fn version_option() {
   test(&re, "--version");
   test(&re, "-V");
 }
+
+#[test]
+fn exit_status_usage() {
+  let (rc, out, _) = Nitrocli::new().run(&["pws", "get"]);
+  assert_eq!(rc, 2);
+  assert_eq!(out, b"");
+
+  let (rc, _, _) = Nitrocli::new().run(&["otp", "get", "--algorithm", "foo", "0"]);
+  assert_eq!(rc, 2);
+}
+
+#[test_device]
+fn exit_status_not_connected() {
+  let (rc, _, _) = Nitrocli::new().run(&["status"]);
+  assert_eq!(rc, 4);
+
+  let (rc, _, _) = Nitrocli::new().run(&["--all-devices", "status"]);
+  assert_eq!(rc, 4);
+}
+
+#[test]
+fn exit_status_classes() {
+  fn test(err: impl Into<nitrokey::Error>, status: i32) {
+    let err = anyhow::Error::from(err.into()).context("Some context");
+    assert_eq!(crate::exit_status(&err), status);
+  }
+
+  assert_eq!(crate::exit_status(&anyhow::anyhow!("Some error")), 1);
+  test(nitrokey::CommandError::WrongPassword, 3);
+  test(nitrokey::CommandError::NotAuthorized, 1);
+  test(nitrokey::CommunicationError::NotConnected, 4);
+  test(nitrokey::CommunicationError::SendingFailure, 4);
+  test(nitrokey::CommandError::SlotNotProgrammed, 5);
+  test(nitrokey::LibraryError::InvalidSlot, 5);
+  test(nitrokey::LibraryError::StringTooLong, 1);
+}
+
+#[test_device]
+fn exit_status_wrong_pin(model: nitrokey::Model) {
+  let mut ncli = Nitrocli::with_model(model);
+  ncli.user_pin("wrong-pin");
+  let (rc, _, _) = ncli.run(&["pws", "status"]);
+  assert_eq!(rc, 3);
+
+  // Reset the retry counter.
+  ncli.user_pin(nitrokey::DEFAULT_USER_PIN);
+  let (rc, _, _) = ncli.run(&["pws", "status"]);
+  assert_eq!(rc, 0);
+}
@@ -0,0 +1,194 @@
+// secret_service.rs
+
+// *************************************************************************
+// * Copyright (C) 2020 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+use std::collections;
+use std::io;
+use std::io::BufRead as _;
+use std::process;
+use std::thread;
+use std::time;
+
+use dbus::arg;
+
+use super::*;
+
+const TIMEOUT: time::Duration = time::Duration::from_secs(5);
+const BUS_NAME: &str = "org.freedesktop.secrets";
+const SERVICE_PATH: &str = "/org/freedesktop/secrets";
+const COLLECTION_PATH: &str = "/org/freedesktop/secrets/collection/nitrokey";
+const SERVICE_INTERFACE: &str = "org.freedesktop.Secret.Service";
+
+type Path = dbus::Path<'static>;
+type Secret = (Path, Vec<u8>, Vec<u8>, String);
+
+/// A private message bus, terminated when dropped.
+struct Bus {
+  daemon: process::Child,
+  address: String,
+}
+
+impl Bus {
+  /// Start a new message bus, or return `None` if `dbus-daemon` is
+  /// not installed.
+  fn start() -> Option<Self> {
+    let result = process::Command::new("dbus-daemon")
+      .arg("--session")
+      .arg("--nofork")
+      .arg("--print-address")
+      .stdout(process::Stdio::piped())
+      .stderr(process::Stdio::null())
+      .spawn();
+    let mut daemon = match result {
+      Ok(daemon) => daemon,
+      Err(err) if err.kind() == io::ErrorKind::NotFound => return None,
+      Err(err) => panic!("Failed to start dbus-daemon: {}", err),
+    };
+    let mut address = String::new();
+    let stdout = daemon.stdout.take().unwrap();
+    let _ = io::BufReader::new(stdout).read_line(&mut address).unwrap();
+
+    Some(Self {
+      daemon,
+      address: address.trim().to_string(),
+    })
+  }
+
+  fn connect(&self) -> dbus::blocking::Connection {
+    let mut channel = dbus::channel::Channel::open_private(&self.address).unwrap();
+    channel.register().unwrap();
+    dbus::blocking::Connection::from(channel)
+  }
+}
+
+impl Drop for Bus {
+  fn drop(&mut self) {
+    let _ = self.daemon.kill();
+    let _ = self.daemon.wait();
+  }
+}
+
+/// Run the Secret Service on the given bus and wait until it is
+/// available.
+fn start_service(
+  mut ncli: Nitrocli,
+  bus: &Bus,
+) -> (
+  dbus::blocking::Connection,
+  thread::JoinHandle<anyhow::Result<String>>,
+) {
+  let address = bus.address.clone();
+  let handle = thread::spawn(move || ncli.handle(&["secret-service", "--address", &address]));
+
+  let connection = bus.connect();
+  let proxy = connection.with_proxy("org.freedesktop.DBus", "/org/freedesktop/DBus", TIMEOUT);
+  for _ in 0..100 {
+    let (owned,): (bool,) = proxy
+      .method_call("org.freedesktop.DBus", "NameHasOwner", (BUS_NAME,))
+      .unwrap();
+    if owned {
+      return (connection, handle);
+    }
+    thread::sleep(time::Duration::from_millis(50));
+  }
+  panic!("Secret Service did not become available")
+}
+
+fn open_session(connection: &dbus::blocking::Connection) -> Path {
+  let proxy = connection.with_proxy(BUS_NAME, SERVICE_PATH, TIMEOUT);
+  let (_, session): (arg::Variant<Box<dyn arg::RefArg>>, Path) = proxy
+    .method_call(
+      SERVICE_INTERFACE,
+      "OpenSession",
+      ("plain", arg::Variant(String::new())),
+    )
+    .unwrap();
+  session
+}
+
+#[test]
+fn collection() {
+  let bus = match Bus::start() {
+    Some(bus) => bus,
+    None => return,
+  };
+  let (connection, handle) = start_service(Nitrocli::new(), &bus);
+
+  let _ = open_session(&connection);
+  let proxy = connection.with_proxy(BUS_NAME, SERVICE_PATH, TIMEOUT);
+  let (collection,): (Path,) = proxy
+    .method_call(SERVICE_INTERFACE, "ReadAlias", ("default",))
+    .unwrap();
+  assert_eq!(&*collection, COLLECTION_PATH);
+
+  let (collections,): (arg::Variant<Vec<Path>>,) = proxy
+    .method_call(
+      "org.freedesktop.DBus.Properties",
+      "Get",
+      (SERVICE_INTERFACE, "Collections"),
+    )
+    .unwrap();
+  assert_eq!(collections.0, vec![collection]);
+
+  let err = proxy
+    .method_call::<(Path, Path), _, _, _>(
+      SERVICE_INTERFACE,
+      "OpenSession",
+      (
+        "dh-ietf1024-sha256-aes128-cbc-pkcs7",
+        arg::Variant(vec![0u8]),
+      ),
+    )
+    .unwrap_err();
+  assert_eq!(err.name(), Some("org.freedesktop.DBus.Error.NotSupported"));
+
+  drop(bus);
+  let _ = handle.join().unwrap().unwrap();
+}
+
+#[test_device]
+fn get_secrets(model: nitrokey::Model) -> anyhow::Result<()> {
+  let mut ncli = Nitrocli::with_model(model);
+  let _ = ncli.handle(&["pws", "set", "4", "secret.example.org", "john", "s3cr3t"])?;
+
+  let bus = match Bus::start() {
+    Some(bus) => bus,
+    None => return Ok(()),
+  };
+  let (connection, handle) = start_service(ncli, &bus);
+  let session = open_session(&connection);
+
+  let mut attributes = collections::HashMap::new();
+  let _ = attributes.insert("name", "secret.example.org");
+  let _ = attributes.insert("login", "john");
+  let proxy = connection.with_proxy(BUS_NAME, SERVICE_PATH, TIMEOUT);
+  let (unlocked, _locked): (Vec<Path>, Vec<Path>) = proxy
+    .method_call(SERVICE_INTERFACE, "SearchItems", (attributes,))
+    .unwrap();
+  assert_eq!(unlocked.len(), 1);
+  assert_eq!(&*unlocked[0], format!("{}/4", COLLECTION_PATH).as_str());
+
+  let (secrets,): (collections::HashMap<Path, Secret>,) = proxy
+    .method_call(SERVICE_INTERFACE, "GetSecrets", (unlocked.clone(), session))
+    .unwrap();
+  assert_eq!(secrets[&unlocked[0]].2, b"s3cr3t");
+
+  drop(bus);
+  let _ = handle.join().unwrap()?;
+  Ok(())
+}
@@ -83,3 +83,42 @@ $"#,
   assert!(re.is_match(&out), out);
   Ok(())
 }
+
+#[test]
+fn format_invalid() {
+  let res = Nitrocli::new().handle(&["status", "--format", "{serial"]);
+  let err = res.unwrap_err().to_string();
+  assert_eq!(err, "Unterminated placeholder in format template");
+}
+
+#[test_device]
+fn format(model: nitrokey::Model) -> anyhow::Result<()> {
+  let re = regex::Regex::new(r#"^0x[[:xdigit:]]{8} v\d+\.\d+ [0-3]\n$"#).unwrap();
+
+  let out =
+    Nitrocli::with_model(model).handle(&["status", "--format", "{serial} {fw} {user_retries}"])?;
+  assert!(re.is_match(&out), out);
+  Ok(())
+}
+
+#[test_device]
+fn all_devices_not_found() {
+  let res = Nitrocli::new().handle(&["--all-devices", "status"]);
+  let err = res.unwrap_err().to_string();
+  assert_eq!(err, "Nitrokey device not found");
+}
+
+#[test_device(pro)]
+fn all_devices(model: nitrokey::Model) -> anyhow::Result<()> {
+  let re = regex::Regex::new(
+    r#"^Device 0x[[:xdigit:]]{8} \(.+\):
+Status:
+  model:             Pro
+"#,
+  )
+  .unwrap();
+
+  let out = Nitrocli::with_model(model).handle(&["--all-devices", "status"])?;
+  assert!(re.is_match(&out), out);
+  Ok(())
+}
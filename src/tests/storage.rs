@@ -0,0 +1,64 @@
+// storage.rs
+
+// *************************************************************************
+// * Copyright (C) 2020 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+use super::*;
+
+#[test_device(pro)]
+fn info_pro(model: nitrokey::Model) {
+  let res = Nitrocli::with_model(model).handle(&["storage", "info"]);
+  assert_eq!(
+    res.unwrap_err().to_string(),
+    "This command is only available on the Nitrokey Storage"
+  );
+}
+
+#[test_device(storage)]
+fn info(model: nitrokey::Model) -> anyhow::Result<()> {
+  let re = regex::Regex::new(
+    r#"^Storage:
+  CPU ID:                  0x[[:xdigit:]]+
+  firmware version:        v\d+\.\d+ \(internal \d+\)
+  SD card:
+    serial number:         0x[[:xdigit:]]+
+    size:                  \d+ GB
+    manufacturer:          0x[[:xdigit:]]{2}
+    OEM:                   0x[[:xdigit:]]{4}
+    manufacturing date:    20\d{2}-\d{2}
+    new card warning:      (yes|no)
+    filled with random:    (yes|no)
+$"#,
+  )
+  .unwrap();
+
+  let out = Nitrocli::with_model(model).handle(&["storage", "info"])?;
+  assert!(re.is_match(&out), out);
+  Ok(())
+}
+
+#[test_device(storage)]
+fn clear_warning(model: nitrokey::Model) -> anyhow::Result<()> {
+  let mut ncli = Nitrocli::with_model(model);
+  let out = ncli.handle(&["storage", "clear-warning"])?;
+  assert!(out.is_empty());
+
+  let mut manager = nitrokey::force_take()?;
+  let device = manager.connect_storage()?;
+  assert!(!device.get_storage_status()?.new_sd_card_found);
+  Ok(())
+}
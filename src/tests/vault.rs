@@ -0,0 +1,106 @@
+// vault.rs
+
+// *************************************************************************
+// * Copyright (C) 2020 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+use std::env;
+use std::fs;
+use std::path;
+
+use super::*;
+
+/// Create the path of a temporary vault file, removing any existing
+/// file.
+fn vault_path(name: &str) -> path::PathBuf {
+  let path = env::temp_dir().join(format!("nitrocli-vault-{}-{}", name, std::process::id()));
+  let _ = fs::remove_file(&path);
+  path
+}
+
+#[test]
+fn missing_vault() {
+  let path = vault_path("missing");
+  let err = Nitrocli::new()
+    .handle(&["vault", "list", "--file", path.to_str().unwrap()])
+    .unwrap_err()
+    .to_string();
+  assert_eq!(err, format!("Failed to read vault {}", path.display()));
+}
+
+#[test]
+fn invalid_vault() {
+  let path = vault_path("invalid");
+  fs::write(&path, "foobar").unwrap();
+  let err = Nitrocli::new()
+    .handle(&["vault", "list", "--file", path.to_str().unwrap()])
+    .unwrap_err()
+    .to_string();
+  let _ = fs::remove_file(&path);
+  assert_eq!(err, format!("Failed to read vault {}", path.display()));
+}
+
+#[test_device]
+fn init_add_get_rm(model: nitrokey::Model) -> anyhow::Result<()> {
+  let path = vault_path("device");
+  let file = path.to_str().unwrap();
+
+  let mut ncli = Nitrocli::with_model(model);
+  let _ = ncli.handle(&["reset", "--yes"])?;
+  let _ = ncli.handle(&["pws", "set", "0", "other", "login", "password"])?;
+
+  let out = ncli.handle(&["vault", "init", "--file", file])?;
+  assert_eq!(
+    out,
+    format!("Created vault {} with its key in PWS slot 1\n", file)
+  );
+  let res = ncli.handle(&["vault", "init", "--file", file]);
+  assert_eq!(
+    res.unwrap_err().to_string(),
+    format!("Vault {} already exists", file)
+  );
+
+  let _ = ncli.handle(&["vault", "add", "--file", file, "web", "john", "pass1"])?;
+  let _ = ncli.handle(&["vault", "add", "--file", file, "mail", "jane", "pass2"])?;
+  let res = ncli.handle(&["vault", "add", "--file", file, "mail", "x", "y"]);
+  assert_eq!(
+    res.unwrap_err().to_string(),
+    "Entry mail already exists in the vault"
+  );
+
+  let out = ncli.handle(&["vault", "list", "--file", file])?;
+  assert_eq!(out, "mail\nweb\n");
+  let out = ncli.handle(&["vault", "get", "--file", file, "web"])?;
+  assert_eq!(out, "name:     web\nlogin:    john\npassword: pass1\n");
+  let out = ncli.handle(&["vault", "get", "--file", file, "-q", "-p", "mail"])?;
+  assert_eq!(out, "pass2\n");
+
+  let _ = ncli.handle(&["vault", "rm", "--file", file, "web"])?;
+  let out = ncli.handle(&["vault", "list", "--file", file])?;
+  assert_eq!(out, "mail\n");
+
+  // Without its key the vault cannot be opened anymore.
+  let _ = ncli.handle(&["pws", "clear", "1"])?;
+  let res = ncli.handle(&["vault", "list", "--file", file]);
+  let _ = fs::remove_file(&path);
+  let err = res.unwrap_err().to_string();
+  assert!(
+    err.starts_with("No PWS slot holds the key of vault "),
+    "{}",
+    err
+  );
+  Ok(())
+}
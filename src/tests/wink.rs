@@ -0,0 +1,36 @@
+// wink.rs
+
+// *************************************************************************
+// * Copyright (C) 2020 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+use super::*;
+
+#[test_device(pro)]
+fn wink_pro(model: nitrokey::Model) {
+  let res = Nitrocli::with_model(model).handle(&["wink"]);
+  assert_eq!(
+    res.unwrap_err().to_string(),
+    "This command is only available on the Nitrokey Storage"
+  );
+}
+
+#[test_device(storage)]
+fn wink_storage(model: nitrokey::Model) -> anyhow::Result<()> {
+  let out = Nitrocli::with_model(model).handle(&["wink"])?;
+  assert!(out.is_empty());
+  Ok(())
+}
@@ -0,0 +1,302 @@
+// vault.rs
+
+// *************************************************************************
+// * Copyright (C) 2020 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+use std::env;
+use std::fs;
+use std::io::Write as _;
+use std::os::unix::fs::OpenOptionsExt as _;
+use std::path;
+
+use anyhow::Context as _;
+use chacha20poly1305::aead::Aead as _;
+use chacha20poly1305::aead::NewAead as _;
+
+/// The name of the PWS slots holding the key of a vault.
+pub const SLOT_NAME: &str = "vault";
+
+/// The magic bytes identifying a vault file and its format version.
+const MAGIC: &[u8; 8] = b"NCVAULT\x01";
+/// The length of the identifier of a vault, in bytes.
+const ID_LEN: usize = 8;
+/// The length of the salt used for deriving the key, in bytes.
+const SALT_LEN: usize = 16;
+/// The length of the XChaCha20-Poly1305 nonce, in bytes.
+const NONCE_LEN: usize = 24;
+/// The length of the unencrypted header of a vault file.
+const HEADER_LEN: usize = MAGIC.len() + ID_LEN + SALT_LEN;
+
+/// The characters a vault secret is made of.
+const SECRET_CHARS: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789";
+/// The length of a vault secret, which is the maximum length of a PWS
+/// password.
+const SECRET_LEN: usize = 20;
+
+/// An entry stored in a vault.
+#[derive(Clone, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct Entry {
+  pub name: String,
+  pub login: String,
+  pub password: String,
+}
+
+/// A password vault stored on the host.
+///
+/// The entries of a vault are encrypted using XChaCha20-Poly1305 with a
+/// key derived from a secret stored in a PWS slot. The slot is
+/// identified by the `SLOT_NAME` name and the identifier of the vault
+/// as the login.
+#[derive(Debug)]
+pub struct Vault {
+  id: [u8; ID_LEN],
+  salt: [u8; SALT_LEN],
+  pub entries: Vec<Entry>,
+}
+
+/// Fill the given buffer with random data.
+fn random(buffer: &mut [u8]) -> anyhow::Result<()> {
+  getrandom::getrandom(buffer).context("Failed to generate random data")
+}
+
+/// Format the given bytes as a lower case hexadecimal string.
+fn hex(bytes: &[u8]) -> String {
+  bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Generate a new random secret for a vault.
+pub fn generate_secret() -> anyhow::Result<String> {
+  let mut secret = String::with_capacity(SECRET_LEN);
+  let mut buffer = [0; 1];
+  while secret.len() < SECRET_LEN {
+    random(&mut buffer)?;
+    // Reject values that would bias the distribution of characters.
+    let limit = 256 - 256 % SECRET_CHARS.len();
+    if usize::from(buffer[0]) < limit {
+      secret.push(char::from(
+        SECRET_CHARS[usize::from(buffer[0]) % SECRET_CHARS.len()],
+      ));
+    }
+  }
+  Ok(secret)
+}
+
+/// Read the identifier of the vault in the given file data.
+pub fn id(data: &[u8]) -> anyhow::Result<String> {
+  if data.len() < HEADER_LEN + NONCE_LEN || !data.starts_with(MAGIC) {
+    anyhow::bail!("The data is not a vault")
+  }
+  Ok(hex(&data[MAGIC.len()..MAGIC.len() + ID_LEN]))
+}
+
+/// Return the default path of the vault file.
+pub fn default_path() -> anyhow::Result<path::PathBuf> {
+  let dir = match env::var_os("XDG_DATA_HOME") {
+    Some(dir) => path::PathBuf::from(dir),
+    None => env::var_os("HOME")
+      .map(|home| path::PathBuf::from(home).join(".local").join("share"))
+      .ok_or_else(|| anyhow::anyhow!("Failed to determine the home directory"))?,
+  };
+  Ok(dir.join("nitrocli").join("vault"))
+}
+
+impl Vault {
+  /// Create a new, empty vault with a random identifier.
+  pub fn new() -> anyhow::Result<Self> {
+    let mut id = [0; ID_LEN];
+    let mut salt = [0; SALT_LEN];
+    random(&mut id)?;
+    random(&mut salt)?;
+    Ok(Self {
+      id,
+      salt,
+      entries: Vec::new(),
+    })
+  }
+
+  /// Retrieve the identifier of the vault.
+  pub fn id(&self) -> String {
+    hex(&self.id)
+  }
+
+  /// Derive the encryption key from the given secret.
+  fn cipher(salt: &[u8], secret: &str) -> chacha20poly1305::XChaCha20Poly1305 {
+    use sha2::Digest as _;
+
+    let key = sha2::Sha256::new()
+      .chain(MAGIC)
+      .chain(salt)
+      .chain(secret.as_bytes())
+      .finalize();
+    chacha20poly1305::XChaCha20Poly1305::new(&key)
+  }
+
+  /// Decrypt the vault in the given file data using the given secret.
+  pub fn decrypt(data: &[u8], secret: &str) -> anyhow::Result<Self> {
+    let _ = id(data)?;
+    let (header, rest) = data.split_at(HEADER_LEN);
+    let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+
+    let mut vault = Self {
+      id: [0; ID_LEN],
+      salt: [0; SALT_LEN],
+      entries: Vec::new(),
+    };
+    vault
+      .id
+      .copy_from_slice(&header[MAGIC.len()..MAGIC.len() + ID_LEN]);
+    vault.salt.copy_from_slice(&header[MAGIC.len() + ID_LEN..]);
+
+    let payload = chacha20poly1305::aead::Payload {
+      msg: ciphertext,
+      aad: header,
+    };
+    let plaintext = Self::cipher(&vault.salt, secret)
+      .decrypt(chacha20poly1305::XNonce::from_slice(nonce), payload)
+      .map_err(|_| anyhow::anyhow!("The vault has been modified or the key is wrong"))?;
+    vault.entries =
+      serde_json::from_slice(&plaintext).context("Failed to parse the vault entries")?;
+    Ok(vault)
+  }
+
+  /// Encrypt the vault using the given secret.
+  pub fn encrypt(&self, secret: &str) -> anyhow::Result<Vec<u8>> {
+    let mut header = Vec::with_capacity(HEADER_LEN);
+    header.extend_from_slice(MAGIC);
+    header.extend_from_slice(&self.id);
+    header.extend_from_slice(&self.salt);
+
+    let mut nonce = [0; NONCE_LEN];
+    random(&mut nonce)?;
+
+    let plaintext = serde_json::to_vec(&self.entries).context("Failed to serialize the vault")?;
+    let payload = chacha20poly1305::aead::Payload {
+      msg: &plaintext,
+      aad: &header,
+    };
+    let ciphertext = Self::cipher(&self.salt, secret)
+      .encrypt(chacha20poly1305::XNonce::from_slice(&nonce), payload)
+      .map_err(|_| anyhow::anyhow!("Failed to encrypt the vault"))?;
+
+    let mut data = header;
+    data.extend_from_slice(&nonce);
+    data.extend_from_slice(&ciphertext);
+    Ok(data)
+  }
+
+  /// Find the entry with the given name.
+  pub fn find(&self, name: &str) -> Option<&Entry> {
+    self.entries.iter().find(|entry| entry.name == name)
+  }
+}
+
+/// Read the vault file at the given path.
+pub fn read(path: &path::Path) -> anyhow::Result<Vec<u8>> {
+  fs::read(path).with_context(|| format!("Failed to read vault {}", path.display()))
+}
+
+/// Write the given data to the vault file at the given path.
+///
+/// The data is written to a temporary file first, which then replaces
+/// the vault file, so that the vault is never left partially written.
+pub fn write(path: &path::Path, data: &[u8]) -> anyhow::Result<()> {
+  if let Some(dir) = path.parent() {
+    fs::create_dir_all(dir)
+      .with_context(|| format!("Failed to create directory {}", dir.display()))?;
+  }
+
+  let mut tmp = path.as_os_str().to_owned();
+  tmp.push(".tmp");
+  let tmp = path::PathBuf::from(tmp);
+  let mut file = fs::OpenOptions::new()
+    .write(true)
+    .create(true)
+    .truncate(true)
+    .mode(0o600)
+    .open(&tmp)
+    .with_context(|| format!("Failed to create {}", tmp.display()))?;
+  file
+    .write_all(data)
+    .and_then(|_| file.sync_all())
+    .with_context(|| format!("Failed to write {}", tmp.display()))?;
+  fs::rename(&tmp, path).with_context(|| format!("Failed to write vault {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn entry(name: &str) -> Entry {
+    Entry {
+      name: name.to_string(),
+      login: format!("{}-login", name),
+      password: format!("{}-password", name),
+    }
+  }
+
+  #[test]
+  fn secret() {
+    let secret = generate_secret().unwrap();
+    assert_eq!(secret.len(), SECRET_LEN);
+    assert!(secret.bytes().all(|c| SECRET_CHARS.contains(&c)));
+    assert_ne!(secret, generate_secret().unwrap());
+  }
+
+  #[test]
+  fn encrypt_decrypt() {
+    let mut vault = Vault::new().unwrap();
+    vault.entries.push(entry("foo"));
+    vault.entries.push(entry("bar"));
+
+    let data = vault.encrypt("secret").unwrap();
+    assert_eq!(id(&data).unwrap(), vault.id());
+    assert_eq!(vault.id().len(), 2 * ID_LEN);
+    // A new nonce is used for every encryption.
+    assert_ne!(data, vault.encrypt("secret").unwrap());
+
+    let decrypted = Vault::decrypt(&data, "secret").unwrap();
+    assert_eq!(decrypted.id(), vault.id());
+    assert_eq!(decrypted.entries, vault.entries);
+    assert_eq!(decrypted.find("bar"), Some(&entry("bar")));
+    assert_eq!(decrypted.find("baz"), None);
+  }
+
+  #[test]
+  fn decrypt_invalid() {
+    let vault = Vault::new().unwrap();
+    let data = vault.encrypt("secret").unwrap();
+
+    let err = Vault::decrypt(&data, "wrong").unwrap_err();
+    assert_eq!(
+      err.to_string(),
+      "The vault has been modified or the key is wrong"
+    );
+
+    // The header is authenticated as well.
+    let mut modified = data.clone();
+    modified[MAGIC.len()] ^= 1;
+    assert!(Vault::decrypt(&modified, "secret").is_err());
+
+    let mut modified = data;
+    let last = modified.len() - 1;
+    modified[last] ^= 1;
+    assert!(Vault::decrypt(&modified, "secret").is_err());
+
+    let err = Vault::decrypt(b"foobar", "secret").unwrap_err();
+    assert_eq!(err.to_string(), "The data is not a vault");
+  }
+}